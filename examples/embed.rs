@@ -0,0 +1,36 @@
+//! Runs a task of a throwaway project from Rust and prints its progress.
+//!
+//! cargo run --example embed
+
+use pavidi::{Config, Event, LoadOptions, RunOptions, Runner, TaskGraph};
+use std::fs;
+use std::sync::Arc;
+
+fn main() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("pavidi_example_{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("p.toml"), r#"
+[runner]
+setup = "echo preparing"
+build = { cmds = ["echo compiling", "echo linking"], deps = ["setup"] }
+"#)?;
+
+    let config = Config::load(&dir.join("p.toml"), LoadOptions::FULL)?;
+    let plan = TaskGraph::plan(&config, "build")?;
+    for step in &plan.steps {
+        println!("plan: {} ({} command(s))", step.task, step.commands.len());
+    }
+
+    let result = Runner::new(config).run(&plan, RunOptions {
+        capture: Some(Box::new(std::io::sink())),
+        events: Some(Arc::new(|event: &Event| match event {
+            Event::TaskStarted { task } => println!("started  {}", task),
+            Event::CommandStarted { task, command } => println!("[{}] $ {}", task, command),
+            Event::OutputChunk { task, line, .. } => println!("[{}] {}", task, line),
+            Event::TaskFinished { task, status, duration, .. } => println!("finished {} ({:?}, {:?})", task, status, duration),
+        })),
+        ..Default::default()
+    });
+    fs::remove_dir_all(&dir)?;
+    result
+}
@@ -1,12 +1,44 @@
-use clap::Parser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::path::PathBuf;
+use crate::runner::report::{parse_report_spec, ReportSpec};
+use crate::utils::TimestampStyle;
 
 #[derive(Parser)]
 #[command(name = "p", version, about = "Pavidi: Minimalist Project Runner")]
 pub struct Cli {
+    /// Run as if p was started in DIR
+    #[arg(short = 'C', long = "directory", value_name = "DIR", global = true)]
+    pub directory: Option<PathBuf>,
+
+    /// Use this configuration file instead of p.toml (also P_CONFIG)
+    #[arg(long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Show progress messages (-v), plus expanded commands, env and cache decisions (-vv)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// When to color output; `auto` follows NO_COLOR, CLICOLOR_FORCE and whether output is a terminal
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto, global = true)]
+    pub color: ColorChoice,
+
+    /// Print --list and --env output directly, even when it is longer than the terminal (see [project] pager)
+    #[arg(long, global = true)]
+    pub no_pager: bool,
+
     /// List all available tasks
     #[arg(short, long)]
     pub list: bool,
 
+    /// With --list: show whether each task is up to date, its last result and missing required files
+    #[arg(long, requires = "list")]
+    pub status: bool,
+
     /// Inspect environment variables
     #[arg(short, long)]
     pub env: bool,
@@ -19,17 +51,289 @@ pub struct Cli {
     #[arg(short = 'i', long = "info")]
     pub info: bool,
 
+    /// Print --list, --info, --env or --dry-run output as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// With --env: print `export KEY='value'` lines for the resolved environment
+    #[arg(long, requires = "env", conflicts_with_all = ["json", "dotenv"])]
+    pub export: bool,
+
+    /// With --env: print the resolved environment as a .env file
+    #[arg(long, requires = "env", conflicts_with = "json")]
+    pub dotenv: bool,
+
+    /// Shell syntax for --export
+    #[arg(long, value_enum, default_value_t = ExportShell::Posix, requires = "export")]
+    pub shell: ExportShell,
+
+    /// With --env: print exactly the environment task commands receive, after [env_policy]
+    #[arg(long, requires = "env", conflicts_with_all = ["export", "dotenv"])]
+    pub effective: bool,
+
+    /// With --env: list the profiles found in `.env.<profile>` files
+    #[arg(long, requires = "env", conflicts_with_all = ["json", "export", "dotenv", "effective"])]
+    pub profiles: bool,
+
+    /// With --env: save PROFILE in `.p/profile` as the default when P_ENV is not set
+    #[arg(long = "use", value_name = "PROFILE", requires = "env", conflicts_with_all = ["profiles", "unset", "json", "export", "dotenv", "effective"])]
+    pub use_profile: Option<String>,
+
+    /// With --env: forget the profile saved with --use
+    #[arg(long, requires = "env", conflicts_with_all = ["profiles", "json", "export", "dotenv", "effective"])]
+    pub unset: bool,
+
+    /// With --env: save KEY=VALUE for this project in `.p/session-env.toml` (repeatable)
+    #[arg(long, value_name = "KEY=VALUE", requires = "env", conflicts_with_all = ["profiles", "use_profile", "unset", "json", "export", "dotenv", "effective"])]
+    pub set: Vec<String>,
+
+    /// With --env: remove KEY from the variables saved with --set (repeatable)
+    #[arg(long, value_name = "KEY", requires = "env", conflicts_with_all = ["profiles", "use_profile", "unset", "json", "export", "dotenv", "effective"])]
+    pub unset_var: Vec<String>,
+
+    /// Include values that look like secrets in --export/--dotenv/--effective output
+    #[arg(long)]
+    pub include_secrets: bool,
+
     /// Run in dry-run mode (print commands without executing)
     #[arg(short = 'd', long = "dry-run")]
     pub dry_run: bool,
 
-    /// The task to run (defaults to "default")
+    /// With --dry-run: show how the plan differs from the last run, command by command
+    #[arg(long, requires = "dry_run")]
+    pub diff: bool,
+
+    /// Print the task's own commands, expanded as a run would, one per line (no deps, nothing run)
+    #[arg(long, conflicts_with_all = ["dry_run", "json", "list", "info", "env", "group"])]
+    pub list_cmds: bool,
+
+    /// Write a run report, e.g. `junit=report.xml` or `json=report.json` (repeatable)
+    #[arg(long, value_name = "FORMAT=PATH", value_parser = parse_report_spec)]
+    pub report: Vec<ReportSpec>,
+
+    /// Write a Chrome trace-event timeline of the run (open in Perfetto or chrome://tracing)
+    #[arg(long, value_name = "PATH")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Fail before running if the programs in p.lock.toml are missing or have other versions
+    #[arg(long)]
+    pub locked: bool,
+
+    /// Continue a task from the command that failed in its last run, skipping the ones that succeeded
+    #[arg(long, conflicts_with = "dry_run")]
+    pub resume: bool,
+
+    /// Prefix each line of command output with the time (`clock`, the default) or the time since p started (`relative`)
+    #[arg(long, value_enum, value_name = "STYLE", num_args = 0..=1, require_equals = true, default_missing_value = "clock")]
+    pub timestamps: Option<TimestampStyle>,
+
+    /// Wait for another run of this project to finish instead of failing (see [project] lock)
+    #[arg(long, conflicts_with = "dry_run")]
+    pub wait: bool,
+
+    /// Let p:rm, p:cp and p:mv change files outside the project directory
+    #[arg(long)]
+    pub allow_outside: bool,
+
+    /// Write newline-delimited JSON progress events to this open file descriptor (unix), e.g. `3`
+    #[arg(long, value_name = "FD", conflicts_with_all = ["progress_pipe", "dry_run"])]
+    pub progress_fd: Option<i32>,
+
+    /// Write newline-delimited JSON progress events to this named pipe or file
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    pub progress_pipe: Option<PathBuf>,
+
+    /// Run every task named GROUP:<name>, in the order they are declared (same as `p GROUP:`)
+    #[arg(long, value_name = "GROUP", conflicts_with = "TASK")]
+    pub group: Option<String>,
+
+    /// The task to run (defaults to "default"); `GROUP:` runs a whole group, and with --list it lists one group
     #[arg(name = "TASK")]
     pub task: Option<String>,
 
     /// Arguments to pass to the task
     #[arg(last = true)]
     pub args: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ExportShell {
+    /// sh, bash, zsh
+    Posix,
+    Fish,
+    Powershell,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TargetOs {
+    Windows,
+    Linux,
+    Macos,
+}
+
+impl TargetOs {
+    /// The task field holding this OS's commands.
+    pub fn key(self) -> &'static str {
+        match self {
+            TargetOs::Windows => "windows",
+            TargetOs::Linux => "linux",
+            TargetOs::Macos => "macos",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// List, view and tail execution logs
+    Logs(LogsArgs),
+    /// Show the task dependency graph
+    Graph(GraphArgs),
+    /// Check the project and environment for common problems
+    Doctor {
+        /// Create missing state directories and restore their write permission
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Add a task to p.toml
+    NewTask(NewTaskArgs),
+    /// Remove a task from p.toml
+    RemoveTask {
+        #[arg(name = "TASK")]
+        task: String,
+    },
+    /// Show the outcome of the last run; exits non-zero if it failed
+    Status {
+        /// Only succeed if this task took part in the last run and did not fail
+        task: Option<String>,
+
+        /// Print .p/status.json as it is
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rewrite p.toml and its extensions in the canonical layout
+    Fmt {
+        /// Only show what would change; exit non-zero if anything would
+        #[arg(long)]
+        check: bool,
+
+        /// Format only this file (e.g. p.toml) instead of p.toml and every p.*.toml
+        #[arg(long, value_name = "FILE")]
+        only: Option<PathBuf>,
+    },
+    /// Record the versions of the programs tasks run in p.lock.toml
+    Lock,
+    /// Run a task if needed and copy the artifacts it declares into a directory, with a manifest
+    Artifacts {
+        /// Collect the artifacts of this task and its dependencies (default: every task that declares some)
+        task: Option<String>,
+
+        /// Directory to copy the artifacts into, keeping their paths
+        #[arg(long, value_name = "DIR", required_unless_present = "check")]
+        out: Option<PathBuf>,
+
+        /// Only verify that the artifacts exist and are newer than their task's sources; runs nothing
+        #[arg(long, conflicts_with = "out")]
+        check: bool,
+    },
+    /// Download and verify the files listed in extends_remote into the cache
+    FetchRemotes {
+        /// Download them again even when cached
+        #[arg(long)]
+        refresh: bool,
+    },
+    /// Replace this binary with the latest release (or --version X.Y.Z)
+    Upgrade {
+        /// Only report whether a newer release exists
+        #[arg(long)]
+        check: bool,
+
+        /// Install this release instead of the latest, e.g. 0.3.1 (also to downgrade)
+        #[arg(long, value_name = "X.Y.Z")]
+        version: Option<String>,
+    },
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Args)]
+pub struct NewTaskArgs {
+    #[arg(name = "TASK")]
+    pub task: String,
+
+    /// A command to run; after --os, a command for that OS only (repeatable)
+    #[arg(long = "cmd", value_name = "CMD", required = true)]
+    pub cmds: Vec<String>,
+
+    /// A task to run first (repeatable)
+    #[arg(long = "dep", value_name = "TASK")]
+    pub deps: Vec<String>,
+
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// Give the following --cmd values to this OS (repeatable)
+    #[arg(long, value_enum, value_name = "OS")]
+    pub os: Vec<TargetOs>,
+
+    /// Replace the task if it already exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct GraphArgs {
+    /// Only show this task and its dependencies
+    #[arg(name = "TASK")]
+    pub task: Option<String>,
+
+    /// Emit Graphviz DOT instead of a tree
+    #[arg(long)]
+    pub dot: bool,
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct LogsArgs {
+    #[command(subcommand)]
+    pub action: Option<LogsAction>,
+
+    /// Only show logs for this task
+    #[arg(name = "TASK")]
+    pub task: Option<String>,
+
+    /// Only show logs of failed runs (non-zero exit code)
+    #[arg(long)]
+    pub failed: bool,
+
+    /// Number of entries to list
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+
+    /// Follow the most recent log of TASK as it grows
+    #[arg(short = 'f', long, requires = "TASK")]
+    pub follow: bool,
+}
+
+#[derive(Subcommand)]
+pub enum LogsAction {
+    /// Print a log by index (1 = most recent) or id
+    Show {
+        /// Index from `p logs` or the log id
+        id: String,
+    },
 }
 
 #[cfg(test)]
@@ -41,4 +345,65 @@ mod tests {
     fn verify_cli() {
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn parse_logs_subcommand() {
+        let cli = Cli::try_parse_from(["p", "logs", "build", "--failed"]).unwrap();
+        match cli.command {
+            Some(Commands::Logs(args)) => {
+                assert_eq!(args.task.as_deref(), Some("build"));
+                assert!(args.failed);
+                assert!(args.action.is_none());
+            },
+            _ => panic!("expected logs subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(["p", "logs", "show", "3"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Logs(LogsArgs { action: Some(LogsAction::Show { .. }), .. }))));
+
+        let cli = Cli::try_parse_from(["p", "graph", "ci", "--dot"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Graph(GraphArgs { dot: true, task: Some(_) }))));
+
+        // -C and --config are global, so they also work after a subcommand
+        let cli = Cli::try_parse_from(["p", "graph", "-C", "../app", "--config", "ci.toml"]).unwrap();
+        assert_eq!(cli.directory.as_deref(), Some(std::path::Path::new("../app")));
+        assert_eq!(cli.config.as_deref(), Some(std::path::Path::new("ci.toml")));
+
+        let cli = Cli::try_parse_from(["p", "-vv", "build"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+        let cli = Cli::try_parse_from(["p", "logs", "-q"]).unwrap();
+        assert!(cli.quiet);
+        assert!(Cli::try_parse_from(["p", "-q", "-v", "build"]).is_err());
+
+        let cli = Cli::try_parse_from(["p", "upgrade", "--version", "0.3.1"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Upgrade { check: false, version: Some(_) })));
+
+        let cli = Cli::try_parse_from(["p", "--group", "db"]).unwrap();
+        assert_eq!(cli.group.as_deref(), Some("db"));
+        assert!(Cli::try_parse_from(["p", "--group", "db", "build"]).is_err());
+
+        let cli = Cli::try_parse_from(["p", "--locked", "build"]).unwrap();
+        assert!(cli.locked);
+
+        let cli = Cli::try_parse_from(["p", "doctor", "--fix"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Doctor { fix: true })));
+
+        // Plain task names still run tasks
+        let cli = Cli::try_parse_from(["p", "build"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.task.as_deref(), Some("build"));
+    }
+
+    #[test]
+    fn parse_report_flags() {
+        let cli = Cli::try_parse_from(["p", "--report", "junit=out.xml", "--report", "json=out.json", "test"]).unwrap();
+        assert_eq!(cli.report.len(), 2);
+        assert_eq!(cli.task.as_deref(), Some("test"));
+        assert!(Cli::try_parse_from(["p", "--report", "html=out.html", "test"]).is_err());
+
+        // --trace stays a switch; the timeline has its own flag
+        let cli = Cli::try_parse_from(["p", "--trace", "--trace-file", "trace.json", "test"]).unwrap();
+        assert!(cli.trace);
+        assert_eq!(cli.trace_file, Some(PathBuf::from("trace.json")));
+    }
 }
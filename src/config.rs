@@ -1,29 +1,177 @@
 use anyhow::{Context, Result, bail};
 use colored::*;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use indexmap::IndexMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::env;
-use crate::runner::task::RunnerTask;
+use std::sync::{LazyLock, Mutex, Once};
 use regex::Regex;
-use crate::utils::{run_shell_command, CaptureMode, detect_shell};
+use crate::runner::task::{unknown_keys, RunnerTask, TaskCapabilities};
+use crate::runner::capability::canonicalize_rule;
+use crate::runner::portable::builtin_output;
+use log::info;
+use crate::utils::{run_shell_command, TimestampStyle, CaptureMode, CommandSettings, EnvFilter, DEFAULT_BUFFER_LIMIT_KB, detect_shell};
+use crate::diagnostic::Diagnostic;
+use crate::notify::parse_duration;
+use crate::process::DEFAULT_GRACE_PERIOD;
+use crate::ui::{glyph, Glyph, UiStyle};
+use crate::remote_config::{self, RemoteInclude};
+use crate::state::read_state;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct PavidiConfig {
     pub project: Option<ProjectConfig>,
     pub module: Option<ModuleConfig>,
     pub capability: Option<CapabilityConfig>,
+    pub notify: Option<NotifyConfig>,
+    pub env_policy: Option<EnvPolicy>,
     #[serde(default)] 
-    pub env: HashMap<String, String>,
-    pub runner: Option<HashMap<String, RunnerTask>>,
+    pub env: IndexMap<String, String>,
+    /// Types, allowed values and defaults of env vars, checked before tasks run
+    #[serde(default)]
+    pub env_schema: IndexMap<String, EnvSpec>,
+    #[serde(default, deserialize_with = "crate::runner::task::deserialize_tasks")]
+    pub runner: Option<IndexMap<String, RunnerTask>>,
+    /// Task tables with `{{param.NAME}}` placeholders, used by `[runner.x] template = "name"`
+    #[serde(default)]
+    pub template: IndexMap<String, toml::Value>,
+    /// Extensions only: when the file applies
+    pub when: Option<ExtensionGuard>,
+    /// `p.toml` only: config files fetched by URL and merged before the extensions
+    #[serde(default)]
+    pub extends_remote: Vec<RemoteInclude>,
 
     #[serde(skip)]
-    pub env_provenance: HashMap<String, Vec<(String, String)>>,
+    pub env_provenance: IndexMap<String, Vec<(String, String)>>,
     #[serde(skip)]
     pub extensions_applied: Vec<(String, Metadata)>,
+    /// Extensions whose `[when]` did not match: `(file name, reason)`
+    #[serde(skip)]
+    pub extensions_skipped: Vec<(String, String)>,
+    /// `.env` files layered over `[env]`, in the order they were read
+    #[serde(skip)]
+    pub dotenv_applied: Vec<String>,
+    /// `P_ENV`, or the profile saved with `p e --use`
+    #[serde(skip)]
+    pub profile: Option<ActiveProfile>,
+    /// Tasks made from a template: `(task, template)`
+    #[serde(skip)]
+    pub task_templates: IndexMap<String, String>,
     #[serde(skip)]
     pub original_metadata: Option<Metadata>,
+    /// Directory containing `p.toml`
+    #[serde(skip)]
+    pub root_dir: PathBuf,
+    /// How commands run, checked when the config is loaded
+    #[serde(skip)]
+    pub command_settings: CommandSettings,
+}
+
+/// The `[when]` table of an extension. Every given condition must hold for the file to be merged.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExtensionGuard {
+    /// `linux`, `macos` or `windows`
+    pub os: Option<Vec<String>>,
+    /// Variables that must be set to a non-empty value
+    pub env_set: Option<Vec<String>>,
+    /// Variables that must have exactly these values
+    pub env: Option<IndexMap<String, String>>,
+}
+
+impl ExtensionGuard {
+    /// Why the extension does not apply on `os` with the environment `var`, if it does not.
+    pub fn skip_reason(&self, os: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+        if let Some(oses) = &self.os
+            && !oses.iter().any(|o| o == os) {
+            return Some(format!("os is {}, not {}", os, oses.join(" or ")));
+        }
+        for key in self.env_set.iter().flatten() {
+            if var(key).is_none_or(|v| v.is_empty()) {
+                return Some(format!("{} is not set", key));
+            }
+        }
+        for (key, expected) in self.env.iter().flatten() {
+            match var(key) {
+                Some(value) if value == *expected => {},
+                Some(value) => return Some(format!("{} is '{}', not '{}'", key, value, expected)),
+                None => return Some(format!("{} is not set (expected '{}')", key, expected)),
+            }
+        }
+        None
+    }
+}
+
+/// One variable of `[env_schema]`.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EnvSpec {
+    #[serde(rename = "type", default)]
+    pub kind: EnvType,
+    /// Used when no layer sets the variable and p was not started with it
+    pub default: Option<String>,
+    #[serde(rename = "enum")]
+    pub allowed: Option<Vec<String>>,
+    /// Defaults to `true` unless there is a `default`
+    pub required: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl EnvType {
+    fn name(self) -> &'static str {
+        match self {
+            EnvType::String => "string",
+            EnvType::Int => "int",
+            EnvType::Float => "float",
+            EnvType::Bool => "bool",
+        }
+    }
+
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            EnvType::String => true,
+            EnvType::Int => value.parse::<i64>().is_ok(),
+            EnvType::Float => value.parse::<f64>().is_ok(),
+            EnvType::Bool => ["true", "false", "1", "0", "yes", "no", "on", "off"].contains(&value.to_ascii_lowercase().as_str()),
+        }
+    }
+}
+
+impl EnvSpec {
+    /// `int`, or `staging | prod` for an enum.
+    pub fn describe(&self) -> String {
+        match &self.allowed {
+            Some(allowed) => allowed.join(" | "),
+            None => self.kind.name().to_string(),
+        }
+    }
+
+    /// What is wrong with `value`, if anything. `None` means the variable is not set.
+    pub fn problem(&self, value: Option<&str>) -> Option<String> {
+        let Some(value) = value else {
+            let required = self.required.unwrap_or(self.default.is_none());
+            return required.then(|| "required but not set".to_string());
+        };
+        if !self.kind.accepts(value) {
+            return Some(format!("expected {}, got '{}'", self.kind.name(), value));
+        }
+        if let Some(allowed) = &self.allowed
+            && !allowed.iter().any(|a| a == value) {
+            return Some(format!("'{}' is not one of {}", value, allowed.join(", ")));
+        }
+        None
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -42,97 +190,728 @@ pub enum LogStrategy {
     None,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    Json,
+    Both,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogMode {
+    PerCommand,
+    Combined,
+}
+
+/// What a run does when another run of the project holds `.p/lock`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockMode {
+    #[default]
+    Fail,
+    Wait,
+    Off,
+}
+
+/// Which files a `sources` scan leaves out besides `exclude`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourcesIgnore {
+    /// Everything the globs match
+    #[default]
+    None,
+    /// What `.gitignore`, `.ignore` and `.git/info/exclude` ignore is left out
+    Gitignore,
+}
+
+/// `timestamps = true` (wall clock) or `"clock"` / `"relative"`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum TimestampSetting {
+    Enabled(bool),
+    Style(TimestampStyle),
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct ProjectConfig {
     #[serde(flatten)]
     pub metadata: Metadata,
     pub shell: Option<String>,
     pub log_strategy: Option<LogStrategy>,
     pub log_plain: Option<bool>,
+    pub log_format: Option<LogFormat>,
+    pub log_mode: Option<LogMode>,
+    pub log_dir: Option<String>,
+    pub ascii: Option<bool>,
+    pub ui: Option<UiStyle>,
+    /// `false` never pages `p --list` and `p --env` output
+    pub pager: Option<bool>,
     pub secret_patterns: Option<Vec<String>>,
+    /// Default `warn_after` for every task, e.g. "30s"
+    pub warn_after: Option<String>,
+    /// Binary for tasks with an `image`, e.g. "podman" (default "docker")
+    pub container_runtime: Option<String>,
+    /// KB of output kept per stream for parallel tasks' summaries (default 512)
+    pub buffer_limit_kb: Option<usize>,
+    /// Fail sh commands that reference an unset variable (`set -u`)
+    pub strict_env: Option<bool>,
+    /// `false` reads no `.env` files at all
+    pub dotenv: Option<bool>,
+    /// `.env` files to layer over `[env]`, in order, instead of the default cascade
+    pub dotenv_files: Option<Vec<String>>,
+    /// How long commands get to exit after SIGTERM before they are killed, e.g. "10s" (default 3s)
+    pub grace_period: Option<String>,
+    /// Commands run after a run is stopped by SIGINT or SIGTERM, once `finally` has run
+    pub on_abort: Option<Vec<String>>,
+    /// When another run holds `.p/lock`: "fail" (default), "wait" or "off"
+    pub lock: Option<LockMode>,
+    /// Prefix command output lines with the time: true, "clock" or "relative"
+    pub timestamps: Option<TimestampSetting>,
+    /// Write the timestamps into log files too
+    pub log_timestamps: Option<bool>,
+    /// Fail instead of warning when two tasks declare overlapping `outputs`
+    pub strict_outputs: Option<bool>,
+    /// Fail instead of warning when a task or template has a key p does not know
+    pub strict_keys: Option<bool>,
+    /// Default `sources_ignore` for every task: "gitignore" or "none"
+    pub sources_ignore: Option<SourcesIgnore>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct ModuleConfig {
     #[serde(flatten)]
     pub metadata: Metadata,
     pub shell: Option<String>,
     pub log_strategy: Option<LogStrategy>,
     pub log_plain: Option<bool>,
+    pub log_format: Option<LogFormat>,
+    pub log_mode: Option<LogMode>,
+    pub log_dir: Option<String>,
+    pub ascii: Option<bool>,
+    pub ui: Option<UiStyle>,
+    /// `false` never pages `p --list` and `p --env` output
+    pub pager: Option<bool>,
     pub secret_patterns: Option<Vec<String>>,
+    /// Default `warn_after` for every task, e.g. "30s"
+    pub warn_after: Option<String>,
+    /// Binary for tasks with an `image`, e.g. "podman" (default "docker")
+    pub container_runtime: Option<String>,
+    /// KB of output kept per stream for parallel tasks' summaries (default 512)
+    pub buffer_limit_kb: Option<usize>,
+    /// Fail sh commands that reference an unset variable (`set -u`)
+    pub strict_env: Option<bool>,
+    /// `false` reads no `.env` files at all
+    pub dotenv: Option<bool>,
+    /// `.env` files to layer over `[env]`, in order, instead of the default cascade
+    pub dotenv_files: Option<Vec<String>>,
+    /// How long commands get to exit after SIGTERM before they are killed, e.g. "10s" (default 3s)
+    pub grace_period: Option<String>,
+    /// Commands run after a run is stopped by SIGINT or SIGTERM, once `finally` has run
+    pub on_abort: Option<Vec<String>>,
+    /// When another run holds `.p/lock`: "fail" (default), "wait" or "off"
+    pub lock: Option<LockMode>,
+    /// Prefix command output lines with the time: true, "clock" or "relative"
+    pub timestamps: Option<TimestampSetting>,
+    /// Write the timestamps into log files too
+    pub log_timestamps: Option<bool>,
+    /// Fail instead of warning when two tasks declare overlapping `outputs`
+    pub strict_outputs: Option<bool>,
+    /// Fail instead of warning when a task or template has a key p does not know
+    pub strict_keys: Option<bool>,
+    /// Default `sources_ignore` for every task: "gitignore" or "none"
+    pub sources_ignore: Option<SourcesIgnore>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Enforcement {
+    Deny,
+    Prompt,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyOn {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NotifyConfig {
+    pub on: Option<Vec<NotifyOn>>,
+    pub desktop: Option<bool>,
+    pub webhook: Option<String>,
+    /// Minimum run time before notifying, e.g. "30s", "2m"
+    pub min_duration: Option<String>,
+}
+
+/// `[env_policy]`: which variables of p's own environment reach task commands.
+/// `[env]` and `.env` values are always passed.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct EnvPolicy {
+    /// Pass p's environment on (default true)
+    pub inherit: Option<bool>,
+    /// Glob patterns of variables still passed with `inherit = false`; PATH always is
+    pub allow: Option<Vec<String>>,
+    /// Glob patterns of variables never passed, PATH included
+    pub deny: Option<Vec<String>>,
+}
+
+/// `allow_net = false` denies all network access, `allow_net = ["github.com"]` limits it to hosts.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum NetPolicy {
+    Flag(bool),
+    Hosts(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct CapabilityConfig {
     pub allow_paths: Option<Vec<String>>,
+    pub allow_exec: Option<Vec<String>>,
+    pub deny_paths: Option<Vec<String>>,
+    pub read_only_paths: Option<Vec<String>>,
+    pub audit: Option<bool>,
+    pub enforcement: Option<Enforcement>,
+    pub allow_net: Option<NetPolicy>,
+}
+
+/// Resolves relative path entries against the project directory; `~/` expands to the home directory.
+fn resolve_relative(paths: &mut Option<Vec<String>>, dir: &Path) {
+    if let Some(paths) = paths {
+        for p in paths.iter_mut() {
+            *p = expand_path_vars(p, dir);
+            if let Some(rest) = p.strip_prefix("~/")
+                && let Some(home) = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+                *p = Path::new(&home).join(rest).to_string_lossy().into_owned();
+            } else if !Path::new(p.as_str()).is_absolute() {
+                *p = dir.join(p.as_str()).to_string_lossy().into_owned();
+            }
+        }
+    }
+}
+
+/// Variables p sets to the project directory, for commands and path fields.
+/// Tasks run in the directory of `p.toml`, so all three name it.
+pub const PATH_VARS: &[&str] = &["P_ROOT", "P_CONFIG_DIR", "P_TASK_DIR"];
+
+static PATH_VAR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\$\{(P_ROOT|P_CONFIG_DIR|P_TASK_DIR)\}|\$(P_ROOT|P_CONFIG_DIR|P_TASK_DIR)\b").unwrap()
+});
+
+/// The absolute form of the project directory `dir`, as `P_ROOT` holds it.
+pub fn project_dir(dir: &Path) -> PathBuf {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let absolute = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+    // `/work/app/.` reads better as `/work/app`
+    absolute.components().collect()
+}
+
+/// `${P_ROOT}`, `${P_CONFIG_DIR}` and `${P_TASK_DIR}` (or `$P_ROOT`, ...) in `value`
+/// replaced by the project directory `dir`. Other variables are left alone.
+pub fn expand_path_vars(value: &str, dir: &Path) -> String {
+    if !value.contains("$") {
+        return value.to_string();
+    }
+    let root = project_dir(dir);
+    PATH_VAR_RE.replace_all(value, regex::NoExpand(&root.to_string_lossy())).into_owned()
+}
+
+fn canonicalize_all(paths: &mut Option<Vec<String>>) {
+    if let Some(paths) = paths {
+        for p in paths.iter_mut() {
+            *p = canonicalize_rule(p);
+        }
+    }
+}
+
+impl CapabilityConfig {
+    /// Resolves relative entries against the project directory. Symlinks in them
+    /// are resolved later by [`CapabilityConfig::canonicalized`].
+    fn resolve_paths(&mut self, dir: &Path) {
+        for paths in [&mut self.allow_paths, &mut self.deny_paths, &mut self.read_only_paths] {
+            resolve_relative(paths, dir);
+        }
+    }
+
+    /// Returns a copy with every rule canonicalized, so checks can compare them
+    /// directly against canonicalized targets. Done when a task runs rather than
+    /// at load, so `--list` and completion never touch the rules' directories.
+    pub fn canonicalized(&self) -> CapabilityConfig {
+        let mut caps = self.clone();
+        for paths in [&mut caps.allow_paths, &mut caps.deny_paths, &mut caps.read_only_paths] {
+            canonicalize_all(paths);
+        }
+        caps
+    }
+
+    /// Returns a copy with a task's extra grants appended. Grants only widen
+    /// lists that exist: an unrestricted list stays unrestricted.
+    pub fn with_grants(&self, grants: &TaskCapabilities) -> CapabilityConfig {
+        let mut effective = self.clone();
+        let widen = |base: &mut Option<Vec<String>>, extra: &Option<Vec<String>>| {
+            if let (Some(base_items), Some(extra_items)) = (base.as_mut(), extra) {
+                for item in extra_items {
+                    if !base_items.contains(item) {
+                        base_items.push(item.clone());
+                    }
+                }
+            }
+        };
+        widen(&mut effective.allow_paths, &grants.allow_paths);
+        widen(&mut effective.allow_exec, &grants.allow_exec);
+        effective
+    }
+}
+
+fn merge_unique(base: &mut Option<Vec<String>>, ext: Option<Vec<String>>) {
+    if let Some(ext_items) = ext {
+        let base_items = base.get_or_insert(vec![]);
+        for item in ext_items {
+            if !base_items.contains(&item) {
+                base_items.push(item);
+            }
+        }
+    }
 }
 
 fn merge_configurations(base: &mut PavidiConfig, extension: PavidiConfig) {
     // Merge Env (Overwrite)
     base.env.extend(extension.env);
+    base.env_schema.extend(extension.env_schema);
+    base.template.extend(extension.template);
 
     // Merge Runner Tasks (Overwrite)
     if let Some(ext_runner) = extension.runner {
-        let base_runner = base.runner.get_or_insert_with(HashMap::new);
+        let base_runner = base.runner.get_or_insert_with(IndexMap::new);
         base_runner.extend(ext_runner);
     }
 
-    // Merge Capability (Allow Paths) - Append unique paths
+    // Merge Notify (Overwrite per field)
+    if let Some(ext_notify) = extension.notify {
+        let base_notify = base.notify.get_or_insert_with(NotifyConfig::default);
+        if let Some(on) = ext_notify.on { base_notify.on = Some(on); }
+        if let Some(d) = ext_notify.desktop { base_notify.desktop = Some(d); }
+        if let Some(w) = ext_notify.webhook { base_notify.webhook = Some(w); }
+        if let Some(m) = ext_notify.min_duration { base_notify.min_duration = Some(m); }
+    }
+
+    // Merge Env Policy - Append unique patterns, inherit overwrites
+    if let Some(ext_policy) = extension.env_policy {
+        let base_policy = base.env_policy.get_or_insert_with(EnvPolicy::default);
+        if let Some(i) = ext_policy.inherit { base_policy.inherit = Some(i); }
+        merge_unique(&mut base_policy.allow, ext_policy.allow);
+        merge_unique(&mut base_policy.deny, ext_policy.deny);
+    }
+
+    // Merge Capability - Append unique entries to every list
     if let Some(ext_cap) = extension.capability {
-        if let Some(ext_paths) = ext_cap.allow_paths {
-            let base_cap = base.capability.get_or_insert(CapabilityConfig { allow_paths: Some(vec![]) });
-            let base_paths = base_cap.allow_paths.get_or_insert(vec![]);
-            for p in ext_paths {
-                if !base_paths.contains(&p) {
-                    base_paths.push(p);
+        let base_cap = base.capability.get_or_insert_with(CapabilityConfig::default);
+        merge_unique(&mut base_cap.allow_paths, ext_cap.allow_paths);
+        merge_unique(&mut base_cap.allow_exec, ext_cap.allow_exec);
+        merge_unique(&mut base_cap.deny_paths, ext_cap.deny_paths);
+        merge_unique(&mut base_cap.read_only_paths, ext_cap.read_only_paths);
+        // Host lists are unioned; a boolean always replaces the previous policy
+        match (&mut base_cap.allow_net, ext_cap.allow_net) {
+            (Some(NetPolicy::Hosts(base_hosts)), Some(NetPolicy::Hosts(ext_hosts))) => {
+                for h in ext_hosts {
+                    if !base_hosts.contains(&h) {
+                        base_hosts.push(h);
+                    }
                 }
-            }
+            },
+            (base_net, Some(ext_net)) => *base_net = Some(ext_net),
+            (_, None) => {},
         }
+        if let Some(a) = ext_cap.audit { base_cap.audit = Some(a); }
+        if let Some(e) = ext_cap.enforcement { base_cap.enforcement = Some(e); }
     }
 
     // Merge Project Config (Settings only)
-    if let Some(ext_proj) = extension.project {
-        if let Some(base_proj) = &mut base.project {
-            if let Some(s) = ext_proj.shell { base_proj.shell = Some(s); }
-            if let Some(l) = ext_proj.log_strategy { base_proj.log_strategy = Some(l); }
-            if let Some(p) = ext_proj.log_plain { base_proj.log_plain = Some(p); }
+    if let Some(ext_proj) = extension.project
+        && let Some(base_proj) = &mut base.project {
+        if let Some(s) = ext_proj.shell { base_proj.shell = Some(s); }
+        if let Some(l) = ext_proj.log_strategy { base_proj.log_strategy = Some(l); }
+        if let Some(p) = ext_proj.log_plain { base_proj.log_plain = Some(p); }
+        if let Some(f) = ext_proj.log_format { base_proj.log_format = Some(f); }
+        if let Some(m) = ext_proj.log_mode { base_proj.log_mode = Some(m); }
+        if let Some(d) = ext_proj.log_dir { base_proj.log_dir = Some(d); }
+        if let Some(a) = ext_proj.ascii { base_proj.ascii = Some(a); }
+        if let Some(u) = ext_proj.ui { base_proj.ui = Some(u); }
+        if let Some(p) = ext_proj.pager { base_proj.pager = Some(p); }
+        if let Some(w) = ext_proj.warn_after { base_proj.warn_after = Some(w); }
+        if let Some(r) = ext_proj.container_runtime { base_proj.container_runtime = Some(r); }
+        if let Some(b) = ext_proj.buffer_limit_kb { base_proj.buffer_limit_kb = Some(b); }
+        if let Some(u) = ext_proj.strict_env { base_proj.strict_env = Some(u); }
+        if let Some(d) = ext_proj.dotenv { base_proj.dotenv = Some(d); }
+        if let Some(f) = ext_proj.dotenv_files { base_proj.dotenv_files = Some(f); }
+        if let Some(g) = ext_proj.grace_period { base_proj.grace_period = Some(g); }
+        if let Some(a) = ext_proj.on_abort { base_proj.on_abort = Some(a); }
+        if let Some(l) = ext_proj.lock { base_proj.lock = Some(l); }
+        if let Some(t) = ext_proj.timestamps { base_proj.timestamps = Some(t); }
+        if let Some(t) = ext_proj.log_timestamps { base_proj.log_timestamps = Some(t); }
+        if let Some(o) = ext_proj.strict_outputs { base_proj.strict_outputs = Some(o); }
+        if let Some(k) = ext_proj.strict_keys { base_proj.strict_keys = Some(k); }
+        if let Some(i) = ext_proj.sources_ignore { base_proj.sources_ignore = Some(i); }
             
-            // Append secret patterns
-            if let Some(ext_patterns) = ext_proj.secret_patterns {
-                let base_patterns = base_proj.secret_patterns.get_or_insert(vec![]);
-                base_patterns.extend(ext_patterns);
-            }
+        // Append secret patterns
+        if let Some(ext_patterns) = ext_proj.secret_patterns {
+            let base_patterns = base_proj.secret_patterns.get_or_insert(vec![]);
+            base_patterns.extend(ext_patterns);
         }
     }
 
     // Merge Module Config (Settings only)
-    if let Some(ext_mod) = extension.module {
-        if let Some(base_mod) = &mut base.module {
-            if let Some(s) = ext_mod.shell { base_mod.shell = Some(s); }
-            if let Some(l) = ext_mod.log_strategy { base_mod.log_strategy = Some(l); }
-            if let Some(p) = ext_mod.log_plain { base_mod.log_plain = Some(p); }
-
-            // Append secret patterns
-            if let Some(ext_patterns) = ext_mod.secret_patterns {
-                let base_patterns = base_mod.secret_patterns.get_or_insert(vec![]);
-                base_patterns.extend(ext_patterns);
+    if let Some(ext_mod) = extension.module
+        && let Some(base_mod) = &mut base.module {
+        if let Some(s) = ext_mod.shell { base_mod.shell = Some(s); }
+        if let Some(l) = ext_mod.log_strategy { base_mod.log_strategy = Some(l); }
+        if let Some(p) = ext_mod.log_plain { base_mod.log_plain = Some(p); }
+        if let Some(f) = ext_mod.log_format { base_mod.log_format = Some(f); }
+        if let Some(m) = ext_mod.log_mode { base_mod.log_mode = Some(m); }
+        if let Some(d) = ext_mod.log_dir { base_mod.log_dir = Some(d); }
+        if let Some(a) = ext_mod.ascii { base_mod.ascii = Some(a); }
+        if let Some(u) = ext_mod.ui { base_mod.ui = Some(u); }
+        if let Some(p) = ext_mod.pager { base_mod.pager = Some(p); }
+        if let Some(w) = ext_mod.warn_after { base_mod.warn_after = Some(w); }
+        if let Some(r) = ext_mod.container_runtime { base_mod.container_runtime = Some(r); }
+        if let Some(b) = ext_mod.buffer_limit_kb { base_mod.buffer_limit_kb = Some(b); }
+        if let Some(u) = ext_mod.strict_env { base_mod.strict_env = Some(u); }
+        if let Some(d) = ext_mod.dotenv { base_mod.dotenv = Some(d); }
+        if let Some(f) = ext_mod.dotenv_files { base_mod.dotenv_files = Some(f); }
+        if let Some(g) = ext_mod.grace_period { base_mod.grace_period = Some(g); }
+        if let Some(a) = ext_mod.on_abort { base_mod.on_abort = Some(a); }
+        if let Some(l) = ext_mod.lock { base_mod.lock = Some(l); }
+        if let Some(t) = ext_mod.timestamps { base_mod.timestamps = Some(t); }
+        if let Some(t) = ext_mod.log_timestamps { base_mod.log_timestamps = Some(t); }
+        if let Some(o) = ext_mod.strict_outputs { base_mod.strict_outputs = Some(o); }
+        if let Some(k) = ext_mod.strict_keys { base_mod.strict_keys = Some(k); }
+        if let Some(i) = ext_mod.sources_ignore { base_mod.sources_ignore = Some(i); }
+
+        // Append secret patterns
+        if let Some(ext_patterns) = ext_mod.secret_patterns {
+            let base_patterns = base_mod.secret_patterns.get_or_insert(vec![]);
+            base_patterns.extend(ext_patterns);
+        }
+    }
+}
+
+impl PavidiConfig {
+    /// The `container_runtime` from `[project]` or `[module]`.
+    pub fn default_container_runtime(&self) -> Option<&str> {
+        self.project.as_ref().and_then(|p| p.container_runtime.as_deref())
+            .or(self.module.as_ref().and_then(|m| m.container_runtime.as_deref()))
+    }
+
+    /// `strict_env` from `[project]` or `[module]`.
+    pub fn strict_env(&self) -> bool {
+        self.project.as_ref().and_then(|p| p.strict_env)
+            .or(self.module.as_ref().and_then(|m| m.strict_env))
+            .unwrap_or(false)
+    }
+
+    /// `sources_ignore` of `task`, else from `[project]` or `[module]`.
+    pub fn sources_ignore(&self, task: &RunnerTask) -> SourcesIgnore {
+        task.sources_ignore()
+            .or(self.project.as_ref().and_then(|p| p.sources_ignore))
+            .or(self.module.as_ref().and_then(|m| m.sources_ignore))
+            .unwrap_or_default()
+    }
+
+    /// `strict_keys` from `[project]` or `[module]`.
+    pub fn strict_keys(&self) -> bool {
+        self.project.as_ref().and_then(|p| p.strict_keys)
+            .or(self.module.as_ref().and_then(|m| m.strict_keys))
+            .unwrap_or(false)
+    }
+
+    /// `strict_outputs` from `[project]` or `[module]`.
+    pub fn strict_outputs(&self) -> bool {
+        self.project.as_ref().and_then(|p| p.strict_outputs)
+            .or(self.module.as_ref().and_then(|m| m.strict_outputs))
+            .unwrap_or(false)
+    }
+
+    /// The `.env` files to read, lowest precedence first: `dotenv_files` if set, else
+    /// `.env`, `.env.local`, `.env.<profile>`, `.env.<profile>.local`. Empty with `dotenv = false`.
+    pub fn dotenv_files(&self, profile: Option<&str>) -> Vec<String> {
+        let enabled = self.project.as_ref().and_then(|p| p.dotenv)
+            .or(self.module.as_ref().and_then(|m| m.dotenv))
+            .unwrap_or(true);
+        if !enabled {
+            return vec![];
+        }
+        let custom = self.project.as_ref().and_then(|p| p.dotenv_files.clone())
+            .or(self.module.as_ref().and_then(|m| m.dotenv_files.clone()));
+        custom.unwrap_or_else(|| {
+            let mut files = vec![".env".to_string(), ".env.local".to_string()];
+            if let Some(profile) = profile.filter(|p| !p.is_empty()) {
+                files.push(format!(".env.{}", profile));
+                files.push(format!(".env.{}.local", profile));
             }
+            files
+        })
+    }
+
+    /// `on_abort` from `[project]` or `[module]`.
+    pub fn on_abort(&self) -> &[String] {
+        self.project.as_ref().and_then(|p| p.on_abort.as_deref())
+            .or(self.module.as_ref().and_then(|m| m.on_abort.as_deref()))
+            .unwrap_or_default()
+    }
+
+    /// `lock` from `[project]` or `[module]`.
+    pub fn lock_mode(&self) -> LockMode {
+        self.project.as_ref().and_then(|p| p.lock)
+            .or(self.module.as_ref().and_then(|m| m.lock))
+            .unwrap_or_default()
+    }
+
+    /// The project-wide `warn_after` default from `[project]` or `[module]`.
+    pub fn default_warn_after(&self) -> Option<&str> {
+        self.project.as_ref().and_then(|p| p.warn_after.as_deref())
+            .or(self.module.as_ref().and_then(|m| m.warn_after.as_deref()))
+    }
+
+    /// `ui` (or `ascii = true`) from `[project]` or `[module]`.
+    pub fn ui_style(&self) -> Option<UiStyle> {
+        let style = self.project.as_ref().and_then(|p| p.ui)
+            .or(self.module.as_ref().and_then(|m| m.ui));
+        let ascii = self.project.as_ref().and_then(|p| p.ascii)
+            .or(self.module.as_ref().and_then(|m| m.ascii));
+        style.or(ascii.filter(|a| *a).map(|_| UiStyle::Plain))
+    }
+
+    /// `pager` from `[project]` or `[module]`; on unless set to false.
+    pub fn pager(&self) -> bool {
+        self.project.as_ref().and_then(|p| p.pager)
+            .or(self.module.as_ref().and_then(|m| m.pager))
+            .unwrap_or(true)
+    }
+}
+
+/// `buffer_limit_kb`, `timestamps`, `log_timestamps` and `grace_period` from `[project]` or
+/// `[module]`, with `[env_policy]` compiled.
+fn command_settings(config: &PavidiConfig) -> Result<CommandSettings> {
+    let limit = config.project.as_ref().and_then(|p| p.buffer_limit_kb)
+        .or(config.module.as_ref().and_then(|m| m.buffer_limit_kb));
+    let setting = config.project.as_ref().and_then(|p| p.timestamps)
+        .or(config.module.as_ref().and_then(|m| m.timestamps));
+    let log = config.project.as_ref().and_then(|p| p.log_timestamps)
+        .or(config.module.as_ref().and_then(|m| m.log_timestamps));
+    let timestamps = match setting {
+        Some(TimestampSetting::Enabled(true)) => Some(TimestampStyle::Clock),
+        Some(TimestampSetting::Style(style)) => Some(style),
+        Some(TimestampSetting::Enabled(false)) | None => None,
+    };
+    let grace = config.project.as_ref().and_then(|p| p.grace_period.as_deref())
+        .or(config.module.as_ref().and_then(|m| m.grace_period.as_deref()));
+    let grace_period = match grace {
+        Some(value) => parse_duration(value).with_context(|| format!("Invalid grace_period '{}'", value))?,
+        None => DEFAULT_GRACE_PERIOD,
+    };
+    Ok(CommandSettings {
+        buffer_limit: limit.unwrap_or(DEFAULT_BUFFER_LIMIT_KB).max(1) * 1024,
+        timestamps,
+        log_timestamps: log.unwrap_or(false),
+        grace_period,
+        env_filter: config.env_policy.as_ref().map(EnvFilter::new).transpose().context("Invalid [env_policy]")?,
+    })
+}
+
+/// The configuration file to use: `--config`/`P_CONFIG` if given, else `p.toml`,
+/// both relative to the `-C` directory (or `cwd`). Its parent is the project directory.
+pub fn resolve_config_path(cwd: &Path, directory: Option<&Path>, config: Option<&Path>) -> PathBuf {
+    let base = match directory {
+        Some(d) => cwd.join(d),
+        None => cwd.to_path_buf(),
+    };
+    base.join(config.unwrap_or(Path::new("p.toml")))
+}
+
+/// The `p.*.toml` extensions next to `config_path`, in load (alphabetical) order.
+pub fn extension_files(config_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = config_path.parent().unwrap_or(Path::new("."));
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    // `p.*.toml`, matched by hand: a read_dir is all it takes, and the directory
+    // name is never taken as a pattern. A --config file named like an extension
+    // is the base layer, not an extension of itself.
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| {
+            name.len() >= "p..toml".len() && name.starts_with("p.") && name.ends_with(".toml")
+        }))
+        .filter(|entry| Some(entry.file_name().as_os_str()) != config_path.file_name())
+        .map(|entry| dir.join(entry.file_name()))
+        .collect();
+
+    // Sort alphabetically to ensure deterministic order
+    files.sort();
+    Ok(files)
+}
+
+/// What `load_config_with` does beyond reading and merging `p.toml` and its extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadOptions {
+    /// Run the `$(...)` commands of dynamic env vars
+    pub resolve_dynamic_env: bool,
+    /// Layer `.env`, `.env.local` and `.env.<P_ENV>[.local]` over `[env]`
+    pub load_dotenv: bool,
+    /// Fail when the resolved env does not match `[env_schema]`
+    pub validate_env: bool,
+    /// Download `extends_remote` files that are not cached yet; otherwise they
+    /// are read from the cache only, and a missing one is left out with a warning
+    pub fetch_remote: bool,
+}
+
+impl LoadOptions {
+    /// Everything a run needs.
+    pub const FULL: LoadOptions = LoadOptions { resolve_dynamic_env: true, load_dotenv: true, validate_env: true, fetch_remote: true };
+    /// Tasks and metadata only: spawns nothing, reads no `.env` and makes no network
+    /// requests. For listing, completion and other views that never look at env values.
+    pub const SHALLOW: LoadOptions = LoadOptions { resolve_dynamic_env: false, load_dotenv: false, validate_env: false, fetch_remote: false };
+}
+
+impl PavidiConfig {
+    /// Loads `p.toml` at `path` with its extensions, as `load_config_with` does.
+    pub fn load(path: &Path, options: LoadOptions) -> Result<Self> {
+        load_config_with(path, options)
+    }
+}
+
+pub fn load_config(config_path: &Path) -> Result<PavidiConfig> {
+    load_config_with(config_path, LoadOptions::FULL)
+}
+
+/// Loads and merges the configuration without running dynamic `$(...)` env
+/// commands. Side-effect free; `.env` values are included.
+pub fn load_config_static(config_path: &Path) -> Result<PavidiConfig> {
+    load_config_with(config_path, LoadOptions { resolve_dynamic_env: false, load_dotenv: true, validate_env: false, fetch_remote: false })
+}
+
+pub fn load_config_with(config_path: &Path, options: LoadOptions) -> Result<PavidiConfig> {
+    let mut config = load_files(config_path, options)?;
+    if options.load_dotenv {
+        // Tasks see a saved profile as P_ENV, the way they see one that was exported
+        if let Some(profile) = &config.profile
+            && profile.source == ProfileSource::File
+            && !config.env.contains_key("P_ENV") {
+            config.env_provenance.entry("P_ENV".to_string()).or_default().push((PROFILE_FILE.to_string(), profile.name.clone()));
+            config.env.insert("P_ENV".to_string(), profile.name.clone());
         }
+        load_session_env(&mut config)?;
+        let profile = config.profile.as_ref().map(|p| p.name.clone());
+        load_dotenv(&mut config, profile.as_deref())?;
     }
+    apply_path_vars(&mut config);
+    if options.load_dotenv {
+        apply_env_defaults(&mut config);
+    }
+    if options.resolve_dynamic_env {
+        resolve_dynamic_env(&mut config)?;
+    }
+    if options.validate_env {
+        let violations: Vec<String> = env_schema_status(&config).into_iter()
+            .filter_map(|(key, _, problem)| problem.map(|p| format!("{}: {}", key, p)))
+            .collect();
+        if !violations.is_empty() {
+            bail!("{} The environment does not match [env_schema]:\n  {}", glyph(Glyph::Fail), violations.join("\n  "));
+        }
+    }
+    Ok(config)
+}
+
+/// How many parsed files `parse_file` keeps: a project with its extensions and remote includes.
+const PARSED_CAPACITY: usize = 16;
+
+// The files parsed last in this process, by path and a hash of the content they
+// were parsed from, with the unknown task keys found in them; least recently used
+// first. Wrappers and embedders load the same config many times; an edited file
+// no longer matches and is parsed again.
+static PARSED: LazyLock<Mutex<VecDeque<(PathBuf, blake3::Hash, ParsedFile)>>> = LazyLock::new(Default::default);
+
+/// One config file as `parse_file` returns it.
+#[derive(Clone)]
+struct ParsedFile {
+    config: PavidiConfig,
+    /// Keys its tasks and templates have that p does not know
+    unknown_keys: Vec<Diagnostic>,
+    /// Parsed just now rather than taken from `PARSED`
+    fresh: bool,
 }
 
-pub fn load_config(dir: &Path) -> Result<PavidiConfig> {
-    let config_path = dir.join("p.toml");
+/// Parses one config file; errors name the file and the line at fault.
+fn parse_file(path: &Path, content: &str) -> Result<ParsedFile> {
+    let hash = blake3::hash(content.as_bytes());
+    {
+        let mut parsed = PARSED.lock().unwrap();
+        if let Some(i) = parsed.iter().position(|(p, h, _)| p == path && *h == hash) {
+            let entry = parsed.remove(i).unwrap();
+            let file = ParsedFile { fresh: false, ..entry.2.clone() };
+            parsed.push_back(entry);
+            return Ok(file);
+        }
+    }
+    let file = ParsedFile { config: parse_toml(path, content)?, unknown_keys: unknown_keys(&shown_path(path), content), fresh: true };
+    let mut parsed = PARSED.lock().unwrap();
+    parsed.retain(|(p, _, _)| p != path);
+    if parsed.len() >= PARSED_CAPACITY {
+        parsed.pop_front();
+    }
+    parsed.push_back((path.to_path_buf(), hash, file.clone()));
+    Ok(file)
+}
+
+fn parse_toml(path: &Path, content: &str) -> Result<PavidiConfig> {
+    toml::from_str(content).map_err(|e| Diagnostic::from_toml(&shown_path(path), content, &e).into())
+}
+
+/// `path` relative to where p runs, as a compiler would print it.
+fn shown_path(path: &Path) -> PathBuf {
+    env::current_dir().ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Fails on the first unknown key with `strict_keys`, which is read from the merged
+/// config so an extension can turn it on. Otherwise warns about the keys of files
+/// parsed just now, so each is reported once per process however often it loads.
+fn report_unknown_keys(config: &PavidiConfig, unknown_keys: Vec<(Diagnostic, bool)>) -> Result<()> {
+    if config.strict_keys() {
+        return match unknown_keys.into_iter().next() {
+            Some((diagnostic, _)) => Err(diagnostic.into()),
+            None => Ok(()),
+        };
+    }
+    for (diagnostic, _) in unknown_keys.iter().filter(|(_, fresh)| *fresh) {
+        let hint = diagnostic.hint.as_ref().map(|h| format!(" ({})", h)).unwrap_or_default();
+        log::warn!("{} {}{}; it is ignored", glyph(Glyph::Warn).yellow(), diagnostic.summary(), hint);
+    }
+    Ok(())
+}
+
+fn load_files(config_path: &Path, options: LoadOptions) -> Result<PavidiConfig> {
+    let dir = config_path.parent().unwrap_or(Path::new("."));
+    let file_name = config_path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
     if !config_path.exists() {
-        bail!("❌ Critical: 'p.toml' not found in {:?}.", dir);
+        bail!("{} Critical: '{}' not found in {:?}.", glyph(Glyph::Fail), file_name, dir);
     }
-    let content = fs::read_to_string(&config_path).context("Failed to read p.toml")?;
+    let content = fs::read_to_string(config_path).with_context(|| format!("Failed to read {}", file_name))?;
     
     // 1. Parse p.toml (Base Layer)
-    let mut config: PavidiConfig = toml::from_str(&content).context("Failed to parse p.toml")?;
+    let base = parse_file(config_path, &content)?;
+    let mut unknown_keys: Vec<(Diagnostic, bool)> = base.unknown_keys.into_iter().map(|d| (d, base.fresh)).collect();
+    let mut config = base.config;
+    if config.when.is_some() {
+        bail!("{} [when] can only be used in extensions (p.*.toml), not in '{}'", glyph(Glyph::Fail), file_name);
+    }
 
     // Initialize provenance tracking
-    config.env_provenance = HashMap::new();
+    config.env_provenance = IndexMap::new();
     for (k, v) in &config.env {
         config.env_provenance.insert(k.clone(), vec![("p.toml".to_string(), v.clone())]);
     }
@@ -145,131 +924,368 @@ pub fn load_config(dir: &Path) -> Result<PavidiConfig> {
     }
     
     config.extensions_applied = Vec::new();
+    config.extensions_skipped = Vec::new();
+    config.root_dir = dir.to_path_buf();
+    config.profile = active_profile(dir);
 
     // Resolve relative paths in capabilities
     if let Some(caps) = &mut config.capability {
-        if let Some(paths) = &mut caps.allow_paths {
-            let resolved: Vec<String> = paths.iter().map(|p| {
-                let path = Path::new(p);
-                if path.is_absolute() {
-                    p.clone()
-                } else {
-                    dir.join(p).to_string_lossy().into_owned()
+        caps.resolve_paths(dir);
+    }
+
+    // 1.4 Remote includes (extends_remote), named by their URL; local extensions still win over them
+    if !config.extends_remote.is_empty() {
+        let cache = remote_config::cache_dir()?;
+        let caps = config.capability.clone();
+        for include in std::mem::take(&mut config.extends_remote) {
+            let content = if options.fetch_remote {
+                remote_config::fetch(&include, &cache, false, caps.as_ref())?.0
+            } else {
+                match remote_config::read_cached(&include, &cache)? {
+                    Some(content) => content,
+                    None => {
+                        log::warn!("{} extends_remote '{}' is not cached yet, so its tasks are left out; `p fetch-remotes` downloads it",
+                            glyph(Glyph::Warn).yellow(), include.url);
+                        continue;
+                    },
                 }
-            }).collect();
-            *paths = resolved;
+            };
+            let parsed = parse_file(Path::new(&include.url), &content)?;
+            unknown_keys.extend(parsed.unknown_keys.into_iter().map(|d| (d, parsed.fresh)));
+            let remote = parsed.config;
+            if !remote.extends_remote.is_empty() {
+                bail!("{} extends_remote '{}' has its own extends_remote; remote includes cannot be nested", glyph(Glyph::Fail), include.url);
+            }
+            apply_extension(&mut config, include.url, remote);
         }
     }
 
     // 1.5 Load Extensions (p.*.toml)
-    let pattern = dir.join("p.*.toml");
-    let pattern_str = pattern.to_str().ok_or_else(|| anyhow::anyhow!("Invalid path pattern"))?;
-    
-    let mut extension_files: Vec<PathBuf> = glob::glob(pattern_str)?
-        .filter_map(Result::ok)
-        .collect();
-    
-    // Sort alphabetically to ensure deterministic order
-    extension_files.sort();
-
-    for ext_path in extension_files {
-        eprintln!("{} Loading extension config: {}", "➕".blue(), ext_path.file_name().unwrap().to_string_lossy());
-        let ext_content = fs::read_to_string(&ext_path).context("Failed to read extension config")?;
-        let mut ext_config: PavidiConfig = toml::from_str(&ext_content).context("Failed to parse extension config")?;
-
+    for ext_path in extension_files(config_path)? {
+        let ext_content = fs::read_to_string(&ext_path).with_context(|| format!("Failed to read {}", ext_path.display()))?;
+        let parsed = parse_file(&ext_path, &ext_content)?;
+        unknown_keys.extend(parsed.unknown_keys.into_iter().map(|d| (d, parsed.fresh)));
+        let ext_config = parsed.config;
         let ext_name = ext_path.file_name().unwrap().to_string_lossy().to_string();
+        if !ext_config.extends_remote.is_empty() {
+            bail!("{} extends_remote can only be used in '{}', not in the extension '{}'", glyph(Glyph::Fail), file_name, ext_name);
+        }
+        apply_extension(&mut config, ext_name, ext_config);
+    }
 
-        // Capture extension metadata
-        let meta = if let Some(p) = &ext_config.project {
-            p.metadata.clone()
-        } else if let Some(m) = &ext_config.module {
-            m.metadata.clone()
-        } else {
-            Metadata { name: None, version: None, authors: None, description: None }
-        };
-        config.extensions_applied.push((ext_name.clone(), meta));
-
-        // Update provenance for vars in extension
-        for (k, v) in &ext_config.env {
-            config.env_provenance.entry(k.clone()).or_default().push((ext_name.clone(), v.clone()));
-        }
-
-        // Resolve relative paths in extension capability BEFORE merging
-        if let Some(caps) = &mut ext_config.capability {
-             if let Some(paths) = &mut caps.allow_paths {
-                let resolved: Vec<String> = paths.iter().map(|p| {
-                    let path = Path::new(p);
-                    if path.is_absolute() {
-                        p.clone()
-                    } else {
-                        // Resolve relative to the directory
-                        dir.join(p).to_string_lossy().into_owned()
+    report_unknown_keys(&config, unknown_keys)?;
+    expand_templates(&mut config)?;
+
+    // Resolve relative paths in per-task capability grants, and ${P_ROOT} in the
+    // path fields read before any command runs
+    if let Some(runner) = &mut config.runner {
+        for task in runner.values_mut() {
+            if let RunnerTask::Full { capabilities, sources, exclude, outputs, .. } = task {
+                if let Some(grants) = capabilities {
+                    resolve_relative(&mut grants.allow_paths, dir);
+                }
+                for paths in [sources, exclude, outputs].into_iter().flatten() {
+                    for path in paths.iter_mut() {
+                        *path = expand_path_vars(path, dir);
                     }
-                }).collect();
-                *paths = resolved;
+                }
             }
         }
-
-        merge_configurations(&mut config, ext_config);
     }
 
+    config.command_settings = command_settings(&config)?;
+
     // Validation: Exclusive Project vs Module
     if config.project.is_some() && config.module.is_some() {
-        bail!("❌ Configuration Error: 'p.toml' cannot contain both [project] and [module] sections. Please use only one.");
+        bail!("{} Configuration Error: 'p.toml' cannot contain both [project] and [module] sections. Please use only one.", glyph(Glyph::Fail));
     }
 
-    // 2. Load .env using dotenvy (Override Layer)
-    // Determines filename: .env or .env.prod based on P_ENV
-    let env_filename = env::var("P_ENV")
-        .map(|v| format!(".env.{}", v))
-        .unwrap_or_else(|_| ".env".to_string());
-    
-    let env_path = dir.join(&env_filename);
+    Ok(config)
+}
+
+/// Merges one extension over `config` unless its `[when]` says otherwise,
+/// recording it under `ext_name` (a file name, or the URL of a remote include).
+fn apply_extension(config: &mut PavidiConfig, ext_name: String, mut ext_config: PavidiConfig) {
+    if let Some(reason) = ext_config.when.as_ref().and_then(|w| w.skip_reason(env::consts::OS, |k| match k {
+        "P_ENV" => config.profile.as_ref().map(|p| p.name.clone()),
+        _ => env::var(k).ok(),
+    })) {
+        info!("{} Skipping extension config: {} ({})", glyph(Glyph::Extension).blue(), ext_name, reason);
+        config.extensions_skipped.push((ext_name, reason));
+        return;
+    }
+    info!("{} Loading extension config: {}", glyph(Glyph::Extension).blue(), ext_name);
+
+    // Capture extension metadata
+    let meta = if let Some(p) = &ext_config.project {
+        p.metadata.clone()
+    } else if let Some(m) = &ext_config.module {
+        m.metadata.clone()
+    } else {
+        Metadata { name: None, version: None, authors: None, description: None }
+    };
+    config.extensions_applied.push((ext_name.clone(), meta));
+
+    // Update provenance for vars in extension
+    for (k, v) in &ext_config.env {
+        config.env_provenance.entry(k.clone()).or_default().push((ext_name.clone(), v.clone()));
+    }
+
+    // Resolve relative paths in extension capability BEFORE merging
+    if let Some(caps) = &mut ext_config.capability {
+        caps.resolve_paths(&config.root_dir);
+    }
+
+    merge_configurations(config, ext_config);
+}
+
+/// Replaces every `template = "..."` task with its template, `{{param.NAME}}` filled
+/// in from `params`, so nothing after loading sees templates.
+fn expand_templates(config: &mut PavidiConfig) -> Result<()> {
+    let Some(runner) = &mut config.runner else { return Ok(()) };
+    for (name, task) in runner.iter_mut() {
+        let RunnerTask::Template(instance) = task else { continue };
+        let template = config.template.get(&instance.template).with_context(|| format!(
+            "{} Task '{}' uses template '{}', which is not defined in [template]", glyph(Glyph::Fail), name, instance.template))?;
+        if !template.is_table() {
+            bail!("{} [template.{}] must be a table like a [runner] task, with cmds", glyph(Glyph::Fail), instance.template);
+        }
+
+        let mut missing = vec![];
+        let value = fill_params(template, &instance.params, &mut missing);
+        if !missing.is_empty() {
+            missing.sort();
+            missing.dedup();
+            bail!("{} Task '{}' is missing params for template '{}': {}", glyph(Glyph::Fail), name, instance.template, missing.join(", "));
+        }
+        let mut expanded = RunnerTask::from_toml(value)
+            .map_err(|e| anyhow::anyhow!(e.describe(Some(name))))
+            .with_context(|| format!("Invalid [template.{}] (used by task '{}')", instance.template, name))?;
+        match &mut expanded {
+            RunnerTask::Full { deps, description, .. } => {
+                deps.extend(instance.deps.iter().cloned());
+                if let Some(d) = &instance.description {
+                    *description = Some(d.clone());
+                }
+            },
+            _ => bail!("{} [template.{}] cannot itself use a template", glyph(Glyph::Fail), instance.template),
+        }
+        config.task_templates.insert(name.clone(), instance.template.clone());
+        *task = expanded;
+    }
+    Ok(())
+}
+
+/// `value` with `{{param.NAME}}` replaced in every string; names without a value go to `missing`.
+fn fill_params(value: &toml::Value, params: &IndexMap<String, String>, missing: &mut Vec<String>) -> toml::Value {
+    static PARAM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{\s*param\.([A-Za-z0-9_-]+)\s*\}\}").unwrap());
+    match value {
+        toml::Value::String(s) => toml::Value::String(PARAM.replace_all(s, |caps: &regex::Captures| {
+            params.get(&caps[1]).cloned().unwrap_or_else(|| {
+                missing.push(caps[1].to_string());
+                String::new()
+            })
+        }).into_owned()),
+        toml::Value::Array(items) => toml::Value::Array(items.iter().map(|v| fill_params(v, params, missing)).collect()),
+        toml::Value::Table(table) => toml::Value::Table(table.iter().map(|(k, v)| (k.clone(), fill_params(v, params, missing))).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Where `p e --use` saves the profile, relative to the project root.
+pub const PROFILE_FILE: &str = ".p/profile";
+
+/// Where the active profile came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileSource {
+    /// The `P_ENV` environment variable
+    EnvVar,
+    /// `.p/profile`, written by `p e --use`
+    File,
+}
+
+/// The profile that picks `.env.<profile>` and is passed to tasks as `P_ENV`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveProfile {
+    pub name: String,
+    pub source: ProfileSource,
+}
+
+impl ActiveProfile {
+    pub fn describe_source(&self) -> &'static str {
+        match self.source {
+            ProfileSource::EnvVar => "P_ENV",
+            ProfileSource::File => PROFILE_FILE,
+        }
+    }
+}
+
+/// The profile for the project in `root`: `P_ENV` when it is set, else the one saved
+/// in `.p/profile`. An empty `P_ENV` means no profile, whatever was saved.
+pub fn active_profile(root: &Path) -> Option<ActiveProfile> {
+    resolve_profile(env::var("P_ENV").ok(), root)
+}
+
+fn resolve_profile(p_env: Option<String>, root: &Path) -> Option<ActiveProfile> {
+    if let Some(name) = p_env {
+        return (!name.is_empty()).then_some(ActiveProfile { name, source: ProfileSource::EnvVar });
+    }
+    let saved = fs::read_to_string(root.join(PROFILE_FILE)).ok()?;
+    let name = saved.trim();
+    (!name.is_empty()).then(|| ActiveProfile { name: name.to_string(), source: ProfileSource::File })
+}
+
+/// The profiles that have a `.env.<profile>` or `.env.<profile>.local` file in `root`, sorted.
+pub fn available_profiles(root: &Path) -> Result<Vec<String>> {
+    let mut profiles = Vec::new();
+    for entry in fs::read_dir(root).with_context(|| format!("Failed to read {}", root.display()))? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let Some(rest) = name.strip_prefix(".env.") else { continue };
+        let profile = rest.strip_suffix(".local").unwrap_or(rest);
+        // `.env.local` belongs to every profile, and `.env.example` is a template
+        if profile.is_empty() || profile == "local" || profile == "example" || profiles.iter().any(|p| p == profile) {
+            continue;
+        }
+        profiles.push(profile.to_string());
+    }
+    profiles.sort();
+    Ok(profiles)
+}
+
+/// Where `p e --set` keeps variables for the project, relative to the project root.
+pub const SESSION_ENV_FILE: &str = ".p/session-env.toml";
+
+/// The variables saved with `p e --set`, sorted. Empty when nothing was saved.
+pub fn session_env(root: &Path) -> Result<BTreeMap<String, String>> {
+    let vars = read_state(&root.join(SESSION_ENV_FILE), |content| Ok(toml::from_str(content)?))?;
+    Ok(vars.unwrap_or_default())
+}
+
+/// Layers the variables saved with `p e --set` over `[env]`; `.env` files still win.
+fn load_session_env(config: &mut PavidiConfig) -> Result<()> {
+    for (key, val) in session_env(&config.root_dir)? {
+        config.env_provenance.entry(key.clone()).or_default().push(("session".to_string(), val.clone()));
+        config.env.insert(key, val);
+    }
+    Ok(())
+}
+
+/// Layers the `.env` files over `[env]`, each overriding the ones before it.
+/// `profile` is `P_ENV`. Missing files are skipped.
+fn load_dotenv(config: &mut PavidiConfig, profile: Option<&str>) -> Result<()> {
+    config.dotenv_applied = Vec::new();
+    for env_filename in config.dotenv_files(profile) {
+        let env_path = config.root_dir.join(&env_filename);
+        if !env_path.exists() {
+            continue;
+        }
+        info!("{} Loading environment from: {}", glyph(Glyph::Env).green(), env_filename.bold());
 
-    if env_path.exists() {
-        eprintln!("{} Loading environment from: {}", "🌿".green(), env_filename.bold());
-        
         // We use from_path_iter to get the vars as a Map, NOT setting them globally yet.
         // This keeps the separation clean until execution.
-        for item in dotenvy::from_path_iter(&env_path)? {
-            let (key, val) = item?;
-            
+        let items = dotenvy::from_path_iter(&env_path).with_context(|| format!("Failed to read {}", env_path.display()))?;
+        for item in items {
+            let (key, val) = item.with_context(|| format!("Failed to parse {}", env_path.display()))?;
+
             // Track provenance
             config.env_provenance.entry(key.clone()).or_default().push((env_filename.clone(), val.clone()));
-            
-            // .env overrides p.toml
+
+            // .env overrides p.toml and the files before it
             config.env.insert(key, val);
         }
+        config.dotenv_applied.push(env_filename);
     }
 
+    Ok(())
+}
+
+/// Sets `P_ROOT`, `P_CONFIG_DIR` and `P_TASK_DIR`. They win over variables of the
+/// same name from `[env]` or `.env`, with a warning.
+fn apply_path_vars(config: &mut PavidiConfig) {
+    // A run loads the config more than once; say it the first time
+    static SHADOW_NOTICE: Once = Once::new();
+    let root = project_dir(&config.root_dir).to_string_lossy().into_owned();
+    let shadowed: Vec<String> = PATH_VARS.iter().filter_map(|name| {
+        let value = config.env.get(*name).filter(|v| **v != root)?;
+        let source = config.env_provenance.get(*name).and_then(|h| h.last()).map(|(s, _)| s.as_str()).unwrap_or("[env]");
+        Some(format!("{} is set by p to the project directory; the value '{}' from {} is ignored", name, value, source))
+    }).collect();
+    if !shadowed.is_empty() {
+        SHADOW_NOTICE.call_once(|| shadowed.iter().for_each(|message| log::warn!("{} {}", glyph(Glyph::Warn).yellow(), message)));
+    }
+    for name in PATH_VARS {
+        config.env_provenance.entry(name.to_string()).or_default().push(("builtin".to_string(), root.clone()));
+        config.env.insert(name.to_string(), root.clone());
+    }
+}
+
+/// Sets `[env_schema]` defaults for variables no layer set and p was not started with.
+fn apply_env_defaults(config: &mut PavidiConfig) {
+    for (key, spec) in &config.env_schema {
+        if let Some(default) = &spec.default
+            && !config.env.contains_key(key)
+            && env::var_os(key).is_none() {
+            config.env_provenance.entry(key.clone()).or_default().push(("schema-default".to_string(), default.clone()));
+            config.env.insert(key.clone(), default.clone());
+        }
+    }
+}
+
+/// Every `[env_schema]` variable with the value tasks get (from the layers, else
+/// inherited) and what is wrong with it. Unresolved `$(...)` values are not checked.
+pub fn env_schema_status(config: &PavidiConfig) -> Vec<(String, Option<String>, Option<String>)> {
+    config.env_schema.iter().map(|(key, spec)| {
+        let value = config.env.get(key).cloned().or_else(|| env::var(key).ok());
+        let problem = match value.as_deref() {
+            Some(v) if dynamic_env_command(v).is_some() => None,
+            v => spec.problem(v),
+        };
+        (key.clone(), value, problem)
+    }).collect()
+}
+
+/// The command of a dynamic env value (`"$(git rev-parse HEAD)"`), if it is one.
+pub fn dynamic_env_command(value: &str) -> Option<&str> {
+    value.strip_prefix("$(")?.strip_suffix(')').filter(|cmd| !cmd.contains('\n'))
+}
+
+fn resolve_dynamic_env(config: &mut PavidiConfig) -> Result<()> {
     // 3. Dynamic Env Var Resolution
     let shell_pref = config.project.as_ref().and_then(|p| p.shell.as_ref())
         .or(config.module.as_ref().and_then(|m| m.shell.as_ref()));
     let shell = detect_shell(shell_pref);
     
-    let re = Regex::new(r"^\$\((.*)\)$").unwrap();
     let mut updates = HashMap::new();
 
     for (k, v) in &config.env {
-        if let Some(caps) = re.captures(v) {
-            let cmd = caps.get(1).map(|m| m.as_str()).unwrap_or("");
-            if !cmd.trim().is_empty() {
-                // Execute command
-                let (code, output) = run_shell_command(
-                    cmd, 
-                    &config.env, 
-                    CaptureMode::Buffer,
-                    &format!("env:{}", k),
-                    &shell,
-                    None 
-                )?;
-                
-                if code != 0 {
-                    bail!("❌ Failed to resolve dynamic environment variable '{}': Command '{}' failed with exit code {}.", k, cmd, code);
-                }
-                
+        if let Some(cmd) = dynamic_env_command(v)
+            && !cmd.trim().is_empty() {
+            if let Some(output) = builtin_output(cmd) {
+                let output = output.with_context(|| format!("Failed to resolve dynamic environment variable '{}'", k))?;
                 updates.insert(k.clone(), output.trim().to_string());
+                continue;
+            }
+            // Execute command
+            let (code, output) = run_shell_command(
+                cmd, 
+                &config.env, 
+                &config.command_settings,
+                CaptureMode::Buffer,
+                &format!("env:{}", k),
+                &shell,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )?;
+            
+            if code != 0 {
+                bail!("{} Failed to resolve dynamic environment variable '{}': Command '{}' failed with exit code {}.", glyph(Glyph::Fail), k, cmd, code);
             }
+            
+            updates.insert(k.clone(), output.trim().to_string());
         }
     }
     
@@ -280,5 +1296,516 @@ pub fn load_config(dir: &Path) -> Result<PavidiConfig> {
     
     config.env.extend(updates);
 
-    Ok(config)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    #[test]
+    fn test_resolve_config_path() {
+        let cwd = Path::new("/work");
+        assert_eq!(resolve_config_path(cwd, None, None), PathBuf::from("/work/p.toml"));
+        assert_eq!(resolve_config_path(cwd, Some(Path::new("app")), None), PathBuf::from("/work/app/p.toml"));
+        assert_eq!(resolve_config_path(cwd, Some(Path::new("/srv")), None), PathBuf::from("/srv/p.toml"));
+        // --config is relative to -C, like every other path after it
+        assert_eq!(resolve_config_path(cwd, Some(Path::new("app")), Some(Path::new("ci.toml"))), PathBuf::from("/work/app/ci.toml"));
+        assert_eq!(resolve_config_path(cwd, None, Some(Path::new("/etc/p/ci.toml"))), PathBuf::from("/etc/p/ci.toml"));
+    }
+
+    #[test]
+    fn test_load_from_other_directory() {
+        // Nothing here depends on the process cwd
+        let dir = scratch("other_dir");
+        fs::write(dir.join("p.ci.toml"), r#"
+[capability]
+allow_paths = ["build"]
+
+[runner]
+lint = "cargo clippy"
+"#).unwrap();
+        fs::write(dir.join("p.extra.toml"), "[runner]\nextra = \"true\"\n").unwrap();
+
+        let config = load_config(&dir.join("p.ci.toml")).unwrap();
+        assert_eq!(config.root_dir, *dir);
+        let allow = config.capability.unwrap().allow_paths.unwrap();
+        assert_eq!(allow, vec![dir.join("build").to_string_lossy().into_owned()]);
+        // Extensions next to the file are merged, the file itself is not loaded twice
+        assert_eq!(config.extensions_applied.len(), 1);
+        assert!(config.runner.unwrap().contains_key("extra"));
+
+        let missing = load_config(&dir.join("p.toml")).unwrap_err().to_string();
+        assert!(missing.contains("'p.toml' not found"), "{}", missing);
+    }
+
+    #[test]
+    fn test_extension_guards() {
+        let guard: ExtensionGuard = toml::from_str("os = [\"macos\"]\nenv_set = [\"CI\"]\nenv = { P_ENV = \"production\" }\n").unwrap();
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |key: &str| pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string())
+        };
+        let all = vars(&[("CI", "true"), ("P_ENV", "production")]);
+        assert_eq!(guard.skip_reason("macos", all), None);
+        assert_eq!(guard.skip_reason("linux", all).as_deref(), Some("os is linux, not macos"));
+        assert_eq!(guard.skip_reason("macos", vars(&[("CI", ""), ("P_ENV", "production")])).as_deref(), Some("CI is not set"));
+        assert_eq!(guard.skip_reason("macos", vars(&[("CI", "1"), ("P_ENV", "staging")])).as_deref(), Some("P_ENV is 'staging', not 'production'"));
+        assert_eq!(guard.skip_reason("macos", vars(&[("CI", "1")])).as_deref(), Some("P_ENV is not set (expected 'production')"));
+        assert_eq!(ExtensionGuard::default().skip_reason("windows", vars(&[])), None);
+        assert!(toml::from_str::<ExtensionGuard>("platform = [\"linux\"]").is_err());
+    }
+
+    #[test]
+    fn test_guarded_extensions_are_skipped() {
+        let dir = scratch("guards");
+        fs::write(dir.join("p.toml"), "[env]\nMODE = \"base\"\n").unwrap();
+        fs::write(dir.join("p.a.toml"), format!("[when]\nos = [\"{}\"]\n\n[env]\nMODE = \"a\"\n", env::consts::OS)).unwrap();
+        fs::write(dir.join("p.b.toml"), "[when]\nos = [\"plan9\"]\n\n[env]\nMODE = \"b\"\n").unwrap();
+
+        let config = load_config(&dir.join("p.toml")).unwrap();
+        assert_eq!(config.env["MODE"], "a");
+        assert_eq!(config.extensions_applied.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(), ["p.a.toml"]);
+        assert_eq!(config.extensions_skipped, [("p.b.toml".to_string(), format!("os is {}, not plan9", env::consts::OS))]);
+
+        fs::write(dir.join("p.toml"), "[when]\nenv_set = [\"CI\"]\n").unwrap();
+        let err = load_config(&dir.join("p.toml")).unwrap_err().to_string();
+        assert!(err.contains("[when] can only be used in extensions"), "{}", err);
+    }
+
+    #[test]
+    fn test_tasks_and_env_keep_declaration_order() {
+        let dir = scratch("order");
+        fs::write(dir.join("p.toml"), "[env]\nZETA = \"1\"\nALPHA = \"$(echo 2)\"\nMID = \"3\"\n\n[runner]\ntest = \"true\"\nbuild = \"true\"\ndeploy = \"true\"\n").unwrap();
+        fs::write(dir.join("p.extra.toml"), "[runner]\nall = \"true\"\nbuild = \"false\"\n").unwrap();
+
+        let config = load_config(&dir.join("p.toml")).unwrap();
+        let tasks: Vec<&String> = config.runner.as_ref().unwrap().keys().collect();
+        assert_eq!(tasks, ["test", "build", "deploy", "all"]);
+        let env: Vec<&String> = config.env.keys().take(3).collect();
+        assert_eq!(env, ["ZETA", "ALPHA", "MID"]);
+    }
+
+    #[test]
+    fn test_parse_errors_name_file_and_line() {
+        let dir = scratch("broken");
+        fs::write(dir.join("p.toml"), "[project]\nname = \"demo\"\n\n[runner]\nbuild = \"cargo build\"\n").unwrap();
+        fs::write(dir.join("p.ci.toml"), "[runner]\nlint = \"cargo clippy\"\n\n[env]\nRETRIES = 5\n").unwrap();
+
+        let err = load_config(&dir.join("p.toml")).unwrap_err();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.file, dir.join("p.ci.toml"));
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(5), Some(11)));
+        assert!(err.to_string().contains("5 | RETRIES = 5\n"), "{}", err);
+
+        fs::remove_file(dir.join("p.ci.toml")).unwrap();
+        fs::write(dir.join("p.toml"), "[runner]\nbuild = \"cargo build\"\nbuild = \"make\"\n").unwrap();
+        let err = load_config(&dir.join("p.toml")).unwrap_err();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!((diagnostic.file.file_name().unwrap().to_str(), diagnostic.line), (Some("p.toml"), Some(3)));
+    }
+
+    #[test]
+    fn test_shallow_load_runs_nothing() {
+        let dir = scratch("shallow");
+        let sentinel = dir.join("sentinel");
+        let vars: String = (0..20).map(|i| format!("VAR_{} = \"$(touch '{}'; echo {})\"\n", i, sentinel.display(), i)).collect();
+        fs::write(dir.join("p.toml"), format!("[env]\n{}\n[runner]\nbuild = \"true\"\n", vars)).unwrap();
+        fs::write(dir.join(".env"), "FROM_DOTENV=1\n").unwrap();
+
+        // What `p --list` and completion load
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        assert!(config.runner.unwrap().contains_key("build"));
+        assert!(!sentinel.exists(), "a dynamic env command ran");
+        assert!(config.env["VAR_0"].starts_with("$("));
+        assert!(!config.env.contains_key("FROM_DOTENV"));
+
+        // A run resolves everything, as before
+        let config = load_config(&dir.join("p.toml")).unwrap();
+        assert!(sentinel.exists());
+        assert_eq!(config.env["VAR_7"], "7");
+        assert_eq!(config.env["FROM_DOTENV"], "1");
+    }
+
+    #[test]
+    fn test_project_settings_stay_on_the_config() {
+        let dir = scratch("settings");
+        fs::write(dir.join("p.toml"), "[project]\nname = \"demo\"\nui = \"minimal\"\npager = false\nbuffer_limit_kb = 8\n").unwrap();
+        fs::write(dir.join("p.ci.toml"), "[project]\ntimestamps = \"relative\"\nlog_timestamps = true\ngrace_period = \"250ms\"\n").unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        assert_eq!(config.ui_style(), Some(UiStyle::Minimal));
+        assert!(!config.pager());
+        let settings = &config.command_settings;
+        assert_eq!(settings.buffer_limit, 8 * 1024);
+        assert_eq!(settings.timestamps, Some(TimestampStyle::Relative));
+        assert!(settings.log_timestamps);
+        assert_eq!(settings.grace_period, std::time::Duration::from_millis(250));
+
+        assert!(settings.env_filter.is_none());
+
+        fs::write(dir.join("p.ci.toml"), "[env_policy]\ndeny = [\"AWS_*\"]\n").unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        let filter = config.command_settings.env_filter.as_ref().unwrap();
+        assert!(!filter.passes("AWS_PROFILE") && filter.passes("HOME"));
+
+        for (file, problem) in [("[project]\ngrace_period = \"soon\"\n", "Invalid grace_period 'soon'"), ("[env_policy]\ndeny = [\"[x\"]\n", "Invalid [env_policy]")] {
+            fs::write(dir.join("p.ci.toml"), file).unwrap();
+            let err = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap_err();
+            assert!(format!("{:#}", err).contains(problem), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn test_extension_files_match_by_name() {
+        // Glob characters in the directory name are just characters
+        let dir = scratch("ext[1]");
+        for file in ["p.toml", "p.ci.toml", "p.a.b.toml", "p.toml.bak", "pp.x.toml", "p.x.yaml", "p.toml~"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let names: Vec<String> = extension_files(&dir.join("p.toml")).unwrap().iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["p.a.b.toml", "p.ci.toml"]);
+        assert_eq!(extension_files(&dir.join("p.ci.toml")).unwrap(), [dir.join("p.a.b.toml")]);
+    }
+
+    #[test]
+    fn test_reload_sees_edited_files() {
+        let dir = scratch("reload");
+        fs::write(dir.join("p.toml"), "[runner]
+build = \"true\"\n").unwrap();
+        assert!(load_config_static(&dir.join("p.toml")).unwrap().runner.unwrap().contains_key("build"));
+        fs::write(dir.join("p.toml"), "[runner]
+lint = \"true\"\n").unwrap();
+        let tasks = load_config_static(&dir.join("p.toml")).unwrap().runner.unwrap();
+        assert_eq!(tasks.keys().collect::<Vec<_>>(), ["lint"]);
+    }
+
+    const UNKNOWN_KEYS: &str = include_str!("testdata/unknown_keys.toml");
+
+    #[test]
+    fn test_unknown_keys_are_located() {
+        let found = unknown_keys(Path::new("p.toml"), UNKNOWN_KEYS);
+        let summaries: Vec<String> = found.iter().map(Diagnostic::summary).collect();
+        assert_eq!(summaries, [
+            "p.toml:6:1: Task 'build' has an unknown key `timout`",
+            "p.toml:14:1: Template 'check' has an unknown key `dep`",
+            "p.toml:15:1: Template 'check' has an unknown key `command`",
+        ]);
+        let hints: Vec<Option<&str>> = found.iter().map(|d| d.hint.as_deref()).collect();
+        assert_eq!(hints, [Some("did you mean `timeout`?"), Some("did you mean `deps`?"), None]);
+    }
+
+    #[test]
+    fn test_unknown_keys_warn_by_default() {
+        let dir = scratch("unknown_keys");
+        fs::write(dir.join("p.toml"), UNKNOWN_KEYS).unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        let runner = config.runner.unwrap();
+        assert!(matches!(runner["build"], RunnerTask::Full { timeout: None, .. }));
+        assert!(matches!(&runner["lint"], RunnerTask::Full { deps, .. } if deps.is_empty()));
+    }
+
+    #[test]
+    fn test_strict_keys_fail_with_a_diagnostic() {
+        let dir = scratch("strict_keys");
+        fs::write(dir.join("p.toml"), UNKNOWN_KEYS.replace("[project]\n", "[project]\nstrict_keys = true\n")).unwrap();
+        // Also when the file comes from the parse cache the second time
+        for _ in 0..2 {
+            let err = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.message, "Task 'build' has an unknown key `timout`");
+            assert_eq!(diagnostic.hint.as_deref(), Some("did you mean `timeout`?"));
+            assert_eq!((diagnostic.line, diagnostic.column), (Some(7), Some(1)));
+            assert!(diagnostic.to_string().contains("7 | timout = 30\n"), "{}", diagnostic);
+        }
+
+        // An extension can turn it on for the whole project
+        fs::write(dir.join("p.toml"), UNKNOWN_KEYS).unwrap();
+        fs::write(dir.join("p.strict.toml"), "[project]\nstrict_keys = true\n").unwrap();
+        let err = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap_err();
+        assert_eq!(err.downcast_ref::<Diagnostic>().unwrap().line, Some(6));
+    }
+
+    #[test]
+    fn test_parsed_files_are_bounded() {
+        let dir = scratch("parsed");
+        for i in 0..PARSED_CAPACITY + 4 {
+            let path = dir.join(format!("p.{}.toml", i));
+            parse_file(&path, &format!("[runner]\ntask_{} = \"true\"\n", i)).unwrap();
+        }
+        let path = dir.join("p.toml");
+        for task in ["build", "lint", "build"] {
+            let config = parse_file(&path, &format!("[runner]\n{} = \"true\"\n", task)).unwrap().config;
+            assert!(config.runner.unwrap().contains_key(task));
+        }
+        let parsed = PARSED.lock().unwrap();
+        assert!(parsed.len() <= PARSED_CAPACITY);
+        // An edited file replaces its old entry
+        assert_eq!(parsed.iter().filter(|(p, _, _)| p == &path).count(), 1);
+    }
+
+    #[test]
+    fn test_load_time_of_a_large_config() {
+        let dir = scratch("large");
+        let mut toml = String::from("[project]\nname = \"large\"\n\n[capability]\nallow_paths = [\"src\", \"target\"]\n\n[env]\n");
+        toml.extend((0..50).map(|i| format!("VAR_{} = \"value-{}\"\n", i, i)));
+        for i in 0..200 {
+            let deps = if i == 0 { String::new() } else { format!("\"task-{}\"", i - 1) };
+            toml.push_str(&format!("\n[runner.task-{i}]\ndescription = \"Task {i}\"\ncmds = [\"echo {i}\", \"p:mkdir -p out/{i}\"]\ndeps = [{deps}]\nsources = [\"src/**/*.rs\"]\noutputs = [\"out/{i}\"]\n"));
+        }
+        fs::write(dir.join("p.toml"), toml).unwrap();
+        fs::write(dir.join("p.ci.toml"), "[runner]\nci = { deps = [\"task-199\"] }\n").unwrap();
+
+        // Generous, to hold on slow CI machines and debug builds; a regression
+        // to per-load globbing or path resolution on a slow disk shows up here
+        let started = std::time::Instant::now();
+        for _ in 0..10 {
+            let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+            assert_eq!(config.runner.unwrap().len(), 201);
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "10 loads took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_profile_precedence() {
+        let dir = scratch("profile");
+        // Nothing saved and no P_ENV
+        assert_eq!(resolve_profile(None, &dir), None);
+
+        fs::create_dir_all(dir.join(".p")).unwrap();
+        fs::write(dir.join(PROFILE_FILE), "staging\n").unwrap();
+        let saved = resolve_profile(None, &dir).unwrap();
+        assert_eq!((saved.name.as_str(), saved.source), ("staging", ProfileSource::File));
+
+        // P_ENV wins over the saved profile, and an empty one means none
+        let explicit = resolve_profile(Some("prod".to_string()), &dir).unwrap();
+        assert_eq!((explicit.name.as_str(), explicit.source), ("prod", ProfileSource::EnvVar));
+        assert_eq!(resolve_profile(Some(String::new()), &dir), None);
+    }
+
+    #[test]
+    fn test_available_profiles() {
+        let dir = scratch("profiles");
+        for file in [".env", ".env.local", ".env.prod", ".env.prod.local", ".env.staging.local", ".env.example", "env.dev"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        assert_eq!(available_profiles(&dir).unwrap(), ["prod", "staging"]);
+    }
+
+    #[test]
+    fn test_session_env_precedence() {
+        let dir = scratch("session_env");
+        fs::write(dir.join("p.toml"), "[env]\nA = \"toml\"\nB = \"toml\"\nC = \"toml\"\n").unwrap();
+        fs::create_dir_all(dir.join(".p")).unwrap();
+        fs::write(dir.join(SESSION_ENV_FILE), "B = \"session\"\nC = \"session\"\n").unwrap();
+        fs::write(dir.join(".env"), "C=dotenv\n").unwrap();
+
+        let config = load_config(&dir.join("p.toml")).unwrap();
+        assert_eq!((config.env["A"].as_str(), config.env["B"].as_str(), config.env["C"].as_str()), ("toml", "session", "dotenv"));
+        let sources: Vec<&str> = config.env_provenance["C"].iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(sources, ["p.toml", "session", ".env"]);
+    }
+
+    #[test]
+    fn test_path_vars_in_commands_and_fields() {
+        let dir = scratch("path_vars");
+        fs::write(dir.join("p.toml"), r#"
+[env]
+P_ROOT = "/elsewhere"
+OUT = "${P_ROOT}/out"
+
+[capability]
+allow_paths = ["${P_ROOT}/build", "$P_CONFIG_DIR/cache"]
+
+[runner.build]
+cmds = ["echo ${P_TASK_DIR}"]
+sources = ["${P_ROOT}/src/**/*.rs"]
+exclude = ["$P_ROOT/src/gen/**"]
+outputs = ["${P_CONFIG_DIR}/target/app"]
+capabilities = { allow_paths = ["${P_TASK_DIR}/tmp"] }
+"#).unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        let root = project_dir(&dir).to_string_lossy().into_owned();
+
+        // The builtin wins over [env], and commands see all three
+        for name in PATH_VARS {
+            assert_eq!(config.env[*name], root);
+        }
+        assert_eq!(config.env_provenance["P_ROOT"].last().unwrap().0, "builtin");
+        assert_eq!(crate::utils::expand_command("echo ${P_TASK_DIR}", &[], &config.env, crate::utils::ShellKind::Sh), format!("echo {}", root));
+
+        let caps = config.capability.as_ref().unwrap();
+        assert_eq!(caps.allow_paths.as_deref().unwrap(), [format!("{}/build", root), format!("{}/cache", root)]);
+        let task = &config.runner.as_ref().unwrap()["build"];
+        assert_eq!(task.sources(), [format!("{}/src/**/*.rs", root)]);
+        assert_eq!(task.exclude(), [format!("{}/src/gen/**", root)]);
+        assert_eq!(task.outputs(), [format!("{}/target/app", root)]);
+        assert_eq!(task.capabilities().unwrap().allow_paths.as_deref().unwrap(), [format!("{}/tmp", root)]);
+    }
+
+    #[test]
+    fn test_expand_path_vars() {
+        let dir = Path::new("/work/app");
+        let root = project_dir(dir).to_string_lossy().into_owned();
+        assert_eq!(expand_path_vars("${P_ROOT}/a:$P_TASK_DIR", dir), format!("{}/a:{}", root, root));
+        // Longer names and other variables are left alone
+        assert_eq!(expand_path_vars("$P_ROOTS/${HOME}/$P_ROOT_X", dir), "$P_ROOTS/${HOME}/$P_ROOT_X");
+        assert_eq!(expand_path_vars("src/**", dir), "src/**");
+    }
+
+    #[test]
+    fn test_dotenv_cascade() {
+        let dir = scratch("dotenv");
+        fs::write(dir.join("p.toml"), "[env]\nMODE = \"toml\"\nURL = \"toml\"\n").unwrap();
+        fs::write(dir.join(".env"), "MODE=base\nURL=base\nNAME=base\n").unwrap();
+        fs::write(dir.join(".env.local"), "MODE=local\n").unwrap();
+        fs::write(dir.join(".env.prod"), "MODE=prod\nURL=prod\n").unwrap();
+        fs::write(dir.join(".env.prod.local"), "MODE=prod-local\n").unwrap();
+
+        let mut config = load_files(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        load_dotenv(&mut config, Some("prod")).unwrap();
+        assert_eq!((config.env["MODE"].as_str(), config.env["URL"].as_str(), config.env["NAME"].as_str()), ("prod-local", "prod", "base"));
+        assert_eq!(config.dotenv_applied, [".env", ".env.local", ".env.prod", ".env.prod.local"]);
+        let sources: Vec<&str> = config.env_provenance["MODE"].iter().map(|(source, _)| source.as_str()).collect();
+        assert_eq!(sources, ["p.toml", ".env", ".env.local", ".env.prod", ".env.prod.local"]);
+
+        // No profile: the profile files are not read
+        let mut config = load_files(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        load_dotenv(&mut config, None).unwrap();
+        assert_eq!(config.env["MODE"], "local");
+
+        // A custom list, with a missing file skipped
+        fs::write(dir.join("p.toml"), "[project]\nname = \"x\"\ndotenv_files = [\".env.prod\", \"missing.env\", \".env\"]\n").unwrap();
+        let mut config = load_files(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        load_dotenv(&mut config, Some("prod")).unwrap();
+        assert_eq!(config.dotenv_applied, [".env.prod", ".env"]);
+        assert_eq!(config.env["MODE"], "base");
+
+        fs::write(dir.join("p.toml"), "[project]\nname = \"x\"\ndotenv = false\n").unwrap();
+        let mut config = load_files(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        load_dotenv(&mut config, Some("prod")).unwrap();
+        assert!(config.env.is_empty() && config.dotenv_applied.is_empty());
+
+        // A file that exists but cannot be read is an error
+        fs::write(dir.join("p.toml"), "").unwrap();
+        fs::remove_file(dir.join(".env.local")).unwrap();
+        fs::create_dir(dir.join(".env.local")).unwrap();
+        let mut config = load_files(&dir.join("p.toml"), LoadOptions::FULL).unwrap();
+        assert!(load_dotenv(&mut config, None).unwrap_err().to_string().contains(".env.local"));
+    }
+
+    #[test]
+    fn test_env_spec_problems() {
+        let spec = |toml: &str| -> EnvSpec { toml::from_str(toml).unwrap() };
+        let cases: &[(&str, Option<&str>, Option<&str>)] = &[
+            ("type = \"int\"", Some("8080"), None),
+            ("type = \"int\"", Some("80.5"), Some("expected int, got '80.5'")),
+            ("type = \"float\"", Some("0.25"), None),
+            ("type = \"float\"", Some("fast"), Some("expected float, got 'fast'")),
+            ("type = \"bool\"", Some("Yes"), None),
+            ("type = \"bool\"", Some("maybe"), Some("expected bool, got 'maybe'")),
+            ("", Some("anything"), None),
+            ("enum = [\"staging\", \"prod\"]", Some("prod"), None),
+            ("enum = [\"staging\", \"prod\"]", Some("dev"), Some("'dev' is not one of staging, prod")),
+            ("type = \"int\"\nenum = [\"1\", \"x\"]", Some("x"), Some("expected int, got 'x'")),
+            ("type = \"bool\"", None, Some("required but not set")),
+            ("default = \"8080\"", None, None),
+            ("required = false", None, None),
+            ("default = \"1\"\nrequired = true", None, Some("required but not set")),
+        ];
+        for (toml, value, expected) in cases {
+            assert_eq!(spec(toml).problem(*value).as_deref(), *expected, "{} with {:?}", toml, value);
+        }
+        assert!(toml::from_str::<EnvSpec>("type = \"uuid\"").is_err());
+    }
+
+    #[test]
+    fn test_env_schema_validation() {
+        let dir = scratch("env_schema");
+        fs::write(dir.join("p.toml"), r#"
+[env]
+DEBUG = "maybe"
+TARGET = "dev"
+
+[env_schema]
+PORT = { type = "int", default = "8080" }
+DEBUG = { type = "bool" }
+TARGET = { enum = ["staging", "prod"] }
+PAVIDI_TEST_UNSET_TOKEN = {}
+"#).unwrap();
+        let err = load_config(&dir.join("p.toml")).unwrap_err().to_string();
+        assert!(err.contains("DEBUG: expected bool, got 'maybe'\n  TARGET: 'dev' is not one of staging, prod\n  PAVIDI_TEST_UNSET_TOKEN: required but not set"), "{}", err);
+
+        // Defaults fill in, with their own provenance
+        let config = load_config_static(&dir.join("p.toml")).unwrap();
+        assert_eq!(config.env["PORT"], "8080");
+        assert_eq!(config.env_provenance["PORT"], [("schema-default".to_string(), "8080".to_string())]);
+        let failing: Vec<bool> = env_schema_status(&config).iter().map(|(_, _, problem)| problem.is_some()).collect();
+        assert_eq!(failing, [false, true, true, true]);
+    }
+
+    #[test]
+    fn test_dynamic_env_builtins() {
+        let dir = scratch("dynamic_builtins");
+        fs::write(dir.join("p.toml"), r#"
+[env]
+BUILD_ID = "$(p:uuid)"
+YEAR = "$(p:date --utc %Y)"
+"#).unwrap();
+        let config = load_config(&dir.join("p.toml")).unwrap();
+        assert_eq!(config.env["BUILD_ID"].len(), 36);
+        assert_eq!(config.env["YEAR"], chrono::Utc::now().format("%Y").to_string());
+    }
+
+    #[test]
+    fn test_task_templates() {
+        let dir = scratch("templates");
+        fs::write(dir.join("p.toml"), r#"
+[template.build-service]
+description = "Build a service"
+cmds = ["cargo build -p {{param.pkg}}", "echo {{ param.pkg }} {{param.profile}}"]
+outputs = ["target/debug/{{param.pkg}}"]
+deps = ["fmt"]
+
+[runner]
+fmt = "cargo fmt"
+build-api = { template = "build-service", params = { pkg = "api", profile = "dev" } }
+
+[runner.build-worker]
+template = "build-service"
+params = { pkg = "worker", profile = "release" }
+deps = ["build-api"]
+description = "Build the worker"
+"#).unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        let runner = config.runner.as_ref().unwrap();
+        let RunnerTask::Full { outputs, deps, description, .. } = &runner["build-api"] else { panic!("not expanded") };
+        assert_eq!(runner["build-api"].commands_for("linux").unwrap(), ["cargo build -p api", "echo api dev"]);
+        assert_eq!(outputs.as_deref(), Some(&["target/debug/api".to_string()][..]));
+        assert_eq!((deps.as_slice(), description.as_deref()), (&["fmt".to_string()][..], Some("Build a service")));
+
+        let RunnerTask::Full { deps, description, .. } = &runner["build-worker"] else { panic!("not expanded") };
+        assert_eq!(deps, &["fmt", "build-api"]);
+        assert_eq!(description.as_deref(), Some("Build the worker"));
+        assert_eq!(config.task_templates.get("build-worker").map(String::as_str), Some("build-service"));
+        assert!(!config.task_templates.contains_key("fmt"));
+    }
+
+    #[test]
+    fn test_task_template_errors() {
+        let dir = scratch("template_errors");
+        let load = |toml: &str| {
+            fs::write(dir.join("p.toml"), toml).unwrap();
+            load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap_err().to_string()
+        };
+        let template = "[template.svc]\ncmds = [\"run {{param.pkg}} {{param.port}}\"]\n";
+
+        let err = load(&format!("{}[runner]\napi = {{ template = \"svc\", params = {{ pkg = \"api\" }} }}\n", template));
+        assert!(err.contains("Task 'api' is missing params for template 'svc': port"), "{}", err);
+        let err = load("[runner]\napi = { template = \"nope\" }\n");
+        assert!(err.contains("Task 'api' uses template 'nope', which is not defined in [template]"), "{}", err);
+        let err = load("[template]\nsvc = \"run\"\n[runner]\napi = { template = \"svc\" }\n");
+        assert!(err.contains("[template.svc] must be a table"), "{}", err);
+    }
 }
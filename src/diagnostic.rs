@@ -0,0 +1,148 @@
+use serde::Serialize;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// A problem in a configuration file, located by file, line and column.
+/// Rendered like a compiler error; `--json` prints it as an object.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    /// 1-based
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// 1-based, in characters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    /// The offending line and the span under it, for rendering
+    #[serde(skip)]
+    snippet: Option<(String, Range<usize>)>,
+}
+
+impl Diagnostic {
+    /// A TOML syntax or type error in `file`, whose text is `source`.
+    pub fn from_toml(file: &Path, source: &str, err: &toml::de::Error) -> Self {
+        Self::new(file, source, err.message(), err.span())
+    }
+
+    /// A TOML syntax error found while editing `file` in place (`p fmt`, `p new-task`).
+    pub fn from_toml_edit(file: &Path, source: &str, err: &toml_edit::TomlError) -> Self {
+        Self::new(file, source, err.message(), err.span())
+    }
+
+    /// A problem p finds in `file` itself, such as an unknown task key, at `span` of `source`.
+    pub fn at(file: &Path, source: &str, message: String, hint: Option<String>, span: Option<Range<usize>>) -> Self {
+        let mut diagnostic = Diagnostic { file: file.to_path_buf(), line: None, column: None, message, hint, snippet: None };
+        if let Some(span) = span {
+            diagnostic.locate(source, span);
+        }
+        diagnostic
+    }
+
+    fn new(file: &Path, source: &str, message: &str, span: Option<Range<usize>>) -> Self {
+        let message = message.trim().to_string();
+        let hint = hint_for(&message);
+        Self::at(file, source, message, hint, span)
+    }
+
+    /// `file:line:column: message`, for one-line listings such as `p doctor`.
+    pub fn summary(&self) -> String {
+        format!("{}: {}", self.location(), self.message)
+    }
+
+    fn location(&self) -> String {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", self.file.display(), line, column),
+            _ => self.file.display().to_string(),
+        }
+    }
+
+    fn locate(&mut self, source: &str, span: Range<usize>) {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        let column = source[line_start..start].chars().count();
+        // Multi-line spans (a whole table) are marked to the end of their first line
+        let width = source[start..span.end.clamp(start, line_end)].chars().count().max(1);
+
+        self.line = Some(source[..line_start].matches('\n').count() + 1);
+        self.column = Some(column + 1);
+        self.snippet = Some((text.to_string(), column..column + width));
+    }
+}
+
+/// An example of the expected value when a task field has the wrong type.
+fn hint_for(message: &str) -> Option<String> {
+    let field = message.strip_prefix("Invalid `")?.split('`').next()?;
+    let example = match field {
+        "timeout" | "retry_delay" => format!("a number of seconds, e.g. {} = 30", field),
+        "retry" | "priority" | "nice" => format!("an integer, e.g. {} = 2", field),
+        "warn_after" => "a duration, e.g. warn_after = \"30s\"".to_string(),
+        "max_memory" => "a size, e.g. max_memory = \"2G\"".to_string(),
+        "allowed_exit_codes" => "a list of integers, e.g. allowed_exit_codes = [0, 1]".to_string(),
+        "parallel" | "ignore_failure" => format!("true or false, e.g. {} = true", field),
+        "cmds" | "deps" | "fallbacks" | "sources" | "exclude" | "outputs" | "require_files" | "windows" | "linux" | "macos" | "finally"
+            | "container_args" | "push_files" | "pull_files" => format!("a list of strings, e.g. {} = [\"...\"]", field),
+        _ => return None,
+    };
+    Some(format!("`{}` is {}", field, example))
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n --> {}", self.message, self.location())?;
+        if let (Some(line), Some((text, marker))) = (self.line, &self.snippet) {
+            let gutter = " ".repeat(line.to_string().len());
+            write!(f, "\n{} |\n{} | {}\n{} | {}{}", gutter, line, text, gutter, " ".repeat(marker.start), "^".repeat(marker.len()))?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\n = hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> Diagnostic {
+        let err = toml::from_str::<toml::Table>(source).unwrap_err();
+        Diagnostic::from_toml(Path::new("p.toml"), source, &err)
+    }
+
+    #[test]
+    fn test_locates_syntax_errors() {
+        let diagnostic = parse_error("[runner]\nbuild = \"cargo build\"\ntest = \"cargo test\" --lib\n");
+        let rendered = diagnostic.to_string();
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(3), Some(21)), "{}", rendered);
+        assert!(rendered.contains(" --> p.toml:3:21\n"), "{}", rendered);
+        assert!(rendered.contains("3 | test = \"cargo test\" --lib\n  |                     ^"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_hint_for_task_field_types() {
+        let source = "[runner.build]\ncmds = [\"cargo build\"]\ntimeout = \"30\"\n";
+        let err = toml::from_str::<crate::config::PavidiConfig>(source).unwrap_err();
+        let diagnostic = Diagnostic::from_toml(Path::new("p.toml"), source, &err);
+        assert_eq!(diagnostic.line, Some(1));
+        assert_eq!(diagnostic.hint.as_deref(), Some("`timeout` is a number of seconds, e.g. timeout = 30"));
+        assert_eq!(hint_for("Invalid task 'build': a task is a command string, a list of commands or a table, not an integer"), None);
+    }
+
+    #[test]
+    fn test_serializes_location() {
+        let json = serde_json::to_value(parse_error("a = 1\nb = = 2\n")).unwrap();
+        assert_eq!(json["file"], "p.toml");
+        assert_eq!(json["line"], 2);
+        assert!(json.get("hint").is_none());
+        assert!(json.get("snippet").is_none());
+        assert_eq!(parse_error("a = 1\nb = = 2\n").summary().split(": ").next(), Some("p.toml:2:5"));
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use indexmap::{IndexMap, IndexSet};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use crate::config::{load_config_with, LoadOptions, PavidiConfig};
+use super::apply_project_settings;
+use crate::runner::cache::file_hash;
+use crate::runner::task::RunnerTask;
+use crate::ui::{glyph, Glyph};
+use crate::state::write_atomic;
+use super::task::handle_runner_entry;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// `manifest.json`, written next to the collected artifacts.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    /// Relative to the project and to the output directory, with `/` separators
+    pub path: String,
+    /// The task that declares it
+    pub task: String,
+    pub size: u64,
+    pub blake3: String,
+}
+
+/// A file matched by an artifact glob, relative to the project.
+#[derive(Debug, PartialEq)]
+struct Artifact {
+    task: String,
+    path: PathBuf,
+}
+
+/// The tasks whose artifacts `p artifacts [task]` collects: `task` and its
+/// dependencies, dependencies first, or every task that declares artifacts.
+fn artifact_tasks(config: &PavidiConfig, task: Option<&str>) -> Result<Vec<String>> {
+    let runner = config.runner.as_ref().context("No [runner] section defined in config")?;
+    let declares = |name: &String| runner.get(name).is_some_and(|t| !t.artifacts().is_empty());
+
+    let Some(task) = task else {
+        let tasks: Vec<String> = runner.keys().filter(|name| declares(name)).cloned().collect();
+        if tasks.is_empty() {
+            bail!("No task declares artifacts; add artifacts = [\"...\"] to the tasks that build release files");
+        }
+        return Ok(tasks);
+    };
+    if !runner.contains_key(task) {
+        bail!("Task '{}' not found", task);
+    }
+
+    // Dependencies first; `visiting` ends cycles, which a run would report
+    fn visit(name: &str, runner: &IndexMap<String, RunnerTask>, visiting: &mut HashSet<String>, order: &mut IndexSet<String>) {
+        if order.contains(name) || !visiting.insert(name.to_string()) {
+            return;
+        }
+        if let Some(task) = runner.get(name) {
+            for dep in task.deps() {
+                visit(dep, runner, visiting, order);
+            }
+            order.insert(name.to_string());
+        }
+    }
+    let mut order = IndexSet::new();
+    visit(task, runner, &mut HashSet::new(), &mut order);
+
+    let tasks: Vec<String> = order.into_iter().filter(declares).collect();
+    if tasks.is_empty() {
+        bail!("Neither task '{}' nor its dependencies declare artifacts", task);
+    }
+    Ok(tasks)
+}
+
+/// The files `pattern` matches under `root`, relative to it. Directories stand for the files in them.
+fn match_pattern(root: &Path, task: &str, pattern: &str) -> Result<Vec<PathBuf>> {
+    let relative = Path::new(pattern);
+    if relative.is_absolute() || relative.components().any(|c| c == Component::ParentDir) {
+        bail!("Artifact '{}' of task '{}' is outside the project; artifacts keep their path relative to it", pattern, task);
+    }
+    let full = format!("{}/{}", glob::Pattern::escape(&root.display().to_string()), pattern);
+    let mut files = vec![];
+    for entry in glob::glob(&full).with_context(|| format!("Invalid artifact pattern '{}'", pattern))? {
+        let path = entry?;
+        if path.is_dir() {
+            files_under(&path, &mut files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files.into_iter().filter_map(|p| p.strip_prefix(root).ok().map(Path::to_path_buf)).collect())
+}
+
+fn files_under(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<std::io::Result<_>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files_under(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Every artifact file of `tasks`; a pattern that matches nothing is an error.
+fn find_artifacts(config: &PavidiConfig, tasks: &[String], root: &Path) -> Result<Vec<Artifact>> {
+    let runner = config.runner.as_ref().context("No [runner] section defined in config")?;
+    let mut artifacts: Vec<Artifact> = vec![];
+    for task in tasks {
+        for pattern in runner[task.as_str()].artifacts() {
+            let files = match_pattern(root, task, pattern)?;
+            if files.is_empty() {
+                bail!("{} Artifact '{}' of task '{}' matched no files", glyph(Glyph::Fail), pattern, task);
+            }
+            for path in files {
+                if !artifacts.iter().any(|a| a.path == path) {
+                    artifacts.push(Artifact { task: task.clone(), path });
+                }
+            }
+        }
+    }
+    Ok(artifacts)
+}
+
+fn slash_path(path: &Path) -> String {
+    path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Copies `artifacts` from `root` into `out`, keeping their relative paths, and writes the manifest.
+fn collect(artifacts: &[Artifact], root: &Path, out: &Path) -> Result<Manifest> {
+    fs::create_dir_all(out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let out_dir = crate::utils::canonicalize(out)?;
+    let mut files = vec![];
+    for artifact in artifacts {
+        let source = root.join(&artifact.path);
+        // A pattern like dist/** would otherwise pick up an earlier bundle inside dist/
+        if crate::utils::canonicalize(&source).is_ok_and(|p| p.starts_with(&out_dir)) {
+            continue;
+        }
+        let target = out.join(&artifact.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let size = fs::copy(&source, &target)
+            .with_context(|| format!("Failed to copy {} to {}", source.display(), target.display()))?;
+        files.push(ManifestEntry {
+            path: slash_path(&artifact.path),
+            task: artifact.task.clone(),
+            size,
+            blake3: file_hash(&source)?,
+        });
+    }
+    let manifest = Manifest { version: MANIFEST_VERSION, files };
+    write_atomic(&out.join(MANIFEST_FILE), &format!("{}\n", serde_json::to_string_pretty(&manifest)?))?;
+    Ok(manifest)
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).with_context(|| format!("Failed to read the modification time of {}", path.display()))
+}
+
+/// `--check`: what is wrong with the artifacts of `tasks`, without running or copying
+/// anything. An artifact older than one of its task's `sources` is stale.
+fn check_artifacts(config: &PavidiConfig, tasks: &[String], root: &Path) -> Result<(usize, Vec<String>)> {
+    let runner = config.runner.as_ref().context("No [runner] section defined in config")?;
+    let mut checked = 0;
+    let mut problems = vec![];
+    for task_name in tasks {
+        let task = &runner[task_name.as_str()];
+        let mut newest: Option<(SystemTime, PathBuf)> = None;
+        // Sources outside the project (`../shared/**`) are not compared
+        for pattern in task.sources() {
+            for source in match_pattern(root, task_name, pattern).unwrap_or_default() {
+                let time = modified(&root.join(&source))?;
+                if newest.as_ref().is_none_or(|(t, _)| time > *t) {
+                    newest = Some((time, source));
+                }
+            }
+        }
+        for pattern in task.artifacts() {
+            let files = match_pattern(root, task_name, pattern)?;
+            if files.is_empty() {
+                problems.push(format!("'{}' of task '{}' matched no files", pattern, task_name));
+            }
+            for file in files {
+                checked += 1;
+                if let Some((source_time, source)) = &newest
+                    && modified(&root.join(&file))? < *source_time {
+                    problems.push(format!("'{}' of task '{}' is older than its source {}", slash_path(&file), task_name, slash_path(source)));
+                }
+            }
+        }
+    }
+    Ok((checked, problems))
+}
+
+pub fn handle_artifacts(config_path: &Path, task: Option<&str>, out: Option<&Path>, check: bool) -> Result<()> {
+    let root = config_path.parent().unwrap_or(Path::new("."));
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    apply_project_settings(&config);
+    let tasks = artifact_tasks(&config, task)?;
+
+    if check {
+        let (checked, problems) = check_artifacts(&config, &tasks, root)?;
+        if !problems.is_empty() {
+            bail!("{} Artifacts are missing or stale:\n  {}", glyph(Glyph::Fail), problems.join("\n  "));
+        }
+        println!("{} {} artifact files of {} are present and newer than their sources", glyph(Glyph::Ok), checked, tasks.join(", "));
+        return Ok(());
+    }
+
+    let out = out.context("--out DIR is required unless --check is given")?;
+    // Builds what is stale; tasks whose outputs are up to date are skipped by the cache
+    let roots = match task {
+        Some(task) => vec![task.to_string()],
+        None => tasks.clone(),
+    };
+    for root_task in roots {
+        handle_runner_entry(config_path, root_task, vec![], false, false, false, &[], None, false, false, false, None)?;
+    }
+
+    let artifacts = find_artifacts(&config, &tasks, root)?;
+    let manifest = collect(&artifacts, root, out)?;
+    let bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+    println!("{} Collected {} files ({} bytes) into {} with {}",
+        glyph(Glyph::Project), manifest.files.len(), bytes, out.display().to_string().bold(), MANIFEST_FILE);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::test_support::{scratch, Scratch};
+
+    fn project(name: &str, tasks: &str) -> (Scratch, PavidiConfig) {
+        let dir = scratch(&format!("artifacts_{}", name));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target/release")).unwrap();
+        fs::create_dir_all(dir.join("dist/docs")).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join("target/release/p"), "binary").unwrap();
+        fs::write(dir.join("dist/p-linux.tar.gz"), "tarball").unwrap();
+        fs::write(dir.join("dist/docs/index.html"), "<html>").unwrap();
+        fs::write(dir.join("p.toml"), tasks).unwrap();
+        let config = load_config_with(&dir.join("p.toml"), LoadOptions::SHALLOW).unwrap();
+        (dir, config)
+    }
+
+    const TASKS: &str = r#"
+[runner.build]
+cmds = ["cargo build --release"]
+sources = ["src/**/*.rs"]
+artifacts = ["target/release/p"]
+
+[runner.package]
+deps = ["build", "docs"]
+artifacts = ["dist/*.tar.gz"]
+
+[runner.docs]
+artifacts = ["dist/docs"]
+
+[runner.lint]
+cmds = ["cargo clippy"]
+"#;
+
+    #[test]
+    fn test_artifact_tasks() {
+        let (_dir, config) = project("tasks", TASKS);
+        assert_eq!(artifact_tasks(&config, Some("package")).unwrap(), ["build", "docs", "package"]);
+        assert_eq!(artifact_tasks(&config, None).unwrap(), ["build", "package", "docs"]);
+        assert!(artifact_tasks(&config, Some("lint")).unwrap_err().to_string().contains("Neither task 'lint' nor its dependencies declare artifacts"));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let (dir, config) = project("manifest", TASKS);
+        let tasks = artifact_tasks(&config, Some("package")).unwrap();
+        let out = dir.join("bundle");
+        let manifest = collect(&find_artifacts(&config, &tasks, &dir).unwrap(), &dir, &out).unwrap();
+
+        let files: Vec<(&str, &str, u64)> = manifest.files.iter().map(|f| (f.path.as_str(), f.task.as_str(), f.size)).collect();
+        assert_eq!(files, [
+            ("target/release/p", "build", 6),
+            ("dist/docs/index.html", "docs", 6),
+            ("dist/p-linux.tar.gz", "package", 7),
+        ]);
+        assert_eq!(manifest.files[0].blake3, blake3::hash(b"binary").to_hex().to_string());
+        assert_eq!(fs::read_to_string(out.join("dist/p-linux.tar.gz")).unwrap(), "tarball");
+
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(out.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(written["version"], 1);
+        assert_eq!(written["files"][2], serde_json::json!({
+            "path": "dist/p-linux.tar.gz",
+            "task": "package",
+            "size": 7,
+            "blake3": blake3::hash(b"tarball").to_hex().to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_missing_and_outside_artifacts() {
+        let (dir, config) = project("missing", "[runner.build]\nartifacts = [\"target/release/p\", \"dist/*.zip\"]\n[runner.escape]\nartifacts = [\"../secrets\"]\n");
+        let err = find_artifacts(&config, &["build".to_string()], &dir).unwrap_err().to_string();
+        assert!(err.ends_with("Artifact 'dist/*.zip' of task 'build' matched no files"), "{}", err);
+        let err = find_artifacts(&config, &["escape".to_string()], &dir).unwrap_err().to_string();
+        assert!(err.contains("Artifact '../secrets' of task 'escape' is outside the project"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_finds_stale_artifacts() {
+        let (dir, config) = project("stale", TASKS);
+        let tasks = artifact_tasks(&config, Some("build")).unwrap();
+        let now = SystemTime::now();
+        fs::File::options().write(true).open(dir.join("src/main.rs")).unwrap().set_modified(now - Duration::from_secs(60)).unwrap();
+        assert_eq!(check_artifacts(&config, &tasks, &dir).unwrap(), (1, vec![]));
+
+        // The source changed after the binary was built
+        fs::File::options().write(true).open(dir.join("target/release/p")).unwrap().set_modified(now - Duration::from_secs(120)).unwrap();
+        let (_, problems) = check_artifacts(&config, &tasks, &dir).unwrap();
+        assert_eq!(problems, ["'target/release/p' of task 'build' is older than its source src/main.rs"]);
+
+        fs::remove_file(dir.join("target/release/p")).unwrap();
+        let (checked, problems) = check_artifacts(&config, &tasks, &dir).unwrap();
+        assert_eq!((checked, problems), (0, vec!["'target/release/p' of task 'build' matched no files".to_string()]));
+    }
+}
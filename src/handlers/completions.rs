@@ -0,0 +1,179 @@
+use anyhow::{Result, bail};
+use indexmap::IndexMap;
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::env;
+use std::path::PathBuf;
+use crate::cli::Cli;
+use crate::config::{load_config_with, resolve_config_path, LoadOptions};
+use crate::runner::task::{task_group, RunnerTask};
+use std::collections::BTreeSet;
+
+/// Hidden command the completion scripts call for task names.
+pub const COMPLETE_TASKS: &str = "__complete-tasks";
+
+// Task names are only known at completion time, so each script asks
+// `p __complete-tasks` for them wherever a TASK argument is expected.
+const ZSH_TASKS: &str = r#"_p_tasks() {
+    local -a tasks
+    tasks=("${(@f)$(p __complete-tasks --shell zsh 2>/dev/null)}")
+    _describe -t tasks 'task' tasks
+}
+
+"#;
+
+const BASH_TASKS: &str = r#"            opts="${opts} $(p __complete-tasks --shell bash 2>/dev/null)""#;
+
+const FISH_TASKS: &str = r#"complete -c p -n "__fish_p_needs_command" -f -a "(p __complete-tasks --shell fish 2>/dev/null)"
+"#;
+
+const POWERSHELL_TASKS: &str = r#"            p __complete-tasks --shell powershell 2>$null | ForEach-Object {
+                $name, $desc = $_ -split "`t", 2
+                [CompletionResult]::new($name, $name, [CompletionResultType]::ParameterValue, $(if ($desc) { $desc } else { $name }))
+            }"#;
+
+/// The clap completion script for `shell`, with task name completion added.
+pub fn completion_script(shell: Shell) -> Result<String> {
+    let mut buf = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "p", &mut buf);
+    let script = String::from_utf8(buf)?;
+
+    let hooked = match shell {
+        // The first option list is the top-level one, where TASK is completed
+        Shell::Bash => insert_after_line(&script, |l| l.trim_start().starts_with("opts=\"-"), BASH_TASKS),
+        Shell::Zsh => {
+            let script = replace_line(&script, |l| l.starts_with("'::TASK -- "), |l| l.replacen(":_default'", ":_p_tasks'", 1));
+            script.map(|s| s.replacen("_p() {", &format!("{}_p() {{", ZSH_TASKS), 1))
+        },
+        Shell::Fish => Some(format!("{}{}", script, FISH_TASKS)),
+        Shell::PowerShell => insert_after_line(&script, |l| l.trim() == "'p' {", POWERSHELL_TASKS),
+        _ => Some(script.clone()),
+    };
+    match hooked {
+        Some(s) => Ok(s),
+        None => bail!("Could not add task completion to the {} script", shell),
+    }
+}
+
+fn insert_after_line(script: &str, matches: impl Fn(&str) -> bool, insert: &str) -> Option<String> {
+    let mut out = String::with_capacity(script.len() + insert.len());
+    let mut done = false;
+    for line in script.lines() {
+        out.push_str(line);
+        out.push('\n');
+        if !done && matches(line) {
+            out.push_str(insert);
+            out.push('\n');
+            done = true;
+        }
+    }
+    done.then_some(out)
+}
+
+fn replace_line(script: &str, matches: impl Fn(&str) -> bool, replace: impl Fn(&str) -> String) -> Option<String> {
+    let mut out = String::with_capacity(script.len());
+    let mut done = false;
+    for line in script.lines() {
+        if !done && matches(line) {
+            out.push_str(&replace(line));
+            done = true;
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    done.then_some(out)
+}
+
+pub fn handle_completions(shell: Shell) -> Result<()> {
+    print!("{}", completion_script(shell)?);
+    Ok(())
+}
+
+/// One completion entry per task and per group (`db:` runs every `db:*` task), sorted,
+/// in the format the shell's script expects.
+pub fn task_completions(tasks: &IndexMap<String, RunnerTask>, shell: Option<&str>) -> Vec<String> {
+    let mut entries: Vec<(String, String)> = tasks.iter().map(|(name, task)| {
+        let desc = match task {
+            RunnerTask::Full { description: Some(d), .. } => d.lines().next().unwrap_or_default(),
+            _ => "",
+        };
+        (name.clone(), desc.to_string())
+    }).collect();
+    let groups: BTreeSet<&str> = tasks.keys().filter_map(|name| task_group(name)).collect();
+    entries.extend(groups.into_iter().map(|group| (format!("{}:", group), format!("every {}: task", group))));
+    entries.sort();
+    entries.into_iter().map(|(name, desc)| {
+        match shell {
+            Some("bash") => name,
+            // _describe splits on the first unescaped colon
+            Some("zsh") if desc.is_empty() => name.replace(':', "\\:"),
+            Some("zsh") => format!("{}:{}", name.replace(':', "\\:"), desc),
+            _ if desc.is_empty() => name,
+            _ => format!("{}\t{}", name, desc),
+        }
+    }).collect()
+}
+
+/// Prints task names for the completion scripts. Never fails and never prints
+/// anything else: no p.toml or a broken one simply completes nothing.
+pub fn handle_complete_tasks(shell: Option<&str>) -> Result<()> {
+    let Ok(cwd) = env::current_dir() else { return Ok(()) };
+    let config_file = env::var_os("P_CONFIG").map(PathBuf::from);
+    let config_path = resolve_config_path(&cwd, None, config_file.as_deref());
+    if !config_path.exists() {
+        return Ok(());
+    }
+    if let Ok(config) = load_config_with(&config_path, LoadOptions::SHALLOW)
+        && let Some(tasks) = &config.runner {
+        for line in task_completions(tasks, shell) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks() -> IndexMap<String, RunnerTask> {
+        toml::from_str(r#"
+test = "cargo test"
+
+[build]
+cmds = ["cargo build"]
+description = "Compile the project\nin debug mode"
+
+["db:migrate"]
+cmds = ["sqlx migrate run"]
+"#).unwrap()
+    }
+
+    #[test]
+    fn test_task_completions() {
+        let tasks = tasks();
+        assert_eq!(task_completions(&tasks, Some("bash")), ["build", "db:", "db:migrate", "test"]);
+        assert_eq!(task_completions(&tasks, Some("zsh")), ["build:Compile the project", "db\\::every db: task", "db\\:migrate", "test"]);
+        assert_eq!(task_completions(&tasks, Some("fish")), ["build\tCompile the project", "db:\tevery db: task", "db:migrate", "test"]);
+        assert_eq!(task_completions(&tasks, None), ["build\tCompile the project", "db:\tevery db: task", "db:migrate", "test"]);
+    }
+
+    #[test]
+    fn test_scripts_call_task_helper() {
+        let zsh = completion_script(Shell::Zsh).unwrap();
+        assert!(zsh.contains("p __complete-tasks --shell zsh"));
+        assert!(zsh.contains(":_p_tasks' \\"));
+        assert!(!zsh.contains("'__complete-tasks:"), "hidden helper must not be offered");
+
+        for shell in [Shell::Bash, Shell::Fish, Shell::PowerShell] {
+            let script = completion_script(shell).unwrap();
+            assert!(script.contains("p __complete-tasks"), "{} script", shell);
+            assert_eq!(script.matches(COMPLETE_TASKS).count(), 1, "{} offers the hidden helper", shell);
+        }
+        let bash = completion_script(Shell::Bash).unwrap();
+        let hook = bash.lines().position(|l| l.contains("p __complete-tasks")).unwrap();
+        assert!(bash.lines().nth(hook - 1).unwrap().contains("--list"), "tasks must extend the top-level options");
+        assert!(completion_script(Shell::Elvish).is_ok());
+    }
+}
@@ -0,0 +1,443 @@
+use anyhow::{Result, bail};
+use colored::*;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use crate::config::{env_schema_status, extension_files, load_config_static, load_config_with, LoadOptions, PavidiConfig};
+use crate::diagnostic::Diagnostic;
+use crate::handlers::lock::{lock_problems, LOCK_FILE};
+use crate::logger::log_root;
+use crate::runner::capability::program_word;
+use crate::runner::graph::TaskGraph;
+use crate::runner::outputs::output_conflicts;
+use crate::ui::{self, glyph, CiMode, Glyph};
+use crate::utils::detect_shell;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of one diagnostic, with a remediation hint when it did not pass.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Check { name, status: Status::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check { name, status: Status::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check { name, status: Status::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+/// Shell words that are never found on PATH but always work.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "break", "cd", "continue", "echo", "eval", "exec", "exit", "export",
+    "false", "for", "if", "popd", "pushd", "read", "set", "shift", "source", "test", "trap",
+    "true", "type", "ulimit", "umask", "unset", "wait", "while",
+];
+
+/// An error chain on one line, without the glyph some errors start with.
+fn error_detail(e: &anyhow::Error) -> String {
+    if let Some(diagnostic) = e.downcast_ref::<Diagnostic>() {
+        return diagnostic.summary();
+    }
+    let message = format!("{:#}", e);
+    message.trim_start_matches(glyph(Glyph::Fail)).trim_start().to_string()
+}
+
+/// Every `p.*.toml` extension can be read and parsed on its own.
+pub fn check_extensions(config_path: &Path) -> Check {
+    let files = match extension_files(config_path) {
+        Ok(files) => files,
+        Err(e) => return Check::fail("Extensions", format!("{:#}", e), "Check the project directory path"),
+    };
+    let broken: Vec<String> = files.iter().filter_map(|path| {
+        let name = path.file_name()?.to_string_lossy().into_owned();
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str::<PavidiConfig>(&content).err().map(|e| Diagnostic::from_toml(Path::new(&name), &content, &e).summary()),
+            Err(e) => Some(format!("{}: {}", name, e)),
+        }
+    }).collect();
+
+    match (files.len(), broken.is_empty()) {
+        (0, _) => Check::pass("Extensions", "none"),
+        (n, true) => Check::pass("Extensions", format!("{} loaded", n)),
+        (_, false) => Check::fail("Extensions", broken.join("; "), "Fix or remove the listed files"),
+    }
+}
+
+/// No task depends on a missing task or on itself.
+pub fn check_task_graph(config: &PavidiConfig) -> Check {
+    let Some(tasks) = &config.runner else {
+        return Check::warn("Tasks", "no [runner] section", "Add tasks under [runner] in p.toml");
+    };
+    let graph = TaskGraph::from_tasks(tasks);
+    let mut problems: Vec<String> = graph.missing_deps().into_iter()
+        .map(|(task, dep)| format!("'{}' depends on missing task '{}'", task, dep))
+        .collect();
+    problems.extend(graph.cycles().into_iter().map(|cycle| format!("circular dependency {}", cycle.join(" -> "))));
+
+    if problems.is_empty() {
+        Check::pass("Tasks", format!("{} defined, dependencies resolve", tasks.len()))
+    } else {
+        Check::fail("Tasks", problems.join("; "), "Fix the deps lists; `p graph` shows the whole picture")
+    }
+}
+
+/// No two tasks declare overlapping `outputs`; a failure with `strict_outputs`.
+pub fn check_outputs(config: &PavidiConfig) -> Check {
+    let conflicts = config.runner.as_ref().map(|tasks| output_conflicts(tasks, None)).unwrap_or_default();
+    if conflicts.is_empty() {
+        return Check::pass("Outputs", "no overlaps");
+    }
+    let detail = conflicts.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ");
+    let hint = "Give each task its own output paths, or merge the tasks";
+    if config.strict_outputs() {
+        Check::fail("Outputs", detail, hint)
+    } else {
+        Check::warn("Outputs", detail, hint)
+    }
+}
+
+/// `@os` markers and `os_mode` only appear in tasks that have OS-specific commands.
+pub fn check_os_commands(config: &PavidiConfig) -> Check {
+    let mut problems: Vec<String> = config.runner.iter().flatten()
+        .filter_map(|(name, task)| task.os_problem().map(|p| format!("'{}' {}", name, p)))
+        .collect();
+    problems.sort();
+    if problems.is_empty() {
+        Check::pass("OS commands", "consistent")
+    } else {
+        Check::warn("OS commands", problems.join("; "), "Add windows, linux or macos lists, or remove @os and os_mode")
+    }
+}
+
+/// The shell tasks run in can be found.
+pub fn check_shell(config: &PavidiConfig) -> Check {
+    let shell_pref = config.project.as_ref().and_then(|p| p.shell.as_ref())
+        .or(config.module.as_ref().and_then(|m| m.shell.as_ref()));
+    let shell = detect_shell(shell_pref);
+    match which::which(&shell) {
+        Ok(path) => Check::pass("Shell", format!("{} ({})", shell, path.display())),
+        Err(_) => Check::fail("Shell", format!("'{}' not found", shell), "Install it, or set `shell` under [project] to one that is"),
+    }
+}
+
+/// Programs started by task commands: `(task, program)`, sorted.
+/// Builtins, `p:` portable commands and programs given through variables are skipped.
+pub fn invoked_programs(config: &PavidiConfig) -> BTreeSet<(String, String)> {
+    let Some(tasks) = &config.runner else { return BTreeSet::new() };
+    let mut programs = BTreeSet::new();
+    for (name, task) in tasks {
+        for cmd in task.commands() {
+            let Some(word) = program_word(&cmd) else { continue };
+            if word.starts_with("p:") || word.contains('$') || SHELL_BUILTINS.contains(&word.as_str())
+                || !word.starts_with(|c: char| c.is_alphanumeric() || c == '.' || c == '/' || c == '_') {
+                continue;
+            }
+            programs.insert((name.clone(), word));
+        }
+    }
+    programs
+}
+
+/// Programs invoked by task commands that cannot be found: `(task, program)`, sorted.
+pub fn missing_programs(config: &PavidiConfig) -> Vec<(String, String)> {
+    invoked_programs(config).into_iter().filter(|(_, word)| {
+        if word.contains('/') || word.contains('\\') {
+            !config.root_dir.join(word).exists()
+        } else {
+            which::which(word).is_err()
+        }
+    }).collect()
+}
+
+pub fn check_programs(config: &PavidiConfig) -> Check {
+    let missing = missing_programs(config);
+    if missing.is_empty() {
+        return Check::pass("Programs", "every task command resolves");
+    }
+    let list: Vec<String> = missing.iter().map(|(task, program)| format!("{} (task '{}')", program, task)).collect();
+    Check::warn("Programs", format!("not found: {}", list.join(", ")), "Install them or add them to PATH before running those tasks")
+}
+
+/// p.lock.toml, if there is one, lists the programs tasks run and matches what is installed.
+pub fn check_lockfile(config: &PavidiConfig) -> Check {
+    match lock_problems(config) {
+        Ok(None) => Check::pass("Lockfile", "none"),
+        Ok(Some((stale, _))) if !stale.is_empty() => {
+            Check::warn("Lockfile", format!("{} is stale: {}", LOCK_FILE, stale.join(", ")), "Run `p lock` to update it")
+        },
+        Ok(Some((_, differing))) if !differing.is_empty() => {
+            Check::warn("Lockfile", format!("installed tools differ: {}", differing.join(", ")), "Install the locked versions, or run `p lock` to accept these")
+        },
+        Ok(Some(_)) => Check::pass("Lockfile", "installed tools match"),
+        Err(e) => Check::fail("Lockfile", error_detail(&e), format!("Fix or delete {}, then run `p lock`", LOCK_FILE)),
+    }
+}
+
+/// Dynamic `$(...)` env commands all succeed.
+pub fn check_dynamic_env(config_path: &Path, config: &PavidiConfig) -> Check {
+    let dynamic = config.env.values().filter(|v| v.starts_with("$(") && v.ends_with(')')).count();
+    if dynamic == 0 {
+        return Check::pass("Dynamic env", "none");
+    }
+    match load_config_with(config_path, LoadOptions { validate_env: false, ..LoadOptions::FULL }) {
+        Ok(_) => Check::pass("Dynamic env", format!("{} resolved", dynamic)),
+        Err(e) => Check::fail("Dynamic env", error_detail(&e), "Run the command by hand to see why it fails"),
+    }
+}
+
+/// The env matches `[env_schema]`. Dynamic values are checked when a task runs.
+pub fn check_env_schema(config: &PavidiConfig) -> Check {
+    if config.env_schema.is_empty() {
+        return Check::pass("Env schema", "no [env_schema]");
+    }
+    let problems: Vec<String> = env_schema_status(config).into_iter()
+        .filter_map(|(key, _, problem)| problem.map(|p| format!("{}: {}", key, p)))
+        .collect();
+    if problems.is_empty() {
+        Check::pass("Env schema", format!("{} variable(s) match", config.env_schema.len()))
+    } else {
+        Check::fail("Env schema", problems.join("; "), "Set these in [env] or a .env file, or fix their values; `p -e` shows each one")
+    }
+}
+
+/// Every `allow_paths` entry exists.
+pub fn check_allow_paths(config: &PavidiConfig) -> Check {
+    let Some(allowed) = config.capability.as_ref().and_then(|c| c.allow_paths.as_ref()) else {
+        return Check::pass("Allowed paths", "no allow_paths policy");
+    };
+    let missing: Vec<&str> = allowed.iter()
+        .filter(|p| !p.contains('*') && !Path::new(p).exists())
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        Check::pass("Allowed paths", format!("{} entries exist", allowed.len()))
+    } else {
+        Check::warn("Allowed paths", format!("missing: {}", missing.join(", ")), "Create them, or remove stale entries from [capability] allow_paths")
+    }
+}
+
+/// `dir` exists and is writable. With `fix`, creates it or restores the owner's write permission.
+pub fn check_state_dir(name: &'static str, dir: &Path, fix: bool) -> Check {
+    if fix {
+        if !dir.exists() {
+            let _ = fs::create_dir_all(dir);
+        } else if !is_writable(dir) {
+            restore_write(dir);
+        }
+    }
+    if !dir.exists() {
+        return Check::warn(name, format!("{} does not exist yet", dir.display()), "It is created on first use, or run `p doctor --fix`");
+    }
+    if is_writable(dir) {
+        Check::pass(name, format!("{} is writable", dir.display()))
+    } else {
+        Check::fail(name, format!("{} is not writable", dir.display()), "Fix its permissions, or run `p doctor --fix` as its owner")
+    }
+}
+
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    let ok = fs::write(&probe, b"").is_ok();
+    let _ = fs::remove_file(&probe);
+    ok
+}
+
+#[cfg(unix)]
+fn restore_write(dir: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = fs::metadata(dir) {
+        let mut perms = meta.permissions();
+        // u+rwx only: never widen access for anyone else
+        perms.set_mode(perms.mode() | 0o700);
+        let _ = fs::set_permissions(dir, perms);
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_write(_dir: &Path) {}
+
+/// How console output will look. Informational only.
+pub fn check_terminal() -> Check {
+    let tty = if std::io::stdout().is_terminal() { "terminal" } else { "not a terminal" };
+    let colors = if colored::control::SHOULD_COLORIZE.should_colorize() { "colors on" } else { "colors off" };
+    let ci = match ui::ci_mode() {
+        CiMode::None => "",
+        CiMode::Generic => ", CI",
+        CiMode::GitHubActions => ", GitHub Actions",
+    };
+    Check::pass("Terminal", format!("{}, {}{}, {} style", tty, colors, ci, ui::style().name()))
+}
+
+/// Runs every check against the project at `config_path`.
+pub fn run_checks(config_path: &Path, fix: bool) -> Vec<Check> {
+    let mut checks = vec![check_extensions(config_path)];
+    match load_config_static(config_path) {
+        Ok(config) => {
+            checks.push(Check::pass("Configuration", format!("{} parses", config_path.display())));
+            checks.push(check_task_graph(&config));
+            checks.push(check_outputs(&config));
+            checks.push(check_os_commands(&config));
+            checks.push(check_shell(&config));
+            checks.push(check_programs(&config));
+            checks.push(check_lockfile(&config));
+            checks.push(check_dynamic_env(config_path, &config));
+            checks.push(check_env_schema(&config));
+            checks.push(check_allow_paths(&config));
+            let state_dir = config.root_dir.join(".p");
+            checks.push(check_state_dir("State directory", &state_dir, fix));
+            let logs = log_root(&config);
+            if !logs.starts_with(&state_dir) {
+                checks.push(check_state_dir("Log directory", &logs, fix));
+            }
+        },
+        Err(e) => checks.push(Check::fail("Configuration", error_detail(&e), "Fix the error above, then run `p doctor` again")),
+    }
+    checks.push(check_terminal());
+    checks
+}
+
+pub fn handle_doctor(config_path: &Path, fix: bool) -> Result<()> {
+    let checks = run_checks(config_path, fix);
+    for check in &checks {
+        let mark = match check.status {
+            Status::Pass => glyph(Glyph::Ok).green(),
+            Status::Warn => glyph(Glyph::Warn).yellow(),
+            Status::Fail => glyph(Glyph::Fail).red(),
+        };
+        println!("{} {}: {}", mark, check.name.bold(), check.detail);
+        if let Some(hint) = &check.hint {
+            println!("    {}", hint.dimmed());
+        }
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        bail!("{} check(s) failed", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    fn config(toml: &str) -> PavidiConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_check_extensions() {
+        let dir = scratch("ext");
+        fs::write(dir.join("p.toml"), "").unwrap();
+        assert_eq!(check_extensions(&dir.join("p.toml")).detail, "none");
+
+        fs::write(dir.join("p.local.toml"), "[env]\nA = \"1\"\n").unwrap();
+        fs::write(dir.join("p.zz.toml"), "[env\n").unwrap();
+        let check = check_extensions(&dir.join("p.toml"));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.starts_with("p.zz.toml:1:5: "), "{}", check.detail);
+    }
+
+    #[test]
+    fn test_check_task_graph() {
+        let ok = config("[runner]\na = \"true\"\n[runner.b]\ndeps = [\"a\"]\n");
+        assert_eq!(check_task_graph(&ok).status, Status::Pass);
+
+        let broken = config("[runner.a]\ndeps = [\"b\", \"gone\"]\n[runner.b]\ndeps = [\"a\"]\n");
+        let check = check_task_graph(&broken);
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("'a' depends on missing task 'gone'"), "{}", check.detail);
+        assert!(check.detail.contains("circular dependency"), "{}", check.detail);
+    }
+
+    #[test]
+    fn test_check_outputs() {
+        let tasks = "[runner]\nbuild = { cmds = [\"true\"], outputs = [\"dist/**\"] }\nbundle = { cmds = [\"true\"], outputs = [\"dist/app.js\"] }\n";
+        let check = check_outputs(&config(tasks));
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.detail, "'build' (dist/**) and 'bundle' (dist/app.js) write overlapping outputs");
+        assert_eq!(check_outputs(&config(&format!("[project]\nstrict_outputs = true\n{}", tasks))).status, Status::Fail);
+        assert_eq!(check_outputs(&config("[runner]\na = \"true\"\n")).status, Status::Pass);
+    }
+
+    #[test]
+    fn test_check_os_commands() {
+        assert_eq!(check_os_commands(&config("[runner.a]\ncmds = [\"x\", \"@os\"]\nlinux = [\"y\"]\n")).status, Status::Pass);
+        let check = check_os_commands(&config("[runner.a]\ncmds = [\"x\", \"@os\"]\n"));
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.detail.starts_with("'a' uses @os"), "{}", check.detail);
+    }
+
+    #[test]
+    fn test_check_shell() {
+        assert_eq!(check_shell(&config("[project]\nshell = \"sh\"\n")).status, Status::Pass);
+        assert_eq!(check_shell(&config("[project]\nshell = \"no-such-shell-xyz\"\n")).status, Status::Fail);
+    }
+
+    #[test]
+    fn test_missing_programs() {
+        let mut cfg = config(r#"
+[runner]
+build = ["cd src", "FOO=1 no-such-tool-xyz --flag", "echo done", "p:rm -rf out", "$CC main.c"]
+script = "./scripts/gen.sh"
+"#);
+        let dir = scratch("programs");
+        cfg.root_dir = dir.to_path_buf();
+        assert_eq!(missing_programs(&cfg), [
+            ("build".to_string(), "no-such-tool-xyz".to_string()),
+            ("script".to_string(), "./scripts/gen.sh".to_string()),
+        ]);
+        fs::create_dir_all(cfg.root_dir.join("scripts")).unwrap();
+        fs::write(cfg.root_dir.join("scripts/gen.sh"), "").unwrap();
+        assert_eq!(missing_programs(&cfg).len(), 1);
+        assert_eq!(check_programs(&cfg).status, Status::Warn);
+    }
+
+    #[test]
+    fn test_check_allow_paths() {
+        let dir = scratch("allow");
+        let cfg = config(&format!("[capability]\nallow_paths = ['{}', '{}/missing', '{}/*.log']\n", dir.display(), dir.display(), dir.display()));
+        let check = check_allow_paths(&cfg);
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.detail, format!("missing: {}/missing", dir.display()));
+        assert_eq!(check_allow_paths(&config("")).status, Status::Pass);
+    }
+
+    #[test]
+    fn test_check_state_dir_fix() {
+        let root = scratch("state");
+        let dir = root.join(".p");
+        assert_eq!(check_state_dir("State directory", &dir, false).status, Status::Warn);
+        assert!(!dir.exists());
+        assert_eq!(check_state_dir("State directory", &dir, true).status, Status::Pass);
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn test_missing_config_fails() {
+        let dir = scratch("missing");
+        let checks = run_checks(&dir.join("p.toml"), false);
+        let config = checks.iter().find(|c| c.name == "Configuration").unwrap();
+        assert_eq!(config.status, Status::Fail);
+        assert!(config.hint.is_some());
+    }
+}
@@ -1,31 +1,76 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use colored::*;
-use std::env;
-use std::collections::HashSet;
-use crate::config::load_config;
-use crate::cli::Cli;
+use regex::Regex;
+use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use indexmap::IndexMap;
+use std::ffi::OsString;
+use std::io::Write;
+use crate::config::{active_profile, available_profiles, env_schema_status, load_config_with, session_env, LoadOptions, PavidiConfig, ProfileSource, PROFILE_FILE, SESSION_ENV_FILE};
+use super::apply_project_settings;
+use crate::cli::{Cli, ExportShell};
+use crate::logger::{is_sensitive_key, LogSettings};
+use crate::ui::{self, glyph, Glyph};
+use crate::logger::ensure_state_gitignore;
+use crate::state::write_atomic;
+use crate::utils::child_environment;
+use super::output::{env_summary, print_json};
 
-pub fn handle_env(cli: &Cli) -> Result<()> {
-    let current_dir = env::current_dir()?;
-    // Load config which merges p.toml and .env
-    let config = load_config(&current_dir)?;
+pub fn handle_env(cli: &Cli, config_path: &Path) -> Result<()> {
+    if cli.profiles || cli.use_profile.is_some() || cli.unset {
+        return handle_profiles(cli, config_path);
+    }
+    if !cli.set.is_empty() || !cli.unset_var.is_empty() {
+        return handle_session(cli, config_path);
+    }
+
+    // Load config which merges p.toml and .env; schema problems are shown below instead of failing
+    let config = load_config_with(config_path, LoadOptions { validate_env: false, ..LoadOptions::FULL })?;
+    apply_project_settings(&config);
+
+    if cli.effective {
+        let patterns = LogSettings::from_config(&config).secret_patterns;
+        let vars = effective(&child_environment(config.command_settings.env_filter.as_ref(), &config.env), &patterns, cli.include_secrets);
+        if cli.json {
+            return print_json(&vars);
+        }
+        for (key, value) in vars {
+            println!("{}={}", key, value);
+        }
+        return Ok(());
+    }
+
+    if cli.json {
+        return print_json(&env_summary(&config));
+    }
+
+    if cli.export || cli.dotenv {
+        let patterns = LogSettings::from_config(&config).secret_patterns;
+        let vars = exportable(&config.env, &patterns, cli.include_secrets);
+        let format = if cli.dotenv { ExportFormat::Dotenv } else { ExportFormat::Shell(cli.shell) };
+        print!("{}", render_exports(&vars, format));
+        return Ok(());
+    }
+
+    let mut out = ui::Pager::new();
+    print_profile(&mut out, &config)?;
 
     if cli.trace {
-        println!("{} Environment Variable Trace:", "🔍".cyan());
+        writeln!(out, "{} Environment Variable Trace:", glyph(Glyph::Inspect).cyan())?;
         
         let mut keys: Vec<&String> = config.env_provenance.keys().collect();
         keys.sort();
 
         for key in keys {
             let history = &config.env_provenance[key];
-            println!("{}:", key.bold());
+            writeln!(out, "{}:", key.bold())?;
             for (idx, (source, val)) in history.iter().enumerate() {
                 let prefix = if idx == history.len() - 1 { "└──".green() } else { "├──".blue() };
-                println!("  {} {} = {} ({})", prefix, source, val, if idx == history.len() - 1 { "active".green() } else { "overridden".red().dimmed() });
+                writeln!(out, "  {} {} = {} ({})", prefix, source, val, if idx == history.len() - 1 { "active".green() } else { "overridden".red().dimmed() })?;
             }
         }
     } else {
-        println!("{} Environment Variables (Layered):", "🔍".cyan());
+        writeln!(out, "{} Environment Variables (Layered):", glyph(Glyph::Inspect).cyan())?;
         
         // Identify all unique sources involved, preserving order if possible
         let mut ordered_sources = Vec::new();
@@ -44,16 +89,22 @@ pub fn handle_env(cli: &Cli) -> Result<()> {
             }
         }
 
-        // 3. Other sources (.env, dynamic) found in provenance
+        // 3. .env files, in the order they were layered
+        for file in &config.dotenv_applied {
+            if seen_sources.insert(file.clone()) {
+                ordered_sources.push(file.clone());
+            }
+        }
+
+        // 4. Other sources (dynamic) found in provenance
         // We'll collect them and append them. Typically .env is last.
         // But we want to preserve some logical order.
         let mut other_sources = Vec::new();
         for history in config.env_provenance.values() {
             for (source, _) in history {
-                if !seen_sources.contains(source) {
-                    if seen_sources.insert(source.clone()) {
-                        other_sources.push(source.clone());
-                    }
+                if !seen_sources.contains(source)
+                    && seen_sources.insert(source.clone()) {
+                    other_sources.push(source.clone());
                 }
             }
         }
@@ -62,7 +113,7 @@ pub fn handle_env(cli: &Cli) -> Result<()> {
         ordered_sources.extend(other_sources);
 
         for source in ordered_sources {
-            println!("\n[{}]", source.yellow().bold());
+            writeln!(out, "\n[{}]", source.yellow().bold())?;
             
             // Find vars defined/modified in this source
             let mut vars_in_source = Vec::new();
@@ -79,19 +130,284 @@ pub fn handle_env(cli: &Cli) -> Result<()> {
             vars_in_source.sort_by_key(|k| k.0);
             
             if vars_in_source.is_empty() {
-                println!("  (none)");
+                writeln!(out, "  (none)")?;
             }
 
             for (key, val, is_active) in vars_in_source {
                 if is_active {
-                     println!("  {} = {}", key.bold(), val);
+                     writeln!(out, "  {} = {}", key.bold(), val)?;
                 } else {
                      // Show as overridden
-                     println!("  {} = {} {}", key.dimmed().strikethrough(), val.dimmed().strikethrough(), "(overridden)".red().italic());
+                     writeln!(out, "  {} = {} {}", key.dimmed().strikethrough(), val.dimmed().strikethrough(), "(overridden)".red().italic())?;
                 }
             }
         }
     }
 
+    print_schema_status(&mut out, &config)?;
+    out.finish()
+}
+
+/// `p e --profiles`, `--use <profile>` and `--unset`.
+fn handle_profiles(cli: &Cli, config_path: &Path) -> Result<()> {
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    let root = &config.root_dir;
+    let saved = root.join(PROFILE_FILE);
+
+    if let Some(name) = &cli.use_profile {
+        if name.is_empty() || name.contains(['/', '\\']) || name.chars().any(char::is_whitespace) {
+            bail!("Invalid profile name '{}': use a plain name such as 'staging'", name);
+        }
+        let state_dir = root.join(".p");
+        std::fs::create_dir_all(&state_dir).with_context(|| format!("Failed to create {}", state_dir.display()))?;
+        ensure_state_gitignore(&state_dir);
+        write_atomic(&saved, &format!("{}\n", name))?;
+        println!("{} Using profile '{}' (saved in {})", glyph(Glyph::Ok).green(), name.bold(), PROFILE_FILE);
+        if !available_profiles(root)?.contains(name) {
+            println!("{} No .env.{} file was found; only P_ENV will be set", glyph(Glyph::Warn).yellow(), name);
+        }
+    } else if cli.unset {
+        if saved.exists() {
+            std::fs::remove_file(&saved).with_context(|| format!("Failed to remove {}", saved.display()))?;
+            println!("{} Removed the saved profile", glyph(Glyph::Ok).green());
+        } else {
+            println!("No profile is saved");
+        }
+    } else {
+        let active = active_profile(root);
+        let profiles = available_profiles(root)?;
+        if profiles.is_empty() {
+            println!("No profiles: add a .env.<profile> file to create one");
+        }
+        for profile in profiles {
+            if active.as_ref().is_some_and(|a| a.name == profile) {
+                println!("* {}", profile.green().bold());
+            } else {
+                println!("  {}", profile);
+            }
+        }
+    }
+
+    // P_ENV keeps winning over what was just saved
+    if (cli.use_profile.is_some() || cli.unset)
+        && let Some(active) = active_profile(root)
+        && active.source == ProfileSource::EnvVar {
+        println!("{} P_ENV is set to '{}', which takes precedence in this shell", glyph(Glyph::Warn).yellow(), active.name);
+    }
+    Ok(())
+}
+
+/// `p e --set KEY=VALUE` and `--unset-var KEY`: edit `.p/session-env.toml`.
+fn handle_session(cli: &Cli, config_path: &Path) -> Result<()> {
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    let root = &config.root_dir;
+    let patterns = LogSettings::from_config(&config).secret_patterns;
+    let mut vars = session_env(root)?;
+
+    for assignment in &cli.set {
+        let Some((key, value)) = assignment.split_once('=') else {
+            bail!("Invalid assignment '{}': expected KEY=VALUE", assignment);
+        };
+        if !is_valid_name(key) {
+            bail!("Invalid variable name '{}'", key);
+        }
+        // The file is plain text next to the project; credentials belong in .env or the shell
+        if is_secret(key, value, &patterns) {
+            bail!("Refusing to save '{}': it looks like a secret. Put it in a .env file or export it instead", key);
+        }
+        vars.insert(key.to_string(), value.to_string());
+        println!("{} Saved {} (in {})", glyph(Glyph::Ok).green(), key.bold(), SESSION_ENV_FILE);
+    }
+    for key in &cli.unset_var {
+        if vars.remove(key).is_some() {
+            println!("{} Removed {}", glyph(Glyph::Ok).green(), key.bold());
+        } else {
+            println!("{} is not saved", key);
+        }
+    }
+
+    let path = root.join(SESSION_ENV_FILE);
+    if vars.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        return Ok(());
+    }
+    let state_dir = root.join(".p");
+    std::fs::create_dir_all(&state_dir).with_context(|| format!("Failed to create {}", state_dir.display()))?;
+    ensure_state_gitignore(&state_dir);
+    write_atomic(&path, &toml::to_string(&vars)?)
+}
+
+/// The header line naming the active profile and where it came from.
+fn print_profile(out: &mut impl Write, config: &PavidiConfig) -> Result<()> {
+    match &config.profile {
+        Some(profile) => writeln!(out, "{} Profile: {} (from {})", glyph(Glyph::Env).green(), profile.name.bold(), profile.describe_source())?,
+        None => writeln!(out, "{} Profile: {}", glyph(Glyph::Env).green(), "none".dimmed())?,
+    }
+    Ok(())
+}
+
+/// One line per `[env_schema]` variable: its type and whether the value matches.
+fn print_schema_status(out: &mut impl Write, config: &PavidiConfig) -> Result<()> {
+    if config.env_schema.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "\n{}", "[env_schema]".yellow().bold())?;
+    for (key, value, problem) in env_schema_status(config) {
+        let kind = format!("({})", config.env_schema[&key].describe()).dimmed();
+        match problem {
+            Some(problem) => writeln!(out, "  {} {} {}: {}", glyph(Glyph::Fail).red(), key.bold(), kind, problem.red())?,
+            None => writeln!(out, "  {} {} {} = {}", glyph(Glyph::Ok).green(), key.bold(), kind, value.unwrap_or_default())?,
+        }
+    }
     Ok(())
 }
+
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Shell(ExportShell),
+    Dotenv,
+}
+
+fn is_secret(key: &str, value: &str, patterns: &[Regex]) -> bool {
+    is_sensitive_key(key) || patterns.iter().any(|re| re.is_match(key) || re.is_match(value))
+}
+
+fn is_valid_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Final values to export: sorted, secrets dropped unless asked for, and names
+/// that no shell could assign skipped.
+fn exportable<'a>(env: &'a IndexMap<String, String>, patterns: &[Regex], include_secrets: bool) -> BTreeMap<&'a str, &'a str> {
+    env.iter()
+        .filter(|(k, v)| include_secrets || !is_secret(k, v, patterns))
+        .filter(|(k, _)| {
+            let valid = is_valid_name(k);
+            if !valid {
+                log::warn!("{} Skipping '{}': not a valid variable name", glyph(Glyph::Warn), k);
+            }
+            valid
+        })
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect()
+}
+
+/// The child environment for display: sorted, secrets redacted unless asked for.
+fn effective(child: &HashMap<OsString, OsString>, patterns: &[Regex], include_secrets: bool) -> BTreeMap<String, String> {
+    child.iter()
+        .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+        .map(|(k, v)| {
+            let value = if include_secrets || !is_secret(&k, &v, patterns) { v } else { "[REDACTED]".to_string() };
+            (k, value)
+        })
+        .collect()
+}
+
+fn single_quote(value: &str, shell: ExportShell) -> String {
+    match shell {
+        ExportShell::Posix => format!("'{}'", value.replace('\'', "'\\''")),
+        ExportShell::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+        ExportShell::Powershell => format!("'{}'", value.replace('\'', "''")),
+    }
+}
+
+// dotenv expands `$VAR` in unquoted and double-quoted values, single quotes are literal
+fn dotenv_value(value: &str) -> String {
+    let plain = value.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:,@+%".contains(c));
+    if plain {
+        value.to_string()
+    } else if !value.contains('\'') && !value.contains('\n') {
+        format!("'{}'", value)
+    } else {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$").replace('\n', "\\n");
+        format!("\"{}\"", escaped)
+    }
+}
+
+fn render_exports(vars: &BTreeMap<&str, &str>, format: ExportFormat) -> String {
+    let mut out = String::new();
+    for (key, value) in vars {
+        let line = match format {
+            ExportFormat::Dotenv => format!("{}={}", key, dotenv_value(value)),
+            ExportFormat::Shell(shell @ ExportShell::Posix) => format!("export {}={}", key, single_quote(value, shell)),
+            ExportFormat::Shell(shell @ ExportShell::Fish) => format!("set -gx {} {}", key, single_quote(value, shell)),
+            ExportFormat::Shell(shell @ ExportShell::Powershell) => format!("$env:{} = {}", key, single_quote(value, shell)),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> IndexMap<String, String> {
+        [
+            ("PLAIN", "value"),
+            ("SPACED", "hello world"),
+            ("QUOTES", r#"it's "quoted" \ back"#),
+            ("DOLLAR", "$HOME and ${USER}"),
+            ("MULTI", "line one\nline two"),
+            ("EMPTY", ""),
+            ("API_TOKEN", "abc123"),
+            ("DSN", "postgres://user:hunter2@db"),
+            ("not-valid", "x"),
+        ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_secrets_are_skipped() {
+        let env = sample();
+        let patterns = vec![Regex::new("hunter2").unwrap()];
+        let vars = exportable(&env, &patterns, false);
+        assert!(!vars.contains_key("API_TOKEN"));
+        assert!(!vars.contains_key("DSN"));
+        assert!(!vars.contains_key("not-valid"));
+        assert!(vars.contains_key("PLAIN"));
+
+        let all = exportable(&env, &patterns, true);
+        assert!(all.contains_key("API_TOKEN") && all.contains_key("DSN"));
+    }
+
+    #[test]
+    fn test_dotenv_round_trip() {
+        let env = sample();
+        let vars = exportable(&env, &[], true);
+        let rendered = render_exports(&vars, ExportFormat::Dotenv);
+        let parsed: BTreeMap<String, String> = dotenvy::from_read_iter(rendered.as_bytes()).map(Result::unwrap).collect();
+        let expected: BTreeMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_posix_export_round_trip() {
+        let env = sample();
+        let vars = exportable(&env, &[], true);
+        let mut script = render_exports(&vars, ExportFormat::Shell(ExportShell::Posix));
+        for key in vars.keys() {
+            script.push_str(&format!("printf '%s=%s\\0' {k} \"${k}\"\n", k = key));
+        }
+        let output = std::process::Command::new("sh").arg("-c").arg(&script).env_clear().output().unwrap();
+        let parsed: BTreeMap<String, String> = String::from_utf8(output.stdout).unwrap()
+            .split_terminator('\0')
+            .map(|kv| { let (k, v) = kv.split_once('=').unwrap(); (k.to_string(), v.to_string()) })
+            .collect();
+        let expected: BTreeMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn test_shell_quoting() {
+        let vars = BTreeMap::from([("A", "it's")]);
+        assert_eq!(render_exports(&vars, ExportFormat::Shell(ExportShell::Posix)), "export A='it'\\''s'\n");
+        assert_eq!(render_exports(&vars, ExportFormat::Shell(ExportShell::Fish)), "set -gx A 'it\\'s'\n");
+        assert_eq!(render_exports(&vars, ExportFormat::Shell(ExportShell::Powershell)), "$env:A = 'it''s'\n");
+    }
+}
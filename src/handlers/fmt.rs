@@ -0,0 +1,304 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
+use crate::config::extension_files;
+use crate::runner::task::TASK_KEYS;
+use crate::diagnostic::Diagnostic;
+use crate::ui::{glyph, Glyph};
+use crate::state::write_atomic;
+
+/// Top-level sections in canonical order; anything else follows in its original order.
+const SECTION_ORDER: &[&str] = &["when", "project", "module", "env", "env_schema", "env_policy", "capability", "notify", "template", "runner"];
+
+/// Task fields holding command lists, written one command per line.
+const COMMAND_KEYS: &[&str] = &["cmds", "windows", "linux", "macos", "finally"];
+
+fn rank(order: &[&str], key: &str) -> usize {
+    order.iter().position(|k| *k == key).unwrap_or(order.len())
+}
+
+/// Every table in the document, by path, in document order.
+fn table_paths(table: &Table, path: &mut Vec<String>, out: &mut Vec<(Vec<String>, Option<isize>)>) {
+    for (key, item) in table.iter() {
+        if let Item::Table(sub) = item {
+            path.push(key.to_string());
+            out.push((path.clone(), sub.position()));
+            table_paths(sub, path, out);
+            path.pop();
+        }
+    }
+}
+
+fn table_at<'a>(doc: &'a mut DocumentMut, path: &[String]) -> Option<&'a mut Table> {
+    let mut table = doc.as_table_mut();
+    for key in path {
+        table = table.get_mut(key)?.as_table_mut()?;
+    }
+    Some(table)
+}
+
+/// Reformats `text` with the canonical layout. Comments stay with the key or table they precede.
+pub fn format_config(text: &str, file_name: &str) -> Result<String> {
+    let mut doc: DocumentMut = text.parse().map_err(|e| Diagnostic::from_toml_edit(Path::new(file_name), text, &e))?;
+
+    // Sections in canonical order, tasks alphabetical, each table right after its parent
+    let mut tables = vec![];
+    table_paths(doc.as_table(), &mut vec![], &mut tables);
+    tables.sort_by(|(a, a_pos), (b, b_pos)| {
+        rank(SECTION_ORDER, &a[0]).cmp(&rank(SECTION_ORDER, &b[0]))
+            .then_with(|| if rank(SECTION_ORDER, &a[0]) < SECTION_ORDER.len() { a.cmp(b) } else { a_pos.cmp(b_pos) })
+    });
+
+    let root_has_values = doc.as_table().iter().any(|(_, item)| !item.is_table());
+    let file_header = if root_has_values { String::new() } else { take_file_header(&mut doc, &tables) };
+    let mut first_header = true;
+    for (position, (path, _)) in tables.iter().enumerate() {
+        let Some(table) = table_at(&mut doc, path) else { continue };
+        table.set_position(position as isize);
+
+        if path.len() == 1 && path[0] == "runner" {
+            table.sort_values();
+        }
+        if path.len() == 2 && path[0] == "runner" {
+            format_task(table);
+        }
+
+        if table.is_implicit() {
+            continue;
+        }
+        // One blank line between sections, none before the first one
+        let prefix = table.decor().prefix().and_then(|p| p.as_str()).unwrap_or("").to_string();
+        let prefix = if first_header && !root_has_values {
+            format!("{}{}", file_header, prefix.trim_start_matches(['\n', '\r']))
+        } else if !prefix.starts_with('\n') && !prefix.starts_with("\r\n") {
+            format!("\n{}", prefix)
+        } else {
+            prefix
+        };
+        table.decor_mut().set_prefix(prefix);
+        first_header = false;
+    }
+
+    // Tasks written inline under [runner] (`lint = { cmds = [...] }`)
+    if let Some(runner) = doc.get_mut("runner").and_then(Item::as_table_mut) {
+        for (_, item) in runner.iter_mut() {
+            if let Some(task) = item.as_inline_table_mut() {
+                task.sort_values_by(|a, _, b, _| compare_task_keys(a, b));
+                task.fmt();
+            }
+        }
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Removes and returns the comments at the top of the file that are set apart
+/// from the first table by a blank line, so they stay at the top.
+fn take_file_header(doc: &mut DocumentMut, tables: &[(Vec<String>, Option<isize>)]) -> String {
+    let Some((path, _)) = tables.iter().filter(|(_, pos)| pos.is_some()).min_by_key(|(_, pos)| *pos) else {
+        return String::new();
+    };
+    let Some(table) = table_at(doc, path) else { return String::new() };
+    let prefix = table.decor().prefix().and_then(|p| p.as_str()).unwrap_or("").to_string();
+    match prefix.rfind("\n\n") {
+        Some(end) if prefix[..end].contains('#') => {
+            table.decor_mut().set_prefix(prefix[end + 2..].to_string());
+            format!("{}\n\n", prefix[..end].trim_start_matches('\n'))
+        },
+        _ => String::new(),
+    }
+}
+
+fn compare_task_keys(a: &str, b: &str) -> Ordering {
+    rank(TASK_KEYS, a).cmp(&rank(TASK_KEYS, b))
+}
+
+fn format_task(task: &mut Table) {
+    // Stable, so unknown keys keep their order after the known ones
+    task.sort_values_by(|a, _, b, _| compare_task_keys(a, b));
+    for key in COMMAND_KEYS {
+        if let Some(array) = task.get_mut(key).and_then(Item::as_array_mut) {
+            format_command_array(array);
+        }
+    }
+}
+
+/// The comment lines of a whitespace/comment prefix, trimmed.
+fn comment_lines(prefix: &str) -> Vec<&str> {
+    prefix.lines().map(str::trim).filter(|l| l.starts_with('#')).collect()
+}
+
+fn raw_prefix(value: &Value) -> &str {
+    value.decor().prefix().and_then(|p| p.as_str()).unwrap_or("")
+}
+
+fn format_command_array(array: &mut Array) {
+    let trailing_comments: Vec<String> = comment_lines(array.trailing().as_str().unwrap_or("")).into_iter().map(String::from).collect();
+    let has_comments = !trailing_comments.is_empty() || array.iter().any(|v| !comment_lines(raw_prefix(v)).is_empty());
+
+    if array.len() <= 1 && !has_comments {
+        array.fmt();
+        array.set_trailing("");
+        array.set_trailing_comma(false);
+        return;
+    }
+
+    for value in array.iter_mut() {
+        let mut prefix = String::new();
+        for comment in comment_lines(raw_prefix(value)) {
+            prefix.push_str("\n    ");
+            prefix.push_str(comment);
+        }
+        prefix.push_str("\n    ");
+        value.decor_mut().set_prefix(prefix);
+        value.decor_mut().set_suffix("");
+    }
+    let mut trailing = String::new();
+    for comment in &trailing_comments {
+        trailing.push_str("\n    ");
+        trailing.push_str(comment);
+    }
+    trailing.push('\n');
+    array.set_trailing(trailing);
+    array.set_trailing_comma(true);
+}
+
+/// A minimal line diff of `old` and `new`: removed lines start with `-`, added ones with `+`,
+/// unchanged ones near a change with a space.
+pub fn line_diff(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence table, filled from the end
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut lines: Vec<(char, &str)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', a[i]));
+            i += 1;
+        } else {
+            lines.push(('+', b[j]));
+            j += 1;
+        }
+    }
+
+    // Keep two lines of context around changes
+    let changed: Vec<usize> = lines.iter().enumerate().filter(|(_, (c, _))| *c != ' ').map(|(i, _)| i).collect();
+    let near = |i: usize| changed.iter().any(|&c| c.abs_diff(i) <= 2);
+    let mut out = String::new();
+    let mut skipped = false;
+    for (i, (mark, line)) in lines.iter().enumerate() {
+        if near(i) {
+            if skipped && !out.is_empty() {
+                out.push_str("...\n");
+            }
+            skipped = false;
+            out.push(*mark);
+            out.push_str(line);
+            out.push('\n');
+        } else {
+            skipped = true;
+        }
+    }
+    out
+}
+
+/// `p.toml` and its extensions, or only `only` when given.
+fn files_to_format(config_path: &Path, only: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let dir = config_path.parent().unwrap_or(Path::new("."));
+    match only {
+        Some(file) => Ok(vec![dir.join(file)]),
+        None => {
+            let mut files = vec![config_path.to_path_buf()];
+            files.extend(extension_files(config_path)?);
+            Ok(files)
+        },
+    }
+}
+
+pub fn handle_fmt(config_path: &Path, check: bool, only: Option<&Path>) -> Result<()> {
+    let mut unformatted = 0;
+    for path in files_to_format(config_path, only)? {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let formatted = format_config(&text, &name)?;
+        if formatted == text {
+            continue;
+        }
+        unformatted += 1;
+        if check {
+            println!("{} {}", "---".red(), name);
+            println!("{} {} (formatted)", "+++".green(), name);
+            for line in line_diff(&text, &formatted).lines() {
+                match line.chars().next() {
+                    Some('-') => println!("{}", line.red()),
+                    Some('+') => println!("{}", line.green()),
+                    _ => println!("{}", line),
+                }
+            }
+        } else {
+            write_atomic(&path, &formatted)?;
+            println!("{} Formatted {}", glyph(Glyph::Ok).green(), name);
+        }
+    }
+
+    if check && unformatted > 0 {
+        bail!("{} file(s) need formatting, run `p fmt`", unformatted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = include_str!("testdata/fmt.input.toml");
+    const EXPECTED: &str = include_str!("testdata/fmt.expected.toml");
+
+    #[test]
+    fn test_format_golden() {
+        let formatted = format_config(INPUT, "p.toml").unwrap();
+        assert_eq!(formatted, EXPECTED, "\n{}", line_diff(EXPECTED, &formatted));
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        assert_eq!(format_config(EXPECTED, "p.toml").unwrap(), EXPECTED);
+        let once = format_config(INPUT, "p.toml").unwrap();
+        assert_eq!(format_config(&once, "p.toml").unwrap(), once);
+    }
+
+    #[test]
+    fn test_formatted_config_is_equivalent() {
+        let before: toml::Value = toml::from_str(INPUT).unwrap();
+        let after: toml::Value = toml::from_str(EXPECTED).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_format_inline_tasks() {
+        let formatted = format_config("[runner]\nb = { cmds = [\"x\"], deps = [\"a\"] }\na = \"true\"\n", "p.local.toml").unwrap();
+        assert_eq!(formatted, "[runner]\na = \"true\"\nb = { deps = [\"a\"], cmds = [\"x\"] }\n");
+    }
+
+    #[test]
+    fn test_line_diff() {
+        let diff = line_diff("a\nb\nc\nd\ne\nf\ng\n", "a\nb\nc\nD\ne\nf\ng\n");
+        assert_eq!(diff, " b\n c\n-d\n+D\n e\n f\n");
+        assert_eq!(line_diff("same\n", "same\n"), "");
+    }
+}
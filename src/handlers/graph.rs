@@ -0,0 +1,36 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use std::path::Path;
+use crate::cli::GraphArgs;
+use crate::config::{load_config_with, LoadOptions};
+use super::apply_project_settings;
+use crate::runner::graph::{render_dot, render_tree, TaskGraph};
+use crate::ui::{self, glyph, Glyph};
+
+pub fn handle_graph(config_path: &Path, args: &GraphArgs) -> Result<()> {
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    apply_project_settings(&config);
+    let tasks = config.runner.as_ref().context("No [runner] section defined in config")?;
+    let graph = TaskGraph::from_tasks(tasks);
+
+    let roots = match &args.task {
+        Some(task) if !graph.contains(task) => bail!("Task '{}' not found", task),
+        Some(task) => vec![task.clone()],
+        None => graph.roots(),
+    };
+
+    // Problems go to stderr so `--dot` output can be piped straight into Graphviz
+    for (task, dep) in graph.missing_deps() {
+        eprintln!("{} Task '{}' depends on missing task '{}'", glyph(Glyph::Warn).yellow(), task, dep);
+    }
+    for cycle in graph.cycles() {
+        eprintln!("{} Circular dependency: {}", glyph(Glyph::Warn).yellow(), cycle.join(" -> "));
+    }
+
+    if args.dot {
+        print!("{}", render_dot(&graph, &roots));
+    } else {
+        print!("{}", render_tree(&graph, &roots, ui::ascii()));
+    }
+    Ok(())
+}
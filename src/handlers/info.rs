@@ -1,11 +1,17 @@
 use anyhow::Result;
 use colored::*;
-use std::env;
-use crate::config::{load_config, Metadata};
+use std::path::Path;
+use crate::config::{load_config_with, LoadOptions, Metadata, NetPolicy};
+use super::apply_project_settings;
+use super::output::{print_json, InfoSummary};
 
-pub fn handle_info() -> Result<()> {
-    let current_dir = env::current_dir()?;
-    let config = load_config(&current_dir)?;
+pub fn handle_info(config_path: &Path, json: bool) -> Result<()> {
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    apply_project_settings(&config);
+
+    if json {
+        return print_json(&InfoSummary::from(&config));
+    }
 
     let metadata: Option<&Metadata> = if let Some(p) = &config.project {
         Some(&p.metadata)
@@ -31,7 +37,7 @@ pub fn handle_info() -> Result<()> {
                  } else if original.is_some() {
                      print!(" {} (new)", "(added)".green().italic());
                  }
-                 println!("");
+                 println!();
             }
         };
 
@@ -39,20 +45,19 @@ pub fn handle_info() -> Result<()> {
         print_diff("Version", &meta.version, original.and_then(|m| m.version.as_ref()));
         print_diff("Description", &meta.description, original.and_then(|m| m.description.as_ref()));
         
-        if let Some(authors) = &meta.authors {
-            if !authors.is_empty() {
-                print!("{}: {}", "Authors".cyan(), authors.join(", "));
-                 // Check if modified
-                 let orig_authors = original.and_then(|m| m.authors.as_ref());
-                 if let Some(orig) = orig_authors {
-                     if authors != orig {
-                         print!(" {}", "(modified)".yellow().italic());
-                     }
-                 } else if original.is_some() {
-                     print!(" {}", "(new)".green().italic());
+        if let Some(authors) = &meta.authors
+            && !authors.is_empty() {
+            print!("{}: {}", "Authors".cyan(), authors.join(", "));
+             // Check if modified
+             let orig_authors = original.and_then(|m| m.authors.as_ref());
+             if let Some(orig) = orig_authors {
+                 if authors != orig {
+                     print!(" {}", "(modified)".yellow().italic());
                  }
-                 println!("");
-            }
+             } else if original.is_some() {
+                 print!(" {}", "(new)".green().italic());
+             }
+             println!();
         }
     } else {
         println!("{}", "No project/module metadata found.".yellow());
@@ -68,11 +73,33 @@ pub fn handle_info() -> Result<()> {
              if let Some(desc) = &meta.description {
                  print!(": {}", desc.dimmed());
              }
-             println!("");
+             println!();
         }
     } else {
         println!("{}", "  (none)".dimmed());
     }
+    for (name, reason) in &config.extensions_skipped {
+        println!("- {} {}", name.dimmed(), format!("(skipped: {})", reason).dimmed());
+    }
+
+    if let Some(caps) = &config.capability {
+        println!("\n{}", "Capabilities".bold().underline());
+        let print_list = |label: &str, list: &Option<Vec<String>>| {
+            if let Some(items) = list {
+                println!("{}: {}", label.cyan(), items.join(", "));
+            }
+        };
+        print_list("Allow paths", &caps.allow_paths);
+        print_list("Deny paths", &caps.deny_paths);
+        print_list("Read-only paths", &caps.read_only_paths);
+        print_list("Allow exec", &caps.allow_exec);
+        let net = match &caps.allow_net {
+            None | Some(NetPolicy::Flag(true)) => "unrestricted".to_string(),
+            Some(NetPolicy::Flag(false)) => "disabled (best-effort for shell commands)".to_string(),
+            Some(NetPolicy::Hosts(hosts)) => hosts.join(", "),
+        };
+        println!("{}: {}", "Network".cyan(), net);
+    }
 
     Ok(())
 }
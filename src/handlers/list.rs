@@ -1,53 +1,226 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
+use chrono::{DateTime, Local};
 use colored::*;
-use crate::config::load_config;
-use crate::runner::task::RunnerTask;
+use rayon::prelude::*;
+use crate::config::{load_config_with, LoadOptions, PavidiConfig};
+use super::apply_project_settings;
+use crate::runner::cache::{stale_reason, Sources};
+use crate::runner::history::read_history;
+use crate::runner::task::{group_members, task_group, RunnerTask};
+use crate::runner::wait::missing_files;
 
-use std::env;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::io::Write;
+use crate::ui::{self, glyph, Glyph};
+use super::output::{print_json, TaskState, TaskSummary};
 
-pub fn handle_list() -> Result<()> {
-    let current_dir = env::current_dir()?;
-    let config = load_config(&current_dir)?;
+/// Task names by group, groups and names sorted. Tasks without a group come first, under `None`.
+fn grouped<'a>(names: impl IntoIterator<Item = &'a String>) -> BTreeMap<Option<&'a str>, Vec<&'a String>> {
+    let mut groups: BTreeMap<Option<&str>, Vec<&String>> = BTreeMap::new();
+    for name in names {
+        groups.entry(task_group(name)).or_default().push(name);
+    }
+    for names in groups.values_mut() {
+        names.sort();
+    }
+    groups
+}
+
+/// The state of each of `names`. Cache checks hash every task's sources, so they run in parallel.
+fn task_states(config: &PavidiConfig, names: &[&String]) -> Result<BTreeMap<String, TaskState>> {
+    let history = read_history(&config.root_dir)?;
+    let Some(tasks) = &config.runner else { return Ok(BTreeMap::new()) };
+    Ok(names.par_iter().map(|name| {
+        let task = &tasks[*name];
+        let up_to_date = match (Sources::of(task, config), task) {
+            (Some(srcs), RunnerTask::Full { outputs: Some(outs), .. }) =>
+                stale_reason(name, &srcs, outs, &config.env).ok().map(|reason| reason.is_none()),
+            _ => None,
+        };
+        let last = history.tasks.get(*name);
+        let state = TaskState {
+            up_to_date,
+            last_status: last.map(|r| if r.cached { "cached".to_string() } else { r.status.clone() }),
+            last_run: last.map(|r| r.finished),
+            missing_files: missing_files(task.require_files(), &config.root_dir).unwrap_or_default(),
+        };
+        (name.to_string(), state)
+    }).collect())
+}
+
+/// `2m ago`, `1h ago`: how long before `now`, in its largest unit.
+fn ago(then: DateTime<Local>, now: DateTime<Local>) -> String {
+    let secs = (now - then).num_seconds().max(0);
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// `text` cut to `room` columns with an ellipsis, or nothing when too little is left to read.
+fn fit(text: &str, room: usize) -> String {
+    let len = text.chars().count();
+    if len <= room {
+        text.to_string()
+    } else if room < 12 {
+        String::new()
+    } else {
+        format!("{}…", text.chars().take(room - 1).collect::<String>())
+    }
+}
+
+/// `ok 2m ago`, `failed 1h ago` or `never run`.
+fn last_result(state: &TaskState, now: DateTime<Local>) -> String {
+    match (&state.last_status, state.last_run) {
+        (Some(status), Some(at)) => format!("{} {}", if status == "passed" { "ok" } else { status }, ago(at, now)),
+        _ => "never run".to_string(),
+    }
+}
+
+/// The cache dot and last result, the result padded to `width` columns.
+fn state_columns(state: &TaskState, now: DateTime<Local>, width: usize) -> String {
+    let dot = match (state.up_to_date, ui::ascii()) {
+        (Some(true), false) => "●".green(),
+        (Some(true), true) => "*".green(),
+        (Some(false), false) => "○".dimmed(),
+        (Some(false), true) => "o".dimmed(),
+        (None, _) => " ".normal(),
+    };
+    let last = last_result(state, now);
+    let padding = " ".repeat(width.saturating_sub(last.chars().count()));
+    let last = match state.last_status.as_deref() {
+        Some("passed") | Some("cached") => last.green(),
+        Some("failed") => last.red(),
+        Some(_) => last.yellow(),
+        None => last.dimmed(),
+    };
+    format!("{} {}{}", dot, last, padding)
+}
+
+pub fn handle_list(config_path: &Path, json: bool, group: Option<&str>, status: bool) -> Result<()> {
+    // The cache hashes the resolved env, so --status needs the env a run would see
+    let options = if status { LoadOptions { validate_env: false, fetch_remote: false, ..LoadOptions::FULL } } else { LoadOptions::SHALLOW };
+    let config = load_config_with(config_path, options)?;
+    apply_project_settings(&config);
+    let members = match (group, &config.runner) {
+        (Some(group), Some(tasks)) => {
+            let members = group_members(tasks, group);
+            if members.is_empty() {
+                bail!("Group '{}' has no tasks", group.trim_end_matches(':'));
+            }
+            Some(members)
+        },
+        (Some(group), None) => bail!("Group '{}' has no tasks", group.trim_end_matches(':')),
+        (None, _) => None,
+    };
+    let listed = |name: &String| members.as_ref().is_none_or(|m| m.contains(&name));
+
+    let mut states = if status {
+        let names: Vec<&String> = config.runner.iter().flat_map(|r| r.keys()).filter(|name| listed(name)).collect();
+        task_states(&config, &names)?
+    } else {
+        BTreeMap::new()
+    };
+
+    if json {
+        let mut tasks: Vec<TaskSummary> = config.runner.iter().flatten()
+            .filter(|(name, _)| listed(name))
+            .map(|(name, task)| TaskSummary {
+                template: config.task_templates.get(name).cloned(),
+                state: states.remove(name),
+                ..TaskSummary::new(name, task)
+            })
+            .collect();
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+        return print_json(&tasks);
+    }
     
+    let mut out = ui::Pager::new();
     if let Some(p) = &config.project {
         let name = p.metadata.name.as_deref().unwrap_or("Unnamed Project");
-        println!("{} {} {}", "📦".green(), name.bold(), "(Project)".dimmed());
+        writeln!(out, "{} {} {}", glyph(Glyph::Project).green(), name.bold(), "(Project)".dimmed())?;
     } else if let Some(m) = &config.module {
         let name = m.metadata.name.as_deref().unwrap_or("Unnamed Module");
-        println!("{} {} {}", "🧩".cyan(), name.bold(), "(Module)".dimmed());
+        writeln!(out, "{} {} {}", glyph(Glyph::Module).cyan(), name.bold(), "(Module)".dimmed())?;
     }
-    println!();
+    writeln!(out)?;
 
-    if let Some(runner_tasks) = config.runner {
-        println!("{}", "Available Tasks:".bold().underline());
-        
-        let mut max_len = 0;
-        let mut tasks: Vec<(&String, Option<&String>)> = Vec::new();
+    if let Some(runner_tasks) = &config.runner {
+        writeln!(out, "{}", "Available Tasks:".bold().underline())?;
 
-        for (name, task) in &runner_tasks {
-            if name.len() > max_len {
-                max_len = name.len();
+        let groups = grouped(runner_tasks.keys().filter(|name| listed(name)));
+        let max_len = groups.values().flatten().map(|name| name.len()).max().unwrap_or(0);
+        // Headers only once tasks use groups; a flat list stays flat
+        let headers = groups.keys().any(Option::is_some);
+        let indent = if headers { "    " } else { "  " };
+        let now = Local::now();
+        let last_width = states.values()
+            .map(|state| last_result(state, now).len())
+            .max().unwrap_or(0);
+        let width = ui::terminal_width();
+
+        for (group, names) in &groups {
+            if headers {
+                writeln!(out, "  {}", group.unwrap_or("(general)").bold())?;
+            }
+            for name in names {
+                let padding = " ".repeat(max_len - name.len() + 2);
+                let description = match &runner_tasks[*name] {
+                    RunnerTask::Full { description: Some(d), .. } => d.as_str(),
+                    _ => "",
+                };
+                let origin = match config.task_templates.get(*name) {
+                    Some(template) => format!(" (template: {})", template),
+                    None => String::new(),
+                };
+                let Some(state) = states.get(*name) else {
+                    writeln!(out, "{}{}{}{}{}", indent, name.cyan(), padding, description.italic(), origin.dimmed())?;
+                    continue;
+                };
+                let missing = match (state.missing_files.is_empty(), description.is_empty() && origin.is_empty()) {
+                    (true, _) => String::new(),
+                    (false, bare) => format!("{}{} missing {}", if bare { "" } else { " " }, glyph(Glyph::Warn), state.missing_files.join(", ")),
+                };
+                // On a narrow terminal the description gives way; the state columns never do
+                let used = indent.len() + name.len() + padding.len() + 2 + last_width + 2;
+                let room = width.map_or(usize::MAX, |w| w.saturating_sub(used + origin.chars().count() + missing.chars().count()));
+                let line = format!("{}{}{}{}  {}{}{}", indent, name.cyan(), padding, state_columns(state, now, last_width),
+                    fit(description, room).italic(), origin.dimmed(), missing.yellow());
+                writeln!(out, "{}", line.trim_end())?;
             }
-            
-            let desc = match task {
-                RunnerTask::Full { description, .. } => description.as_ref(),
-                _ => None,
-            };
-            tasks.push((name, desc));
-        }
-        
-        // Sort for consistent output
-        tasks.sort_by(|a, b| a.0.cmp(b.0));
-
-        for (name, desc) in tasks {
-            let padding = " ".repeat(max_len - name.len() + 2);
-            let empty_string = String::new();
-            let description = desc.unwrap_or(&empty_string);
-            println!("  {}{}{}", name.cyan(), padding, description.italic());
         }
     } else {
-        println!("No tasks defined in configuration.");
+        writeln!(out, "No tasks defined in configuration.")?;
+    }
+
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grouped() {
+        let names: Vec<String> = ["test", "db:seed", "build:release", "db:migrate", "build:debug", "lint"]
+            .iter().map(|n| n.to_string()).collect();
+        let groups = grouped(&names);
+        assert_eq!(groups.keys().copied().collect::<Vec<_>>(), [None, Some("build"), Some("db")]);
+        assert_eq!(groups[&None], ["lint", "test"]);
+        assert_eq!(groups[&Some("db")], ["db:migrate", "db:seed"]);
     }
 
-    Ok(())
+    #[test]
+    fn test_ago_and_fit() {
+        let now = Local::now();
+        for (secs, expected) in [(0, "0s ago"), (59, "59s ago"), (150, "2m ago"), (3600 * 5, "5h ago"), (86400 * 3, "3d ago"), (-10, "0s ago")] {
+            assert_eq!(ago(now - chrono::Duration::seconds(secs), now), expected);
+        }
+        assert_eq!(fit("Build the docs", 40), "Build the docs");
+        assert_eq!(fit("Build the documentation site", 14), "Build the doc…");
+        assert_eq!(fit("Build the documentation site", 8), "");
+    }
 }
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+use std::time::Duration;
+use wait_timeout::ChildExt;
+use crate::config::{load_config_static, PavidiConfig};
+use crate::handlers::doctor::invoked_programs;
+use crate::ui::{glyph, Glyph};
+
+pub const LOCK_FILE: &str = "p.lock.toml";
+
+const HEADER: &str = "# Versions of the programs tasks run, written by `p lock`.\n# `p --locked <task>` fails when they differ.\n\n";
+
+/// Programs that do not print their version with `--version`.
+const VERSION_ARGS: &[(&str, &[&str])] = &[
+    ("go", &["version"]),
+    ("java", &["-version"]),
+    ("javac", &["-version"]),
+    ("kubectl", &["version", "--client"]),
+    ("lua", &["-v"]),
+    ("ssh", &["-V"]),
+];
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static VERSION_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+(\.\d+)+[0-9A-Za-z.+-]*").unwrap());
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lockfile {
+    /// Program name -> version, or `blake3:<hash>` of the binary when it prints none
+    #[serde(default)]
+    pub tools: BTreeMap<String, String>,
+}
+
+pub fn lock_path(config: &PavidiConfig) -> PathBuf {
+    config.root_dir.join(LOCK_FILE)
+}
+
+pub fn read_lockfile(path: &Path) -> Result<Option<Lockfile>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    toml::from_str(&content).map(Some).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// The version a program prints, from the first line that contains one.
+fn parse_version_output(output: &str) -> Option<String> {
+    output.lines().find_map(|line| VERSION_RE.find(line)).map(|m| m.as_str().to_string())
+}
+
+/// Runs the program's version flag; falls back to hashing the binary when that prints no version.
+fn probe_version(name: &str, path: &Path) -> Result<String> {
+    let args = VERSION_ARGS.iter().find(|(program, _)| *program == name).map_or(&["--version"][..], |(_, args)| args);
+    let output = Command::new(path).args(args)
+        .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn()
+        .ok()
+        .and_then(|mut child| match child.wait_timeout(PROBE_TIMEOUT) {
+            Ok(Some(_)) => {
+                let mut text = String::new();
+                child.stdout.take()?.read_to_string(&mut text).ok()?;
+                child.stderr.take()?.read_to_string(&mut text).ok()?;
+                Some(text)
+            },
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                None
+            },
+        });
+    if let Some(version) = output.as_deref().and_then(parse_version_output) {
+        return Ok(version);
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("blake3:{}", blake3::hash(&bytes).to_hex()))
+}
+
+/// Finds `name` on the PATH tasks get (`[env]` PATH, else p's own).
+fn find_program(config: &PavidiConfig, name: &str) -> Option<PathBuf> {
+    let paths = config.env.get("PATH").map(OsString::from).or_else(|| std::env::var_os("PATH"));
+    which::which_in(name, paths, &config.root_dir).ok()
+}
+
+/// Programs on PATH that tasks start. Project scripts (`./gen.sh`) are versioned with the project.
+fn locked_programs(config: &PavidiConfig) -> Vec<String> {
+    let mut names: Vec<String> = invoked_programs(config).into_iter()
+        .map(|(_, word)| word)
+        .filter(|word| !word.contains('/') && !word.contains('\\'))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The version of each program in `names`, or `None` where it is not installed.
+fn probe_all<'a>(config: &PavidiConfig, names: impl IntoIterator<Item = &'a String>) -> Result<BTreeMap<String, Option<String>>> {
+    names.into_iter().map(|name| {
+        let version = find_program(config, name).map(|path| probe_version(name, &path)).transpose()?;
+        Ok((name.clone(), version))
+    }).collect()
+}
+
+/// `(name, locked, found)` for every tool that is missing or has another version.
+fn mismatches(expected: &BTreeMap<String, String>, found: &BTreeMap<String, Option<String>>) -> Vec<(String, String, String)> {
+    expected.iter().filter_map(|(name, version)| {
+        let actual = found.get(name).cloned().flatten();
+        (actual.as_ref() != Some(version))
+            .then(|| (name.clone(), version.clone(), actual.unwrap_or_else(|| "(not found)".to_string())))
+    }).collect()
+}
+
+/// `- name locked` / `+ name found` lines, as in a diff.
+fn diff(mismatches: &[(String, String, String)]) -> Vec<String> {
+    mismatches.iter().flat_map(|(name, locked, found)| [format!("- {} {}", name, locked), format!("+ {} {}", name, found)]).collect()
+}
+
+/// Fails with a diff when a program in p.lock.toml is missing or has another version.
+pub fn verify_lock(config_path: &Path) -> Result<()> {
+    let config = load_config_static(config_path)?;
+    let path = lock_path(&config);
+    let Some(lock) = read_lockfile(&path)? else {
+        bail!("--locked was given, but {} does not exist. Create it with `p lock`", path.display());
+    };
+    let lines = diff(&mismatches(&lock.tools, &probe_all(&config, lock.tools.keys())?));
+    if !lines.is_empty() {
+        bail!("Tools differ from {}:\n  {}\nInstall the locked versions, or run `p lock` to accept these", LOCK_FILE, lines.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Whether the lockfile still covers exactly the programs tasks run, and their versions.
+/// `None` when the project has no lockfile.
+pub fn lock_problems(config: &PavidiConfig) -> Result<Option<(Vec<String>, Vec<String>)>> {
+    let Some(lock) = read_lockfile(&lock_path(config))? else { return Ok(None) };
+    let programs = locked_programs(config);
+    let mut stale: Vec<String> = programs.iter().filter(|p| !lock.tools.contains_key(*p)).map(|p| format!("{} not locked", p)).collect();
+    stale.extend(lock.tools.keys().filter(|t| !programs.contains(t)).map(|t| format!("{} no longer used", t)));
+    let differing = mismatches(&lock.tools, &probe_all(config, lock.tools.keys())?).into_iter()
+        .map(|(name, locked, found)| format!("{} {} -> {}", name, locked, found))
+        .collect();
+    Ok(Some((stale, differing)))
+}
+
+pub fn handle_lock(config_path: &Path) -> Result<()> {
+    let config = load_config_static(config_path)?;
+    let mut lock = Lockfile::default();
+    for (name, version) in probe_all(&config, &locked_programs(&config))? {
+        match version {
+            Some(version) => {
+                println!("  {} {}", name, version.dimmed());
+                lock.tools.insert(name, version);
+            },
+            None => log::warn!("{} '{}' was not found on PATH and is not locked", glyph(Glyph::Warn).yellow(), name),
+        }
+    }
+    let path = lock_path(&config);
+    fs::write(&path, format!("{}{}", HEADER, toml::to_string(&lock)?))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("{} Locked {} tool(s) in {}", glyph(Glyph::Lock).green(), lock.tools.len(), LOCK_FILE);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::test_support::{scratch, Scratch};
+
+    fn stub(dir: &Path, name: &str, script: &str) {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn project(name: &str) -> (Scratch, PathBuf) {
+        let root = scratch(&format!("lock_{}", name));
+        let bin = root.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        stub(&bin, "fake-cc", "echo 'fake-cc (GCC) 13.2.1 20240210'");
+        stub(&bin, "go", "[ \"$1\" = version ] && echo 'go version go1.22.4 linux/amd64'");
+        stub(&bin, "silent", "exit 0");
+        fs::write(root.join("p.toml"), format!(r#"
+[env]
+PATH = "{}"
+
+[runner]
+build = ["fake-cc -o app main.c", "go build ./...", "silent", "./scripts/gen.sh", "echo done"]
+"#, bin.display())).unwrap();
+        let config_path = root.join("p.toml");
+        (root, config_path)
+    }
+
+    #[test]
+    fn test_parse_version_output() {
+        assert_eq!(parse_version_output("cargo 1.95.0 (f2d3ce0bd 2026-03-21)").as_deref(), Some("1.95.0"));
+        assert_eq!(parse_version_output("\nThis is perl 5, version 36, subversion 0 (v5.36.0)").as_deref(), Some("5.36.0"));
+        assert_eq!(parse_version_output("OpenSSH_9.6p1 Ubuntu-3ubuntu13, OpenSSL 3.0.13").as_deref(), Some("9.6p1"));
+        assert_eq!(parse_version_output("usage: tool [options]"), None);
+    }
+
+    #[test]
+    fn test_lock_and_verify() {
+        let (root, config_path) = project("verify");
+        handle_lock(&config_path).unwrap();
+        let content = fs::read_to_string(root.join(LOCK_FILE)).unwrap();
+        let lock: Lockfile = toml::from_str(&content).unwrap();
+        assert_eq!(lock.tools.keys().collect::<Vec<_>>(), ["fake-cc", "go", "silent"]);
+        assert_eq!(lock.tools["fake-cc"], "13.2.1");
+        assert_eq!(lock.tools["go"], "1.22.4");
+        assert!(lock.tools["silent"].starts_with("blake3:"));
+        verify_lock(&config_path).unwrap();
+
+        // Locking again gives the same bytes
+        handle_lock(&config_path).unwrap();
+        assert_eq!(fs::read_to_string(root.join(LOCK_FILE)).unwrap(), content);
+
+        stub(&root.join("bin"), "fake-cc", "echo 'fake-cc (GCC) 14.1.0'");
+        fs::remove_file(root.join("bin/go")).unwrap();
+        let err = verify_lock(&config_path).unwrap_err().to_string();
+        assert!(err.contains("- fake-cc 13.2.1\n  + fake-cc 14.1.0\n  - go 1.22.4\n  + go (not found)\n"), "{}", err);
+    }
+
+    #[test]
+    fn test_lock_problems() {
+        let (root, config_path) = project("stale");
+        let config = load_config_static(&config_path).unwrap();
+        assert_eq!(lock_problems(&config).unwrap(), None);
+
+        fs::write(root.join(LOCK_FILE), "[tools]\nfake-cc = \"13.2.1\"\nmake = \"4.4\"\n").unwrap();
+        let (stale, differing) = lock_problems(&config).unwrap().unwrap();
+        assert_eq!(stale, ["go not locked", "silent not locked", "make no longer used"]);
+        assert_eq!(differing, ["make 4.4 -> (not found)"]);
+    }
+}
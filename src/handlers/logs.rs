@@ -0,0 +1,140 @@
+use anyhow::{Result, bail, Context};
+use colored::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use crate::cli::{LogsAction, LogsArgs};
+use crate::log_index::{find_entry, scan_logs, LogEntry};
+use crate::config::{load_config_with, LoadOptions};
+use super::apply_project_settings;
+use crate::logger::log_root;
+use crate::ui::{glyph, Glyph};
+
+pub fn handle_logs(config_path: &Path, args: &LogsArgs) -> Result<()> {
+    let config = load_config_with(config_path, LoadOptions::SHALLOW)?;
+    apply_project_settings(&config);
+    let root = log_root(&config);
+    let mut entries = scan_logs(&root);
+
+    if let Some(LogsAction::Show { id }) = &args.action {
+        let entry = match find_entry(&entries, id) {
+            Some(e) => e,
+            None => bail!("No log matching '{}' in {}", id, root.display()),
+        };
+        return show_log(entry);
+    }
+
+    if let Some(task) = &args.task {
+        entries.retain(|e| &e.task == task);
+    }
+    if args.failed {
+        entries.retain(|e| e.exit_code.is_some_and(|c| c != 0));
+    }
+
+    if args.follow {
+        let task = args.task.as_deref().unwrap_or_default();
+        return follow_log(&root, task, entries.first().map(|e| e.path.clone()));
+    }
+
+    if entries.is_empty() {
+        println!("{}", "No logs found.".yellow());
+        return Ok(());
+    }
+
+    println!("{}", "Recent Logs:".bold().underline());
+    let max_task = entries.iter().take(args.limit).map(|e| e.task.len()).max().unwrap_or(0);
+    for (i, entry) in entries.iter().take(args.limit).enumerate() {
+        let code = match entry.exit_code {
+            Some(0) => "exit 0".green(),
+            Some(c) => format!("exit {}", c).red(),
+            None => "running".yellow(),
+        };
+        let duration = entry.duration_ms
+            .map(|d| format!("{} ms", d))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:>3}  {}  {}  {:<width$}  {}  {}",
+            i + 1,
+            entry.id.dimmed(),
+            entry.timestamp(),
+            entry.task.cyan(),
+            code,
+            duration.dimmed(),
+            width = max_task
+        );
+    }
+
+    Ok(())
+}
+
+fn show_log(entry: &LogEntry) -> Result<()> {
+    let content = fs::read_to_string(&entry.path)
+        .with_context(|| format!("Failed to read {}", entry.path.display()))?;
+    println!("{} {}", glyph(Glyph::Log).dimmed(), entry.path.display().to_string().dimmed());
+
+    for line in content.lines() {
+        if line.starts_with("===") {
+            println!("{}", line.dimmed());
+        } else if let Some(code) = line.strip_prefix("Exit Code: ") {
+            let code = if code.trim() == "0" { code.green() } else { code.red() };
+            println!("{}: {}", "Exit Code".cyan(), code);
+        } else if let Some((key, value)) = line.split_once(": ")
+            && matches!(key, "Task" | "Command" | "Time" | "Duration" | "End Time") {
+            println!("{}: {}", key.cyan(), value);
+        } else {
+            // Body lines keep any ANSI colors preserved by `log_plain = false`
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the latest log for `task` and keeps printing appended output,
+/// switching to newer logs for the same task as they appear.
+fn follow_log(root: &Path, task: &str, initial: Option<PathBuf>) -> Result<()> {
+    let mut current = initial;
+    let mut offset = 0u64;
+
+    if current.is_none() {
+        println!("{} Waiting for logs of '{}'...", glyph(Glyph::Wait).dimmed(), task);
+    }
+
+    loop {
+        if let Some(path) = &current
+            && let Ok(mut file) = File::open(path) {
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            if len < offset {
+                // Truncated or rewritten, start over
+                offset = 0;
+            }
+            if len > offset {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                io::stdout().write_all(&buf)?;
+                io::stdout().flush()?;
+                offset = len;
+            }
+        }
+
+        thread::sleep(Duration::from_millis(500));
+
+        let latest = scan_logs(root).into_iter().find(|e| e.task == task).map(|e| e.path);
+        // A finished log moves from `running/` to its exit code directory; keep the offset
+        let same_log = match (&latest, &current) {
+            (Some(l), Some(c)) => l.file_name() == c.file_name(),
+            _ => false,
+        };
+        if same_log {
+            current = latest;
+        } else if latest.is_some() && latest != current {
+            if let Some(path) = &latest {
+                println!("\n{} {}", "==>".cyan().bold(), path.display());
+            }
+            current = latest;
+            offset = 0;
+        }
+    }
+}
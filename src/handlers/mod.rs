@@ -2,3 +2,32 @@ pub mod task;
 pub mod env;
 pub mod list;
 pub mod info;
+pub mod logs;
+pub mod output;
+pub mod graph;
+pub mod completions;
+pub mod doctor;
+pub mod task_edit;
+pub mod fmt;
+pub mod status;
+pub mod lock;
+pub mod artifacts;
+pub mod remotes;
+#[cfg(feature = "net")]
+pub mod upgrade;
+#[cfg(not(feature = "net"))]
+pub mod upgrade {
+    pub fn handle_upgrade(_config_path: &std::path::Path, _check: bool, _version: Option<&str>) -> anyhow::Result<()> {
+        anyhow::bail!("this build of p was compiled without the 'net' feature; upgrade it the way it was installed")
+    }
+}
+
+/// Applies `ui`, `pager` and `grace_period` from a command's config. They hold for
+/// the whole process, so the CLI applies them; loading a config leaves them alone.
+pub(crate) fn apply_project_settings(config: &crate::config::PavidiConfig) {
+    crate::ui::set_configured_style(config.ui_style());
+    if !config.pager() {
+        crate::ui::disable_pager();
+    }
+    crate::process::set_grace_period(config.command_settings.grace_period);
+}
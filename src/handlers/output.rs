@@ -0,0 +1,260 @@
+// Machine-readable (`--json`) output shared by `--list`, `--info` and `--env`.
+// Field names here are a public interface for editor plugins and scripts:
+// add fields freely, but do not rename or remove them.
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::config::{CapabilityConfig, Metadata, NetPolicy, PavidiConfig};
+use crate::runner::task::RunnerTask;
+
+#[derive(Serialize)]
+pub struct TaskSummary {
+    pub name: String,
+    pub description: Option<String>,
+    pub deps: Vec<String>,
+    // Tasks have no tags or visibility yet; the fields are reserved so the schema stays put
+    pub tags: Vec<String>,
+    pub hidden: bool,
+    pub has_cache: bool,
+    /// The `[template.*]` the task was made from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// With `--status`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<TaskState>,
+}
+
+/// `--list --status`: what can be known about a task without running it.
+#[derive(Serialize)]
+pub struct TaskState {
+    /// `None` for tasks without `sources` and `outputs`
+    pub up_to_date: Option<bool>,
+    /// From `.p/history.json`: `passed`, `failed` or `skipped`
+    pub last_status: Option<String>,
+    pub last_run: Option<DateTime<Local>>,
+    /// `require_files` patterns that match nothing
+    pub missing_files: Vec<String>,
+}
+
+impl TaskSummary {
+    pub fn new(name: &str, task: &RunnerTask) -> Self {
+        let description = match task {
+            RunnerTask::Full { description, .. } => description.clone(),
+            _ => None,
+        };
+        TaskSummary {
+            name: name.to_string(),
+            description,
+            deps: task.deps().to_vec(),
+            tags: vec![],
+            hidden: false,
+            has_cache: task.has_cache(),
+            template: None,
+            state: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MetadataSummary {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+}
+
+impl From<&Metadata> for MetadataSummary {
+    fn from(meta: &Metadata) -> Self {
+        MetadataSummary {
+            name: meta.name.clone(),
+            version: meta.version.clone(),
+            authors: meta.authors.clone().unwrap_or_default(),
+            description: meta.description.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExtensionSummary {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SkippedExtension {
+    pub name: String,
+    /// The `[when]` condition that did not hold
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct NetworkSummary {
+    pub enabled: bool,
+    /// `null` when every host is allowed
+    pub hosts: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct CapabilitySummary {
+    pub allow_paths: Option<Vec<String>>,
+    pub deny_paths: Option<Vec<String>>,
+    pub read_only_paths: Option<Vec<String>>,
+    pub allow_exec: Option<Vec<String>>,
+    pub network: NetworkSummary,
+}
+
+impl From<&CapabilityConfig> for CapabilitySummary {
+    fn from(caps: &CapabilityConfig) -> Self {
+        let network = match &caps.allow_net {
+            None | Some(NetPolicy::Flag(true)) => NetworkSummary { enabled: true, hosts: None },
+            Some(NetPolicy::Flag(false)) => NetworkSummary { enabled: false, hosts: None },
+            Some(NetPolicy::Hosts(hosts)) => NetworkSummary { enabled: true, hosts: Some(hosts.clone()) },
+        };
+        CapabilitySummary {
+            allow_paths: caps.allow_paths.clone(),
+            deny_paths: caps.deny_paths.clone(),
+            read_only_paths: caps.read_only_paths.clone(),
+            allow_exec: caps.allow_exec.clone(),
+            network,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InfoSummary {
+    /// "project", "module" or `null`
+    pub kind: Option<&'static str>,
+    pub metadata: Option<MetadataSummary>,
+    pub extensions: Vec<ExtensionSummary>,
+    pub skipped_extensions: Vec<SkippedExtension>,
+    pub capabilities: Option<CapabilitySummary>,
+}
+
+impl From<&PavidiConfig> for InfoSummary {
+    fn from(config: &PavidiConfig) -> Self {
+        let (kind, metadata) = match (&config.project, &config.module) {
+            (Some(p), _) => (Some("project"), Some(MetadataSummary::from(&p.metadata))),
+            (None, Some(m)) => (Some("module"), Some(MetadataSummary::from(&m.metadata))),
+            (None, None) => (None, None),
+        };
+        InfoSummary {
+            kind,
+            metadata,
+            extensions: config.extensions_applied.iter().map(|(name, meta)| ExtensionSummary {
+                name: name.clone(),
+                version: meta.version.clone(),
+                description: meta.description.clone(),
+            }).collect(),
+            skipped_extensions: config.extensions_skipped.iter()
+                .map(|(name, reason)| SkippedExtension { name: name.clone(), reason: reason.clone() })
+                .collect(),
+            capabilities: config.capability.as_ref().map(CapabilitySummary::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EnvLayer {
+    pub source: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+pub struct EnvVar {
+    pub value: String,
+    pub source: String,
+    /// Every definition in load order; the last one is active
+    pub chain: Vec<EnvLayer>,
+}
+
+/// The resolved environment keyed by variable name.
+pub fn env_summary(config: &PavidiConfig) -> BTreeMap<String, EnvVar> {
+    config.env_provenance.iter().filter_map(|(key, history)| {
+        let (source, value) = history.last()?.clone();
+        let chain = history.iter().map(|(s, v)| EnvLayer { source: s.clone(), value: v.clone() }).collect();
+        Some((key.clone(), EnvVar { value, source, chain }))
+    }).collect()
+}
+
+/// Prints `value` as pretty JSON on stdout.
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    fn config() -> PavidiConfig {
+        let mut config: PavidiConfig = toml::from_str(r#"
+[project]
+name = "demo"
+version = "1.0.0"
+
+[env]
+MODE = "dev"
+
+[capability]
+allow_exec = ["cargo"]
+allow_net = ["crates.io"]
+
+[runner]
+fmt = "cargo fmt"
+
+[runner.build]
+description = "Build it"
+deps = ["fmt"]
+cmds = ["cargo build"]
+sources = ["src/**"]
+outputs = ["target/debug/p"]
+"#).unwrap();
+        config.env_provenance.insert("MODE".to_string(), vec![
+            ("p.toml".to_string(), "dev".to_string()),
+            (".env".to_string(), "prod".to_string()),
+        ]);
+        config
+    }
+
+    #[test]
+    fn test_task_schema() {
+        let config = config();
+        let runner = config.runner.as_ref().unwrap();
+        let build = serde_json::to_value(TaskSummary::new("build", &runner["build"])).unwrap();
+        assert_eq!(keys(&build), ["deps", "description", "has_cache", "hidden", "name", "tags"]);
+        assert_eq!(build["deps"], serde_json::json!(["fmt"]));
+        assert_eq!(build["has_cache"], true);
+
+        let fmt = serde_json::to_value(TaskSummary::new("fmt", &runner["fmt"])).unwrap();
+        assert_eq!(fmt["description"], serde_json::Value::Null);
+        assert_eq!(fmt["has_cache"], false);
+    }
+
+    #[test]
+    fn test_info_schema() {
+        let info = serde_json::to_value(InfoSummary::from(&config())).unwrap();
+        assert_eq!(keys(&info), ["capabilities", "extensions", "kind", "metadata", "skipped_extensions"]);
+        assert_eq!(info["kind"], "project");
+        assert_eq!(keys(&info["metadata"]), ["authors", "description", "name", "version"]);
+        assert_eq!(keys(&info["capabilities"]), ["allow_exec", "allow_paths", "deny_paths", "network", "read_only_paths"]);
+        assert_eq!(info["capabilities"]["network"], serde_json::json!({ "enabled": true, "hosts": ["crates.io"] }));
+    }
+
+    #[test]
+    fn test_env_schema() {
+        let env = serde_json::to_value(env_summary(&config())).unwrap();
+        assert_eq!(keys(&env["MODE"]), ["chain", "source", "value"]);
+        assert_eq!(env["MODE"]["value"], "prod");
+        assert_eq!(env["MODE"]["source"], ".env");
+        assert_eq!(env["MODE"]["chain"][0], serde_json::json!({ "source": "p.toml", "value": "dev" }));
+    }
+}
@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use crate::config::CapabilityConfig;
+use crate::remote_config::{cache_dir, fetch, Fetched, RemoteInclude};
+use crate::ui::{glyph, Glyph};
+
+/// The part of `p.toml` this command needs; read directly, so a file that fails
+/// verification can still be refreshed.
+#[derive(Deserialize)]
+struct Includes {
+    #[serde(default)]
+    extends_remote: Vec<RemoteInclude>,
+    capability: Option<CapabilityConfig>,
+}
+
+/// `p fetch-remotes`: downloads and verifies every `extends_remote` file that is
+/// not cached yet, or all of them with `refresh`.
+pub fn handle_fetch_remotes(config_path: &Path, refresh: bool) -> Result<()> {
+    let content = fs::read_to_string(config_path).with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let includes: Includes = toml::from_str(&content).with_context(|| format!("Failed to parse {}", config_path.display()))?;
+    if includes.extends_remote.is_empty() {
+        println!("No extends_remote in {}", config_path.display());
+        return Ok(());
+    }
+    let cache = cache_dir()?;
+    for include in &includes.extends_remote {
+        match fetch(include, &cache, refresh, includes.capability.as_ref())? {
+            (_, Fetched::Downloaded) => println!("{} {} (downloaded, sha256 verified)", glyph(Glyph::Ok).green(), include.url),
+            (_, Fetched::Cached) => println!("{} {} (cached)", glyph(Glyph::Cached).cyan(), include.url),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use anyhow::{Result, bail};
+use colored::*;
+use std::path::Path;
+use crate::runner::status::{read_status, status_path, RunStatus};
+use crate::ui::{glyph, Glyph};
+
+fn colored_status(status: &str) -> ColoredString {
+    match status {
+        "passed" => status.green(),
+        "failed" => status.red(),
+        _ => status.yellow(),
+    }
+}
+
+fn print_status(status: &RunStatus) {
+    println!("{} {}  {}  run {}", "Last run:".bold(), status.task.cyan(), colored_status(&status.status), status.run_id.dimmed());
+    println!("  started   {}", status.started.format("%Y-%m-%d %H:%M:%S"));
+    println!("  finished  {} ({} ms)", status.finished.format("%Y-%m-%d %H:%M:%S"), status.duration_ms);
+    let width = status.tasks.iter().map(|t| t.name.len()).max().unwrap_or(0);
+    for task in &status.tasks {
+        let cached = if task.cached { " (cached)".dimmed().to_string() } else { String::new() };
+        println!("  {:<width$}  {:<7}  {:>6} ms{}", task.name, colored_status(&task.status), task.duration_ms, cached, width = width);
+    }
+    match (&status.failure_log, &status.combined_log) {
+        (Some(path), _) => println!("  {} {}", glyph(Glyph::Log).dimmed(), path.display()),
+        (None, Some(path)) => println!("  {} {}", glyph(Glyph::Log).dimmed(), path.display()),
+        _ => {},
+    }
+}
+
+pub fn handle_status(config_path: &Path, task: Option<&str>, json: bool) -> Result<()> {
+    let root = config_path.parent().unwrap_or(Path::new("."));
+    let Some(status) = read_status(root)? else {
+        bail!("No run recorded yet ({} not found)", status_path(root).display());
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print_status(&status);
+    }
+
+    if let Some(name) = task {
+        match status.task(name) {
+            None => bail!("Task '{}' was not part of the last run ('{}', run {})", name, status.task, status.run_id),
+            Some(entry) if entry.status == "failed" => bail!("Task '{}' failed in the last run ({})", name, status.run_id),
+            Some(_) => return Ok(()),
+        }
+    }
+    if status.exit_code != 0 {
+        bail!("The last run of '{}' failed (exit code {})", status.task, status.exit_code);
+    }
+    Ok(())
+}
@@ -1,23 +1,369 @@
 use anyhow::{Context, Result, bail};
-use std::env;
 use std::sync::Arc;
-use crate::config::load_config;
-use crate::runner::{recursive_runner, CallStack};
+use colored::*;
+use crate::config::{load_config, load_config_static, LockMode, PavidiConfig};
+use super::apply_project_settings;
+use crate::logger::RunLog;
+use crate::notify::{notify_completion, Outcome};
+use std::time::Instant;
+use crate::runner::{progress, recursive_runner, task_commands, CallStack};
+use crate::runner::plan::{build_group_plan, build_plan, Plan};
+use crate::runner::run_lock::{RunLock, LOCK_ENV};
+use crate::runner::last_plan::{diff_plans, read_last_plan, write_last_plan, LastPlan};
+use crate::runner::outputs::{active_tasks, output_conflicts};
+use crate::runner::task::group_members;
+use crate::runner::report::{write_reports, write_trace, ReportSpec, TaskStatus};
+use crate::runner::status::{write_status, RunStatus};
+use crate::runner::history::record_run;
+use std::path::Path;
+use crate::process;
+use crate::utils::{detect_shell, run_shell_command, CaptureMode, TimestampStyle};
+use log::info;
+use crate::ui::{self, glyph, Glyph};
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_runner_entry(config_path: &Path, task_name: String, extra_args: Vec<String>, dry_run: bool, json: bool, trace: bool, reports: &[ReportSpec], trace_file: Option<&Path>, resume: bool, diff: bool, wait: bool, timestamps: Option<TimestampStyle>) -> Result<()> {
+    if dry_run {
+        return handle_dry_run(config_path, &task_name, &extra_args, json, diff, !reports.is_empty() || trace_file.is_some());
+    }
+
+    let mut config = load_config(config_path)?;
+    apply_project_settings(&config);
+    let targets = resolve_targets(&config, &task_name, &extra_args)?;
+    check_outputs(&config, &targets)?;
+
+    // Ctrl+C stops commands running in their own process group before p exits
+    process::install_interrupt_handler();
+
+    // One run per project at a time: they share .p/cache and the logs
+    let root = config_path.parent().unwrap_or(Path::new("."));
+    let mode = if wait { LockMode::Wait } else { config.lock_mode() };
+    let lock = RunLock::acquire(root, &task_name, mode)?;
+    if lock.is_some() {
+        config.env.insert(LOCK_ENV.to_string(), std::process::id().to_string());
+    }
 
-pub fn handle_runner_entry(task_name: String, extra_args: Vec<String>, dry_run: bool, trace: bool) -> Result<()> {
-    let current_dir = env::current_dir()?;
-    let config = load_config(&current_dir)?; 
-    
     // Wrap config in Arc for TaskRunnerAdapter
     let config_arc = Arc::new(config);
 
-    let runner_section = config_arc.runner.as_ref().context("No [runner] section defined in config")?;
-    if !runner_section.contains_key(&task_name) {
-        bail!("Task '{}' not found", task_name);
+    let mut call_stack = CallStack::new();
+    let mut run = RunLog::new(&task_name, &config_arc);
+    if !reports.is_empty() {
+        run = run.capture_report_output();
+    }
+    if resume {
+        run = run.resuming();
+    }
+    if let Some(style) = timestamps {
+        run = run.with_timestamps(style);
+    }
+    // --progress-fd/--progress-pipe
+    if let Some(events) = progress::sink() {
+        run = run.with_events(events);
     }
 
-    let mut call_stack = CallStack::new();
+    let started = Instant::now();
 
     // Root task is allowed to print directly to stdout/stderr (capture = false)
-    recursive_runner(&task_name, &config_arc, &mut call_stack, &extra_args, false, dry_run, trace, 0)
+    let result = targets.iter().try_for_each(|target| {
+        // A group member an earlier member ran as a dependency is not run again,
+        // unless that was a fallback attempt that failed
+        if targets.len() > 1 && run.report.tasks().iter().any(|t| &t.name == target && t.status != TaskStatus::Failed) {
+            return Ok(());
+        }
+        recursive_runner(target, &config_arc, &run, &mut call_stack, &extra_args, false, trace, 0)
+    });
+
+    if let Err(e) = run.finish(result.is_ok()) {
+        log::warn!("{} Failed to finish the run log: {:#}", glyph(Glyph::Warn).yellow(), e);
+    }
+    let failure_log = run.failure_log();
+    if let Err(e) = &result {
+        let msg = e.to_string();
+        ui::annotate_error(msg.trim_start_matches(glyph(Glyph::Fail)).trim_start());
+        match &failure_log {
+            Some(path) => eprintln!("{} Run {} failed, see log {}", glyph(Glyph::Log).dimmed(), run.id.bold(), path.display()),
+            None => eprintln!("{} Run {} failed", glyph(Glyph::Log).dimmed(), run.id.bold()),
+        }
+    }
+
+    // Editors and dashboards read the last outcome from .p/status.json
+    let status = RunStatus::from_run(&run, result.is_ok(), chrono::Local::now());
+    if let Err(e) = write_status(root, &status) {
+        log::warn!("{} Failed to write run status: {:#}", glyph(Glyph::Warn).yellow(), e);
+    }
+    if let Err(e) = record_run(root, &status) {
+        log::warn!("{} Failed to update the task history: {:#}", glyph(Glyph::Warn).yellow(), e);
+    }
+    // Planned from the static config, like a dry run, so `--dry-run --diff` compares like with like
+    let planned = load_config_static(config_path)
+        .and_then(|config| plan_for(&config, &task_name, &targets, &extra_args))
+        .and_then(|plan| write_last_plan(root, &LastPlan::from_plan(&run.id, &plan)));
+    if let Err(e) = planned {
+        log::warn!("{} Failed to write the plan of this run: {:#}", glyph(Glyph::Warn).yellow(), e);
+    }
+
+    // Only the root task notifies; dependencies are part of its run
+    if let Some(notify) = &config_arc.notify {
+        let project = config_arc.project.as_ref().and_then(|p| p.metadata.name.as_deref())
+            .or(config_arc.module.as_ref().and_then(|m| m.metadata.name.as_deref()));
+        notify_completion(notify, config_arc.capability.as_ref(), &Outcome {
+            task: &task_name,
+            success: result.is_ok(),
+            duration: started.elapsed(),
+            project,
+            log: failure_log.as_deref(),
+        });
+    }
+
+    // A report that cannot be written fails an otherwise green run
+    let report_result = write_reports(reports, &run.id, &task_name, &run.report)
+        .and_then(|_| match trace_file {
+            Some(path) => write_trace(path, &run.id, &run.report),
+            None => Ok(()),
+        });
+    if process::abort_signal().is_some() {
+        run_on_abort(&config_arc);
+    }
+    result.and(report_result)
+}
+
+/// Warns about tasks this run may start that write overlapping `outputs`,
+/// or fails with `strict_outputs`.
+fn check_outputs(config: &PavidiConfig, targets: &[String]) -> Result<()> {
+    let Some(tasks) = &config.runner else { return Ok(()) };
+    let conflicts = output_conflicts(tasks, Some(&active_tasks(tasks, targets)));
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    if config.strict_outputs() {
+        let list: Vec<String> = conflicts.iter().map(|c| format!("  {}", c)).collect();
+        bail!("{} Tasks write overlapping outputs (strict_outputs):\n{}", glyph(Glyph::Fail), list.join("\n"));
+    }
+    for conflict in conflicts {
+        log::warn!("{} {}; each may make the other look up to date", glyph(Glyph::Warn).yellow(), conflict);
+    }
+    Ok(())
+}
+
+/// `[project] on_abort`, after a signal stopped the run. Each command runs even if one before it fails.
+fn run_on_abort(config: &PavidiConfig) {
+    let cmds = config.on_abort();
+    if cmds.is_empty() {
+        return;
+    }
+    info!("{} Running on_abort commands", glyph(Glyph::Cleanup).magenta());
+    let shell_pref = config.project.as_ref().and_then(|p| p.shell.as_ref())
+        .or(config.module.as_ref().and_then(|m| m.shell.as_ref()));
+    let shell_cmd = detect_shell(shell_pref);
+    for cmd in cmds {
+        info!("{} Executing: {}", "::".blue(), cmd);
+        match run_shell_command(cmd, &config.env, &config.command_settings, CaptureMode::Inherit, "on_abort", &shell_cmd, None, None, None, None, None, None) {
+            Ok((0, _)) => {},
+            Ok((code, _)) => log::warn!("{} on_abort command '{}' failed (code {})", glyph(Glyph::Warn).yellow(), cmd, code),
+            Err(e) => log::warn!("{} on_abort command '{}' failed: {:#}", glyph(Glyph::Warn).yellow(), cmd, e),
+        }
+    }
+}
+
+/// The tasks `p <task>` runs: the task itself, or every member of a group (`p build:`).
+fn resolve_targets(config: &PavidiConfig, task_name: &str, extra_args: &[String]) -> Result<Vec<String>> {
+    let runner = config.runner.as_ref().context("No [runner] section defined in config")?;
+    if runner.contains_key(task_name) {
+        return Ok(vec![task_name.to_string()]);
+    }
+    if !task_name.ends_with(':') {
+        bail!("Task '{}' not found", task_name);
+    }
+    let group = task_name.trim_end_matches(':');
+    let members = group_members(runner, group);
+    if members.is_empty() {
+        bail!("Group '{}' has no tasks (no task is named '{}:<name>')", group, group);
+    }
+    if !extra_args.is_empty() {
+        bail!("Arguments after -- cannot be passed to a group; run its tasks one at a time");
+    }
+    Ok(members.into_iter().cloned().collect())
+}
+
+fn plan_for(config: &PavidiConfig, task_name: &str, targets: &[String], extra_args: &[String]) -> Result<Plan> {
+    if targets == [task_name] {
+        build_plan(task_name, config, extra_args)
+    } else {
+        build_group_plan(task_name, targets, config)
+    }
+}
+
+/// Prints the plan of a run. Runs no commands (dynamic env and `run_if`/`skip_if`
+/// included) and writes nothing: no cache, logs, status or reports.
+/// With `diff`, prints how the plan differs from the last run instead.
+fn handle_dry_run(config_path: &Path, task_name: &str, extra_args: &[String], json: bool, diff: bool, wants_reports: bool) -> Result<()> {
+    let config = load_config_static(config_path)?;
+    apply_project_settings(&config);
+    let targets = resolve_targets(&config, task_name, extra_args)?;
+    let mut plan = plan_for(&config, task_name, &targets, extra_args)?;
+    if wants_reports {
+        log::warn!("{} --report and --trace-file are not written in a dry run", glyph(Glyph::Warn).yellow());
+    }
+    if diff {
+        let root = config_path.parent().unwrap_or(Path::new("."));
+        match read_last_plan(root)? {
+            Some(last) => plan.diff = Some(diff_plans(&last, &LastPlan::from_plan("", &plan))),
+            None => eprintln!("{} No previous run to compare with; showing the plan (each run writes .p/last-plan.json)", glyph(Glyph::Warn).yellow()),
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else if let Some(diff) = &plan.diff {
+        diff.print(task_name);
+    } else {
+        plan.print();
+    }
+    Ok(())
+}
+
+/// `p <task> --list-cmds`: the task's own commands as its shell would receive
+/// them, one per line and nothing else. Dynamic env values are resolved as in a
+/// run; dependencies, conditions and `finally` are left out.
+pub fn handle_list_cmds(config_path: &Path, task_name: &str, extra_args: &[String]) -> Result<()> {
+    let config = load_config(config_path)?;
+    apply_project_settings(&config);
+    if task_name.ends_with(':') {
+        bail!("--list-cmds takes a single task, not the group '{}'", task_name);
+    }
+    for cmd in task_commands(task_name, &config, extra_args)? {
+        println!("{}", cmd);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use crate::runner::resume::read_resume_point;
+    use crate::test_support::scratch;
+
+    fn snapshot(dir: &Path, files: &mut BTreeMap<String, Vec<u8>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.insert(format!("{}/", path.display()), vec![]);
+                snapshot(&path, files);
+            } else {
+                files.insert(path.display().to_string(), fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_dry_run_leaves_tree_untouched() {
+        let dir = scratch("dry_run");
+        let d = dir.display();
+        fs::write(dir.join("p.toml"), format!(r#"
+[project]
+log_strategy = "always"
+
+[env]
+STAMP = "$(touch '{d}/dynamic')"
+
+[runner.dep]
+cmds = ["touch '{d}/dep'"]
+sources = ["{d}/src.txt"]
+outputs = ["{d}/out"]
+run_if = "touch '{d}/condition'"
+
+[runner.main]
+cmds = ["touch '{d}/main' ${{STAMP}}"]
+deps = ["dep"]
+"#)).unwrap();
+        fs::write(dir.join("src.txt"), "source").unwrap();
+        fs::write(dir.join(".env"), "MODE=dev\n").unwrap();
+
+        let mut before = BTreeMap::new();
+        snapshot(&dir, &mut before);
+        for (json, diff) in [(false, false), (true, true)] {
+            handle_runner_entry(&dir.join("p.toml"), "main".to_string(), vec![], true, json, false, &[], Some(&dir.join("trace.json")), false, diff, false, None).unwrap();
+        }
+        let mut after = BTreeMap::new();
+        snapshot(&dir, &mut after);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_group_run() {
+        let dir = scratch("group_run");
+        let log = dir.join("order.txt");
+        let l = log.display();
+        fs::write(dir.join("p.toml"), format!(r#"
+[runner]
+"db:seed" = {{ cmds = ["echo seed >> '{l}'"], deps = ["db:migrate"] }}
+"db:migrate" = "echo migrate >> '{l}'"
+"db:reset" = "echo reset >> '{l}'"
+database = "echo database >> '{l}'"
+"#)).unwrap();
+        let config_path = dir.join("p.toml");
+
+        handle_runner_entry(&config_path, "db:".to_string(), vec![], false, false, false, &[], None, false, false, false, None).unwrap();
+        // Declaration order; db:migrate already ran as a dependency of db:seed
+        assert_eq!(fs::read_to_string(&log).unwrap(), "migrate\nseed\nreset\n");
+        // The run's plan is kept for `--dry-run --diff`
+        let last = read_last_plan(&dir).unwrap().unwrap();
+        let tasks: Vec<&str> = last.tasks.iter().map(|t| t.task.as_str()).collect();
+        assert_eq!((last.task.as_str(), &tasks[..]), ("db:", &["db:migrate", "db:seed", "db:reset"][..]));
+
+        let err = handle_runner_entry(&config_path, "data:".to_string(), vec![], false, false, false, &[], None, false, false, false, None).unwrap_err();
+        assert_eq!(err.to_string(), "Group 'data' has no tasks (no task is named 'data:<name>')");
+        let err = handle_runner_entry(&config_path, "db:".to_string(), vec!["x".to_string()], true, false, false, &[], None, false, false, false, None).unwrap_err();
+        assert!(err.to_string().contains("cannot be passed to a group"), "{}", err);
+    }
+
+    #[test]
+    fn test_group_reruns_failed_fallbacks() {
+        let dir = scratch("group_fallbacks");
+        let log = dir.join("order.txt");
+        let (l, d) = (log.display(), dir.display());
+        fs::write(dir.join("p.toml"), format!(r#"
+[runner]
+"pkg:build" = {{ cmds = ["touch '{d}/tool'"], fallbacks = ["pkg:native", "pkg:portable"] }}
+"pkg:native" = "test -f '{d}/tool' && echo native >> '{l}'"
+"pkg:portable" = "echo portable >> '{l}'"
+"#)).unwrap();
+
+        handle_runner_entry(&dir.join("p.toml"), "pkg:".to_string(), vec![], false, false, false, &[], None, false, false, false, None).unwrap();
+        // pkg:native failed as a fallback of pkg:build, so it still runs as a member;
+        // pkg:portable succeeded and is not run again
+        assert_eq!(fs::read_to_string(&log).unwrap(), "portable\nnative\n");
+
+        // The plan lists both alternatives, to be tried, and then pkg:native as a member
+        let config = load_config_static(&dir.join("p.toml")).unwrap();
+        let targets = resolve_targets(&config, "pkg:", &[]).unwrap();
+        let plan = plan_for(&config, "pkg:", &targets, &[]).unwrap();
+        let steps: Vec<(&str, &str)> = plan.steps.iter().map(|s| (s.task.as_str(), s.action)).collect();
+        assert_eq!(steps, [("pkg:native", "try"), ("pkg:portable", "try"), ("pkg:build", "run"), ("pkg:native", "run"), ("pkg:portable", "run")]);
+        assert_eq!(plan.steps[2].fallbacks, ["pkg:native", "pkg:portable"]);
+    }
+
+    #[test]
+    fn test_resume_run() {
+        let dir = scratch("resume_run");
+        let log = dir.join("order.txt");
+        let (l, d) = (log.display(), dir.display());
+        fs::write(dir.join("p.toml"), format!(r#"
+[runner]
+release = ["echo build >> '{l}'", "test -f '{d}/fixed' && echo upload >> '{l}'", "echo tag >> '{l}'"]
+"#)).unwrap();
+        let config_path = dir.join("p.toml");
+
+        assert!(handle_runner_entry(&config_path, "release".to_string(), vec![], false, false, false, &[], None, false, false, false, None).is_err());
+        let point = read_resume_point(&dir, "release").unwrap().unwrap();
+        assert_eq!(point.failed_at, 1);
+
+        fs::write(dir.join("fixed"), "").unwrap();
+        handle_runner_entry(&config_path, "release".to_string(), vec![], false, false, false, &[], None, true, false, false, None).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "build\nupload\ntag\n");
+        // A successful run clears the record
+        assert_eq!(read_resume_point(&dir, "release").unwrap(), None);
+    }
 }
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result, bail};
+use clap::ArgMatches;
+use colored::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use toml_edit::{Array, DocumentMut, Item, Table, value};
+use crate::cli::{NewTaskArgs, TargetOs};
+use crate::config::load_config_static;
+use crate::diagnostic::Diagnostic;
+use crate::ui::{glyph, Glyph};
+
+/// A task as given to `p new-task`.
+#[derive(Debug, Default, PartialEq)]
+pub struct NewTask {
+    pub name: String,
+    pub description: Option<String>,
+    pub deps: Vec<String>,
+    pub cmds: Vec<String>,
+    pub os_cmds: OsCommands,
+}
+
+/// Commands by OS field (`windows`, `linux`, `macos`).
+pub type OsCommands = BTreeMap<&'static str, Vec<String>>;
+
+/// Splits `--cmd` values between the plain command list and the `--os` given before them.
+pub fn group_commands(cmds: &[(usize, String)], os: &[(usize, TargetOs)]) -> Result<(Vec<String>, OsCommands)> {
+    let mut plain = vec![];
+    let mut by_os = OsCommands::new();
+    for (index, cmd) in cmds {
+        match os.iter().rev().find(|(i, _)| i < index) {
+            Some((_, target)) => by_os.entry(target.key()).or_default().push(cmd.clone()),
+            None => plain.push(cmd.clone()),
+        }
+    }
+    for (i, (index, target)) in os.iter().enumerate() {
+        let next = os.get(i + 1).map_or(usize::MAX, |(n, _)| *n);
+        if !cmds.iter().any(|(c, _)| c > index && *c < next) {
+            bail!("--os {} must be followed by at least one --cmd", target.key());
+        }
+    }
+    Ok((plain, by_os))
+}
+
+fn indexed<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str) -> Vec<(usize, T)> {
+    match (matches.indices_of(id), matches.get_many::<T>(id)) {
+        (Some(indices), Some(values)) => indices.zip(values.cloned()).collect(),
+        _ => vec![],
+    }
+}
+
+impl NewTask {
+    /// Builds the task from the parsed arguments; `matches` gives the order of `--os` and `--cmd`.
+    pub fn from_args(args: &NewTaskArgs, matches: &ArgMatches) -> Result<Self> {
+        if args.task.trim().is_empty() {
+            bail!("Task name cannot be empty");
+        }
+        let (cmds, os_cmds) = group_commands(&indexed(matches, "cmds"), &indexed(matches, "os"))?;
+        Ok(NewTask {
+            name: args.task.clone(),
+            description: args.description.clone(),
+            deps: args.deps.clone(),
+            cmds,
+            os_cmds,
+        })
+    }
+
+    fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        if let Some(description) = &self.description {
+            table["description"] = value(description.as_str());
+        }
+        if !self.deps.is_empty() {
+            table["deps"] = value(Array::from_iter(&self.deps));
+        }
+        if !self.cmds.is_empty() {
+            table["cmds"] = value(Array::from_iter(&self.cmds));
+        }
+        for (os, cmds) in &self.os_cmds {
+            table[*os] = value(Array::from_iter(cmds));
+        }
+        table
+    }
+}
+
+fn parse_document(text: &str, file_name: &str) -> Result<DocumentMut> {
+    text.parse().map_err(|e| Diagnostic::from_toml_edit(Path::new(file_name), text, &e).into())
+}
+
+/// `text` with `task` added as a `[runner.<name>]` table. Everything else is kept byte for byte.
+pub fn add_task(text: &str, file_name: &str, task: &NewTask, force: bool) -> Result<String> {
+    let mut doc = parse_document(text, file_name)?;
+    let runner = doc.entry("runner").or_insert_with(|| {
+        let mut runner = Table::new();
+        runner.set_implicit(true);
+        Item::Table(runner)
+    });
+    let runner = runner.as_table_mut().with_context(|| format!("[runner] in {} is not a table", file_name))?;
+
+    let mut table = task.to_table();
+    table.decor_mut().set_prefix("\n");
+    if let Some(existing) = runner.get(&task.name) {
+        if !force {
+            bail!("Task '{}' already exists in {} (use --force to replace it)", task.name, file_name);
+        }
+        // A replaced table stays where it was
+        if let Some(position) = existing.as_table().and_then(Table::position) {
+            table.set_position(position);
+        }
+        if let Some(decor) = existing.as_table().and_then(|t| t.decor().prefix()).cloned() {
+            table.decor_mut().set_prefix(decor);
+        }
+    }
+    runner.insert(&task.name, Item::Table(table));
+    Ok(doc.to_string())
+}
+
+/// `text` without the task `name`, or `None` if this file does not define it.
+pub fn remove_task(text: &str, file_name: &str, name: &str) -> Result<Option<String>> {
+    let mut doc = parse_document(text, file_name)?;
+    let removed = doc.get_mut("runner")
+        .and_then(Item::as_table_like_mut)
+        .and_then(|runner| runner.remove(name));
+    Ok(removed.map(|_| doc.to_string()))
+}
+
+fn file_name(config_path: &Path) -> String {
+    config_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+pub fn handle_new_task(config_path: &Path, args: &NewTaskArgs, matches: &ArgMatches) -> Result<()> {
+    let task = NewTask::from_args(args, matches)?;
+    let name = file_name(config_path);
+    let text = fs::read_to_string(config_path).with_context(|| format!("Failed to read {}", name))?;
+    let edited = add_task(&text, &name, &task, args.force)?;
+
+    // Dependencies may come from extensions, so check against the merged configuration
+    if let Ok(config) = load_config_static(config_path) {
+        let tasks = config.runner.unwrap_or_default();
+        for dep in task.deps.iter().filter(|d| **d != task.name && !tasks.contains_key(*d)) {
+            eprintln!("{} Task '{}' depends on '{}', which is not defined", glyph(Glyph::Warn).yellow(), task.name, dep);
+        }
+    }
+
+    fs::write(config_path, edited).with_context(|| format!("Failed to write {}", name))?;
+    println!("{} Added task '{}' to {}", glyph(Glyph::Ok).green(), task.name.bold(), name);
+    Ok(())
+}
+
+pub fn handle_remove_task(config_path: &Path, task: &str) -> Result<()> {
+    let name = file_name(config_path);
+    let text = fs::read_to_string(config_path).with_context(|| format!("Failed to read {}", name))?;
+    let config = load_config_static(config_path).ok();
+
+    let Some(edited) = remove_task(&text, &name, task)? else {
+        let elsewhere = config.as_ref().and_then(|c| c.runner.as_ref()).is_some_and(|r| r.contains_key(task));
+        if elsewhere {
+            bail!("Task '{}' is not defined in {} but in one of its p.*.toml extensions", task, name);
+        }
+        bail!("Task '{}' not found in {}", task, name);
+    };
+
+    if let Some(tasks) = config.and_then(|c| c.runner) {
+        let mut dependents: Vec<&String> = tasks.iter()
+            .filter(|(n, t)| n.as_str() != task && t.deps().iter().any(|d| d == task))
+            .map(|(n, _)| n)
+            .collect();
+        dependents.sort();
+        for dependent in dependents {
+            eprintln!("{} Task '{}' still depends on '{}'", glyph(Glyph::Warn).yellow(), dependent, task);
+        }
+    }
+
+    fs::write(config_path, edited).with_context(|| format!("Failed to write {}", name))?;
+    println!("{} Removed task '{}' from {}", glyph(Glyph::Ok).green(), task.bold(), name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use crate::cli::{Cli, Commands};
+
+    const ORIGINAL: &str = r#"# Build tooling for the demo
+[project]
+name = "demo"   # keep this comment
+
+[runner]
+fmt = "cargo fmt"     # aligned on purpose
+
+[runner.build]
+cmds = [ "cargo build" ]   # odd spacing
+
+[env]
+MODE = 'dev'
+"#;
+
+    fn parse(args: &[&str]) -> NewTask {
+        let matches = Cli::command().try_get_matches_from(args).unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let Some(Commands::NewTask(new)) = &cli.command else { panic!("not new-task") };
+        NewTask::from_args(new, matches.subcommand_matches("new-task").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_os_commands_follow_their_flag() {
+        let task = parse(&["p", "new-task", "open", "--cmd", "echo opening", "--os", "windows", "--cmd", "start .",
+            "--os", "linux", "--cmd", "xdg-open .", "--cmd", "true", "--dep", "build"]);
+        assert_eq!(task.cmds, ["echo opening"]);
+        assert_eq!(task.os_cmds["windows"], ["start ."]);
+        assert_eq!(task.os_cmds["linux"], ["xdg-open .", "true"]);
+        assert_eq!(task.deps, ["build"]);
+
+        let cmds = [(1, "a".to_string())];
+        let err = group_commands(&cmds, &[(3, TargetOs::Macos)]).unwrap_err();
+        assert!(err.to_string().contains("--os macos must be followed"), "{}", err);
+    }
+
+    #[test]
+    fn test_add_task_preserves_file() {
+        let task = NewTask {
+            name: "lint".to_string(),
+            description: Some("Run clippy".to_string()),
+            deps: vec!["build".to_string()],
+            cmds: vec!["cargo clippy".to_string()],
+            os_cmds: BTreeMap::from([("windows", vec!["cargo clippy --target x86_64-pc-windows-msvc".to_string()])]),
+        };
+        let edited = add_task(ORIGINAL, "p.toml", &task, false).unwrap();
+        let block = r#"
+[runner.lint]
+description = "Run clippy"
+deps = ["build"]
+cmds = ["cargo clippy"]
+windows = ["cargo clippy --target x86_64-pc-windows-msvc"]
+"#;
+        assert!(edited.contains(block), "{}", edited);
+        // Removing the new table gives back the original bytes
+        assert_eq!(edited.replacen(block, "", 1), ORIGINAL);
+
+        let parsed: crate::config::PavidiConfig = toml::from_str(&edited).unwrap();
+        assert_eq!(parsed.runner.unwrap()["lint"].deps(), ["build"]);
+    }
+
+    #[test]
+    fn test_add_task_refuses_overwrite() {
+        let task = NewTask { name: "build".to_string(), cmds: vec!["make".to_string()], ..Default::default() };
+        let err = add_task(ORIGINAL, "p.toml", &task, false).unwrap_err();
+        assert!(err.to_string().contains("already exists in p.toml"), "{}", err);
+
+        let edited = add_task(ORIGINAL, "p.toml", &task, true).unwrap();
+        assert_eq!(edited, ORIGINAL.replace("cmds = [ \"cargo build\" ]   # odd spacing", "cmds = [\"make\"]"));
+    }
+
+    #[test]
+    fn test_add_task_without_runner() {
+        let task = NewTask { name: "db:migrate".to_string(), cmds: vec!["sqlx migrate run".to_string()], ..Default::default() };
+        let edited = add_task("[project]\nname = 'x'", "p.toml", &task, false).unwrap();
+        assert_eq!(edited, "[project]\nname = 'x'\n\n[runner.\"db:migrate\"]\ncmds = [\"sqlx migrate run\"]\n");
+    }
+
+    #[test]
+    fn test_remove_task() {
+        let edited = remove_task(ORIGINAL, "p.toml", "build").unwrap().unwrap();
+        assert_eq!(edited, ORIGINAL.replace("\n[runner.build]\ncmds = [ \"cargo build\" ]   # odd spacing\n", ""));
+        let edited = remove_task(ORIGINAL, "p.toml", "fmt").unwrap().unwrap();
+        assert_eq!(edited, ORIGINAL.replace("fmt = \"cargo fmt\"     # aligned on purpose\n", ""));
+        assert!(remove_task(ORIGINAL, "p.toml", "missing").unwrap().is_none());
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::{Context, Result, anyhow, bail};
+use colored::*;
+use log::info;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::config::{load_config_with, CapabilityConfig, LoadOptions};
+use crate::http;
+use crate::ui::{glyph, Glyph};
+use crate::utils::canonicalize;
+
+/// Releases of this repository; each one carries an archive per target built by `dist`.
+const RELEASES_API: &str = "https://api.github.com/repos/CodeTease/p/releases";
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.name == name)
+    }
+}
+
+/// `1.2.3` or `v1.2.3` as numbers. A pre-release suffix (`-rc.1`) is ignored for comparison.
+fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let core = version.trim().trim_start_matches('v').split(['-', '+']).next().unwrap_or_default();
+    let parts: Vec<&str> = core.split('.').collect();
+    match parts[..] {
+        [major, minor, patch] => match (major.parse(), minor.parse(), patch.parse()) {
+            (Ok(major), Ok(minor), Ok(patch)) => Ok((major, minor, patch)),
+            _ => bail!("Invalid version '{}', expected X.Y.Z", version),
+        },
+        _ => bail!("Invalid version '{}', expected X.Y.Z", version),
+    }
+}
+
+/// The target this binary was built for, as named in release archives.
+fn target_triple() -> Option<&'static str> {
+    if cfg!(all(target_arch = "x86_64", target_os = "linux", target_env = "musl")) {
+        Some("x86_64-unknown-linux-musl")
+    } else if cfg!(all(target_arch = "x86_64", target_os = "linux", target_env = "gnu")) {
+        Some("x86_64-unknown-linux-gnu")
+    } else if cfg!(all(target_arch = "aarch64", target_os = "linux", target_env = "gnu")) {
+        Some("aarch64-unknown-linux-gnu")
+    } else if cfg!(all(target_arch = "x86_64", target_os = "macos")) {
+        Some("x86_64-apple-darwin")
+    } else if cfg!(all(target_arch = "aarch64", target_os = "macos")) {
+        Some("aarch64-apple-darwin")
+    } else if cfg!(all(target_arch = "x86_64", target_os = "windows")) {
+        Some("x86_64-pc-windows-msvc")
+    } else if cfg!(all(target_arch = "aarch64", target_os = "windows")) {
+        Some("aarch64-pc-windows-msvc")
+    } else {
+        None
+    }
+}
+
+/// The archive for `triple`: `pavidi-<triple>.zip` on Windows, `.tar.xz` (or `.tar.gz`) elsewhere.
+fn find_archive<'a>(release: &'a Release, triple: &str) -> Option<&'a Asset> {
+    let stem = format!("{}-{}", env!("CARGO_PKG_NAME"), triple);
+    [".zip", ".tar.xz", ".tar.gz"].iter().find_map(|ext| release.asset(&format!("{}{}", stem, ext)))
+}
+
+/// The hash in a `<archive>.sha256` file (`<hex>  <name>` or just `<hex>`).
+fn parse_checksum(text: &str) -> Option<String> {
+    let hash = text.split_whitespace().next()?.to_ascii_lowercase();
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(hash)
+}
+
+fn verify_checksum(name: &str, data: &[u8], expected: &str) -> Result<()> {
+    let actual = format!("{:x}", Sha256::digest(data));
+    if actual != expected {
+        bail!("Checksum mismatch for {}: expected {}, got {}. Nothing was installed", name, expected, actual);
+    }
+    Ok(())
+}
+
+/// Fails when the binary cannot or should not be replaced in place.
+fn ensure_replaceable(exe: &Path) -> Result<()> {
+    let hint = "If p was installed with a package manager, upgrade it there instead";
+    if exe.components().any(|c| c.as_os_str() == "Cellar") {
+        bail!("{} is managed by Homebrew. Run `brew upgrade pavidi` instead", exe.display());
+    }
+    let dir = exe.parent().context("The executable has no parent directory")?;
+    let readonly = fs::metadata(exe).map(|m| m.permissions().readonly()).unwrap_or(true);
+    let probe = dir.join(format!(".p-upgrade-probe-{}", std::process::id()));
+    if readonly || fs::write(&probe, b"").is_err() {
+        bail!("{} is not writable. {}", exe.display(), hint);
+    }
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// A directory next to the executable, so the new binary can be renamed over the old one.
+struct Staging(PathBuf);
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Unpacks `archive` with the system `tar` (bsdtar on Windows and macOS also reads .zip)
+/// and returns the `p` binary inside it.
+fn extract(archive: &Path, dest: &Path) -> Result<PathBuf> {
+    let status = Command::new("tar").arg("-xf").arg(archive).arg("-C").arg(dest).status()
+        .context("Failed to start 'tar' to unpack the release")?;
+    if !status.success() {
+        bail!("'tar' could not unpack {}", archive.display());
+    }
+    let binary = if cfg!(windows) { "p.exe" } else { "p" };
+    let mut dirs = vec![dest.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.file_name().is_some_and(|n| n == binary) {
+                return Ok(path);
+            }
+        }
+    }
+    bail!("The release archive does not contain '{}'", binary)
+}
+
+/// Moves `new` over `exe`. The rename is atomic on Unix. Windows will not overwrite a running
+/// executable but does allow renaming it, so the old one is moved aside first.
+fn replace_executable(exe: &Path, new: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        fs::set_permissions(new, fs::metadata(exe)?.permissions())?;
+        fs::rename(new, exe).with_context(|| format!("Failed to replace {}", exe.display()))
+    }
+    #[cfg(windows)]
+    {
+        let old = exe.with_extension("exe.old");
+        let _ = fs::remove_file(&old);
+        fs::rename(exe, &old).with_context(|| format!("Failed to move {} aside", exe.display()))?;
+        if let Err(e) = fs::rename(new, exe) {
+            let _ = fs::rename(&old, exe);
+            return Err(e).with_context(|| format!("Failed to replace {}", exe.display()));
+        }
+        Ok(())
+    }
+}
+
+/// GitHub answers 403 or 429 when rate limited, which says more than the status.
+fn request_error(url: &str, e: ureq::Error) -> anyhow::Error {
+    match e {
+        ureq::Error::StatusCode(403 | 429) => anyhow!("GitHub refused the request to {} (rate limited). Try again later", url),
+        e => http::request_error(url, e),
+    }
+}
+
+fn fetch(caps: Option<&CapabilityConfig>, agent: &ureq::Agent, url: &str) -> Result<Vec<u8>> {
+    http::check_url(caps, "p upgrade", url)?;
+    let mut response = agent.get(url).call().map_err(|e| request_error(url, e))?;
+    response.body_mut().with_config().limit(256 * 1024 * 1024).read_to_vec()
+        .map_err(|e| request_error(url, e))
+}
+
+fn fetch_release(caps: Option<&CapabilityConfig>, agent: &ureq::Agent, api: &str, version: Option<&str>) -> Result<Release> {
+    let url = match version {
+        Some(v) => format!("{}/tags/v{}", api, v.trim_start_matches('v')),
+        None => format!("{}/latest", api),
+    };
+    http::check_url(caps, "p upgrade", &url)?;
+    let body = match agent.get(&url).call() {
+        Err(ureq::Error::StatusCode(404)) => match version {
+            Some(v) => bail!("There is no release {}", v),
+            None => bail!("No release has been published yet"),
+        },
+        Err(e) => return Err(request_error(&url, e)),
+        Ok(mut response) => response.body_mut().read_to_string().map_err(|e| request_error(&url, e))?,
+    };
+    serde_json::from_str(&body).with_context(|| format!("Unexpected response from {}", url))
+}
+
+/// What `upgrade` did, for the caller to report.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    UpToDate(String),
+    Available(String),
+    Installed(String),
+}
+
+/// `caps` is the `[capability]` section of the project p runs in, if any; its `allow_net` applies.
+fn upgrade(api: &str, current: &str, exe: &Path, check: bool, version: Option<&str>, caps: Option<&CapabilityConfig>) -> Result<Outcome> {
+    if let Some(v) = version {
+        parse_version(v)?;
+    }
+    let agent = http::agent();
+    let release = fetch_release(caps, &agent, api, version)?;
+    let target = release.tag_name.trim_start_matches('v').to_string();
+    if version.is_none() && parse_version(&target)? <= parse_version(current)? {
+        return Ok(Outcome::UpToDate(target));
+    }
+    if check {
+        return Ok(Outcome::Available(target));
+    }
+
+    ensure_replaceable(exe)?;
+    let triple = target_triple().context("There are no release builds for this platform. Build p from source instead")?;
+    let archive = find_archive(&release, triple)
+        .with_context(|| format!("Release {} has no build for {}", release.tag_name, triple))?;
+    let checksum = release.asset(&format!("{}.sha256", archive.name))
+        .with_context(|| format!("Release {} publishes no checksum for {}, refusing to install it", release.tag_name, archive.name))?;
+
+    info!("{} Downloading {}", "::".blue(), archive.browser_download_url);
+    let expected = parse_checksum(&String::from_utf8_lossy(&fetch(caps, &agent, &checksum.browser_download_url)?))
+        .with_context(|| format!("{} does not contain a sha256 hash", checksum.name))?;
+    let data = fetch(caps, &agent, &archive.browser_download_url)?;
+    verify_checksum(&archive.name, &data, &expected)?;
+
+    let dir = exe.parent().context("The executable has no parent directory")?;
+    let staging = Staging(dir.join(format!(".p-upgrade-{}", std::process::id())));
+    fs::create_dir_all(&staging.0)?;
+    let archive_path = staging.0.join(&archive.name);
+    fs::write(&archive_path, &data)?;
+    let unpacked = staging.0.join("unpacked");
+    fs::create_dir_all(&unpacked)?;
+    let binary = extract(&archive_path, &unpacked)?;
+    replace_executable(exe, &binary)?;
+    Ok(Outcome::Installed(target))
+}
+
+pub fn handle_upgrade(config_path: &Path, check: bool, version: Option<&str>) -> Result<()> {
+    // Run inside a project, its allow_net applies to p's own requests too. A config this
+    // version cannot load is no reason to refuse the upgrade that may read it
+    let caps = load_config_with(config_path, LoadOptions::SHALLOW).ok().and_then(|c| c.capability);
+    let current = env!("CARGO_PKG_VERSION");
+    let exe = std::env::current_exe().context("Cannot locate the running executable")?;
+    // Through symlinks such as ~/.cargo/bin/p or Homebrew's bin/p
+    let exe = canonicalize(&exe).unwrap_or(exe);
+    match upgrade(RELEASES_API, current, &exe, check, version, caps.as_ref())? {
+        Outcome::UpToDate(latest) => println!("{} p {} is up to date (latest release: {})", glyph(Glyph::Ok).green(), current, latest),
+        Outcome::Available(target) if version.is_some() => println!("{} p {} is available (installed: {})", glyph(Glyph::Ok).green(), target, current),
+        Outcome::Available(target) => {
+            println!("{} p {} is available (installed: {}). Run `p upgrade` to install it", glyph(Glyph::Warn).yellow(), target, current);
+        },
+        Outcome::Installed(target) => println!("{} Upgraded p {} -> {} ({})", glyph(Glyph::Ok).green(), current, target, exe.display()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use crate::test_support::{scratch, Scratch};
+
+    /// Serves the files `build` returns for the server's base URL, by path, until the test process exits.
+    fn serve(build: impl FnOnce(&str) -> HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let files = build(&base);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                let _ = reader.read_line(&mut request_line);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = match files.get(path) {
+                    Some(body) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                let mut stream = reader.into_inner();
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                let _ = stream.write_all(body);
+            }
+        });
+        format!("{}/releases", base)
+    }
+
+    /// A release 9.9.9 for this platform whose archive holds a `p` containing "new",
+    /// published with `checksum` (or the archive's real hash).
+    fn release_server(dir: &Path, checksum: Option<&str>) -> String {
+        let stem = format!("pavidi-{}", target_triple().unwrap());
+        fs::create_dir_all(dir.join(&stem)).unwrap();
+        fs::write(dir.join(&stem).join("p"), "new").unwrap();
+        let archive = dir.join(format!("{}.tar.gz", stem));
+        let status = Command::new("tar").arg("-czf").arg(&archive).arg("-C").arg(dir).arg(&stem).status().unwrap();
+        assert!(status.success());
+        let data = fs::read(&archive).unwrap();
+        let hash = checksum.map(str::to_string).unwrap_or_else(|| format!("{:x}", Sha256::digest(&data)));
+
+        serve(|base| {
+            let release = serde_json::json!({
+                "tag_name": "v9.9.9",
+                "assets": [
+                    { "name": format!("{}.tar.gz", stem), "browser_download_url": format!("{}/dl/{}.tar.gz", base, stem) },
+                    { "name": format!("{}.tar.gz.sha256", stem), "browser_download_url": format!("{}/dl/{}.tar.gz.sha256", base, stem) },
+                ],
+            });
+            HashMap::from([
+                ("/releases/latest".to_string(), release.to_string().into_bytes()),
+                (format!("/dl/{}.tar.gz", stem), data),
+                (format!("/dl/{}.tar.gz.sha256", stem), format!("{}  {}.tar.gz\n", hash, stem).into_bytes()),
+            ])
+        })
+    }
+
+    fn install_dir(name: &str) -> Scratch {
+        let dir = scratch(&format!("upgrade_{}", name));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin/p"), "old").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v1.2.3").unwrap(), (1, 2, 3));
+        assert_eq!(parse_version("0.10.0-rc.1").unwrap(), (0, 10, 0));
+        assert!(parse_version("0.1.0").unwrap() < parse_version("0.10.0").unwrap());
+        assert!(parse_version("1.2").is_err());
+        assert_eq!(parse_checksum("ABCDEF0123456789abcdef0123456789abcdef0123456789abcdef0123456789 *p.zip").unwrap().len(), 64);
+        assert!(parse_checksum("not a hash").is_none());
+    }
+
+    #[test]
+    fn test_upgrade_replaces_binary() {
+        let dir = install_dir("happy");
+        let api = release_server(&dir, None);
+        let exe = dir.join("bin/p");
+
+        assert_eq!(upgrade(&api, "0.1.0", &exe, true, None, None).unwrap(), Outcome::Available("9.9.9".to_string()));
+        assert_eq!(fs::read_to_string(&exe).unwrap(), "old");
+        assert_eq!(upgrade(&api, "9.9.9", &exe, false, None, None).unwrap(), Outcome::UpToDate("9.9.9".to_string()));
+
+        assert_eq!(upgrade(&api, "0.1.0", &exe, false, None, None).unwrap(), Outcome::Installed("9.9.9".to_string()));
+        assert_eq!(fs::read_to_string(&exe).unwrap(), "new");
+        // Only the binary is left behind
+        assert_eq!(fs::read_dir(dir.join("bin")).unwrap().count(), 1);
+
+        let err = upgrade(&api, "0.1.0", &exe, false, Some("1.0.0"), None).unwrap_err().to_string();
+        assert_eq!(err, "There is no release 1.0.0");
+    }
+
+    #[test]
+    fn test_upgrade_rejects_checksum_mismatch() {
+        let dir = install_dir("mismatch");
+        let api = release_server(&dir, Some(&"0".repeat(64)));
+        let exe = dir.join("bin/p");
+
+        let err = upgrade(&api, "0.1.0", &exe, false, None, None).unwrap_err().to_string();
+        assert!(err.starts_with("Checksum mismatch for pavidi-"), "{}", err);
+        assert_eq!(fs::read_to_string(&exe).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_offline_error() {
+        // Nothing listens on port 9 (discard) on test machines
+        let dir = install_dir("offline");
+        let err = upgrade("http://127.0.0.1:9/releases", "0.1.0", &dir.join("bin/p"), true, None, None).unwrap_err().to_string();
+        assert!(err.starts_with("Could not reach http://127.0.0.1:9/releases/latest"), "{}", err);
+        // allow_net = false stops it before any request
+        let caps = CapabilityConfig { allow_net: Some(crate::config::NetPolicy::Flag(false)), ..Default::default() };
+        let err = upgrade("http://127.0.0.1:9/releases", "0.1.0", &dir.join("bin/p"), true, None, Some(&caps)).unwrap_err().to_string();
+        assert!(err.contains("network access to '127.0.0.1' is disabled"), "{}", err);
+    }
+}
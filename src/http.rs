@@ -0,0 +1,41 @@
+//! The HTTP client shared by `p upgrade`, `extends_remote` and webhooks. Every
+//! request p makes itself is checked against `[capability] allow_net` first.
+
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+use crate::config::CapabilityConfig;
+use crate::runner::capability::check_net;
+
+/// Proxies come from HTTPS_PROXY/ALL_PROXY, as with curl.
+pub fn agent() -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .timeout_connect(Some(Duration::from_secs(10)))
+        .timeout_recv_response(Some(Duration::from_secs(30)))
+        .user_agent(format!("p/{}", env!("CARGO_PKG_VERSION")))
+        .build()
+        .into()
+}
+
+/// Turns a failed request into an error that says whether the server answered.
+pub fn request_error(url: &str, e: ureq::Error) -> anyhow::Error {
+    match e {
+        ureq::Error::StatusCode(code) => anyhow!("GET {} failed with HTTP {}", url, code),
+        e => anyhow!("Could not reach {}: {}. Check your connection, or set HTTPS_PROXY if you are behind a proxy", url, e),
+    }
+}
+
+/// Refuses `url` unless `allow_net` lets `command` contact its host.
+pub fn check_url(caps: Option<&CapabilityConfig>, command: &str, url: &str) -> Result<()> {
+    let uri: ureq::http::Uri = url.parse().with_context(|| format!("'{}' is not a valid URL", url))?;
+    let host = uri.host().with_context(|| format!("'{}' has no host", url))?;
+    check_net(caps, command, host)
+}
+
+/// The body of `url`, refused when it is larger than `limit` bytes or when
+/// `allow_net` does not let `command` reach it.
+pub fn download(caps: Option<&CapabilityConfig>, command: &str, agent: &ureq::Agent, url: &str, limit: u64) -> Result<Vec<u8>> {
+    check_url(caps, command, url)?;
+    let mut response = agent.get(url).call().map_err(|e| request_error(url, e))?;
+    response.body_mut().with_config().limit(limit).read_to_vec()
+        .with_context(|| format!("Failed to download {}", url))
+}
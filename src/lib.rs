@@ -0,0 +1,59 @@
+//! Pavidi, the task runner behind the `p` command, as a library.
+//!
+//! Load a configuration, look at what a task would do, and run it with its
+//! output and progress delivered to your program:
+//!
+//! ```
+//! use pavidi::{Config, Event, LoadOptions, RunOptions, Runner, TaskGraph};
+//! use std::sync::{Arc, Mutex};
+//!
+//! # let dir = std::env::temp_dir().join(format!("pavidi_doc_{}", std::process::id()));
+//! # std::fs::create_dir_all(&dir).unwrap();
+//! # std::fs::write(dir.join("p.toml"), "[runner]\ngreet = \"echo hello\"\n").unwrap();
+//! let config = Config::load(&dir.join("p.toml"), LoadOptions::FULL)?;
+//! let plan = TaskGraph::plan(&config, "greet")?;
+//! assert_eq!(plan.steps[0].commands, ["echo hello"]);
+//!
+//! let lines = Arc::new(Mutex::new(Vec::new()));
+//! let seen = lines.clone();
+//! Runner::new(config).run(&plan, RunOptions {
+//!     capture: Some(Box::new(std::io::sink())),
+//!     events: Some(Arc::new(move |event| {
+//!         if let Event::OutputChunk { line, .. } = event {
+//!             seen.lock().unwrap().push(line.clone());
+//!         }
+//!     })),
+//!     ..Default::default()
+//! })?;
+//! assert_eq!(*lines.lock().unwrap(), ["hello"]);
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The modules below are what the `p` binary is built from; only the items
+//! re-exported here are meant to stay stable.
+
+pub mod cli;
+pub mod config;
+pub mod diagnostic;
+pub mod runner;
+pub mod handlers;
+#[cfg(feature = "net")]
+pub mod http;
+pub mod utils;
+pub mod logger;
+pub mod log_index;
+pub mod notify;
+pub mod process;
+pub mod remote_config;
+pub mod state;
+pub mod ui;
+#[cfg(test)]
+mod test_support;
+
+pub use config::{LoadOptions, PavidiConfig as Config};
+pub use runner::embed::{RunOptions, Runner};
+pub use runner::events::{Event, EventSink, OutputStream};
+pub use runner::graph::TaskGraph;
+pub use runner::plan::Plan;
+pub use runner::report::TaskStatus;
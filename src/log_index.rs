@@ -0,0 +1,257 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use chrono::DateTime;
+use crate::logger::{LogRecord, RUNNING_DIR};
+
+/// A log file found under `.p/logs/<date>/<exit_code>/` (or `<date>/running/`),
+/// or the log of a combined run, `<date>/<run_id>_<task>.log`.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub path: PathBuf,
+    pub id: String,
+    pub task: String,
+    pub date: String,
+    pub time: String,
+    /// `None` while the command is still running
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<u128>,
+}
+
+impl LogEntry {
+    /// `YYYY-MM-DD HH:MM:SS`, derived from the directory and file name.
+    pub fn timestamp(&self) -> String {
+        if self.time.len() == 6 {
+            format!("{} {}:{}:{}", self.date, &self.time[0..2], &self.time[2..4], &self.time[4..6])
+        } else {
+            format!("{} {}", self.date, self.time)
+        }
+    }
+}
+
+/// Scans the log tree and returns entries, newest first.
+/// JSON logs are listed only when there is no text log with the same name.
+pub fn scan_logs(root: &Path) -> Vec<LogEntry> {
+    let mut entries = Vec::new();
+
+    let Ok(dates) = fs::read_dir(root) else {
+        return entries;
+    };
+
+    for date_dir in dates.flatten() {
+        let date = date_dir.file_name().to_string_lossy().to_string();
+        let Ok(codes) = fs::read_dir(date_dir.path()) else { continue };
+
+        for code_dir in codes.flatten() {
+            let dir_name = code_dir.file_name().to_string_lossy().to_string();
+            if dir_name.ends_with(".log") && !dir_name.starts_with('.') {
+                if let Some(entry) = parse_combined(&code_dir.path(), &date) {
+                    entries.push(entry);
+                }
+                continue;
+            }
+            let exit_code = match dir_name.parse::<i32>() {
+                Ok(code) => Some(code),
+                Err(_) if dir_name == RUNNING_DIR => None,
+                Err(_) => continue,
+            };
+            let Ok(files) = fs::read_dir(code_dir.path()) else { continue };
+
+            for file in files.flatten() {
+                let path = file.path();
+                let ext = path.extension().and_then(|e| e.to_str());
+                let is_orphan_json = ext == Some("json") && !path.with_extension("log").exists();
+                if ext != Some("log") && !is_orphan_json {
+                    continue;
+                }
+                if let Some(entry) = parse_entry(&path, &date, exit_code) {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| (&b.date, &b.time, &b.id).cmp(&(&a.date, &a.time, &a.id)));
+    entries
+}
+
+/// Builds an entry from a `<HHMMSS>_<task>_<hash>.<ext>` file, preferring the
+/// task name and duration recorded inside the file over the file name.
+fn parse_entry(path: &Path, date: &str, exit_code: Option<i32>) -> Option<LogEntry> {
+    let stem = path.file_stem()?.to_str()?;
+    let (time, rest) = stem.split_once('_')?;
+    let (file_task, hash) = rest.rsplit_once('_')?;
+
+    let (task, duration_ms) = if path.extension().and_then(|e| e.to_str()) == Some("json") {
+        let record: Option<LogRecord> = fs::read_to_string(path).ok()
+            .and_then(|s| serde_json::from_str(&s).ok());
+        (record.as_ref().map(|r| r.task.clone()), record.map(|r| r.duration_ms))
+    } else {
+        (read_header(path, "Task: "), read_footer_duration(path))
+    };
+
+    Some(LogEntry {
+        path: path.to_path_buf(),
+        id: hash.to_string(),
+        task: task.unwrap_or_else(|| file_task.to_string()),
+        date: date.to_string(),
+        time: time.to_string(),
+        exit_code,
+        duration_ms,
+    })
+}
+
+/// Builds an entry from a combined `<run_id>_<task>.log`. The run's exit code is
+/// in the footer written when it ends; until then it counts as running.
+fn parse_combined(path: &Path, date: &str) -> Option<LogEntry> {
+    let stem = path.file_stem()?.to_str()?;
+    let (id, file_task) = stem.split_once('_')?;
+    let started = read_header(path, "Time: ")
+        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+        .map(|t| t.format("%H%M%S").to_string());
+
+    Some(LogEntry {
+        path: path.to_path_buf(),
+        id: id.to_string(),
+        task: read_header(path, "Task: ").unwrap_or_else(|| file_task.to_string()),
+        date: date.to_string(),
+        time: started.unwrap_or_default(),
+        exit_code: read_footer(path, "Exit Code: ").and_then(|c| c.parse().ok()),
+        duration_ms: read_footer_duration(path),
+    })
+}
+
+fn read_header(path: &Path, key: &str) -> Option<String> {
+    let reader = BufReader::new(File::open(path).ok()?);
+    reader.lines()
+        .map_while(Result::ok)
+        .take(5)
+        .find_map(|line| line.strip_prefix(key).map(|t| t.to_string()))
+}
+
+fn read_footer_duration(path: &Path) -> Option<u128> {
+    read_footer(path, "Duration: ").and_then(|d| d.trim_end_matches(" ms").parse().ok())
+}
+
+fn read_footer(path: &Path, key: &str) -> Option<String> {
+    // The footer is small; read only the end of potentially large logs
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(512))).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+
+    String::from_utf8_lossy(&tail)
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(key).map(|v| v.to_string()))
+}
+
+/// Resolves a 1-based index into `entries` or a log id (hash or file stem).
+pub fn find_entry<'a>(entries: &'a [LogEntry], key: &str) -> Option<&'a LogEntry> {
+    // Ids are hex hashes and may be all digits, so they take precedence over indices
+    entries.iter()
+        .find(|e| e.id == key || e.path.file_stem().and_then(|s| s.to_str()) == Some(key))
+        .or_else(|| {
+            let index = key.parse::<usize>().ok()?;
+            entries.get(index.checked_sub(1)?)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    fn write_text_log(root: &Path, date: &str, code: i32, file: &str, task: &str, duration: u64) {
+        let dir = root.join(date).join(code.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let content = format!(
+            "=== PAVIDI EXECUTION LOG ===\nTask: {}\nCommand: echo\nTime: now\n=== ENVIRONMENT SNAPSHOT ===\n============================\n\nout\n\n============================\nExit Code: {}\nDuration: {} ms\nEnd Time: now\n============================\n",
+            task, code, duration
+        );
+        fs::write(dir.join(file), content).unwrap();
+    }
+
+    #[test]
+    fn test_scan_orders_and_parses() {
+        let root = scratch("log_index_scan");
+        write_text_log(&root, "2024-01-01", 0, "101500_build_aaaaaa.log", "build", 120);
+        write_text_log(&root, "2024-01-02", 1, "090000_ci_test_bbbbbb.log", "ci/test", 5000);
+        write_text_log(&root, "2024-01-02", 0, "120000_lint_cccccc.log", "lint", 7);
+        fs::write(root.join("2024-01-02").join("0").join("notes.txt"), "ignored").unwrap();
+
+        let entries = scan_logs(&root);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].task, "lint");
+        assert_eq!(entries[1].task, "ci/test");
+        assert_eq!(entries[1].exit_code, Some(1));
+        assert_eq!(entries[1].duration_ms, Some(5000));
+        assert_eq!(entries[1].id, "bbbbbb");
+        assert_eq!(entries[2].timestamp(), "2024-01-01 10:15:00");
+    }
+
+    #[test]
+    fn test_json_logs_listed_without_text_twin() {
+        let root = scratch("log_index_json");
+        write_text_log(&root, "2024-01-01", 0, "101500_build_aaaaaa.log", "build", 120);
+        let dir = root.join("2024-01-01").join("0");
+        fs::write(dir.join("101500_build_aaaaaa.json"), "{}").unwrap();
+        let json = r#"{"task":"deploy","command":"x","start":"2024-01-01T11:00:00+00:00","end":"2024-01-01T11:00:01+00:00","duration_ms":1000,"exit_code":0,"env":{},"output":""}"#;
+        fs::write(dir.join("110001_deploy_dddddd.json"), json).unwrap();
+
+        let entries = scan_logs(&root);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].task, "deploy");
+        assert_eq!(entries[0].duration_ms, Some(1000));
+    }
+
+    #[test]
+    fn test_running_logs_have_no_exit_code() {
+        let root = scratch("log_index_running");
+        let dir = root.join("2024-01-01").join(RUNNING_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("101500_serve_aaaaaa.log"), "=== PAVIDI EXECUTION LOG ===\nTask: serve\n").unwrap();
+
+        let entries = scan_logs(&root);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].task, "serve");
+        assert_eq!(entries[0].exit_code, None);
+        assert_eq!(entries[0].duration_ms, None);
+    }
+
+    #[test]
+    fn test_combined_run_logs() {
+        let root = scratch("log_index_combined");
+        let dir = root.join("2024-01-01");
+        fs::create_dir_all(&dir).unwrap();
+        let header = "=== PAVIDI RUN LOG ===\nRun: abcd1234\nTask: ci/test\nTime: 2024-01-01T09:30:00+00:00\n=== ENVIRONMENT SNAPSHOT ===\n============================\n\n";
+        let section = "=== [lint] cargo clippy ===\nTime: 2024-01-01T09:30:00+00:00\n\nok\n\n--- Exit Code: 0 | Duration: 40 ms ---\n\n";
+        fs::write(dir.join("abcd1234_ci_test.log"), format!("{}{}============================\nExit Code: 1\nDuration: 950 ms\nEnd Time: now\n============================\n", header, section)).unwrap();
+        fs::write(dir.join("ef567890_ci_test.log"), header.replace("09:30", "10:00")).unwrap();
+        fs::write(dir.join(".101500_lint_aaaaaa.log"), "partial").unwrap();
+
+        let entries = scan_logs(&root);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id.as_str(), entries[0].exit_code), ("ef567890", None));
+        assert_eq!(entries[1].task, "ci/test");
+        assert_eq!(entries[1].exit_code, Some(1));
+        assert_eq!(entries[1].duration_ms, Some(950));
+        assert_eq!(entries[1].timestamp(), "2024-01-01 09:30:00");
+    }
+
+    #[test]
+    fn test_find_entry_by_index_or_id() {
+        let root = scratch("log_index_find");
+        write_text_log(&root, "2024-01-01", 0, "101500_build_aaaaaa.log", "build", 1);
+        write_text_log(&root, "2024-01-01", 2, "111500_test_bbbbbb.log", "test", 1);
+        let entries = scan_logs(&root);
+
+        assert_eq!(find_entry(&entries, "1").unwrap().task, "test");
+        assert_eq!(find_entry(&entries, "2").unwrap().task, "build");
+        assert_eq!(find_entry(&entries, "aaaaaa").unwrap().task, "build");
+        assert_eq!(find_entry(&entries, "111500_test_bbbbbb").unwrap().task, "test");
+        assert!(find_entry(&entries, "3").is_none());
+        assert!(find_entry(&entries, "zzzzzz").is_none());
+    }
+}
@@ -1,141 +1,683 @@
 use anyhow::{Context, Result};
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, LineWriter, Write};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
-use chrono::Local;
+use std::collections::BTreeMap;
+use indexmap::IndexMap;
+use std::env;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+use chrono::{DateTime, Local};
 use regex::Regex;
-use crate::config::{PavidiConfig, LogStrategy};
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::config::{PavidiConfig, LogFormat, LogMode, LogStrategy};
 use blake3::Hasher;
+use crate::runner::report::RunReport;
+use crate::runner::events::{Event, EventSink};
+use crate::utils::{CommandSettings, TimestampStyle};
+
+static ANSI_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
 
 pub fn strip_ansi(content: &str) -> String {
-    let re = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
-    re.replace_all(content, "").to_string()
-}
-
-pub fn write_log(
-    task_name: &str,
-    cmd_str: &str,
-    content: &str,
-    config: &PavidiConfig,
-    duration: Duration,
-    exit_code: i32,
-    env_vars: &HashMap<String, String>
-) -> Result<Option<PathBuf>> {
-    // 1. Determine Strategy
-    let (strategy, log_plain) = if let Some(p) = &config.project {
-        (p.log_strategy, p.log_plain.unwrap_or(true))
-    } else if let Some(m) = &config.module {
-        (m.log_strategy, m.log_plain.unwrap_or(true))
-    } else {
-        (None, true)
-    };
-
-    let strategy = strategy.unwrap_or(LogStrategy::None);
-
-    match strategy {
-        LogStrategy::None => return Ok(None),
-        LogStrategy::ErrorOnly => {
-            if exit_code == 0 {
-                return Ok(None);
-            }
+    ANSI_RE.replace_all(content, "").to_string()
+}
+
+/// Root directory that holds `<date>/<exit_code>/` log folders:
+/// `P_LOG_DIR`, then `log_dir` from the config, then `<project_root>/.p/logs`.
+pub fn log_root(config: &PavidiConfig) -> PathBuf {
+    let configured = config.project.as_ref().and_then(|p| p.log_dir.as_deref())
+        .or(config.module.as_ref().and_then(|m| m.log_dir.as_deref()));
+    resolve_log_root(env::var("P_LOG_DIR").ok().as_deref(), configured, &config.root_dir)
+}
+
+fn resolve_log_root(env_override: Option<&str>, configured: Option<&str>, config_dir: &Path) -> PathBuf {
+    match env_override.filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => match configured {
+            // Relative paths are resolved against the directory of p.toml
+            Some(dir) => config_dir.join(dir),
+            None => config_dir.join(".p").join("logs"),
         },
-        LogStrategy::Always => {},
-    }
-
-    // 2. Generate Path
-    let now = Local::now();
-    let date_str = now.format("%Y-%m-%d").to_string();
-    let time_str = now.format("%H%M%S").to_string();
-    
-    // Short Hash
-    let mut hasher = Hasher::new();
-    hasher.update(task_name.as_bytes());
-    hasher.update(time_str.as_bytes());
-    let hash_full = hasher.finalize().to_hex().to_string();
-    let short_hash = &hash_full[0..6];
-
-    let filename = format!("{}_{}_{}.log", time_str, task_name.replace("/", "_"), short_hash);
-    let log_dir = Path::new(".p").join("logs").join(date_str).join(exit_code.to_string());
-    
-    fs::create_dir_all(&log_dir).context("Failed to create log directory")?;
-    
+    }
+}
+
+/// Directory for logs of commands that are still running.
+pub const RUNNING_DIR: &str = "running";
+
+/// A single command execution as stored in JSON logs.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub task: String,
+    pub command: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub duration_ms: u128,
+    pub exit_code: i32,
+    pub env: BTreeMap<String, String>,
+    pub output: String,
+}
+
+/// Logging options resolved from `[project]` / `[module]`.
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub strategy: LogStrategy,
+    pub format: LogFormat,
+    pub mode: LogMode,
+    pub plain: bool,
+    pub secret_patterns: Vec<Regex>,
+    pub root: PathBuf,
+}
+
+impl LogSettings {
+    pub fn from_config(config: &PavidiConfig) -> Self {
+        let (strategy, plain, format, mode, patterns) = if let Some(p) = &config.project {
+            (p.log_strategy, p.log_plain, p.log_format, p.log_mode, p.secret_patterns.as_ref())
+        } else if let Some(m) = &config.module {
+            (m.log_strategy, m.log_plain, m.log_format, m.log_mode, m.secret_patterns.as_ref())
+        } else {
+            (None, None, None, None, None)
+        };
+
+        LogSettings {
+            strategy: strategy.unwrap_or(LogStrategy::None),
+            format: format.unwrap_or(LogFormat::Text),
+            mode: mode.unwrap_or(LogMode::PerCommand),
+            plain: plain.unwrap_or(true),
+            // Invalid regex patterns are ignored
+            secret_patterns: patterns.into_iter().flatten().filter_map(|p| Regex::new(p).ok()).collect(),
+            root: log_root(config),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.strategy != LogStrategy::None
+    }
+
+    fn mask(&self, text: &str) -> String {
+        let mut masked = text.to_string();
+        for re in &self.secret_patterns {
+            masked = re.replace_all(&masked, "[REDACTED]").to_string();
+        }
+        masked
+    }
+}
+
+pub fn is_sensitive_key(key: &str) -> bool {
+    let k_upper = key.to_uppercase();
+    k_upper.contains("KEY") || k_upper.contains("TOKEN") || k_upper.contains("PASS") || k_upper.contains("SECRET")
+}
+
+/// Sorted env snapshot with sensitive values replaced.
+fn redact_env(env_vars: &IndexMap<String, String>) -> BTreeMap<String, String> {
+    env_vars.iter()
+        .map(|(k, v)| {
+            let v = if is_sensitive_key(k) { "[REDACTED]".to_string() } else { v.clone() };
+            (k.clone(), v)
+        })
+        .collect()
+}
+
+/// JSON string contents without the surrounding quotes.
+fn json_escape(text: &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn ensure_gitignore(root: &Path) {
     // Ensure .gitignore exists in .p to hide logs from git
-    let gitignore = Path::new(".p").join(".gitignore");
+    if let Some(state_dir) = root.parent().filter(|d| d.file_name() == Some(".p".as_ref())) {
+        ensure_state_gitignore(state_dir);
+    }
+}
+
+/// Keeps everything under `.p` out of git.
+pub fn ensure_state_gitignore(state_dir: &Path) {
+    let gitignore = state_dir.join(".gitignore");
     if !gitignore.exists() {
         // We ignore errors here as it might be a race condition in parallel execution or permission issue
         // which shouldn't stop logging.
         let _ = fs::write(&gitignore, "# Generated by Pavidi \n*\n");
     }
+}
 
-    let log_path = log_dir.join(filename);
-
-    // 3. Format Content
-    let mut file_content = String::new();
-    
-    // Header
-    file_content.push_str("=== PAVIDI EXECUTION LOG ===\n");
-    file_content.push_str(&format!("Task: {}\n", task_name));
-    file_content.push_str(&format!("Command: {}\n", cmd_str));
-    file_content.push_str(&format!("Time: {}\n", now.to_rfc3339()));
-    file_content.push_str("=== ENVIRONMENT SNAPSHOT ===\n");
-    
-    // Filter sensitive envs
-    let mut sorted_keys: Vec<_> = env_vars.keys().collect();
-    sorted_keys.sort();
-    
-    for k in sorted_keys {
-        let v = &env_vars[k];
-        let k_upper = k.to_uppercase();
-        if k_upper.contains("KEY") || k_upper.contains("TOKEN") || k_upper.contains("PASS") || k_upper.contains("SECRET") {
-             file_content.push_str(&format!("{} = [REDACTED]\n", k));
-        } else {
-             file_content.push_str(&format!("{} = {}\n", k, v));
-        }
-    }
-    file_content.push_str("============================\n\n");
-
-    // Body
-    let body = if log_plain {
-        strip_ansi(content)
-    } else {
-        content.to_string()
-    };
-    file_content.push_str(&body);
-    if !body.ends_with('\n') {
-        file_content.push('\n');
-    }
-
-    // Footer
-    file_content.push_str("\n============================\n");
-    file_content.push_str(&format!("Exit Code: {}\n", exit_code));
-    file_content.push_str(&format!("Duration: {} ms\n", duration.as_millis()));
-    file_content.push_str(&format!("End Time: {}\n", Local::now().to_rfc3339()));
-    file_content.push_str("============================\n");
-
-    // Apply Custom Secret Masking
-    let secret_patterns = if let Some(p) = &config.project {
-        p.secret_patterns.as_ref()
-    } else if let Some(m) = &config.module {
-        m.secret_patterns.as_ref()
-    } else {
-        None
-    };
-
-    if let Some(patterns) = secret_patterns {
-        for pattern in patterns {
-            match Regex::new(pattern) {
-                Ok(re) => {
-                    file_content = re.replace_all(&file_content, "[REDACTED]").to_string();
-                },
-                Err(_) => {
-                    // Ignore invalid regex patterns as per requirements
+/// State shared by every command of one `p <task>` invocation.
+pub struct RunLog {
+    pub id: String,
+    pub root_task: String,
+    pub settings: LogSettings,
+    /// `<date>/<run_id>_<root_task>.log` when `log_mode = "combined"`
+    combined: Option<PathBuf>,
+    // Serializes appends from parallel dependencies
+    writer: Mutex<()>,
+    failure_log: Mutex<Option<PathBuf>>,
+    /// Per-task results for `--report`
+    pub report: RunReport,
+    /// `--resume`: tasks named on the command line continue from their last failed command
+    pub resume: bool,
+    /// Subscriber of an embedding program
+    pub events: Option<EventSink>,
+    /// How the run's commands are started and shown, from the config
+    pub commands: CommandSettings,
+}
+
+impl RunLog {
+    pub fn new(root_task: &str, config: &PavidiConfig) -> Self {
+        let now = Local::now();
+        let mut hasher = Hasher::new();
+        hasher.update(now.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true).as_bytes());
+        hasher.update(root_task.as_bytes());
+        let id = hasher.finalize().to_hex()[0..8].to_string();
+
+        let settings = LogSettings::from_config(config);
+        let combined = (settings.mode == LogMode::Combined).then(|| {
+            settings.root
+                .join(now.format("%Y-%m-%d").to_string())
+                .join(format!("{}_{}.log", id, root_task.replace("/", "_")))
+        });
+
+        RunLog { commands: config.command_settings.clone(), ..Self::with_path(id, root_task, settings, combined) }
+    }
+
+    fn with_path(id: String, root_task: &str, settings: LogSettings, combined: Option<PathBuf>) -> Self {
+        RunLog {
+            id,
+            root_task: root_task.to_string(),
+            settings,
+            combined,
+            writer: Mutex::new(()),
+            failure_log: Mutex::new(None),
+            report: RunReport::new(false),
+            resume: false,
+            events: None,
+            commands: CommandSettings::default(),
+        }
+    }
+
+    /// Keeps output tails of failed commands for the run report.
+    pub fn capture_report_output(mut self) -> Self {
+        self.report = RunReport::new(true);
+        self
+    }
+
+    pub fn resuming(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    /// `--timestamps`, which wins over `[project] timestamps`.
+    pub fn with_timestamps(mut self, style: TimestampStyle) -> Self {
+        self.commands.timestamps = Some(style);
+        self
+    }
+
+    pub fn with_events(mut self, events: EventSink) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events(&event);
+        }
+    }
+
+    /// Remembers the log of the command that failed the run.
+    pub fn record_failure(&self, path: PathBuf) {
+        *self.failure_log.lock().unwrap() = Some(path);
+    }
+
+    pub fn failure_log(&self) -> Option<PathBuf> {
+        self.failure_log.lock().unwrap().clone()
+    }
+
+    pub fn combined_log(&self) -> Option<&Path> {
+        self.combined.as_deref()
+    }
+
+    /// Ends the combined run log with the outcome of the run, in the footer format
+    /// of a per-command log. Nothing happens when no command was logged.
+    pub fn finish(&self, success: bool) -> Result<()> {
+        let Some(path) = self.combined.as_deref().filter(|p| p.exists()) else { return Ok(()) };
+        let _guard = self.writer.lock().unwrap();
+        let end = Local::now();
+        let duration_ms = (end - self.report.started_at()).num_milliseconds().max(0);
+        let mut file = OpenOptions::new().append(true).open(path).context("Failed to open combined log file")?;
+        file.write_all(b"============================\n")?;
+        writeln!(file, "Exit Code: {}", if success { 0 } else { 1 })?;
+        writeln!(file, "Duration: {} ms", duration_ms)?;
+        writeln!(file, "End Time: {}", end.to_rfc3339())?;
+        file.write_all(b"============================\n")?;
+        Ok(())
+    }
+
+    /// Moves a finished command's partial logs into the combined run log.
+    fn append(&self, path: &Path, stream: &LogStream) -> Result<PathBuf> {
+        let _guard = self.writer.lock().unwrap();
+
+        if let Some(text_partial) = &stream.text_path {
+            let is_new = !path.exists();
+            let mut file = OpenOptions::new().create(true).append(true).open(path)
+                .context("Failed to open combined log file")?;
+            if is_new {
+                let mut header = String::new();
+                header.push_str("=== PAVIDI RUN LOG ===\n");
+                header.push_str(&format!("Run: {}\n", self.id));
+                header.push_str(&format!("Task: {}\n", self.root_task));
+                header.push_str(&format!("Time: {}\n", stream.start.to_rfc3339()));
+                header.push_str("=== ENVIRONMENT SNAPSHOT ===\n");
+                for (k, v) in &stream.env {
+                    header.push_str(&format!("{} = {}\n", k, v));
                 }
+                header.push_str("============================\n\n");
+                file.write_all(self.settings.mask(&header).as_bytes())?;
             }
+            io::copy(&mut File::open(text_partial)?, &mut file).context("Failed to write combined log file")?;
+            let _ = fs::remove_file(text_partial);
         }
+
+        if let Some(json_partial) = &stream.json_path {
+            let json_path = path.with_extension("jsonl");
+            let mut file = OpenOptions::new().create(true).append(true).open(&json_path)
+                .context("Failed to open combined JSON log file")?;
+            io::copy(&mut File::open(json_partial)?, &mut file)?;
+            writeln!(file)?;
+            let _ = fs::remove_file(json_partial);
+            if stream.text_path.is_none() {
+                return Ok(json_path);
+            }
+        }
+
+        Ok(path.to_path_buf())
     }
+}
+
+struct StreamFiles {
+    text: Option<LineWriter<File>>,
+    json: Option<BufWriter<File>>,
+}
+
+/// A log written while the command runs. Output goes to a file under
+/// `<date>/running/` (hidden in `<date>/` for a combined run) as it arrives; `finish` appends the footer (exit code,
+/// duration) and moves the file into `<date>/<exit_code>/`.
+pub struct LogStream {
+    start: DateTime<Local>,
+    started: Instant,
+    env: BTreeMap<String, String>,
+    plain: bool,
+    secret_patterns: Vec<Regex>,
+    /// The run's combined log, when the command is appended to it
+    combined: Option<PathBuf>,
+    /// `log_strategy = "error-only"`: a successful command's log is removed
+    discard_success: bool,
+    text_path: Option<PathBuf>,
+    json_path: Option<PathBuf>,
+    files: Mutex<Option<StreamFiles>>,
+}
+
+impl LogStream {
+    /// Opens the partial log files and writes the header. Returns `None` when logging is off.
+    pub fn start(run: &RunLog, task_name: &str, cmd_str: &str, env_vars: &IndexMap<String, String>) -> Result<Option<LogStream>> {
+        let settings = &run.settings;
+        if !settings.enabled() {
+            return Ok(None);
+        }
+
+        let start = Local::now();
+        let time_str = start.format("%H%M%S").to_string();
+
+        // Short Hash
+        let mut hasher = Hasher::new();
+        hasher.update(task_name.as_bytes());
+        hasher.update(start.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true).as_bytes());
+        let hash_full = hasher.finalize().to_hex().to_string();
+        let short_hash = &hash_full[0..6];
 
-    fs::write(&log_path, file_content).context("Failed to write log file")?;
+        // The partial logs of a combined run are hidden next to the run log until appended to it
+        let combined = run.combined.clone();
+        let (running_dir, prefix) = match combined.as_deref().and_then(Path::parent) {
+            Some(date_dir) => (date_dir.to_path_buf(), "."),
+            None => (settings.root.join(start.format("%Y-%m-%d").to_string()).join(RUNNING_DIR), ""),
+        };
+        fs::create_dir_all(&running_dir).context("Failed to create log directory")?;
+        ensure_gitignore(&settings.root);
 
-    Ok(Some(log_path))
+        let stem = format!("{}{}_{}_{}", prefix, time_str, task_name.replace("/", "_"), short_hash);
+        let env = redact_env(env_vars);
+        let command = settings.mask(cmd_str);
+
+        let mut text_path = None;
+        let mut text = None;
+        if settings.format != LogFormat::Json {
+            let path = running_dir.join(format!("{}.log", stem));
+            let mut header = String::new();
+            if combined.is_some() {
+                header.push_str(&format!("=== [{}] {} ===\n", task_name, cmd_str));
+                header.push_str(&format!("Time: {}\n\n", start.to_rfc3339()));
+            } else {
+                header.push_str("=== PAVIDI EXECUTION LOG ===\n");
+                header.push_str(&format!("Task: {}\n", task_name));
+                header.push_str(&format!("Command: {}\n", cmd_str));
+                header.push_str(&format!("Time: {}\n", start.to_rfc3339()));
+                header.push_str("=== ENVIRONMENT SNAPSHOT ===\n");
+                for (k, v) in &env {
+                    header.push_str(&format!("{} = {}\n", k, v));
+                }
+                header.push_str("============================\n\n");
+            }
+            let mut file = LineWriter::new(File::create(&path).context("Failed to create log file")?);
+            file.write_all(settings.mask(&header).as_bytes())?;
+            text_path = Some(path);
+            text = Some(file);
+        }
+
+        let mut json_path = None;
+        let mut json = None;
+        if matches!(settings.format, LogFormat::Json | LogFormat::Both) {
+            let path = running_dir.join(format!("{}.json", stem));
+            let masked_env: BTreeMap<_, _> = env.iter().map(|(k, v)| (k, settings.mask(v))).collect();
+            // Fields are written in two halves around the streamed "output" string
+            let mut file = BufWriter::new(File::create(&path).context("Failed to create JSON log file")?);
+            write!(
+                file,
+                "{{\"task\":{},\"command\":{},\"start\":{},\"env\":{},\"output\":\"",
+                serde_json::to_string(task_name)?,
+                serde_json::to_string(&command)?,
+                serde_json::to_string(&start)?,
+                serde_json::to_string(&masked_env)?,
+            )?;
+            json_path = Some(path);
+            json = Some(file);
+        }
+
+        Ok(Some(LogStream {
+            start,
+            started: Instant::now(),
+            env,
+            plain: settings.plain,
+            secret_patterns: settings.secret_patterns.clone(),
+            combined,
+            discard_success: settings.strategy == LogStrategy::ErrorOnly,
+            text_path,
+            json_path,
+            files: Mutex::new(Some(StreamFiles { text, json })),
+        }))
+    }
+
+    /// Path of the in-progress text (or JSON) log.
+    pub fn path(&self) -> Option<&Path> {
+        self.text_path.as_deref().or(self.json_path.as_deref())
+    }
+
+    /// Where `finish` will leave the log for `exit_code`, or `None` if it will be removed.
+    pub fn final_path(&self, exit_code: i32) -> Option<PathBuf> {
+        if self.discard_success && exit_code == 0 {
+            return None;
+        }
+        if let Some(path) = &self.combined {
+            return Some(path.clone());
+        }
+        let partial = self.text_path.as_ref().or(self.json_path.as_ref())?;
+        let date_dir = partial.parent()?.parent()?;
+        Some(date_dir.join(exit_code.to_string()).join(partial.file_name()?))
+    }
+
+    /// Appends one line of command output. Write errors are ignored so that
+    /// logging problems never fail the task.
+    pub fn write_line(&self, line: &str) {
+        let mut line = if self.plain { strip_ansi(line) } else { line.to_string() };
+        for re in &self.secret_patterns {
+            line = re.replace_all(&line, "[REDACTED]").to_string();
+        }
+        line.push('\n');
+
+        let mut guard = self.files.lock().unwrap();
+        if let Some(files) = guard.as_mut() {
+            if let Some(text) = files.text.as_mut() {
+                let _ = text.write_all(line.as_bytes());
+            }
+            if let Some(json) = files.json.as_mut() {
+                let _ = json.write_all(json_escape(&line).as_bytes());
+            }
+        }
+    }
+
+    /// Writes the footer and moves the log to its final location, under `exit_code`
+    /// even when `allowed_exit_codes` made that a success (`failed` is false).
+    /// Returns the final path, or `None` if the strategy discards this log.
+    pub fn finish(&self, run: &RunLog, exit_code: i32, failed: bool) -> Result<Option<PathBuf>> {
+        let files = match self.files.lock().unwrap().take() {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let duration_ms = self.started.elapsed().as_millis();
+        let end = Local::now();
+
+        if let Some(mut text) = files.text {
+            if self.combined.is_some() {
+                write!(text, "\n--- Exit Code: {} | Duration: {} ms ---\n\n", exit_code, duration_ms)?;
+            } else {
+                text.write_all(b"\n============================\n")?;
+                writeln!(text, "Exit Code: {}", exit_code)?;
+                writeln!(text, "Duration: {} ms", duration_ms)?;
+                writeln!(text, "End Time: {}", end.to_rfc3339())?;
+                text.write_all(b"============================\n")?;
+            }
+            text.flush()?;
+        }
+        if let Some(mut json) = files.json {
+            write!(
+                json,
+                "\",\"end\":{},\"duration_ms\":{},\"exit_code\":{}}}",
+                serde_json::to_string(&end)?, duration_ms, exit_code
+            )?;
+            json.flush()?;
+        }
+
+        if run.settings.strategy == LogStrategy::ErrorOnly && !failed {
+            self.discard();
+            return Ok(None);
+        }
+
+        if let Some(path) = &run.combined {
+            return run.append(path, self).map(Some);
+        }
+
+        let final_dir = run.settings.root
+            .join(self.start.format("%Y-%m-%d").to_string())
+            .join(exit_code.to_string());
+        fs::create_dir_all(&final_dir).context("Failed to create log directory")?;
+
+        let mut final_path = None;
+        for partial in [&self.json_path, &self.text_path].into_iter().flatten() {
+            let target = final_dir.join(partial.file_name().unwrap());
+            fs::rename(partial, &target).context("Failed to move log file")?;
+            final_path = Some(target);
+        }
+        Ok(final_path)
+    }
+
+    fn discard(&self) {
+        for partial in [&self.text_path, &self.json_path].into_iter().flatten() {
+            let _ = fs::remove_file(partial);
+        }
+    }
+}
+
+/// A task's `output_file`: every line its commands print, as they print it.
+/// Shared by the task's commands (and `finally`), so it is opened once per run.
+pub struct OutputFile {
+    plain: bool,
+    file: Mutex<LineWriter<File>>,
+}
+
+impl OutputFile {
+    /// Creates `path` and its parent directories; `append` keeps what a previous run wrote.
+    pub fn open(path: &Path, append: bool, plain: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path)
+            .with_context(|| format!("Failed to open output file {}", path.display()))?;
+        Ok(OutputFile { plain, file: Mutex::new(LineWriter::new(file)) })
+    }
+
+    /// Appends one line of command output, without ANSI codes when `log_plain` is on.
+    /// Write errors are ignored, as for logs.
+    pub fn write_line(&self, line: &str) {
+        let mut line = if self.plain { strip_ansi(line) } else { line.to_string() };
+        line.push('\n');
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    fn settings(root: &Path, strategy: LogStrategy, format: LogFormat, patterns: &[&str]) -> LogSettings {
+        LogSettings {
+            strategy,
+            format,
+            mode: LogMode::PerCommand,
+            plain: true,
+            secret_patterns: patterns.iter().map(|p| Regex::new(p).unwrap()).collect(),
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn sample_env() -> IndexMap<String, String> {
+        let mut env = IndexMap::new();
+        env.insert("API_TOKEN".to_string(), "abc123".to_string());
+        env.insert("MODE".to_string(), "release id=4242".to_string());
+        env
+    }
+
+    #[test]
+    fn test_log_root_resolution() {
+        let base = Path::new("/work/app");
+        assert_eq!(resolve_log_root(None, None, base), Path::new("/work/app/.p/logs"));
+        assert_eq!(resolve_log_root(None, Some("build/logs"), base), Path::new("/work/app/build/logs"));
+        assert_eq!(resolve_log_root(None, Some("/var/log/p"), base), Path::new("/var/log/p"));
+        assert_eq!(resolve_log_root(Some("/ci/logs"), Some("build/logs"), base), Path::new("/ci/logs"));
+        assert_eq!(resolve_log_root(Some(""), None, base), Path::new("/work/app/.p/logs"));
+    }
+
+    #[test]
+    fn test_stream_writes_while_running() {
+        let root = scratch("stream");
+        let run = RunLog::with_path("r".to_string(), "build", settings(&root, LogStrategy::Always, LogFormat::Text, &[]), None);
+        let stream = LogStream::start(&run, "build", "cargo build", &sample_env()).unwrap().unwrap();
+
+        stream.write_line("\x1b[32mCompiling\x1b[0m foo");
+        let partial = stream.path().unwrap().to_path_buf();
+        assert!(partial.to_string_lossy().contains(RUNNING_DIR));
+        // Output is on disk before the command finishes
+        assert!(fs::read_to_string(&partial).unwrap().contains("Compiling foo\n"));
+
+        let expected = stream.final_path(2).unwrap();
+        let path = stream.finish(&run, 2, true).unwrap().unwrap();
+        assert_eq!(path, expected);
+        assert!(!partial.exists());
+        assert_eq!(path.parent().unwrap().file_name().unwrap(), "2");
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("Task: build"));
+        assert!(text.contains("API_TOKEN = [REDACTED]"));
+        assert!(text.contains("Exit Code: 2"));
+    }
+
+    #[test]
+    fn test_json_log_round_trip() {
+        let root = scratch("json");
+        let run = RunLog::with_path("r".to_string(), "build", settings(&root, LogStrategy::Always, LogFormat::Json, &[]), None);
+        let stream = LogStream::start(&run, "build", "cargo build", &sample_env()).unwrap().unwrap();
+        stream.write_line("line \"one\"");
+        stream.write_line("tab\there");
+        let path = stream.finish(&run, 1, true).unwrap().unwrap();
+        assert_eq!(path.extension().unwrap(), "json");
+
+        let json = fs::read_to_string(&path).unwrap();
+        let parsed: LogRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.task, "build");
+        assert_eq!(parsed.command, "cargo build");
+        assert_eq!(parsed.exit_code, 1);
+        assert_eq!(parsed.output, "line \"one\"\ntab\there\n");
+        assert_eq!(parsed.env["API_TOKEN"], "[REDACTED]");
+        assert!(parsed.end >= parsed.start);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        for field in ["task", "command", "start", "end", "duration_ms", "exit_code", "env", "output"] {
+            assert!(value.get(field).is_some(), "missing field {}", field);
+        }
+    }
+
+    #[test]
+    fn test_secret_patterns_in_both_formats() {
+        let root = scratch("secrets");
+        let run = RunLog::with_path("r".to_string(), "build", settings(&root, LogStrategy::Always, LogFormat::Both, &[r"\d{4}"]), None);
+        let stream = LogStream::start(&run, "build", "cargo build --token=4242", &sample_env()).unwrap().unwrap();
+        stream.write_line("secret 4242");
+        let text_path = stream.finish(&run, 0, false).unwrap().unwrap();
+
+        let parsed: LogRecord = serde_json::from_str(&fs::read_to_string(text_path.with_extension("json")).unwrap()).unwrap();
+        assert_eq!(parsed.command, "cargo build --token=[REDACTED]");
+        assert_eq!(parsed.env["MODE"], "release id=[REDACTED]");
+        assert!(!parsed.output.contains("4242"));
+
+        let text = fs::read_to_string(&text_path).unwrap();
+        assert!(!text.contains("4242"));
+    }
+
+    #[test]
+    fn test_error_only_discards_success() {
+        let root = scratch("error_only");
+        let run = RunLog::with_path("r".to_string(), "t", settings(&root, LogStrategy::ErrorOnly, LogFormat::Both, &[]), None);
+        let stream = LogStream::start(&run, "t", "true", &IndexMap::new()).unwrap().unwrap();
+        let partial = stream.path().unwrap().to_path_buf();
+        assert!(stream.finish(&run, 0, false).unwrap().is_none());
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn test_combined_log_from_parallel_writers() {
+        let root = scratch("combined");
+        let path = root.join("abcd1234_ci.log");
+        let run = RunLog::with_path(
+            "abcd1234".to_string(), "ci",
+            settings(&root, LogStrategy::Always, LogFormat::Both, &[]),
+            Some(path.clone()),
+        );
+
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let run = &run;
+                scope.spawn(move || {
+                    let task = format!("dep{}", i);
+                    let stream = LogStream::start(run, &task, "cargo build", &sample_env()).unwrap().unwrap();
+                    stream.write_line(&format!("output of {}", task));
+                    stream.finish(run, i, i != 0).unwrap();
+                });
+            }
+        });
+
+        run.finish(false).unwrap();
+
+        // Nothing is left besides the run logs, and no running/ directory
+        let mut files: Vec<_> = fs::read_dir(&root).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        files.sort();
+        assert_eq!(files, ["abcd1234_ci.jsonl", "abcd1234_ci.log"]);
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.matches("=== PAVIDI RUN LOG ===").count(), 1);
+        assert!(text.ends_with("============================\n") && text.contains("\nExit Code: 1\n"), "{}", text);
+        assert!(text.contains("Run: abcd1234"));
+        for i in 0..4 {
+            assert!(text.contains(&format!("=== [dep{}] cargo build", i)));
+            assert!(text.contains(&format!("output of dep{}\n", i)));
+            assert!(text.contains(&format!("--- Exit Code: {} |", i)));
+        }
+
+        let lines = fs::read_to_string(path.with_extension("jsonl")).unwrap();
+        let records: Vec<LogRecord> = lines.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records.len(), 4);
+    }
 }
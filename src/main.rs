@@ -1,27 +1,113 @@
-mod cli;
-mod config;
-mod runner;
-mod handlers;
-mod utils;
-mod logger;
-
-use anyhow::Result;
-use clap::Parser;
-use cli::Cli;
-use handlers::{task, env, list, info};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use pavidi::{config, process, ui, utils};
+use pavidi::cli::{Cli, Commands};
+use pavidi::diagnostic::Diagnostic;
+use pavidi::runner::{capability, progress, wait};
+use pavidi::handlers::{artifacts, task, env, list, info, logs, graph, completions, doctor, task_edit, fmt, status, lock, remotes, upgrade};
 
 fn main() -> Result<()> {
-    env_logger::init();
-    let cli = Cli::parse();
+    ui::init();
+
+    // Called by completion scripts on every <TAB>; kept out of clap so it is never offered as a completion.
+    // Runs before the logger is set up, so nothing but task names reaches the shell
+    let raw: Vec<String> = std::env::args().skip(1).collect();
+    if raw.first().map(String::as_str) == Some(completions::COMPLETE_TASKS) {
+        let shell = raw.iter().position(|a| a == "--shell").and_then(|i| raw.get(i + 1));
+        return completions::handle_complete_tasks(shell.map(String::as_str));
+    }
+
+    // Kept alongside `cli`: `p new-task` pairs --os and --cmd by their position
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    ui::init_color(cli.color);
+    if cli.no_pager {
+        ui::disable_pager();
+    }
+    ui::init_logging(cli.verbose, cli.quiet, cli.json || cli.export || cli.dotenv);
+    utils::start_clock();
+
+    if cli.allow_outside {
+        capability::allow_outside();
+    }
+
+    let config_arg = cli.config.clone().or_else(|| std::env::var_os("P_CONFIG").map(PathBuf::from));
+    let config_path = config::resolve_config_path(&std::env::current_dir()?, cli.directory.as_deref(), config_arg.as_deref());
+    if (cli.directory.is_some() || config_arg.is_some())
+        && let Some(dir) = config_path.parent() {
+        // Commands, globs and .p/ paths are relative to the project, as with `make -C`
+        std::env::set_current_dir(dir).with_context(|| format!("Cannot change to directory {}", dir.display()))?;
+    }
+
+    let json = cli.json;
+    let result = dispatch(cli, &matches, &config_path);
+    // Editors calling `--json` get config errors as an object on stdout
+    if json
+        && let Err(e) = &result
+        && let Some(diagnostic) = e.downcast_ref::<Diagnostic>() {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "error": diagnostic }))?);
+        std::process::exit(1);
+    }
+    // A wait_for gave up: exit like timeout(1) does
+    if let Err(e) = &result
+        && wait::timed_out()
+        && process::abort_signal().is_none() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(124);
+    }
+    // Stopped by SIGINT/SIGTERM: exit like a shell would, 128 + the signal
+    if let Some(sig) = process::abort_signal() {
+        if let Err(e) = &result {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(128 + sig);
+    }
+    result
+}
+
+fn dispatch(cli: Cli, matches: &ArgMatches, config_path: &Path) -> Result<()> {
+    match &cli.command {
+        Some(Commands::Logs(args)) => return logs::handle_logs(config_path, args),
+        Some(Commands::Graph(args)) => return graph::handle_graph(config_path, args),
+        Some(Commands::Doctor { fix }) => return doctor::handle_doctor(config_path, *fix),
+        Some(Commands::NewTask(args)) => {
+            let sub = matches.subcommand_matches("new-task").expect("parsed as new-task");
+            return task_edit::handle_new_task(config_path, args, sub);
+        },
+        Some(Commands::RemoveTask { task }) => return task_edit::handle_remove_task(config_path, task),
+        Some(Commands::Fmt { check, only }) => return fmt::handle_fmt(config_path, *check, only.as_deref()),
+        Some(Commands::Status { task, json }) => return status::handle_status(config_path, task.as_deref(), *json),
+        Some(Commands::Lock) => return lock::handle_lock(config_path),
+        Some(Commands::Artifacts { task, out, check }) => return artifacts::handle_artifacts(config_path, task.as_deref(), out.as_deref(), *check),
+        Some(Commands::FetchRemotes { refresh }) => return remotes::handle_fetch_remotes(config_path, *refresh),
+        Some(Commands::Upgrade { check, version }) => return upgrade::handle_upgrade(config_path, *check, version.as_deref()),
+        Some(Commands::Completions { shell }) => return completions::handle_completions(*shell),
+        None => {},
+    }
 
     if cli.list {
-        list::handle_list()
+        list::handle_list(config_path, cli.json, cli.task.as_deref().or(cli.group.as_deref()), cli.status)
     } else if cli.info {
-        info::handle_info()
+        info::handle_info(config_path, cli.json)
     } else if cli.env {
-        env::handle_env(&cli)
+        env::handle_env(&cli, config_path)
+    } else if cli.list_cmds {
+        task::handle_list_cmds(config_path, cli.task.as_deref().unwrap_or("default"), &cli.args)
     } else {
-        let task_name = cli.task.unwrap_or_else(|| "default".to_string());
-        task::handle_runner_entry(task_name, cli.args, cli.dry_run, cli.trace)
+        if cli.locked {
+            lock::verify_lock(config_path)?;
+        }
+        if let Some(fd) = cli.progress_fd {
+            progress::install_fd(fd)?;
+        }
+        if let Some(path) = &cli.progress_pipe {
+            progress::install_pipe(path)?;
+        }
+        let task_name = match &cli.group {
+            Some(group) => format!("{}:", group.trim_end_matches(':')),
+            None => cli.task.unwrap_or_else(|| "default".to_string()),
+        };
+        task::handle_runner_entry(config_path, task_name, cli.args, cli.dry_run, cli.json, cli.trace, &cli.report, cli.trace_file.as_deref(), cli.resume, cli.diff, cli.wait, cli.timestamps)
     }
 }
@@ -0,0 +1,203 @@
+use anyhow::{Result, bail};
+use std::path::Path;
+use std::time::Duration;
+use crate::config::{CapabilityConfig, NotifyConfig, NotifyOn};
+use crate::ui::{glyph, Glyph};
+
+/// Result of a root task run, as reported to notification targets.
+pub struct Outcome<'a> {
+    pub task: &'a str,
+    pub success: bool,
+    pub duration: Duration,
+    pub project: Option<&'a str>,
+    pub log: Option<&'a Path>,
+}
+
+/// Parses durations like "500ms", "30s", "2m", "1h". A bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let value: u64 = match num.parse() {
+        Ok(v) => v,
+        Err(_) => bail!("Invalid duration '{}'", s),
+    };
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(value),
+        "" | "s" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value * 60),
+        "h" => Duration::from_secs(value * 3600),
+        other => bail!("Invalid duration unit '{}' in '{}'", other, s),
+    };
+    Ok(duration)
+}
+
+fn should_notify(config: &NotifyConfig, outcome: &Outcome) -> bool {
+    let wanted = if outcome.success { NotifyOn::Success } else { NotifyOn::Failure };
+    let on = config.on.as_deref().unwrap_or(&[NotifyOn::Failure]);
+    if !on.contains(&wanted) {
+        return false;
+    }
+
+    match config.min_duration.as_deref().map(parse_duration) {
+        Some(Ok(min)) => outcome.duration >= min,
+        Some(Err(e)) => {
+            log::warn!("{} [notify] min_duration ignored: {}", glyph(Glyph::Warn), e);
+            true
+        },
+        None => true,
+    }
+}
+
+fn status(outcome: &Outcome) -> &'static str {
+    if outcome.success { "success" } else { "failure" }
+}
+
+fn payload(outcome: &Outcome) -> serde_json::Value {
+    serde_json::json!({
+        "task": outcome.task,
+        "status": status(outcome),
+        "duration_ms": outcome.duration.as_millis() as u64,
+        "project": outcome.project,
+        "log": outcome.log.map(|p| p.display().to_string()),
+    })
+}
+
+/// Sends the configured notifications. Failures are reported as warnings only.
+/// The webhook is subject to `allow_net` in `caps`.
+pub fn notify_completion(config: &NotifyConfig, caps: Option<&CapabilityConfig>, outcome: &Outcome) {
+    if !should_notify(config, outcome) {
+        return;
+    }
+
+    if config.desktop.unwrap_or(false)
+        && let Err(e) = send_desktop(outcome) {
+        log::warn!("{} Desktop notification failed: {}", glyph(Glyph::Warn), e);
+    }
+
+    if let Some(url) = &config.webhook
+        && let Err(e) = send_webhook(caps, url, outcome) {
+        log::warn!("{} Webhook notification failed: {}", glyph(Glyph::Warn), e);
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+fn send_desktop(outcome: &Outcome) -> Result<()> {
+    let icon = glyph(if outcome.success { Glyph::Ok } else { Glyph::Fail });
+    notify_rust::Notification::new()
+        .summary(&format!("{} {}", icon, outcome.task))
+        .body(&format!("{} in {:.1?}", status(outcome), outcome.duration))
+        .show()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "desktop-notify"))]
+fn send_desktop(_outcome: &Outcome) -> Result<()> {
+    bail!("this build of p was compiled without the 'desktop-notify' feature")
+}
+
+#[cfg(feature = "net")]
+fn send_webhook(caps: Option<&CapabilityConfig>, url: &str, outcome: &Outcome) -> Result<()> {
+    crate::http::check_url(caps, "webhook", url)?;
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(10)))
+        .build()
+        .into();
+    agent.post(url)
+        .header("Content-Type", "application/json")
+        .send(payload(outcome).to_string())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "net"))]
+fn send_webhook(_caps: Option<&CapabilityConfig>, _url: &str, outcome: &Outcome) -> Result<()> {
+    let _ = payload(outcome);
+    bail!("this build of p was compiled without the 'net' feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(success: bool, secs: u64) -> Outcome<'static> {
+        Outcome { task: "build", success, duration: Duration::from_secs(secs), project: Some("demo"), log: None }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5d").is_err());
+    }
+
+    #[test]
+    fn test_should_notify_filters() {
+        let config = NotifyConfig { min_duration: Some("30s".to_string()), ..Default::default() };
+        // Defaults to failures only
+        assert!(should_notify(&config, &outcome(false, 60)));
+        assert!(!should_notify(&config, &outcome(true, 60)));
+        // Short runs are not worth a notification
+        assert!(!should_notify(&config, &outcome(false, 5)));
+
+        let both = NotifyConfig { on: Some(vec![NotifyOn::Success, NotifyOn::Failure]), ..Default::default() };
+        assert!(should_notify(&both, &outcome(true, 0)));
+    }
+
+    #[test]
+    fn test_payload_fields() {
+        let log = Path::new(".p/logs/x.log");
+        let o = Outcome { log: Some(log), ..outcome(false, 90) };
+        let p = payload(&o);
+        assert_eq!(p["task"], "build");
+        assert_eq!(p["status"], "failure");
+        assert_eq!(p["duration_ms"], 90_000);
+        assert_eq!(p["project"], "demo");
+        assert_eq!(p["log"], ".p/logs/x.log");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_webhook_posts_payload() {
+        use crate::config::NetPolicy;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = v.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8(body).unwrap()
+        });
+
+        // allow_net is checked before anything is sent
+        let offline = CapabilityConfig { allow_net: Some(NetPolicy::Flag(false)), ..Default::default() };
+        assert!(send_webhook(Some(&offline), &url, &outcome(true, 42)).unwrap_err().to_string().contains("allow_net = false"));
+        let hosts = CapabilityConfig { allow_net: Some(NetPolicy::Hosts(vec!["hooks.slack.com".to_string()])), ..Default::default() };
+        assert!(send_webhook(Some(&hosts), &url, &outcome(true, 42)).unwrap_err().to_string().contains("'127.0.0.1'"));
+        send_webhook(None, &url, &outcome(true, 42)).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["task"], "build");
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["duration_ms"], 42_000);
+    }
+}
@@ -0,0 +1,463 @@
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, Once};
+use std::time::Duration;
+use crate::utils::CaptureMode;
+
+/// How long a stopped command gets to exit after SIGTERM before it is killed, by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+static GRACE_PERIOD_MS: AtomicU64 = AtomicU64::new(DEFAULT_GRACE_PERIOD.as_millis() as u64);
+
+/// Commands started by this process, with whether each has its own process
+/// group: process group ids or process ids on unix, process ids on Windows.
+static RUNNING: LazyLock<Mutex<HashMap<u32, bool>>> = LazyLock::new(Default::default);
+
+/// The signal that is stopping the run, 0 while there is none.
+static ABORT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Set once the commands running at the signal have been stopped.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Sets `[project] grace_period` for the commands a SIGINT/SIGTERM stops. Process-wide,
+/// so only the CLI sets it; timeouts use the grace period of their run.
+pub fn set_grace_period(grace: Duration) {
+    GRACE_PERIOD_MS.store(grace.as_millis() as u64, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+fn grace_period() -> Duration {
+    Duration::from_millis(GRACE_PERIOD_MS.load(Ordering::Relaxed))
+}
+
+/// The signal that is stopping the run (SIGINT or SIGTERM), if any.
+pub fn abort_signal() -> Option<i32> {
+    Some(ABORT_SIGNAL.load(Ordering::SeqCst)).filter(|&sig| sig != 0)
+}
+
+/// `SIGTERM` for 15, for messages.
+pub fn signal_name(sig: i32) -> String {
+    match sig {
+        2 => "SIGINT".to_string(),
+        15 => "SIGTERM".to_string(),
+        _ => format!("signal {}", sig),
+    }
+}
+
+/// Whether a command gets its own process group, so a timeout or Ctrl+C stops
+/// everything it started. Commands that may read the terminal stay in the
+/// foreground group: a background group would be stopped by the terminal on
+/// its first read. Those are stopped by walking their process tree instead.
+pub fn should_isolate(mode: CaptureMode) -> bool {
+    mode == CaptureMode::Buffer || !std::io::stdin().is_terminal()
+}
+
+#[cfg(unix)]
+pub fn isolate(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+/// Windows has no process groups to signal; `taskkill /T` stops the tree.
+#[cfg(not(unix))]
+pub fn isolate(_command: &mut Command) {}
+
+/// A task's `nice` and `max_memory`, applied to the shell that runs each command
+/// and inherited by everything it starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub nice: Option<i32>,
+    /// Bytes of address space (RLIMIT_AS)
+    pub max_memory: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.max_memory.is_none()
+    }
+
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // SAFETY: only async-signal-safe calls (setpriority, setrlimit) between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(nice) = limits.nice
+                    && libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(bytes) = limits.max_memory {
+                    let limit = libc::rlimit { rlim_cur: bytes as libc::rlim_t, rlim_max: bytes as libc::rlim_t };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Windows has no nice levels: any positive `nice` becomes the below-normal
+    /// priority class. Memory caps are not supported.
+    #[cfg(windows)]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::windows::process::CommandExt;
+        const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+        if self.nice.is_some_and(|n| n > 0) {
+            command.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+        }
+        if self.max_memory.is_some() {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| log::warn!("max_memory is not supported on Windows; commands run without a memory cap"));
+        }
+    }
+
+    /// Why a command that failed under `max_memory` most likely ran out of it:
+    /// it was killed by a signal, or its output reports a failed allocation.
+    pub fn memory_failure(&self, status: &std::process::ExitStatus, output: &str) -> Option<String> {
+        let bytes = self.max_memory?;
+        if status.success() {
+            return None;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(sig) = status.signal() {
+                return Some(format!("Command was killed by signal {} after reaching max_memory ({})", sig, format_memory(bytes)));
+            }
+        }
+        let lower = output.to_lowercase();
+        ["out of memory", "cannot allocate memory", "memory allocation", "memoryerror", "bad_alloc"]
+            .iter()
+            .any(|needle| lower.contains(needle))
+            .then(|| format!("Command ran out of memory under max_memory ({})", format_memory(bytes)))
+    }
+}
+
+/// Parses sizes like "512M", "2G" or "1.5GiB" (powers of 1024). A bare number is bytes.
+pub fn parse_memory(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let value: f64 = match num.parse() {
+        Ok(v) => v,
+        Err(_) => bail!("Invalid memory size '{}'", s),
+    };
+    let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B').to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("Invalid memory unit '{}' in '{}'", unit.trim(), s),
+    };
+    let bytes = (value * (1u64 << shift) as f64) as u64;
+    if bytes == 0 {
+        bail!("Memory size '{}' must be greater than zero", s);
+    }
+    Ok(bytes)
+}
+
+fn format_memory(bytes: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if bytes >= 1 << shift && bytes.is_multiple_of(1 << shift) {
+            return format!("{}{}", bytes >> shift, unit);
+        }
+    }
+    format!("{} bytes", bytes)
+}
+
+/// Keeps a running command on the list a signal stops, until dropped.
+pub struct Tracked(u32);
+
+impl Tracked {
+    pub fn new(child: &Child, isolated: bool) -> Self {
+        RUNNING.lock().unwrap().insert(child.id(), isolated);
+        Tracked(child.id())
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Stops `child` and everything it started: SIGTERM, then SIGKILL after
+/// `grace`. Reaps `child`.
+#[cfg(unix)]
+pub fn terminate(child: &mut Child, isolated: bool, grace: Duration) {
+    use wait_timeout::ChildExt;
+    let pid = child.id();
+    if isolated {
+        signal_group(pid, libc::SIGTERM);
+        if !matches!(child.wait_timeout(grace), Ok(Some(_))) {
+            signal_group(pid, libc::SIGKILL);
+        }
+        // The command itself may be gone while its children still run
+        signal_group(pid, libc::SIGKILL);
+    } else {
+        let mut tree = descendants(pid);
+        tree.push(pid);
+        for &p in &tree {
+            signal(p, libc::SIGTERM);
+        }
+        if !matches!(child.wait_timeout(grace), Ok(Some(_))) {
+            tree.extend(descendants(pid));
+        }
+        for &p in &tree {
+            signal(p, libc::SIGKILL);
+        }
+    }
+    let _ = child.wait();
+}
+
+#[cfg(windows)]
+pub fn terminate(child: &mut Child, _isolated: bool, _grace: Duration) {
+    kill_tree(child.id());
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, sig: i32) -> bool {
+    // SAFETY: kill(2) takes plain integers and has no memory-safety preconditions
+    unsafe { libc::kill(pid as libc::pid_t, sig) == 0 }
+}
+
+/// Signals the process group `pgid` (a negative pid to kill(2)).
+#[cfg(unix)]
+fn signal_group(pgid: u32, sig: i32) -> bool {
+    signal(0u32.wrapping_sub(pgid), sig)
+}
+
+/// The processes below `pid`, found with `pgrep -P` (procps on Linux, base system on macOS).
+#[cfg(unix)]
+fn descendants(pid: u32) -> Vec<u32> {
+    let mut found = vec![];
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        let Ok(out) = Command::new("pgrep").args(["-P", &parent.to_string()]).output() else { break };
+        for child in String::from_utf8_lossy(&out.stdout).split_whitespace().filter_map(|p| p.parse().ok()) {
+            found.push(child);
+            queue.push(child);
+        }
+    }
+    found
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) {
+    let _ = Command::new("taskkill").args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null()).status();
+}
+
+/// On SIGINT (Ctrl+C) or SIGTERM (a cancelled CI job), stops the running commands:
+/// they get the signal, then SIGKILL after the grace period. The run then fails,
+/// `finally` commands and `on_abort` run, and p exits with 128 + the signal.
+/// A second signal exits at once. Commands sharing the terminal's process group
+/// receive the terminal's SIGINT directly.
+#[cfg(unix)]
+pub fn install_interrupt_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let mut fds = [0; 2];
+        // SAFETY: pipe(2) fills the two descriptors of a valid array
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+        for sig in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: the handler only calls write(2), which is async-signal-safe
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(sig, &action, std::ptr::null_mut());
+            }
+        }
+        let read_fd = fds[0];
+        std::thread::spawn(move || loop {
+            let mut byte = 0u8;
+            // SAFETY: reads one byte into a local
+            let n = unsafe { libc::read(read_fd, (&mut byte as *mut u8).cast(), 1) };
+            if n == 1 {
+                abort(byte as i32);
+            } else if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            } else {
+                break;
+            }
+        });
+    });
+}
+
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(unix)]
+extern "C" fn on_signal(sig: libc::c_int) {
+    let byte = sig as u8;
+    // SAFETY: write(2) on a pipe is async-signal-safe; a full pipe drops the byte
+    unsafe { libc::write(SIGNAL_PIPE.load(Ordering::Relaxed), (&byte as *const u8).cast(), 1) };
+}
+
+/// Ctrl+C, and closing the console window (reported as Ctrl+C as well).
+#[cfg(windows)]
+pub fn install_interrupt_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let _ = ctrlc::set_handler(|| abort(2));
+    });
+}
+
+fn abort(sig: i32) {
+    if ABORT_SIGNAL.compare_exchange(0, sig, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        std::process::exit(128 + sig);
+    }
+    eprintln!("\n{} Received {}, stopping running commands (again to exit now)", crate::ui::glyph(crate::ui::Glyph::Warn), signal_name(sig));
+    let running: Vec<(u32, bool)> = RUNNING.lock().unwrap().iter().map(|(&id, &isolated)| (id, isolated)).collect();
+    stop_all(&running, sig);
+    STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// Called once a command has exited. After a signal, waits until the handler has
+/// stopped the other commands, so they are not reported while still running.
+pub fn settle_interrupt() {
+    if abort_signal().is_some() {
+        while !STOPPED.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn stop_all(running: &[(u32, bool)], sig: i32) {
+    // Commands in the terminal's group already got the terminal's SIGINT
+    let forward = |id: u32, isolated: bool, sig: i32| match (isolated, sig) {
+        (true, _) => { signal_group(id, sig); },
+        (false, libc::SIGINT) => {},
+        (false, _) => {
+            for pid in descendants(id).into_iter().chain([id]) {
+                signal(pid, sig);
+            }
+        },
+    };
+    for &(id, isolated) in running {
+        forward(id, isolated, sig);
+    }
+    let alive = |&(id, isolated): &(u32, bool)| if isolated { signal_group(id, 0) } else { signal(id, 0) };
+    let deadline = std::time::Instant::now() + grace_period();
+    while std::time::Instant::now() < deadline && running.iter().any(alive) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    for &(id, isolated) in running.iter().filter(|r| alive(r)) {
+        forward(id, isolated, libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn stop_all(running: &[(u32, bool)], _sig: i32) {
+    for &(pid, _) in running {
+        kill_tree(pid);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+    use std::time::Instant;
+    use crate::test_support::scratch;
+
+    /// Whether `pid` still runs; a zombie waiting to be reaped counts as gone.
+    fn alive(pid: &str) -> bool {
+        let out = Command::new("ps").args(["-o", "stat=", "-p", pid]).output().unwrap();
+        let stat = String::from_utf8_lossy(&out.stdout);
+        !stat.trim().is_empty() && !stat.trim().starts_with('Z')
+    }
+
+    fn wait_gone(pid: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if !alive(pid) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_terminate_stops_grandchildren() {
+        let dir = scratch("process");
+
+        for isolated in [true, false] {
+            let pid_file = dir.join(format!("pid_{}", isolated));
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(format!("sleep 30 & echo $! > '{}'; wait", pid_file.display()));
+            if isolated {
+                isolate(&mut command);
+            }
+            let mut child = command.spawn().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !fs::read_to_string(&pid_file).is_ok_and(|p| !p.trim().is_empty()) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(20));
+            }
+            let grandchild = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+            assert!(alive(&grandchild));
+
+            terminate(&mut child, isolated, DEFAULT_GRACE_PERIOD);
+            assert!(wait_gone(&grandchild), "sleep {} survived (isolated = {})", grandchild, isolated);
+        }
+    }
+
+    #[test]
+    fn test_timeout_stops_background_children() {
+        let dir = scratch("timeout");
+        let pid_file = dir.join("pid");
+        let cmd = format!("sleep 30 & echo $! > '{}'; wait", pid_file.display());
+
+        let started = Instant::now();
+        let result = crate::utils::run_shell_command(&cmd, &Default::default(), &Default::default(), CaptureMode::Buffer, "t", "sh", Some(Duration::from_millis(500)), None, None, None, None, None);
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let grandchild = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+        assert!(wait_gone(&grandchild), "sleep {} survived the timeout", grandchild);
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_memory("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_memory("1.5k").unwrap(), 1536);
+        assert_eq!(parse_memory("4096").unwrap(), 4096);
+        assert!(parse_memory("2X").is_err());
+        assert!(parse_memory("0M").is_err());
+        assert_eq!(format_memory(2 << 30), "2G");
+    }
+
+    #[test]
+    fn test_resource_limits_apply_to_commands() {
+        let run = |cmd: &str, limits: ResourceLimits| {
+            crate::utils::run_shell_command(cmd, &Default::default(), &Default::default(), CaptureMode::Buffer, "t", "sh", None, None, None, Some(&limits), None, None)
+        };
+        let base: i32 = run("nice", ResourceLimits::default()).unwrap().1.trim().parse().unwrap();
+        let (code, niced) = run("nice", ResourceLimits { nice: Some(10), max_memory: None }).unwrap();
+        assert_eq!((code, niced.trim().parse::<i32>().unwrap()), (0, (base + 10).min(19)));
+
+        let limits = ResourceLimits { nice: None, max_memory: Some(50 << 20) };
+        assert_eq!(run("echo fits", limits).unwrap(), (0, "fits".to_string()));
+        let err = run("x=$(head -c 200000000 /dev/zero | tr '\\0' a); echo survived", limits).unwrap_err();
+        assert!(err.to_string().contains("max_memory (50M)"), "{}", err);
+    }
+}
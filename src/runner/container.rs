@@ -0,0 +1,123 @@
+use anyhow::{Result, bail};
+use indexmap::IndexMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::logger::is_sensitive_key;
+
+/// Where the project directory is mounted inside the container.
+pub const WORKDIR: &str = "/work";
+
+/// Variables that describe the host and would break or mislead the container.
+pub const HOST_ONLY_VARS: &[&str] = &["PATH", "HOME", "USER", "SHELL", "PWD", "OLDPWD", "TMPDIR", "HOSTNAME", "LANG", "TERM"];
+
+/// Runs a task's commands in `image` (the task's `image` field).
+#[derive(Debug, Clone)]
+pub struct ContainerSpec {
+    /// `docker`, or `container_runtime` (e.g. `podman`)
+    pub runtime: String,
+    pub image: String,
+    /// `container_args`, inserted before the image
+    pub extra_args: Vec<String>,
+    /// Mounted at `/work`
+    pub root: PathBuf,
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+impl ContainerSpec {
+    /// A container name unique to this process, so a timed-out container can be removed.
+    pub fn next_name(run_id: &str) -> String {
+        format!("p-{}-{}-{}", run_id, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The runtime invocation for `cmd`. Sensitive variables are passed by name only
+    /// (`-e KEY`), so their values come from the runtime's environment, not its argv.
+    pub fn args(&self, name: &str, env: &IndexMap<String, String>, cmd: &str) -> Vec<String> {
+        let mut args = vec![
+            self.runtime.clone(), "run".to_string(), "--rm".to_string(),
+            "--name".to_string(), name.to_string(),
+            "-v".to_string(), format!("{}:{}", self.root.display(), WORKDIR),
+            "-w".to_string(), WORKDIR.to_string(),
+        ];
+        let mut keys: Vec<&String> = env.keys().filter(|k| !HOST_ONLY_VARS.contains(&k.as_str())).collect();
+        keys.sort();
+        for key in keys {
+            args.push("-e".to_string());
+            args.push(if is_sensitive_key(key) { key.clone() } else { format!("{}={}", key, env[key]) });
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args.extend([self.image.clone(), "sh".to_string(), "-c".to_string(), cmd.to_string()]);
+        args
+    }
+
+    /// `args` as one line for the host shell.
+    pub fn command_line(&self, name: &str, env: &IndexMap<String, String>, cmd: &str) -> String {
+        shell_words::join(self.args(name, env, cmd))
+    }
+
+    /// Fails with a hint when the runtime binary cannot be found.
+    pub fn check_runtime(&self, task_name: &str) -> Result<()> {
+        if which::which(&self.runtime).is_err() {
+            bail!(
+                "Task '{}' runs in image '{}', but '{}' was not found on PATH. Install it, or set container_runtime (e.g. \"podman\")",
+                task_name, self.image, self.runtime
+            );
+        }
+        Ok(())
+    }
+
+    /// Removes container `name`. Killing `docker run` on timeout leaves the container running.
+    pub fn remove(&self, name: &str) {
+        let _ = Command::new(&self.runtime).args(["rm", "-f", name])
+            .stdout(Stdio::null()).stderr(Stdio::null()).status();
+    }
+}
+
+/// The project root as an absolute path, for the bind mount.
+pub fn mount_root(root: &Path) -> PathBuf {
+    if root.as_os_str().is_empty() {
+        return std::env::current_dir().unwrap_or_default();
+    }
+    std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> ContainerSpec {
+        ContainerSpec {
+            runtime: "docker".to_string(),
+            image: "rust:1.79".to_string(),
+            extra_args: vec!["--network".to_string(), "none".to_string()],
+            root: PathBuf::from("/src/my app"),
+        }
+    }
+
+    #[test]
+    fn test_container_args() {
+        let env = IndexMap::from([
+            ("MODE".to_string(), "dev mode".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("API_TOKEN".to_string(), "hunter2".to_string()),
+        ]);
+        let args = spec().args("p-1", &env, "cargo build --target wasm32-unknown-unknown");
+        assert_eq!(args, [
+            "docker", "run", "--rm", "--name", "p-1", "-v", "/src/my app:/work", "-w", "/work",
+            "-e", "API_TOKEN", "-e", "MODE=dev mode", "--network", "none",
+            "rust:1.79", "sh", "-c", "cargo build --target wasm32-unknown-unknown",
+        ]);
+        let line = spec().command_line("p-1", &env, "echo $MODE");
+        assert!(line.contains("'/src/my app:/work'"), "{}", line);
+        assert!(line.ends_with("rust:1.79 sh -c 'echo $MODE'"), "{}", line);
+        assert!(!line.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_missing_runtime() {
+        let spec = ContainerSpec { runtime: "no-such-runtime-xyz".to_string(), ..spec() };
+        let err = spec.check_runtime("wasm").unwrap_err().to_string();
+        assert!(err.contains("'no-such-runtime-xyz' was not found on PATH"), "{}", err);
+    }
+}
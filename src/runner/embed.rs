@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::config::PavidiConfig;
+use crate::logger::RunLog;
+use super::events::{Event, EventSink};
+use super::plan::Plan;
+use super::{recursive_runner, CallStack};
+
+/// How [`Runner::run`] reports a run.
+#[derive(Default)]
+pub struct RunOptions {
+    /// Receives every line the commands print. When set, command output does not
+    /// reach the terminal.
+    pub capture: Option<Box<dyn Write + Send>>,
+    /// Called for every [`Event`], from the threads running the tasks
+    pub events: Option<EventSink>,
+    /// Arguments for the root task, as after `--` on the command line
+    pub args: Vec<String>,
+}
+
+/// Runs tasks of a loaded configuration, as `p <task>` does: dependencies,
+/// caching, `finally`, logs under `.p/logs` and capabilities all apply.
+pub struct Runner {
+    config: PavidiConfig,
+}
+
+impl Runner {
+    pub fn new(config: PavidiConfig) -> Self {
+        Runner { config }
+    }
+
+    /// Runs the root task of `plan`, or each task of a group plan in turn.
+    pub fn run(&self, plan: &Plan, options: RunOptions) -> Result<()> {
+        let captured = options.capture.is_some();
+        let capture = options.capture.map(Mutex::new);
+        let events = options.events;
+        let sink: EventSink = Arc::new(move |event: &Event| {
+            if let (Some(capture), Event::OutputChunk { line, .. }) = (&capture, event) {
+                let _ = writeln!(capture.lock().unwrap(), "{}", line);
+            }
+            if let Some(events) = &events {
+                events(event);
+            }
+        });
+        let run = RunLog::new(&plan.task, &self.config).with_events(sink);
+
+        let mut roots = plan.steps.iter().filter(|step| step.depth == 0);
+        let result = roots.try_for_each(|root| {
+            // A group member an earlier member ran as a dependency is not run again
+            if run.report.tasks().iter().any(|t| t.name == root.task) {
+                return Ok(());
+            }
+            recursive_runner(&root.task, &self.config, &run, &mut CallStack::new(), &options.args, captured, false, 0)
+        });
+        run.finish(result.is_ok())?;
+        result
+    }
+}
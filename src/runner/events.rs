@@ -0,0 +1,29 @@
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use super::report::TaskStatus;
+
+/// Progress of a run, for programs embedding the runner (see [`crate::Runner`]).
+/// The `p` binary subscribes to none; it prints through `log` as before.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The task was reached; its dependencies run next, then its commands
+    TaskStarted { task: String },
+    /// The command, expanded, is about to run
+    CommandStarted { task: String, command: String },
+    /// One line a command printed, without its line ending
+    OutputChunk { task: String, stream: OutputStream, line: String },
+    /// `cached` when the task was skipped because its outputs were up to date
+    TaskFinished { task: String, status: TaskStatus, duration: Duration, cached: bool },
+}
+
+/// Which of a command's outputs a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Receives every event of a run, from the threads running its tasks.
+pub type EventSink = Arc<dyn Fn(&Event) + Send + Sync>;
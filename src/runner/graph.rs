@@ -0,0 +1,382 @@
+use colored::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use indexmap::IndexMap;
+use std::fmt::Write as _;
+use anyhow::{Context, Result, bail};
+use crate::config::PavidiConfig;
+use super::plan::{build_group_plan, build_plan, Plan};
+use super::task::{group_members, RunnerTask};
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub deps: Vec<String>,
+    pub parallel: bool,
+    pub cached: bool,
+}
+
+/// Tasks and their dependency edges, built once from `[runner]`.
+/// Cycles and missing dependencies are kept so they can be reported.
+#[derive(Debug, Default)]
+pub struct TaskGraph {
+    nodes: BTreeMap<String, Node>,
+}
+
+impl TaskGraph {
+    /// What running `task` (or the group `name:`) would do; see [`build_plan`].
+    pub fn plan(config: &PavidiConfig, task: &str) -> Result<Plan> {
+        let runner = config.runner.as_ref().context("No [runner] section defined in config")?;
+        match task.strip_suffix(':') {
+            Some(group) if !runner.contains_key(task) => {
+                let members: Vec<String> = group_members(runner, group).into_iter().cloned().collect();
+                if members.is_empty() {
+                    bail!("Group '{}' has no tasks (no task is named '{}:<name>')", group, group);
+                }
+                build_group_plan(group, &members, config)
+            },
+            _ => build_plan(task, config, &[]),
+        }
+    }
+
+    pub fn from_tasks(tasks: &IndexMap<String, RunnerTask>) -> Self {
+        let nodes = tasks.iter().map(|(name, task)| {
+            (name.clone(), Node { deps: task.deps().to_vec(), parallel: task.is_parallel(), cached: task.has_cache() })
+        }).collect();
+        TaskGraph { nodes }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.nodes.contains_key(name)
+    }
+
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.get(name)
+    }
+
+    /// Tasks no other task depends on, plus one entry point per cycle that
+    /// is not reachable from them, so every task appears under some root.
+    pub fn roots(&self) -> Vec<String> {
+        let depended: HashSet<&str> = self.nodes.values().flat_map(|n| n.deps.iter().map(String::as_str)).collect();
+        let mut roots: Vec<&str> = self.nodes.keys().map(String::as_str).filter(|n| !depended.contains(n)).collect();
+
+        let mut reached = HashSet::new();
+        for root in &roots {
+            self.reach(root, &mut reached);
+        }
+        for name in self.nodes.keys() {
+            if !reached.contains(name.as_str()) {
+                roots.push(name);
+                self.reach(name, &mut reached);
+            }
+        }
+        roots.into_iter().map(String::from).collect()
+    }
+
+    fn reach<'a>(&'a self, name: &'a str, reached: &mut HashSet<&'a str>) {
+        if !reached.insert(name) {
+            return;
+        }
+        if let Some(node) = self.nodes.get(name) {
+            for dep in &node.deps {
+                self.reach(dep, reached);
+            }
+        }
+    }
+
+    /// `(task, dep)` pairs where `dep` is not a defined task.
+    pub fn missing_deps(&self) -> Vec<(&str, &str)> {
+        self.nodes.iter()
+            .flat_map(|(name, node)| node.deps.iter().map(move |d| (name.as_str(), d.as_str())))
+            .filter(|(_, dep)| !self.contains(dep))
+            .collect()
+    }
+
+    /// Cycles as closed paths, e.g. `["a", "b", "a"]`. Reports at least one
+    /// cycle per strongly connected group, not every overlapping one.
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let mut found = BTreeSet::new();
+        let mut done = HashSet::new();
+        for name in self.nodes.keys() {
+            self.find_cycles(name, &mut Vec::new(), &mut done, &mut found);
+        }
+        found.into_iter().collect()
+    }
+
+    fn find_cycles<'a>(&'a self, name: &'a str, path: &mut Vec<&'a str>, done: &mut HashSet<&'a str>, found: &mut BTreeSet<Vec<String>>) {
+        if let Some(pos) = path.iter().position(|p| *p == name) {
+            // Rotate so the same cycle found from another entry point dedups
+            let mut cycle: Vec<String> = path[pos..].iter().map(|s| s.to_string()).collect();
+            let min = cycle.iter().enumerate().min_by_key(|(_, n)| n.as_str()).map(|(i, _)| i).unwrap_or(0);
+            cycle.rotate_left(min);
+            cycle.push(cycle[0].clone());
+            found.insert(cycle);
+            return;
+        }
+        if done.contains(name) {
+            return;
+        }
+        let Some(node) = self.nodes.get(name) else { return };
+        path.push(name);
+        for dep in &node.deps {
+            self.find_cycles(dep, path, done, found);
+        }
+        path.pop();
+        done.insert(name);
+    }
+}
+
+struct TreeStyle {
+    branch: &'static str,
+    last: &'static str,
+    pipe: &'static str,
+    space: &'static str,
+    repeat: &'static str,
+    arrow: &'static str,
+}
+
+const UNICODE_TREE: TreeStyle = TreeStyle { branch: "├── ", last: "└── ", pipe: "│   ", space: "    ", repeat: "…", arrow: " → " };
+const ASCII_TREE: TreeStyle = TreeStyle { branch: "|-- ", last: "`-- ", pipe: "|   ", space: "    ", repeat: "...", arrow: " -> " };
+
+/// Renders each root as a tree. Subtrees already printed are shown once and
+/// then abbreviated; cycles and missing tasks are marked instead of followed.
+pub fn render_tree(graph: &TaskGraph, roots: &[String], ascii: bool) -> String {
+    let style = if ascii { &ASCII_TREE } else { &UNICODE_TREE };
+    let mut out = String::new();
+    let mut seen = HashSet::new();
+    for root in roots {
+        tree_node(graph, root, "", None, &mut Vec::new(), &mut seen, style, &mut out);
+    }
+    out
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tree_node(
+    graph: &TaskGraph,
+    name: &str,
+    prefix: &str,
+    last: Option<bool>, // None for roots
+    path: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+    style: &TreeStyle,
+    out: &mut String,
+) {
+    let branch = match last {
+        None => "",
+        Some(true) => style.last,
+        Some(false) => style.branch,
+    };
+    let child_prefix = match last {
+        None => String::new(),
+        Some(true) => format!("{}{}", prefix, style.space),
+        Some(false) => format!("{}{}", prefix, style.pipe),
+    };
+
+    let Some(node) = graph.node(name) else {
+        let _ = writeln!(out, "{}{}{} {}", prefix, branch, name.red(), "(missing)".red());
+        return;
+    };
+
+    if path.iter().any(|p| p == name) {
+        let start = path.iter().position(|p| p == name).unwrap_or(0);
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name.to_string());
+        let _ = writeln!(out, "{}{}{} {}", prefix, branch, name.red(), format!("(cycle: {})", cycle.join(style.arrow)).red());
+        return;
+    }
+
+    let mut label = name.to_string();
+    if node.cached {
+        label.push_str(&format!(" {}", "(cached)".dimmed()));
+    }
+    if node.parallel && !node.deps.is_empty() {
+        label.push_str(&format!(" {}", "(parallel)".dimmed()));
+    }
+
+    if !node.deps.is_empty() && !seen.insert(name.to_string()) {
+        let _ = writeln!(out, "{}{}{} {}", prefix, branch, label, style.repeat.dimmed());
+        return;
+    }
+    let _ = writeln!(out, "{}{}{}", prefix, branch, label);
+
+    path.push(name.to_string());
+    for (i, dep) in node.deps.iter().enumerate() {
+        let is_last = i == node.deps.len() - 1;
+        tree_node(graph, dep, &child_prefix, Some(is_last), path, seen, style, out);
+    }
+    path.pop();
+}
+
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Renders the tasks reachable from `roots` as Graphviz DOT. Edges point from a
+/// task to its dependency; cached tasks are filled, parallel dependency groups
+/// are clustered, cycle edges and missing tasks are red.
+pub fn render_dot(graph: &TaskGraph, roots: &[String]) -> String {
+    let mut reached = HashSet::new();
+    for root in roots {
+        graph.reach(root, &mut reached);
+    }
+    let mut names: Vec<&str> = reached.into_iter().collect();
+    names.sort();
+
+    let cycle_edges: HashSet<(String, String)> = graph.cycles().iter()
+        .flat_map(|c| c.windows(2).map(|w| (w[0].clone(), w[1].clone())).collect::<Vec<_>>())
+        .collect();
+
+    let mut dot = String::from("digraph tasks {\n    rankdir=LR;\n    node [shape=box];\n");
+
+    for name in &names {
+        match graph.node(name) {
+            Some(node) if node.cached => {
+                let _ = writeln!(dot, "    {} [style=filled, fillcolor=lightgrey];", dot_id(name));
+            },
+            Some(_) => {
+                let _ = writeln!(dot, "    {};", dot_id(name));
+            },
+            None => {
+                let _ = writeln!(dot, "    {} [label={}, color=red, fontcolor=red, style=dashed];", dot_id(name), dot_id(&format!("{} (missing)", name)));
+            },
+        }
+    }
+
+    // Graphviz allows a node in one cluster only; the first parallel parent wins
+    let mut clustered = HashSet::new();
+    for name in &names {
+        let Some(node) = graph.node(name) else { continue };
+        if !node.parallel || node.deps.is_empty() {
+            continue;
+        }
+        let members: Vec<&String> = node.deps.iter().filter(|d| clustered.insert(d.to_string())).collect();
+        if members.is_empty() {
+            continue;
+        }
+        let _ = writeln!(dot, "    subgraph {} {{", dot_id(&format!("cluster_{}", name)));
+        let _ = writeln!(dot, "        label={};", dot_id(&format!("{} (parallel)", name)));
+        dot.push_str("        style=dashed;\n");
+        for member in members {
+            let _ = writeln!(dot, "        {};", dot_id(member));
+        }
+        dot.push_str("    }\n");
+    }
+
+    for name in &names {
+        let Some(node) = graph.node(name) else { continue };
+        for dep in &node.deps {
+            let edge = (name.to_string(), dep.clone());
+            if cycle_edges.contains(&edge) {
+                let _ = writeln!(dot, "    {} -> {} [color=red];", dot_id(name), dot_id(dep));
+            } else {
+                let _ = writeln!(dot, "    {} -> {};", dot_id(name), dot_id(dep));
+            }
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::strip_ansi;
+
+    fn graph(src: &str) -> TaskGraph {
+        let tasks: IndexMap<String, RunnerTask> = toml::from_str(src).unwrap();
+        TaskGraph::from_tasks(&tasks)
+    }
+
+    fn fixture() -> TaskGraph {
+        graph(r#"
+[build]
+cmds = ["cargo build"]
+sources = ["src/**"]
+outputs = ["target"]
+
+[lint]
+cmds = ["cargo clippy"]
+
+[test]
+deps = ["build"]
+cmds = ["cargo test"]
+
+[ci]
+deps = ["lint", "test", "docs"]
+parallel = true
+
+[release]
+deps = ["build", "ci"]
+"#)
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let g = fixture();
+        let expected = r#"digraph tasks {
+    rankdir=LR;
+    node [shape=box];
+    "build" [style=filled, fillcolor=lightgrey];
+    "ci";
+    "docs" [label="docs (missing)", color=red, fontcolor=red, style=dashed];
+    "lint";
+    "release";
+    "test";
+    subgraph "cluster_ci" {
+        label="ci (parallel)";
+        style=dashed;
+        "lint";
+        "test";
+        "docs";
+    }
+    "ci" -> "lint";
+    "ci" -> "test";
+    "ci" -> "docs";
+    "release" -> "build";
+    "release" -> "ci";
+    "test" -> "build";
+}
+"#;
+        assert_eq!(render_dot(&g, &g.roots()), expected);
+        assert_eq!(g.missing_deps(), vec![("ci", "docs")]);
+    }
+
+    #[test]
+    fn test_dot_restricted_to_root() {
+        let g = fixture();
+        let dot = render_dot(&g, &["test".to_string()]);
+        assert!(dot.contains("\"test\" -> \"build\";"));
+        assert!(!dot.contains("\"ci\""));
+    }
+
+    #[test]
+    fn test_tree_dedups_repeated_subtrees() {
+        let g = fixture();
+        let tree = strip_ansi(&render_tree(&g, &g.roots(), true));
+        assert_eq!(tree, "\
+release
+|-- build (cached)
+`-- ci (parallel)
+    |-- lint
+    |-- test
+    |   `-- build (cached)
+    `-- docs (missing)
+");
+        let g = graph("[a]\ndeps = [\"b\"]\n[b]\ndeps = [\"c\"]\n[c]\ncmds = [\"true\"]\n[x]\ndeps = [\"a\", \"b\"]\n");
+        let tree = strip_ansi(&render_tree(&g, &g.roots(), false));
+        assert_eq!(tree, "x\n├── a\n│   └── b\n│       └── c\n└── b …\n");
+    }
+
+    #[test]
+    fn test_cycles_are_rendered() {
+        let g = graph("[a]\ndeps = [\"b\"]\n[b]\ndeps = [\"a\"]\n[c]\ndeps = [\"c\"]\n");
+        assert_eq!(g.cycles(), vec![vec!["a", "b", "a"], vec!["c", "c"]]);
+        // Nothing is a root in a pure cycle; one entry point per cycle is used
+        assert_eq!(g.roots(), vec!["a", "c"]);
+
+        let tree = strip_ansi(&render_tree(&g, &g.roots(), true));
+        assert_eq!(tree, "a\n`-- b\n    `-- a (cycle: a -> b -> a)\nc\n`-- c (cycle: c -> c)\n");
+        let dot = render_dot(&g, &g.roots());
+        assert!(dot.contains("\"a\" -> \"b\" [color=red];"));
+        assert!(dot.contains("\"c\" -> \"c\" [color=red];"));
+    }
+}
@@ -5,8 +5,10 @@ use std::fs;
 use std::io;
 use std::path::Path;
 use crate::runner::common::expand_globs;
+use crate::runner::capability::check_read;
+use crate::config::CapabilityConfig;
 
-pub fn handle_cat(args: &[String]) -> Result<()> {
+pub fn handle_cat(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let expanded_args = expand_globs(args);
 
     if expanded_args.is_empty() {
@@ -16,6 +18,7 @@ pub fn handle_cat(args: &[String]) -> Result<()> {
 
     for filename in &expanded_args {
         let path = Path::new(filename);
+        check_read(caps, "p:cat", path)?;
         if !path.exists() {
             println!("cat: {}: No such file", filename);
             continue;
@@ -0,0 +1,234 @@
+// Chmod portable handler
+
+use anyhow::{Result, Context, bail};
+use std::fs;
+use std::path::Path;
+use crate::runner::common::expand_globs;
+use crate::runner::capability::check_write;
+use crate::config::CapabilityConfig;
+
+const USAGE: &str = "usage: p:chmod [-R] MODE <paths...>";
+
+/// An octal mode (`755`) or symbolic clauses (`+x`, `u+rwx,go-w`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Octal(u32),
+    Symbolic(Vec<Clause>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    /// Bits of the classes the clause applies to (`u` is 0o700, none means all)
+    who: u32,
+    op: char,
+    /// `r`, `w` and `x` for every class; masked by `who` when applied
+    perms: u32,
+}
+
+pub fn parse_mode(mode: &str) -> Result<Mode> {
+    if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+        let bits = u32::from_str_radix(mode, 8).with_context(|| format!("p:chmod: invalid mode '{}'", mode))?;
+        if bits > 0o7777 {
+            bail!("p:chmod: invalid mode '{}'", mode);
+        }
+        return Ok(Mode::Octal(bits));
+    }
+
+    let clauses = mode.split(',').map(|clause| {
+        let op_at = clause.find(['+', '-', '='])
+            .with_context(|| format!("p:chmod: invalid mode '{}' (expected e.g. 755, +x or u+rwx,go-w)", mode))?;
+        let (who, rest) = clause.split_at(op_at);
+        let mut who_bits = 0;
+        for c in who.chars() {
+            who_bits |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => bail!("p:chmod: invalid mode '{}' ('{}' is not one of u, g, o, a)", mode, c),
+            };
+        }
+        let mut perms = 0;
+        for c in rest[1..].chars() {
+            perms |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => bail!("p:chmod: invalid mode '{}' ('{}' is not one of r, w, x)", mode, c),
+            };
+        }
+        Ok(Clause { who: if who_bits == 0 { 0o777 } else { who_bits }, op: rest.chars().next().unwrap_or('+'), perms })
+    }).collect::<Result<Vec<_>>>()?;
+    Ok(Mode::Symbolic(clauses))
+}
+
+impl Mode {
+    /// The permission bits after applying the mode to `current`.
+    pub fn apply(&self, current: u32) -> u32 {
+        match self {
+            Mode::Octal(bits) => *bits,
+            Mode::Symbolic(clauses) => clauses.iter().fold(current, |mode, clause| {
+                let bits = clause.perms & clause.who;
+                match clause.op {
+                    '+' => mode | bits,
+                    '-' => mode & !bits,
+                    _ => (mode & !clause.who) | bits,
+                }
+            }),
+        }
+    }
+
+    /// Whether the mode sets any execute bit, which Windows has no equivalent for.
+    pub fn sets_execute(&self) -> bool {
+        match self {
+            Mode::Octal(bits) => bits & 0o111 != 0,
+            Mode::Symbolic(clauses) => clauses.iter().any(|c| c.op != '-' && c.perms & 0o111 != 0),
+        }
+    }
+}
+
+pub fn handle_chmod(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
+    let mut recursive = false;
+    let mut mode = None;
+    let mut paths = vec![];
+    for arg in expand_globs(args) {
+        match arg.as_str() {
+            "-R" | "-r" | "--recursive" if mode.is_none() => recursive = true,
+            // The first other argument is the mode, even `-w`
+            _ if mode.is_none() => mode = Some(parse_mode(&arg)?),
+            _ => paths.push(arg),
+        }
+    }
+    let Some(mode) = mode else { bail!("p:chmod: missing mode ({})", USAGE) };
+    if paths.is_empty() {
+        bail!("p:chmod: missing path ({})", USAGE);
+    }
+
+    #[cfg(windows)]
+    if mode.sets_execute() {
+        log::warn!("{} p:chmod: execute bits have no effect on Windows", crate::ui::glyph(crate::ui::Glyph::Warn));
+    }
+
+    for path in &paths {
+        let p = Path::new(path);
+        if !p.exists() {
+            bail!("File not found: {}", path);
+        }
+        chmod_path(p, &mode, recursive, caps)?;
+    }
+    Ok(())
+}
+
+fn chmod_path(path: &Path, mode: &Mode, recursive: bool, caps: Option<&CapabilityConfig>) -> Result<()> {
+    check_write(caps, "p:chmod", path)?;
+    let meta = fs::symlink_metadata(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    // Links themselves have no mode to change; their targets are not followed into
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    set_mode(path, &meta, mode)?;
+    if recursive && meta.is_dir() {
+        for entry in fs::read_dir(path).with_context(|| format!("Failed to read directory: {}", path.display()))? {
+            chmod_path(&entry?.path(), mode, recursive, caps)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, meta: &fs::Metadata, mode: &Mode) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let bits = mode.apply(meta.permissions().mode() & 0o7777);
+    fs::set_permissions(path, fs::Permissions::from_mode(bits))
+        .with_context(|| format!("Failed to change mode of {}", path.display()))
+}
+
+/// Windows only has the readonly attribute: it follows the owner's write bit.
+#[cfg(windows)]
+fn set_mode(path: &Path, meta: &fs::Metadata, mode: &Mode) -> Result<()> {
+    let mut permissions = meta.permissions();
+    let current = if permissions.readonly() { 0o444 } else { 0o666 };
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(mode.apply(current) & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+        .with_context(|| format!("Failed to change attributes of {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{scratch, Scratch};
+
+    #[test]
+    fn test_mode_apply() {
+        let cases = [
+            ("755", 0o600, 0o755),
+            ("+x", 0o644, 0o755),
+            ("-w", 0o664, 0o444),
+            ("u+rwx", 0o000, 0o700),
+            ("go-w,u+x", 0o666, 0o744),
+            ("a=r", 0o755, 0o444),
+            ("o=", 0o757, 0o750),
+        ];
+        for (mode, current, expected) in cases {
+            assert_eq!(parse_mode(mode).unwrap().apply(current), expected, "{} on {:o}", mode, current);
+        }
+        assert!(parse_mode("+x").unwrap().sets_execute());
+        assert!(!parse_mode("a-x").unwrap().sets_execute());
+        for bad in ["", "rwx", "u+q", "z+x", "99"] {
+            assert!(parse_mode(bad).is_err(), "{}", bad);
+        }
+    }
+
+    /// A scratch directory with an empty `bin/tool`.
+    fn tools(name: &str) -> Scratch {
+        let dir = scratch(&format!("chmod_{}", name));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin").join("tool"), "").unwrap();
+        dir
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chmod_sets_mode_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tools("unix");
+        let tool = dir.join("bin").join("tool");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o644)).unwrap();
+
+        handle_chmod(&args(&["+x", &tool.display().to_string()]), None).unwrap();
+        assert_eq!(mode(&tool), 0o755);
+        handle_chmod(&args(&["600", &tool.display().to_string()]), None).unwrap();
+        assert_eq!(mode(&tool), 0o600);
+        handle_chmod(&args(&["-R", "go-rx", &dir.join("bin").display().to_string()]), None).unwrap();
+        assert_eq!((mode(&dir.join("bin")), mode(&tool)), (0o700, 0o600));
+
+        assert!(handle_chmod(&args(&["+x"]), None).is_err());
+        assert!(handle_chmod(&args(&["+x", &dir.join("missing").display().to_string()]), None).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_chmod_toggles_readonly() {
+        let dir = tools("windows");
+        let tool = dir.join("bin").join("tool");
+        let readonly = |p: &Path| fs::metadata(p).unwrap().permissions().readonly();
+
+        handle_chmod(&args(&["-w", &tool.display().to_string()]), None).unwrap();
+        assert!(readonly(&tool));
+        // Execute bits warn but succeed
+        handle_chmod(&args(&["+x", &tool.display().to_string()]), None).unwrap();
+        assert!(readonly(&tool));
+        handle_chmod(&args(&["644", &tool.display().to_string()]), None).unwrap();
+        assert!(!readonly(&tool));
+        handle_chmod(&args(&["-R", "a-w", &dir.join("bin").display().to_string()]), None).unwrap();
+        assert!(readonly(&tool));
+        handle_chmod(&args(&["+w", &tool.display().to_string()]), None).unwrap();
+        assert!(!readonly(&tool));
+    }
+}
@@ -5,8 +5,10 @@ use std::fs;
 use std::path::Path;
 use crate::runner::common::copy_dir_recursive;
 use crate::runner::common::expand_globs;
+use crate::runner::capability::{check_inside_project, check_path_tree, Access};
+use crate::config::CapabilityConfig;
 
-pub fn handle_cp(args: &[String]) -> Result<()> {
+pub fn handle_cp(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let expanded_args = expand_globs(args);
 
     let mut recursive = false;
@@ -46,6 +48,10 @@ pub fn handle_cp(args: &[String]) -> Result<()> {
             dest_path.to_path_buf()
         };
 
+        check_path_tree(caps, "p:cp", src_path, src_path, Access::Read)?;
+        check_inside_project(caps, "p:cp", &target)?;
+        check_path_tree(caps, "p:cp", src_path, &target, Access::Write)?;
+
         if src_path.is_dir() {
             if recursive {
                 copy_dir_recursive(src_path, &target)?;
@@ -0,0 +1,103 @@
+// Date portable handler
+
+use anyhow::{Result, bail};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, Offset, SecondsFormat, Utc};
+use std::io::{self, Write};
+
+const USAGE: &str = "usage: p:date [--utc] [--iso | --epoch | FORMAT]";
+const DEFAULT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+pub fn handle_date(args: &[String]) -> Result<()> {
+    let now = Local::now();
+    write_date(args, now.with_timezone(&Utc), now.offset().fix(), &mut io::stdout())
+}
+
+/// Writes the date and a newline, so `$(p:date ...)` gets the bare value.
+pub fn write_date(args: &[String], now: DateTime<Utc>, local: FixedOffset, out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "{}", render_date(args, now, local)?)?;
+    Ok(())
+}
+
+/// `now` in local time (`local` offset) or UTC, as ISO 8601, seconds since the epoch,
+/// or a strftime format; a leading `+` is accepted as in `date +%Y%m%d`.
+pub fn render_date(args: &[String], now: DateTime<Utc>, local: FixedOffset) -> Result<String> {
+    let mut utc = false;
+    let mut style = None;
+    for arg in args {
+        let next = match arg.as_str() {
+            "--utc" | "-u" => {
+                utc = true;
+                continue;
+            },
+            "--iso" => Style::Iso,
+            "--epoch" => Style::Epoch,
+            flag if flag.starts_with("--") => bail!("p:date: unknown flag '{}' ({})", flag, USAGE),
+            format => Style::Format(format.strip_prefix('+').unwrap_or(format).to_string()),
+        };
+        if style.replace(next).is_some() {
+            bail!("p:date: give one of --iso, --epoch or a format ({})", USAGE);
+        }
+    }
+
+    let time = if utc { now.fixed_offset() } else { now.with_timezone(&local) };
+    Ok(match style.unwrap_or_else(|| Style::Format(DEFAULT_FORMAT.to_string())) {
+        Style::Iso => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        Style::Epoch => now.timestamp().to_string(),
+        Style::Format(format) => {
+            let items: Vec<Item> = StrftimeItems::new(&format).collect();
+            if items.contains(&Item::Error) {
+                bail!("p:date: invalid format '{}'", format);
+            }
+            time.format_with_items(items.into_iter()).to_string()
+        },
+    })
+}
+
+enum Style {
+    Iso,
+    Epoch,
+    Format(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_render_date() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 23, 5, 9).unwrap();
+        let plus_two = FixedOffset::east_opt(2 * 3600).unwrap();
+        let cases: &[(&[&str], &str)] = &[
+            (&[], "2025-01-01 01:05:09"),
+            (&["--utc"], "2024-12-31 23:05:09"),
+            (&["%Y%m%d"], "20250101"),
+            (&["+%Y%m%d-%H%M"], "20250101-0105"),
+            (&["--utc", "%F %T"], "2024-12-31 23:05:09"),
+            (&["--iso"], "2025-01-01T01:05:09+02:00"),
+            (&["--iso", "--utc"], "2024-12-31T23:05:09Z"),
+            (&["--epoch"], "1735686309"),
+            (&["--epoch", "--utc"], "1735686309"),
+        ];
+        for (list, expected) in cases {
+            assert_eq!(render_date(&args(list), now, plus_two).unwrap(), *expected, "{:?}", list);
+        }
+
+        assert!(render_date(&args(&["%Q"]), now, plus_two).unwrap_err().to_string().contains("invalid format"));
+        assert!(render_date(&args(&["--iso", "--epoch"]), now, plus_two).is_err());
+        assert!(render_date(&args(&["--local"]), now, plus_two).is_err());
+    }
+
+    #[test]
+    fn test_write_date_ends_with_one_newline() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let mut out = vec![];
+        write_date(&args(&["--utc", "%Y"]), now, FixedOffset::east_opt(0).unwrap(), &mut out).unwrap();
+        assert_eq!(out, b"2024\n");
+    }
+}
@@ -4,8 +4,10 @@ use anyhow::{Result, Context};
 use std::fs;
 use std::path::Path;
 use crate::runner::common::expand_globs;
+use crate::runner::capability::check_read;
+use crate::config::CapabilityConfig;
 
-pub fn handle_ls(args: &[String]) -> Result<()> {
+pub fn handle_ls(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let mut expanded_args = expand_globs(args);
 
     if expanded_args.is_empty() {
@@ -16,6 +18,7 @@ pub fn handle_ls(args: &[String]) -> Result<()> {
 
     for path_str in expanded_args {
         let path = Path::new(&path_str);
+        check_read(caps, "p:ls", path)?;
         if !path.exists() {
              println!("ls: {}: No such file or directory", path_str);
              continue;
@@ -2,12 +2,18 @@
 
 use anyhow::{Result, Context};
 use std::fs;
+use std::path::Path;
+use crate::runner::capability::check_write;
+use crate::runner::common::expand_globs;
+use crate::config::CapabilityConfig;
 
-pub fn handle_mkdir(args: &[String]) -> Result<()> {
+pub fn handle_mkdir(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let mut parents = false;
     let mut paths = Vec::new();
 
-    for arg in args {
+    let args = expand_globs(args);
+
+    for arg in &args {
         if arg == "-p" {
             parents = true;
         } else if arg.starts_with('-') {
@@ -18,6 +24,7 @@ pub fn handle_mkdir(args: &[String]) -> Result<()> {
     }
 
     for path in paths {
+        check_write(caps, "p:mkdir", Path::new(path))?;
         if parents {
             fs::create_dir_all(path).with_context(|| format!("Failed to create directory (with parents): {}", path))?;
         } else {
@@ -3,4 +3,9 @@ pub mod mkdir;
 pub mod rm;
 pub mod ls;
 pub mod mv;
-pub mod cat;
\ No newline at end of file
+pub mod cat;
+pub mod serve;
+pub mod date;
+pub mod uuid;
+pub mod chmod;
+pub mod stat;
\ No newline at end of file
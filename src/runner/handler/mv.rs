@@ -4,8 +4,10 @@ use anyhow::{Result, Context, bail};
 use std::fs;
 use std::path::Path;
 use crate::runner::common::expand_globs;
+use crate::runner::capability::{check_inside_project, check_path_tree, Access};
+use crate::config::CapabilityConfig;
 
-pub fn handle_mv(args: &[String]) -> Result<()> {
+pub fn handle_mv(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let expanded_args = expand_globs(args);
 
     let mut paths = Vec::new();
@@ -42,6 +44,12 @@ pub fn handle_mv(args: &[String]) -> Result<()> {
             dest_path.to_path_buf()
         };
 
+        // Moving mutates both ends
+        check_inside_project(caps, "p:mv", src_path)?;
+        check_inside_project(caps, "p:mv", &target)?;
+        check_path_tree(caps, "p:mv", src_path, src_path, Access::Write)?;
+        check_path_tree(caps, "p:mv", src_path, &target, Access::Write)?;
+
         fs::rename(src_path, &target).with_context(|| format!("Failed to move from {:?} to {:?}", src_path, target))?;
     }
 
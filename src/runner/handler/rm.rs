@@ -4,8 +4,10 @@ use anyhow::{Result, Context, bail};
 use std::fs;
 use std::path::Path;
 use crate::runner::common::expand_globs;
+use crate::runner::capability::{check_inside_project, check_path_tree, Access};
+use crate::config::CapabilityConfig;
 
-pub fn handle_rm(args: &[String]) -> Result<()> {
+pub fn handle_rm(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
     let args = expand_globs(args);
     
     let mut recursive = false;
@@ -30,6 +32,9 @@ pub fn handle_rm(args: &[String]) -> Result<()> {
             continue;
         }
 
+        check_inside_project(caps, "p:rm", p)?;
+        check_path_tree(caps, "p:rm", p, p, Access::Write)?;
+
         if p.is_dir() {
             if recursive {
                 fs::remove_dir_all(p).with_context(|| format!("Failed to remove directory: {}", path))?;
@@ -0,0 +1,345 @@
+// Serve portable handler: a small static file server for dev tasks
+
+use anyhow::{Result, Context, bail};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use crate::runner::capability::check_read;
+use crate::config::CapabilityConfig;
+use crate::process;
+use crate::utils::canonicalize;
+
+const DEFAULT_PORT: u16 = 8080;
+/// Longest request head accepted; a static server needs little more than the request line
+const MAX_HEAD: usize = 16 * 1024;
+
+pub fn handle_serve(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
+    let mut dir = None;
+    let mut port = DEFAULT_PORT;
+    let mut spa = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => {
+                let value = args.next().context("p:serve: -p needs a port number")?;
+                port = value.parse().with_context(|| format!("p:serve: invalid port '{}'", value))?;
+            },
+            "--spa" => spa = true,
+            flag if flag.starts_with('-') => bail!("p:serve: unknown flag '{}' (usage: p:serve [dir] [-p PORT] [--spa])", flag),
+            path if dir.is_none() => dir = Some(path.to_string()),
+            extra => bail!("p:serve: unexpected argument '{}' (usage: p:serve [dir] [-p PORT] [--spa])", extra),
+        }
+    }
+
+    let dir = dir.unwrap_or_else(|| ".".to_string());
+    check_read(caps, "p:serve", Path::new(&dir))?;
+    let server = Server::bind(Path::new(&dir), port, spa)?;
+    println!("Serving {} at http://{} (Ctrl+C to stop)", dir, server.listener.local_addr()?);
+    server.run(|| process::abort_signal().is_some(), &mut io::stdout())
+}
+
+pub struct Server {
+    listener: TcpListener,
+    root: PathBuf,
+    spa: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Server {
+    /// Listens on 127.0.0.1; port 0 picks a free one.
+    pub fn bind(root: &Path, port: u16, spa: bool) -> Result<Self> {
+        if !root.is_dir() {
+            bail!("p:serve: {} is not a directory", root.display());
+        }
+        let root = canonicalize(root).with_context(|| format!("p:serve: cannot resolve {}", root.display()))?;
+        let listener = TcpListener::bind(("127.0.0.1", port)).with_context(|| format!("p:serve: cannot listen on port {}", port))?;
+        // Polled, so an interrupt is noticed between requests
+        listener.set_nonblocking(true)?;
+        Ok(Server { listener, root, spa })
+    }
+
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Answers requests one at a time until `stop` returns true, logging each to `log`.
+    pub fn run(&self, stop: impl Fn() -> bool, log: &mut dyn Write) -> Result<()> {
+        while !stop() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.answer(stream, log) {
+                        log::debug!("p:serve: {}", e);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(50)),
+                Err(e) => return Err(e).context("p:serve: accept failed"),
+            }
+        }
+        Ok(())
+    }
+
+    fn answer(&self, mut stream: TcpStream, log: &mut dyn Write) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let head = read_head(&mut stream)?;
+        let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+        let response = match method {
+            "GET" | "HEAD" => self.respond(target),
+            _ => Response::text(405, "Method Not Allowed"),
+        };
+        writeln!(log, "{} {} {}", method, target, response.status)?;
+
+        let mut out = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status, reason(response.status), response.content_type, response.body.len());
+        if let Some(location) = &response.location {
+            out.push_str(&format!("Location: {}\r\n", location));
+        }
+        out.push_str("\r\n");
+        stream.write_all(out.as_bytes())?;
+        if method != "HEAD" {
+            stream.write_all(&response.body)?;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn respond(&self, target: &str) -> Response {
+        let path = target.split(['?', '#']).next().unwrap_or_default();
+        let Some(relative) = decode(path).and_then(|p| safe_relative(&p)) else {
+            return Response::text(400, "Bad Request");
+        };
+        let file = self.root.join(&relative);
+        if file.is_dir() {
+            if !path.ends_with('/') {
+                return Response { location: Some(format!("{}/", path)), ..Response::text(301, "Moved Permanently") };
+            }
+            return self.file_or_fallback(&file.join("index.html"));
+        }
+        self.file_or_fallback(&file)
+    }
+
+    /// Whether `file` is inside the root once symlinks are followed; a missing file is.
+    fn inside_root(&self, file: &Path) -> bool {
+        canonicalize(file).map_or(true, |real| real.starts_with(&self.root))
+    }
+
+    /// `file`, or for a missing file the SPA's `index.html`. Checked here, after
+    /// `dir/` became `dir/index.html`, since symlinks may still point outside the root.
+    fn file_or_fallback(&self, file: &Path) -> Response {
+        if !self.inside_root(file) {
+            return Response::text(403, "Forbidden");
+        }
+        let index = self.root.join("index.html");
+        match fs::read(file) {
+            Ok(body) => Response { status: 200, content_type: content_type(file), location: None, body },
+            Err(_) if self.spa && self.inside_root(&index) => match fs::read(&index) {
+                Ok(body) => Response { status: 200, content_type: "text/html; charset=utf-8", location: None, body },
+                Err(_) => Response::text(404, "Not Found"),
+            },
+            Err(_) => Response::text(404, "Not Found"),
+        }
+    }
+}
+
+impl Response {
+    fn text(status: u16, body: &str) -> Self {
+        Response { status, content_type: "text/plain; charset=utf-8", location: None, body: format!("{}\n", body).into_bytes() }
+    }
+}
+
+fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_HEAD {
+            bail!("request head too large");
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Percent-decoding of the request path; `None` if it is not valid UTF-8.
+fn decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = path.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// The request path as a path below the root, or `None` if it would leave it.
+fn safe_relative(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        // A backslash or drive prefix could form a second path on Windows
+        if segment.contains(['\\', ':', '\0']) {
+            return None;
+        }
+        match Path::new(segment).components().next() {
+            Some(Component::Normal(_)) => relative.push(segment),
+            Some(Component::CurDir) => {},
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use crate::test_support::scratch;
+
+    fn request(port: u16, raw: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn get(port: u16, path: &str) -> String {
+        request(port, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path))
+    }
+
+    #[test]
+    fn test_serve_requests() {
+        let dir = scratch("serve");
+        let root = dir.join("dist");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("app.js"), "console.log(1)").unwrap();
+        fs::write(root.join("docs").join("index.html"), "docs").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            fs::create_dir_all(dir.join("private")).unwrap();
+            fs::write(dir.join("private").join("index.html"), "private").unwrap();
+            symlink(dir.join("secret.txt"), root.join("leak.txt")).unwrap();
+            symlink(dir.join("private"), root.join("outside")).unwrap();
+            fs::create_dir_all(root.join("linked")).unwrap();
+            symlink(dir.join("secret.txt"), root.join("linked").join("index.html")).unwrap();
+        }
+
+        for spa in [false, true] {
+            let server = Server::bind(&root, 0, spa).unwrap();
+            let port = server.port().unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+            let flag = stop.clone();
+            let handle = std::thread::spawn(move || {
+                let mut log = vec![];
+                server.run(|| flag.load(Ordering::SeqCst), &mut log).unwrap();
+                String::from_utf8(log).unwrap()
+            });
+
+            let home = get(port, "/");
+            assert!(home.starts_with("HTTP/1.1 200 OK\r\n"), "{}", home);
+            assert!(home.contains("Content-Type: text/html; charset=utf-8\r\n"));
+            assert!(home.ends_with("<h1>home</h1>"));
+            assert!(get(port, "/app.js?v=2").contains("Content-Type: text/javascript"));
+            assert!(get(port, "/docs").contains("Location: /docs/\r\n"));
+            assert!(get(port, "/docs/").ends_with("docs"));
+            assert!(get(port, "/../secret.txt").starts_with("HTTP/1.1 400"));
+            assert!(get(port, "/%2e%2e/secret.txt").starts_with("HTTP/1.1 400"));
+            assert!(request(port, "POST / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+            let head = request(port, "HEAD /app.js HTTP/1.1\r\n\r\n");
+            assert!(head.contains("Content-Length: 14\r\n") && head.ends_with("\r\n\r\n"), "{}", head);
+
+            // Symlinks out of the root, also as the index.html of a directory
+            #[cfg(unix)]
+            {
+                assert!(get(port, "/leak.txt").starts_with("HTTP/1.1 403"));
+                assert!(get(port, "/outside/").starts_with("HTTP/1.1 403"));
+                assert!(get(port, "/linked/").starts_with("HTTP/1.1 403"));
+            }
+
+            let missing = get(port, "/settings/profile");
+            if spa {
+                assert!(missing.starts_with("HTTP/1.1 200") && missing.ends_with("<h1>home</h1>"), "{}", missing);
+            } else {
+                assert!(missing.starts_with("HTTP/1.1 404"), "{}", missing);
+            }
+
+            stop.store(true, Ordering::SeqCst);
+            let log = handle.join().unwrap();
+            assert!(log.starts_with("GET / 200\nGET /app.js?v=2 200\nGET /docs 301\n"), "{}", log);
+        }
+    }
+
+    #[test]
+    fn test_safe_relative() {
+        assert_eq!(safe_relative("/a/./b/"), Some(PathBuf::from("a").join("b")));
+        assert_eq!(safe_relative("/"), Some(PathBuf::new()));
+        assert_eq!(safe_relative("/a/../b"), None);
+        assert_eq!(safe_relative("/C:/Windows"), None);
+        assert_eq!(safe_relative("/a\\..\\b"), None);
+    }
+}
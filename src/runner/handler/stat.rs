@@ -0,0 +1,80 @@
+// Stat portable handler
+
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Local, SecondsFormat};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use crate::runner::common::expand_globs;
+use crate::runner::capability::check_read;
+use crate::config::CapabilityConfig;
+
+pub fn handle_stat(args: &[String], caps: Option<&CapabilityConfig>) -> Result<()> {
+    let paths = expand_globs(args);
+    if paths.is_empty() {
+        bail!("p:stat: missing path (usage: p:stat <paths...>)");
+    }
+    for path in &paths {
+        let p = Path::new(path);
+        check_read(caps, "p:stat", p)?;
+        write_stat(p, &mut io::stdout())?;
+    }
+    Ok(())
+}
+
+/// Size, modification time and mode: what decides whether the cache sees a file as changed.
+pub fn write_stat(path: &Path, out: &mut dyn Write) -> Result<()> {
+    let meta = fs::symlink_metadata(path).with_context(|| format!("p:stat: cannot stat {}", path.display()))?;
+    let kind = if meta.file_type().is_symlink() { "symlink" } else if meta.is_dir() { "directory" } else { "file" };
+    let modified = meta.modified().map(|t| DateTime::<Local>::from(t).to_rfc3339_opts(SecondsFormat::Secs, false));
+    writeln!(out, "{}", path.display())?;
+    writeln!(out, "  type:     {}", kind)?;
+    writeln!(out, "  size:     {} bytes", meta.len())?;
+    writeln!(out, "  modified: {}", modified.as_deref().unwrap_or("unknown"))?;
+    writeln!(out, "  mode:     {}", mode_string(&meta))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode_string(meta: &fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = meta.permissions().mode() & 0o7777;
+    let bits: String = (0..9).rev().map(|i| if mode & (1 << i) == 0 { '-' } else { b"xwr"[i % 3] as char }).collect();
+    format!("{:04o} ({})", mode, bits)
+}
+
+#[cfg(windows)]
+fn mode_string(meta: &fs::Metadata) -> String {
+    if meta.permissions().readonly() { "readonly".to_string() } else { "read-write".to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    #[test]
+    fn test_write_stat() {
+        let dir = scratch("stat");
+        let file = dir.join("out.bin");
+        fs::write(&file, "12345").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o754)).unwrap();
+        }
+
+        let mut out = vec![];
+        write_stat(&file, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("  type:     file\n  size:     5 bytes\n"), "{}", out);
+        let year = Local::now().format("%Y-").to_string();
+        assert!(out.contains(&format!("  modified: {}", year)), "{}", out);
+        #[cfg(unix)]
+        assert!(out.ends_with("  mode:     0754 (rwxr-xr--)\n"), "{}", out);
+        #[cfg(windows)]
+        assert!(out.ends_with("  mode:     read-write\n"), "{}", out);
+
+        assert!(write_stat(&dir.join("missing"), &mut vec![]).is_err());
+    }
+}
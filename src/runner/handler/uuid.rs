@@ -0,0 +1,35 @@
+// Uuid portable handler
+
+use anyhow::{Result, bail};
+use std::io::{self, Write};
+
+pub fn handle_uuid(args: &[String]) -> Result<()> {
+    write_uuid(args, &mut io::stdout())
+}
+
+/// Writes a random (v4) UUID in lowercase hyphenated form, and a newline.
+pub fn write_uuid(args: &[String], out: &mut dyn Write) -> Result<()> {
+    if let Some(arg) = args.first() {
+        bail!("p:uuid: unexpected argument '{}' (usage: p:uuid)", arg);
+    }
+    writeln!(out, "{}", uuid::Uuid::new_v4())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_uuid() {
+        let (mut a, mut b) = (vec![], vec![]);
+        write_uuid(&[], &mut a).unwrap();
+        write_uuid(&[], &mut b).unwrap();
+        let a = String::from_utf8(a).unwrap();
+        assert_ne!(a, String::from_utf8(b).unwrap());
+        let id = a.strip_suffix('\n').unwrap();
+        assert_eq!(uuid::Uuid::parse_str(id).unwrap().get_version_num(), 4);
+        assert_eq!(id.len(), 36);
+        assert!(write_uuid(&["-n".to_string()], &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::logger::ensure_state_gitignore;
+use crate::state::{read_json, write_atomic};
+use super::status::RunStatus;
+
+/// Bumped only for incompatible changes; new fields may be added without it.
+pub const HISTORY_VERSION: u32 = 1;
+
+/// `.p/history.json`: the last result of every task that ever ran here, while
+/// `.p/status.json` only knows the tasks of the last run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskHistory {
+    pub version: u32,
+    pub tasks: BTreeMap<String, LastResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastResult {
+    /// `passed`, `failed` or `skipped`
+    pub status: String,
+    /// Skipped because its outputs were up to date
+    #[serde(default)]
+    pub cached: bool,
+    pub finished: DateTime<Local>,
+    pub run_id: String,
+}
+
+impl Default for TaskHistory {
+    fn default() -> Self {
+        TaskHistory { version: HISTORY_VERSION, tasks: BTreeMap::new() }
+    }
+}
+
+impl TaskHistory {
+    /// Takes the results of `run` over earlier ones.
+    pub fn record(&mut self, run: &RunStatus) {
+        for task in &run.tasks {
+            self.tasks.insert(task.name.clone(), LastResult {
+                status: task.status.clone(),
+                cached: task.cached,
+                finished: run.finished,
+                run_id: run.run_id.clone(),
+            });
+        }
+    }
+}
+
+pub fn history_path(root: &Path) -> PathBuf {
+    root.join(".p").join("history.json")
+}
+
+/// The history so far; empty before the first run.
+pub fn read_history(root: &Path) -> Result<TaskHistory> {
+    Ok(read_json(&history_path(root))?.unwrap_or_default())
+}
+
+/// Adds the tasks of `run` to `.p/history.json`. Concurrent runs may each drop
+/// the other's update; the file only feeds `p --list --status`.
+pub fn record_run(root: &Path, run: &RunStatus) -> Result<()> {
+    let mut history = read_history(root).unwrap_or_default();
+    history.record(run);
+    let path = history_path(root);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        ensure_state_gitignore(dir);
+    }
+    write_atomic(&path, &format!("{}\n", serde_json::to_string_pretty(&history)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::runner::status::{TaskStatusEntry, STATUS_VERSION};
+    use crate::test_support::scratch;
+
+    fn run(id: &str, hour: u32, tasks: &[(&str, &str)]) -> RunStatus {
+        let at = Local.with_ymd_and_hms(2024, 5, 1, hour, 0, 0).unwrap();
+        RunStatus {
+            version: STATUS_VERSION,
+            run_id: id.to_string(),
+            task: tasks[0].0.to_string(),
+            status: "passed".to_string(),
+            exit_code: 0,
+            started: at,
+            finished: at,
+            duration_ms: 0,
+            log_dir: PathBuf::from(".p/logs"),
+            combined_log: None,
+            failure_log: None,
+            tasks: tasks.iter().map(|(name, status)| TaskStatusEntry {
+                name: name.to_string(),
+                status: status.to_string(),
+                duration_ms: 1,
+                cached: false,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_history_keeps_tasks_of_earlier_runs() {
+        let root = scratch("history");
+        assert!(read_history(&root).unwrap().tasks.is_empty());
+
+        record_run(&root, &run("first", 9, &[("lint", "failed"), ("test", "passed")])).unwrap();
+        record_run(&root, &run("second", 10, &[("lint", "passed")])).unwrap();
+        let history = read_history(&root).unwrap();
+        let results: Vec<(&str, &str, &str)> = history.tasks.iter()
+            .map(|(name, r)| (name.as_str(), r.status.as_str(), r.run_id.as_str()))
+            .collect();
+        assert_eq!(results, [("lint", "passed", "second"), ("test", "passed", "first")]);
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::logger::ensure_state_gitignore;
+use crate::state::{read_json, write_atomic};
+use super::plan::Plan;
+
+/// `.p/last-plan.json`: the expanded commands of every task the last real run planned,
+/// for `p <task> --dry-run --diff`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastPlan {
+    pub run_id: String,
+    pub task: String,
+    pub tasks: Vec<PlannedTask>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedTask {
+    pub task: String,
+    /// Commands, then `finally:` commands, as the dry run expands them
+    pub commands: Vec<String>,
+}
+
+/// How a plan differs from the last run, task by task.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanDiff {
+    /// Run the plan is compared with
+    pub against: String,
+    /// Task that run was for
+    pub last_task: String,
+    pub tasks: Vec<TaskDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaskDiff {
+    pub task: String,
+    pub change: Change,
+    /// Every command of an added, removed or changed task; empty when unchanged
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<DiffLine>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", content = "command", rename_all = "lowercase")]
+pub enum DiffLine {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+pub fn last_plan_path(root: &Path) -> PathBuf {
+    root.join(".p").join("last-plan.json")
+}
+
+impl LastPlan {
+    /// Each task once, in plan order, with its commands normalized for comparison.
+    pub fn from_plan(run_id: &str, plan: &Plan) -> Self {
+        let mut tasks: Vec<PlannedTask> = vec![];
+        for step in &plan.steps {
+            if tasks.iter().any(|t| t.task == step.task) {
+                continue;
+            }
+            let commands = step.commands.iter().map(|c| normalize(c))
+                .chain(step.finally.iter().map(|c| format!("finally: {}", normalize(c))))
+                .collect();
+            tasks.push(PlannedTask { task: step.task.clone(), commands });
+        }
+        LastPlan { run_id: run_id.to_string(), task: plan.task.clone(), tasks }
+    }
+}
+
+/// Line endings and surrounding whitespace do not count as a change.
+fn normalize(cmd: &str) -> String {
+    cmd.lines().map(str::trim_end).collect::<Vec<_>>().join("\n").trim().to_string()
+}
+
+/// Compares two plans: tasks in the order of `new`, then the ones only `old` had.
+pub fn diff_plans(old: &LastPlan, new: &LastPlan) -> PlanDiff {
+    let mut tasks: Vec<TaskDiff> = new.tasks.iter().map(|task| {
+        match old.tasks.iter().find(|t| t.task == task.task) {
+            None => TaskDiff {
+                task: task.task.clone(),
+                change: Change::Added,
+                commands: task.commands.iter().cloned().map(DiffLine::Added).collect(),
+            },
+            Some(prev) if prev.commands == task.commands => TaskDiff {
+                task: task.task.clone(),
+                change: Change::Unchanged,
+                commands: vec![],
+            },
+            Some(prev) => TaskDiff {
+                task: task.task.clone(),
+                change: Change::Changed,
+                commands: diff_commands(&prev.commands, &task.commands),
+            },
+        }
+    }).collect();
+    tasks.extend(old.tasks.iter()
+        .filter(|t| !new.tasks.iter().any(|n| n.task == t.task))
+        .map(|t| TaskDiff {
+            task: t.task.clone(),
+            change: Change::Removed,
+            commands: t.commands.iter().cloned().map(DiffLine::Removed).collect(),
+        }));
+    PlanDiff { against: old.run_id.clone(), last_task: old.task.clone(), tasks }
+}
+
+/// Line diff over the longest common subsequence; removals come before additions.
+fn diff_commands(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // lcs[i][j]: length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Replaces the file; only the last run is kept.
+pub fn write_last_plan(root: &Path, plan: &LastPlan) -> Result<()> {
+    let path = last_plan_path(root);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        ensure_state_gitignore(dir);
+    }
+    write_atomic(&path, &format!("{}\n", serde_json::to_string_pretty(plan)?))
+}
+
+pub fn read_last_plan(root: &Path) -> Result<Option<LastPlan>> {
+    read_json(&last_plan_path(root))
+}
+
+impl PlanDiff {
+    pub fn print(&self, task: &str) {
+        let count = |change| self.tasks.iter().filter(|t| t.change == change).count();
+        println!("{} [DRY-RUN] Changes in '{}' since run {}: {} changed, {} added, {} removed",
+            "::".yellow(), task.bold(), self.against.bold(), count(Change::Changed), count(Change::Added), count(Change::Removed));
+        if self.last_task != task {
+            println!("   {}", format!("(that run was of '{}'; tasks it did not plan show as added)", self.last_task).dimmed());
+        }
+        for diff in &self.tasks {
+            match diff.change {
+                Change::Added => println!("  {} {}", "+".green(), diff.task.bold().green()),
+                Change::Removed => println!("  {} {}", "-".red(), diff.task.bold().red()),
+                Change::Changed => println!("  {} {}", "~".yellow(), diff.task.bold().yellow()),
+                Change::Unchanged => println!("    {}", format!("{} (unchanged)", diff.task).dimmed()),
+            }
+            for line in &diff.commands {
+                match line {
+                    DiffLine::Added(cmd) => println!("      {}", format!("+ {}", cmd).green()),
+                    DiffLine::Removed(cmd) => println!("      {}", format!("- {}", cmd).red()),
+                    DiffLine::Same(cmd) => println!("      {}", format!("  {}", cmd).dimmed()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(run_id: &str, tasks: &[(&str, &[&str])]) -> LastPlan {
+        LastPlan {
+            run_id: run_id.to_string(),
+            task: "ci".to_string(),
+            tasks: tasks.iter().map(|(task, cmds)| PlannedTask {
+                task: task.to_string(),
+                commands: cmds.iter().map(|c| c.to_string()).collect(),
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff_commands() {
+        let s = |v: &[&str]| v.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(diff_commands(&s(&["a", "b", "c"]), &s(&["a", "x", "c", "d"])), [
+            DiffLine::Same("a".into()),
+            DiffLine::Removed("b".into()),
+            DiffLine::Added("x".into()),
+            DiffLine::Same("c".into()),
+            DiffLine::Added("d".into()),
+        ]);
+        assert_eq!(diff_commands(&s(&["a"]), &s(&[])), [DiffLine::Removed("a".into())]);
+        assert_eq!(diff_commands(&s(&[]), &s(&["a"])), [DiffLine::Added("a".into())]);
+    }
+
+    #[test]
+    fn test_diff_plans() {
+        let old = plan("aaaa0000", &[("lint", &["cargo clippy"]), ("build", &["cargo build"]), ("old", &["echo old"])]);
+        let new = plan("", &[("lint", &["cargo clippy"]), ("build", &["cargo build --release"]), ("docs", &["mdbook build"])]);
+        let diff = diff_plans(&old, &new);
+        assert_eq!(diff.against, "aaaa0000");
+        let changes: Vec<(&str, Change)> = diff.tasks.iter().map(|t| (t.task.as_str(), t.change)).collect();
+        assert_eq!(changes, [("lint", Change::Unchanged), ("build", Change::Changed), ("docs", Change::Added), ("old", Change::Removed)]);
+        assert_eq!(diff.tasks[1].commands, [DiffLine::Removed("cargo build".into()), DiffLine::Added("cargo build --release".into())]);
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["tasks"][1]["change"], "changed");
+        assert_eq!(json["tasks"][1]["commands"][1], serde_json::json!({"op": "added", "command": "cargo build --release"}));
+        assert!(json["tasks"][0].get("commands").is_none());
+    }
+
+    #[test]
+    fn test_from_plan_normalizes() {
+        let config: crate::config::PavidiConfig = toml::from_str(r#"
+[runner.dep]
+cmds = ["echo dep  \r\n"]
+
+[runner.a]
+cmds = ["echo a"]
+deps = ["dep"]
+
+[runner.ci]
+cmds = ["echo ci"]
+deps = ["dep", "a"]
+finally = ["echo done"]
+"#).unwrap();
+        let last = LastPlan::from_plan("r1", &super::super::plan::build_plan("ci", &config, &[]).unwrap());
+        assert_eq!(last.tasks, plan("r1", &[("dep", &["echo dep"]), ("a", &["echo a"]), ("ci", &["echo ci", "finally: echo done"])]).tasks);
+    }
+}
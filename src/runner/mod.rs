@@ -3,85 +3,162 @@ pub mod cache;
 pub mod portable;
 pub mod handler;
 pub mod common;
+pub mod capability;
+pub mod report;
+pub mod graph;
+pub mod watchdog;
+pub mod container;
+pub mod remote;
+pub mod status;
+pub mod history;
+pub mod plan;
+pub mod last_plan;
+pub mod run_lock;
+pub mod wait;
+pub mod resume;
+pub mod events;
+pub mod progress;
+pub mod embed;
+pub mod outputs;
+pub mod output_filter;
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 use colored::*;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use indexmap::IndexMap;
+use std::path::PathBuf;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
 use std::time::Duration;
-use rayon::prelude::*;
-use crate::config::PavidiConfig;
-use crate::utils::{detect_shell, expand_command, run_shell_command, CaptureMode};
-use crate::logger::write_log;
-use self::task::RunnerTask;
-use self::cache::{is_up_to_date, save_cache};
+use serde_json::json;
+use crate::config::{CapabilityConfig, PavidiConfig, PATH_VARS};
+use crate::utils::{detect_shell, env_diff, expand_command, run_shell_command, unset_variable, CaptureMode, LineSink, ShellKind};
+use crate::logger::{LogStream, OutputFile, RunLog};
+use crate::process::{self, ResourceLimits};
+use crate::notify::parse_duration;
+use self::task::{CommandSpec, OutputMode, RunnerTask};
+use self::cache::{invalidate_scans, is_up_to_date, save_cache, Sources};
 use self::portable::run_portable_command;
-use log::{info, error};
+use self::capability::{check_exec, network_deny_env, EXIT_NOT_PERMITTED};
+use self::report::{TaskOutcome, TaskStatus};
+use self::events::Event;
+use self::status::read_status;
+use self::watchdog::Watchdog;
+use self::output_filter::{LineFilters, OutputFilter};
+use self::container::{ContainerSpec, WORKDIR};
+use self::remote::RemoteSpec;
+use self::resume::{clear_resume_point, cmds_hash, resume_from, write_resume_point, ResumePoint};
+use log::{debug, info, error, log_enabled, Level};
 use std::time::Instant;
 use std::thread;
+use crate::ui::{self, glyph, Glyph};
 
+/// Tasks currently being entered, for cycle detection. Parallel branches share
+/// the tasks above them and only track their own.
+#[derive(Default)]
 pub struct CallStack {
-    stack: HashSet<String>,
+    ancestors: Arc<Vec<String>>,
+    local: Vec<String>,
 }
 
 impl CallStack {
     pub fn new() -> Self {
         Self {
-            stack: HashSet::new(),
+            ancestors: Arc::new(Vec::new()),
+            local: Vec::new(),
         }
     }
 
     pub fn push(&mut self, task_name: &str) -> Result<()> {
-        if self.stack.contains(task_name) {
-            bail!("🔄 Circular dependency detected: {}", task_name);
+        if self.contains(task_name) {
+            bail!("{} Circular dependency detected: {}", glyph(Glyph::Retry), task_name);
         }
-        self.stack.insert(task_name.to_string());
+        self.local.push(task_name.to_string());
         Ok(())
     }
 
     pub fn pop(&mut self, task_name: &str) {
-        self.stack.remove(task_name);
+        if let Some(i) = self.local.iter().rposition(|t| t == task_name) {
+            self.local.remove(i);
+        }
+    }
+
+    fn contains(&self, task_name: &str) -> bool {
+        self.ancestors.iter().chain(&self.local).any(|t| t == task_name)
     }
 
-    pub fn clone_stack(&self) -> Self {
+    /// A starting point for parallel branches. Cloning it is cheap: the tasks
+    /// entered so far are shared, not copied per branch.
+    pub fn branch(&self) -> Self {
+        let mut ancestors = Vec::with_capacity(self.ancestors.len() + self.local.len());
+        ancestors.extend(self.ancestors.iter().chain(&self.local).cloned());
         Self {
-            stack: self.stack.clone(),
+            ancestors: Arc::new(ancestors),
+            local: Vec::new(),
         }
     }
 }
 
-fn execute_command_list(
-    task_name: &str,
-    mut cmds: Vec<String>,
-    config: &PavidiConfig,
-    extra_args: &[String],
+impl Clone for CallStack {
+    fn clone(&self) -> Self {
+        Self {
+            ancestors: Arc::clone(&self.ancestors),
+            local: self.local.clone(),
+        }
+    }
+}
+
+/// How the commands of one task run. Its main commands and its `finally`
+/// commands share everything but the retry and failure settings.
+#[derive(Clone, Copy)]
+struct ExecOptions<'a> {
+    caps: Option<&'a CapabilityConfig>,
+    extra_args: &'a [String],
     capture_output: bool,
-    dry_run: bool,
-    shell_cmd: &str,
+    shell_cmd: &'a str,
     timeout_sec: Option<u64>,
+    warn_after: Option<Duration>,
+    container: Option<&'a ContainerSpec>,
+    remote: Option<&'a RemoteSpec>,
+    output_file: Option<&'a Arc<OutputFile>>,
+    limits: Option<&'a ResourceLimits>,
+    output_filters: &'a [OutputFilter],
+    /// Counts the commands that succeeded, for `--resume`
+    progress: Option<&'a Cell<usize>>,
     retry: u32,
     retry_delay: u64,
     ignore_failure: bool,
+    /// `finally` commands, which still run after SIGINT/SIGTERM
+    cleanup: bool,
     trace: bool,
     depth: usize,
+}
+
+fn execute_command_list(
+    task_name: &str,
+    cmds: Vec<CommandSpec>,
+    config: &PavidiConfig,
+    run: &RunLog,
+    options: &ExecOptions,
 ) -> Result<()> {
+    let ExecOptions {
+        caps, extra_args, capture_output, shell_cmd, timeout_sec, warn_after, container, remote,
+        output_file, limits, output_filters, progress, retry, retry_delay, ignore_failure, cleanup, trace, depth,
+    } = *options;
     if cmds.is_empty() {
         return Ok(());
     }
 
-    // Log configuration
-    let (log_strategy, _) = if let Some(p) = &config.project {
-        (p.log_strategy, p.log_plain)
-    } else if let Some(m) = &config.module {
-        (m.log_strategy, m.log_plain)
-    } else {
-        (None, None)
-    };
-    let log_enabled = log_strategy.unwrap_or(crate::config::LogStrategy::None) != crate::config::LogStrategy::None;
+    let log_enabled = run.settings.enabled();
 
     let capture_mode = if capture_output {
         CaptureMode::Buffer
     } else {
-        if log_enabled {
+        // Timestamps and output_filters apply as lines pass through, so they need the output piped
+        if log_enabled || run.report.captures_output() || output_file.is_some() || run.events.is_some() || run.commands.timestamps.is_some() || !output_filters.is_empty() {
             CaptureMode::Tee
         } else {
             CaptureMode::Inherit
@@ -96,55 +173,150 @@ fn execute_command_list(
 
     let retry_delay_duration = Duration::from_secs(retry_delay);
 
-    for cmd in &mut cmds {
+    // allow_net = false: poison proxy variables for spawned commands (best-effort)
+    let child_env: Cow<IndexMap<String, String>> = match network_deny_env(caps) {
+        Some(overrides) => {
+            static NET_NOTICE: Once = Once::new();
+            NET_NOTICE.call_once(|| {
+                eprintln!("{} Network disabled by allow_net = false (best-effort: proxy variables only)", glyph(Glyph::Lock).yellow());
+            });
+            let mut env = config.env.clone();
+            env.extend(overrides);
+            Cow::Owned(env)
+        },
+        None => Cow::Borrowed(&config.env),
+    };
+
+    if let Some(spec) = container {
+        spec.check_runtime(task_name)?;
+    }
+
+    let on_line: Option<LineSink> = run.events.clone().map(|events| {
+        let task = task_name.to_string();
+        Arc::new(move |line: &str, stream| events(&Event::OutputChunk { task: task.clone(), stream, line: line.to_string() })) as LineSink
+    });
+
+    for spec in &cmds {
+        let cmd = &spec.cmd;
         if trace {
             let indent = "  ".repeat(depth);
-            eprintln!("{} {} [TRACE] Raw command: '{}'", indent, "⚙️".cyan(), cmd);
+            eprintln!("{} {} [TRACE] Raw command: '{}'", indent, glyph(Glyph::Trace).cyan(), cmd);
         }
 
         // Apply Argument Expansion ($1, $2...) and Env Var Interpolation
-        let final_cmd = expand_command(cmd, extra_args, &config.env);
+        let place = task_place(container.is_some(), remote.is_some());
+        let cmd_shell = command_shell(cmd, shell_cmd, place.is_some());
+        let final_cmd = expand_task_command(cmd, extra_args, &config.env, shell_cmd, place);
 
         if trace {
             let indent = "  ".repeat(depth);
-            eprintln!("{} {} [TRACE] Expanded command: '{}'", indent, "⚙️".cyan(), final_cmd);
+            eprintln!("{} {} [TRACE] Expanded command: '{}'", indent, glyph(Glyph::Trace).cyan(), final_cmd);
         }
+        debug!("[{}] expanded: {}", task_name, final_cmd);
 
-        if dry_run {
-            println!("{} [DRY-RUN] Executing: {}", "::".yellow(), final_cmd);
-            continue;
-        }
+        // Shell commands of a task with an `image` run inside the container, those of a task
+        // with a `remote` over ssh; p: builtins stay on the host
+        let is_portable = final_cmd.trim_start().starts_with("p:");
+        let container_name = container.filter(|_| !is_portable).map(|_| ContainerSpec::next_name(&run.id));
+        let remote = remote.filter(|_| !is_portable);
+        let placed = placed_env(&child_env, place.filter(|_| !is_portable));
+        let wrap = |cmd: &str| match (container, &container_name, remote) {
+            (Some(spec), Some(name), _) => spec.command_line(name, &placed, cmd),
+            (_, _, Some(remote)) => remote.command_line(&placed, cmd, false),
+            _ => cmd.to_string(),
+        };
+        let exec_cmd = wrap(&final_cmd);
+        // strict_env: sh itself fails on unset variables, so those the command sets are fine.
+        // Shown and checked against capabilities without the prefix
+        let strict = config.strict_env() && !is_portable && cmd_shell == ShellKind::Sh;
+        let run_cmd = if strict { wrap(&format!("set -u; {}", final_cmd)) } else { exec_cmd.clone() };
+        // Values forwarded to the remote host are part of the command line; keep secrets out of the terminal
+        let shown_cmd = match remote {
+            Some(remote) => remote.command_line(&placed, &final_cmd, true),
+            None => exec_cmd.clone(),
+        };
 
         if !capture_output {
-            info!("{} Executing: {}", "::".blue(), final_cmd);
+            info!("{} Executing: {}", "::".blue(), shown_cmd);
         }
+        run.emit(Event::CommandStarted { task: task_name.to_string(), command: shown_cmd.clone() });
 
         let mut attempt = 0;
         
         loop {
+            // After SIGINT/SIGTERM nothing new starts, except `finally` commands
+            if !cleanup && let Some(sig) = process::abort_signal() {
+                bail!("{} Task '{}' stopped by {}", glyph(Glyph::Fail), task_name, process::signal_name(sig));
+            }
             let start_time = Instant::now();
-            let mut captured_output = String::new();
             let mut exit_code = 0;
             let mut execution_failed = false;
             let mut execution_error = String::new();
+            let mut output = String::new();
+            let span = run.report.open_span(&final_cmd, "command");
+
+            // Output is streamed into the log file while the command runs
+            let log_stream = if log_enabled {
+                match LogStream::start(run, task_name, &final_cmd, &config.env) {
+                    Ok(stream) => stream.map(Arc::new),
+                    Err(e) => {
+                        log::warn!("{} Logging disabled for this command: {}", glyph(Glyph::Warn).yellow(), e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            if trace && let Some(path) = log_stream.as_ref().and_then(|s| s.path()) {
+                let indent = "  ".repeat(depth);
+                eprintln!("{} {} [TRACE] Streaming log to: {}", indent, glyph(Glyph::Log).cyan(), path.display());
+            }
+
+            // Warns while the command is still running; the timeout kill below is unaffected
+            let watchdog = warn_after.map(|threshold| {
+                let (task, cmd) = (task_name.to_string(), final_cmd.clone());
+                Watchdog::start(threshold, move || {
+                    log::warn!("{} [{}] '{}' is still running after {:?} (warn_after)", glyph(Glyph::Timer).yellow(), task, cmd, threshold);
+                })
+            });
 
             // Fallback to legacy portable/shell command
-            if final_cmd.trim_start().starts_with("p:") {
-                    if let Err(e) = run_portable_command(&final_cmd, trace) {
+            if is_portable {
+                    if let Err(e) = run_portable_command(&final_cmd, caps, trace) {
                         execution_failed = true;
                         execution_error = e.to_string();
                         exit_code = 1;
                     }
+            } else if let Err(e) = check_exec(caps, &final_cmd) {
+                // Capability policy refused a program; builtins (p:) are never checked, and the
+                // programs are those of the task, not the docker/ssh wrapping them
+                execution_failed = true;
+                execution_error = e.to_string();
+                exit_code = EXIT_NOT_PERMITTED;
             } else {
-                let result = run_shell_command(&final_cmd, &config.env, capture_mode, task_name, &shell_cmd, timeout_duration);
+                let filters = (!output_filters.is_empty()).then(|| Arc::new(LineFilters::new(output_filters)));
+                let result = run_shell_command(&run_cmd, &child_env, &run.commands, capture_mode, task_name, shell_cmd, timeout_duration, log_stream.clone(), output_file.cloned(), limits, on_line.clone(), filters.clone());
+                if result.is_err()
+                    && let (Some(spec), Some(name)) = (container, &container_name) {
+                    spec.remove(name);
+                }
                 
                 match result {
-                    Ok((code, output)) => {
-                        captured_output = output;
+                    Ok((code, out)) => {
                         exit_code = code;
-                        if code != 0 {
+                        // allowed_exit_codes: listed codes succeed, and 0 fails unless listed
+                        if !spec.allows(code) {
+                            execution_failed = true;
+                            if strict && code != 0 && let Some(var) = unset_variable(&out) {
+                                execution_error = format!("Variable '{}' is not set (strict_env)", var);
+                            } else if code == 0 {
+                                execution_error = "Exit code 0 is not in allowed_exit_codes".to_string();
+                            }
+                        } else if let Some((pattern, line)) = filters.and_then(|f| f.failure()) {
                             execution_failed = true;
+                            execution_error = format!("Output matched fail filter '{}': {}", pattern, line);
                         }
+                        output = out;
                     },
                     Err(e) => {
                         execution_failed = true;
@@ -156,32 +328,65 @@ fn execute_command_list(
 
             if trace {
                  let indent = "  ".repeat(depth);
-                 eprintln!("{} {} [TRACE] Command finished in {:.2?}. Exit code: {}", indent, "⏱️".cyan(), start_time.elapsed(), exit_code);
+                 eprintln!("{} {} [TRACE] Command finished in {:.2?}. Exit code: {}", indent, glyph(Glyph::Timer).cyan(), start_time.elapsed(), exit_code);
+            }
+
+            let mut attrs = BTreeMap::new();
+            if let (Some(dog), Some(threshold)) = (watchdog, warn_after)
+                && dog.finish() {
+                let elapsed = start_time.elapsed();
+                run.report.command_slow(task_name, &final_cmd, threshold, elapsed);
+                attrs.insert("overage_ms".to_string(), json!(elapsed.saturating_sub(threshold).as_millis() as u64));
+            }
+            attrs.insert("exit_code".to_string(), json!(exit_code));
+            attrs.insert("attempt".to_string(), json!(attempt + 1));
+            if let Ok(cwd) = std::env::current_dir() {
+                attrs.insert("cwd".to_string(), json!(cwd.display().to_string()));
             }
+            if !execution_error.is_empty() {
+                attrs.insert("error".to_string(), json!(execution_error));
+            }
+            run.report.close_span(span, attrs);
+
+            let log_path = match &log_stream {
+                Some(stream) => {
+                    if !execution_error.is_empty() {
+                        stream.write_line(&format!("Execution Error: {}", execution_error));
+                    }
+                    stream.finish(run, exit_code, execution_failed).unwrap_or_else(|e| {
+                        log::warn!("{} Failed to save log: {}", glyph(Glyph::Warn).yellow(), e);
+                        None
+                    })
+                },
+                None => None,
+            };
             
             if !execution_failed {
                 // Success
-                if log_enabled {
-                        if let Ok(Some(path)) = write_log(task_name, &final_cmd, &captured_output, config, start_time.elapsed(), exit_code, &config.env) {
-                            info!("{} Log saved: {}", "📝".dimmed(), path.display());
-                        }
+                if let Some(path) = log_path {
+                    info!("{} Log saved: {}", glyph(Glyph::Log).dimmed(), path.display());
+                }
+                if run.commands.timestamps.is_some() && !capture_output {
+                    info!("{} Done in {:.1}s", glyph(Glyph::Timer).dimmed(), start_time.elapsed().as_secs_f64());
+                }
+                if let Some(done) = progress {
+                    done.set(done.get() + 1);
                 }
                 break;
             } else {
                 // Failure
-                if log_enabled {
-                    let log_content = if !execution_error.is_empty() {
-                        format!("Execution Error: {}", execution_error)
-                    } else {
-                        captured_output.clone()
-                    };
-                        let _ = write_log(task_name, &final_cmd, &log_content, config, start_time.elapsed(), exit_code, &config.env);
+                if let Some(path) = log_path {
+                    run.record_failure(path);
                 }
 
+                if !cleanup && let Some(sig) = process::abort_signal() {
+                    run.report.command_failed(task_name, &final_cmd, &format!("Stopped by {}", process::signal_name(sig)), &output);
+                    bail!("{} Task '{}' stopped by {} at: '{}'", glyph(Glyph::Fail), task_name, process::signal_name(sig), final_cmd);
+                }
                 if attempt < retry {
                     attempt += 1;
                     if !capture_output {
-                        info!("{} Command failed. Retrying ({}/{}) in {}s...", "🔄".yellow(), attempt, retry, retry_delay_duration.as_secs());
+                        info!("{} Command failed. Retrying ({}/{}) in {}s...", glyph(Glyph::Retry).yellow(), attempt, retry, retry_delay_duration.as_secs());
                     }
                     thread::sleep(retry_delay_duration);
                     continue;
@@ -189,16 +394,21 @@ fn execute_command_list(
                     // All retries failed
                     if ignore_failure {
                             if !execution_error.is_empty() {
-                            log::warn!("{} Command failed but ignored: {}", "⚠️".yellow(), execution_error);
+                            log::warn!("{} Command failed but ignored: {}", glyph(Glyph::Warn).yellow(), execution_error);
                             } else {
-                            log::warn!("{} Command failed but ignored (code {})", "⚠️".yellow(), exit_code);
+                            log::warn!("{} Command failed but ignored (code {})", glyph(Glyph::Warn).yellow(), exit_code);
+                            }
+                            if let Some(done) = progress {
+                                done.set(done.get() + 1);
                             }
                             break;
                     } else {
+                            let message = if execution_error.is_empty() { format!("Exit code {}", exit_code) } else { execution_error.clone() };
+                            run.report.command_failed(task_name, &final_cmd, &message, &output);
                             if !execution_error.is_empty() {
-                            bail!("❌ Task '{}' failed at: '{}' -> {}", task_name, final_cmd, execution_error);
+                            bail!("{} Task '{}' failed at: '{}' -> {}", glyph(Glyph::Fail), task_name, final_cmd, execution_error);
                             } else {
-                            bail!("❌ Task '{}' failed at: '{}' -> Exit code {}", task_name, final_cmd, exit_code);
+                            bail!("{} Task '{}' failed at: '{}' -> Exit code {}", glyph(Glyph::Fail), task_name, final_cmd, exit_code);
                             }
                     }
                 }
@@ -208,16 +418,196 @@ fn execute_command_list(
     Ok(())
 }
 
+/// The shell that parses `cmd`, for quoting the arguments appended to it: `p:`
+/// builtins are split like sh, and in a container or on a remote host sh runs it.
+fn command_shell(cmd: &str, shell_cmd: &str, wrapped: bool) -> ShellKind {
+    if wrapped || cmd.trim_start().starts_with("p:") {
+        ShellKind::Sh
+    } else {
+        ShellKind::detect(shell_cmd)
+    }
+}
+
+/// Where the commands of a container or remote task run, as `P_ROOT`, `P_CONFIG_DIR`
+/// and `P_TASK_DIR` name it there: the mount point in a container, and on a remote
+/// host the directory its shell is in (`remote_cwd`, else the login directory).
+pub(crate) fn task_place(container: bool, remote: bool) -> Option<&'static str> {
+    if container {
+        Some(WORKDIR)
+    } else if remote {
+        Some("$PWD")
+    } else {
+        None
+    }
+}
+
+/// `env` with the path variables set to `place`, for a command that does not run on the host.
+pub(crate) fn placed_env<'a>(env: &'a IndexMap<String, String>, place: Option<&str>) -> Cow<'a, IndexMap<String, String>> {
+    let Some(dir) = place else { return Cow::Borrowed(env) };
+    let mut env = env.clone();
+    for name in PATH_VARS {
+        env.insert(name.to_string(), dir.to_string());
+    }
+    Cow::Owned(env)
+}
+
+/// A task command as its shell receives it: `$1`/`$@` and `${VAR}` expanded,
+/// arguments quoted for that shell. Runs, plans and `--list-cmds` all expand here.
+/// `place` is from [`task_place`]; `p:` builtins run on the host wherever the task runs.
+pub(crate) fn expand_task_command(cmd: &str, extra_args: &[String], env: &IndexMap<String, String>, shell_cmd: &str, place: Option<&str>) -> String {
+    let env = placed_env(env, place.filter(|_| !cmd.trim_start().starts_with("p:")));
+    expand_command(cmd, extra_args, &env, command_shell(cmd, shell_cmd, place.is_some()))
+}
+
+/// The shell task commands run in: `shell` from `[project]` or `[module]`, else the system's.
+pub(crate) fn task_shell(config: &PavidiConfig) -> String {
+    let shell_pref = config.project.as_ref().and_then(|p| p.shell.as_ref())
+        .or(config.module.as_ref().and_then(|m| m.shell.as_ref()));
+    detect_shell(shell_pref)
+}
+
+/// The main commands of `task_name` on this OS, expanded as a run would expand
+/// them, without its dependencies, fallbacks or `finally`. A container or remote
+/// task lists what runs inside the container or on the host.
+pub fn task_commands(task_name: &str, config: &PavidiConfig, extra_args: &[String]) -> Result<Vec<String>> {
+    let task = config.runner.as_ref().and_then(|r| r.get(task_name))
+        .with_context(|| format!("Task '{}' not found", task_name))?;
+    let place = task_place(task.container(config.default_container_runtime(), &config.root_dir).is_some(), task.remote().is_some());
+    let shell_cmd = task_shell(config);
+    Ok(task.command_specs_for(std::env::consts::OS)?.iter()
+        .map(|spec| expand_task_command(&spec.cmd, extra_args, &config.env, &shell_cmd, place))
+        .collect())
+}
+
+/// Where a task's `output_file` goes: `${VAR}` and `{{project.name}}` /
+/// `{{project.version}}` expanded, relative paths under the project root.
+fn output_file_path(template: &str, config: &PavidiConfig) -> PathBuf {
+    let metadata = config.project.as_ref().map(|p| &p.metadata)
+        .or(config.module.as_ref().map(|m| &m.metadata));
+    let mut path = expand_command(template, &[], &config.env, ShellKind::Sh);
+    for (placeholder, value) in [
+        ("{{project.name}}", metadata.and_then(|m| m.name.as_deref())),
+        ("{{project.version}}", metadata.and_then(|m| m.version.as_deref())),
+    ] {
+        path = path.replace(placeholder, value.unwrap_or(""));
+    }
+    config.root_dir.join(path)
+}
+
+/// The order parallel dependencies start in: higher `priority` first, then
+/// the longest duration recorded in `.p/status.json`, then as declared.
+fn start_order(deps: &[String], config: &PavidiConfig) -> Vec<String> {
+    let last_run = read_status(&config.root_dir).ok().flatten();
+    let priority = |dep: &String| config.runner.as_ref().and_then(|r| r.get(dep)).map_or(0, RunnerTask::priority);
+    let duration = |dep: &String| last_run.as_ref().and_then(|s| s.task(dep)).map_or(0, |t| t.duration_ms);
+
+    let mut order = deps.to_vec();
+    // Stable, so ties keep their declared order
+    order.sort_by_key(|dep| (Reverse(priority(dep)), Reverse(duration(dep))));
+    order
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn recursive_runner(
     task_name: &str, 
     config: &PavidiConfig, 
+    run: &RunLog,
     call_stack: &mut CallStack,
     extra_args: &[String],
     capture_output: bool, // true = buffer output (for parallel), false = inherit
-    dry_run: bool,
     trace: bool,
     depth: usize,
 ) -> Result<()> {
+    run_recorded(task_name, config, run, call_stack, extra_args, capture_output, trace, depth).map(|_| ())
+}
+
+/// [`recursive_runner`], keeping whether the task ran or was skipped.
+#[allow(clippy::too_many_arguments)]
+fn run_recorded(
+    task_name: &str,
+    config: &PavidiConfig,
+    run: &RunLog,
+    call_stack: &mut CallStack,
+    extra_args: &[String],
+    capture_output: bool,
+    trace: bool,
+    depth: usize,
+) -> Result<TaskOutcome> {
+    let started = Instant::now();
+    run.emit(Event::TaskStarted { task: task_name.to_string() });
+    let span = run.report.open_span(task_name, "task");
+    let result = run_task(task_name, config, run, call_stack, extra_args, capture_output, trace, depth);
+
+    let mut attrs = BTreeMap::new();
+    attrs.insert("status".to_string(), json!(if result.is_ok() { "ok" } else { "failed" }));
+    if let Ok(TaskOutcome::Skipped(reason)) = &result {
+        attrs.insert("skipped".to_string(), json!(reason));
+        attrs.insert("cached".to_string(), json!(*reason == "up-to-date"));
+    }
+    run.report.close_span(span, attrs);
+    run.report.record(task_name, started, &result);
+    let status = match &result {
+        Ok(TaskOutcome::Ran) => TaskStatus::Passed,
+        Ok(TaskOutcome::Skipped(_)) => TaskStatus::Skipped,
+        Err(_) => TaskStatus::Failed,
+    };
+    let cached = matches!(result, Ok(TaskOutcome::Skipped("up-to-date")));
+    run.emit(Event::TaskFinished { task: task_name.to_string(), status, duration: started.elapsed(), cached });
+    result
+}
+
+/// Tries each of `chain` in order and stops at the first that succeeds (or is
+/// skipped as up-to-date or by `skip_if`; one skipped by `run_if` does not count). Every
+/// attempt runs on its own copy of the call stack, so a failed one leaves no trace
+/// in it; its report entry still shows it as failed.
+#[allow(clippy::too_many_arguments)]
+fn run_fallbacks(
+    task_name: &str,
+    chain: &[String],
+    config: &PavidiConfig,
+    run: &RunLog,
+    call_stack: &CallStack,
+    capture_output: bool,
+    trace: bool,
+    depth: usize,
+) -> Result<()> {
+    let runner = config.runner.as_ref().unwrap();
+    let mut failures = vec![];
+    for (i, alternative) in chain.iter().enumerate() {
+        if !runner.contains_key(alternative) {
+            bail!("Fallback '{}' of task '{}' not found", alternative, task_name);
+        }
+        if trace {
+            eprintln!("{} [TRACE] Trying fallback {} of {}: {}", "  ".repeat(depth), i + 1, chain.len(), alternative);
+        }
+        let mut attempt_stack = call_stack.clone();
+        let (outcome, reason) = match run_recorded(alternative, config, run, &mut attempt_stack, &[], capture_output, trace, depth + 1) {
+            // A failed run_if means the alternative does not apply here (its tool is missing)
+            Ok(TaskOutcome::Skipped("run_if")) => ("skipped", "run_if condition failed".to_string()),
+            Ok(_) => return Ok(()),
+            // Ctrl+C stops the chain rather than moving on to the next alternative
+            Err(e) if process::abort_signal().is_some() => return Err(e),
+            Err(e) => ("failed", e.to_string().trim_start_matches(glyph(Glyph::Fail)).trim_start().to_string()),
+        };
+        if let Some(next) = chain.get(i + 1) {
+            log::warn!("{} '{}' {}, trying '{}'", glyph(Glyph::Warn).yellow(), alternative, outcome, next);
+        }
+        failures.push(format!("  {}: {}", alternative, reason));
+    }
+    bail!("{} Task '{}' failed: no fallback succeeded:\n{}", glyph(Glyph::Fail), task_name, failures.join("\n"))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_task(
+    task_name: &str,
+    config: &PavidiConfig,
+    run: &RunLog,
+    call_stack: &mut CallStack,
+    extra_args: &[String],
+    capture_output: bool,
+    trace: bool,
+    depth: usize,
+) -> Result<TaskOutcome> {
     if trace {
         let indent = "  ".repeat(depth);
         eprintln!("{} [TRACE] Entering task: {}", indent, task_name.bold());
@@ -230,62 +620,100 @@ pub fn recursive_runner(
     let task = runner_section.get(task_name).expect("Task check passed before");
 
     // Destructure task config
-    let (mut cmds, deps, parallel_deps, run_if, skip_if, sources, outputs, windows, linux, macos, ignore_failure, timeout_sec, retry, retry_delay, finally_cmds) = match task {
-        RunnerTask::Single(cmd) => (vec![cmd.clone()], vec![], false, None, None, None, None, None, None, None, false, None, None, None, None),
-        RunnerTask::List(cmds) => (cmds.clone(), vec![], false, None, None, None, None, None, None, None, false, None, None, None, None),
-        RunnerTask::Full { cmds, deps, parallel, run_if, skip_if, sources, outputs, windows, linux, macos, ignore_failure, timeout, retry, retry_delay, finally, .. } => 
-            (cmds.clone(), deps.clone(), *parallel, run_if.clone(), skip_if.clone(), sources.clone(), outputs.clone(), windows.clone(), linux.clone(), macos.clone(), *ignore_failure, *timeout, *retry, *retry_delay, finally.clone()),
+    let (deps, parallel_deps, run_if, skip_if, sources, outputs, ignore_failure, timeout_sec, retry, retry_delay, finally_cmds) = match task {
+        RunnerTask::Single(_) | RunnerTask::List(_) | RunnerTask::Template(_) => (vec![], false, None, None, None, None, false, None, None, None, None),
+        RunnerTask::Full { deps, parallel, run_if, skip_if, outputs, ignore_failure, timeout, retry, retry_delay, finally, .. } => 
+            (deps.clone(), *parallel, run_if.clone(), skip_if.clone(), Sources::of(task, config), outputs.clone(), *ignore_failure, *timeout, *retry, *retry_delay, finally.clone()),
     };
 
+    let container = task.container(config.default_container_runtime(), &config.root_dir);
+    let remote = task.remote();
+    if container.is_some() && remote.is_some() {
+        bail!("Task '{}' sets both image and remote; a task runs either in a container or on a remote host", task_name);
+    }
+    let limits = task.resource_limits()
+        .with_context(|| format!("Invalid max_memory for task '{}'", task_name))?;
+    // Applied to the local process only: the container runtime or ssh client here
+    if !limits.is_empty() && (container.is_some() || remote.is_some()) {
+        log::warn!("{} nice and max_memory of task '{}' are ignored for container and remote commands", glyph(Glyph::Warn).yellow(), task_name);
+    }
+    let limits = Some(limits).filter(|l| !l.is_empty() && container.is_none() && remote.is_none());
+    let warn_after = task.warn_after().or(config.default_warn_after())
+        .map(parse_duration)
+        .transpose()
+        .with_context(|| format!("Invalid warn_after for task '{}'", task_name))?;
+
+    // Effective capabilities: project policy plus this task's own grants.
+    // Computed per task, so grants never leak into dependencies.
+    let task_caps = config.capability.as_ref().map(|base| match task.capabilities() {
+        Some(grants) => base.with_grants(grants),
+        None => base.clone(),
+    }.canonicalized());
+
     // 1. Run Dependencies
     if !deps.is_empty() {
         if parallel_deps {
             if !capture_output {
-                info!("{} Running dependencies in parallel: {:?}...", "🚀".cyan(), deps);
+                info!("{} Running dependencies in parallel: {:?}...", glyph(Glyph::Parallel).cyan(), deps);
             }
             
-            // Snapshot the stack to avoid capturing &mut CallStack in the closure
-            let stack_snapshot = call_stack.clone_stack();
-
-            // Rayon parallel iterator
-            let errors: Vec<String> = deps
-                .par_iter()
-                .map(|dep_name| {
-                    let mut local_stack = stack_snapshot.clone_stack();
- 
-                    // Parallel deps MUST capture output to prevent mixed logs
-                    // Note: Depth increments for parallel tasks too, but trace output might be interleaved
-                    recursive_runner(dep_name, config, &mut local_stack, &[], true, dry_run, trace, depth + 1)
-                        .map_err(|e| format!("Dep '{}' failed: {}", dep_name, e))
-                })
-                .filter_map(|res| res.err())
-                .collect();
+            // Snapshot the stack to avoid capturing &mut CallStack in the closure;
+            // branches share it and only track the tasks they enter themselves
+            let stack_snapshot = call_stack.branch();
+            let parent_span = run.report.current_span();
+
+            // An ordered queue rather than par_iter, so the pool's threads pick
+            // up the dependencies in start_order as they free up
+            let order = start_order(&deps, config);
+            let next = AtomicUsize::new(0);
+            let failures = Mutex::new(vec![]);
+            let workers = rayon::current_num_threads().min(order.len());
+            rayon::scope(|scope| {
+                for _ in 0..workers {
+                    scope.spawn(|_| {
+                        while let Some(dep_name) = order.get(next.fetch_add(1, Ordering::SeqCst)) {
+                            let mut local_stack = stack_snapshot.clone();
+
+                            // Parallel deps MUST capture output to prevent mixed logs
+                            // Note: Depth increments for parallel tasks too, but trace output might be interleaved
+                            let result = run.report.in_lane(parent_span, || {
+                                recursive_runner(dep_name, config, run, &mut local_stack, &[], true, trace, depth + 1)
+                            });
+                            if let Err(e) = result {
+                                failures.lock().unwrap().push((dep_name, format!("Dep '{}' failed: {}", dep_name, e)));
+                            }
+                        }
+                    });
+                }
+            });
+            // Reported by task name, whichever finished first
+            let mut failures = failures.into_inner().unwrap();
+            failures.sort();
+            let errors: Vec<String> = failures.into_iter().map(|(_, e)| e).collect();
 
             if !errors.is_empty() {
-                for e in &errors { error!("{} {}", "❌".red(), e); }
+                for e in &errors { error!("{} {}", glyph(Glyph::Fail).red(), e); }
                 bail!("Dependency execution failed.");
             }
         } else {
             if !capture_output {
-                info!("{} Running dependencies sequentially...", "🔗".blue());
+                info!("{} Running dependencies sequentially...", glyph(Glyph::Sequence).blue());
             }
             for dep in deps {
-                recursive_runner(&dep, config, call_stack, &[], capture_output, dry_run, trace, depth + 1)?;
+                recursive_runner(&dep, config, run, call_stack, &[], capture_output, trace, depth + 1)?;
             }
         }
     }
 
     // 2. Logic Gates (Conditional Execution)
     // Detect shell (needed for condition checks)
-    let shell_pref = config.project.as_ref().and_then(|p| p.shell.as_ref())
-        .or(config.module.as_ref().and_then(|m| m.shell.as_ref()));
-    let shell_cmd = detect_shell(shell_pref);
+    let shell_cmd = task_shell(config);
 
     // skip_if
     if let Some(raw_cmd) = skip_if {
-        let cmd = expand_command(&raw_cmd, extra_args, &config.env);
+        let cmd = expand_command(&raw_cmd, extra_args, &config.env, ShellKind::detect(&shell_cmd));
         // Silent execution
-        let (code, _) = run_shell_command(&cmd, &config.env, CaptureMode::Buffer, task_name, &shell_cmd, None)?;
+        let (code, _) = run_shell_command(&cmd, &config.env, &run.commands, CaptureMode::Buffer, task_name, &shell_cmd, None, None, None, None, None, None)?;
         
         if trace {
              eprintln!("{} [TRACE] skip_if check: '{}' -> exit code {}", "  ".repeat(depth), cmd, code);
@@ -293,18 +721,18 @@ pub fn recursive_runner(
 
         if code == 0 {
             if !capture_output {
-                info!("{} Skipping task '{}' because 'skip_if' condition met.", "⏭️".yellow(), task_name.bold());
+                info!("{} Skipping task '{}' because 'skip_if' condition met.", glyph(Glyph::Skip).yellow(), task_name.bold());
             }
             call_stack.pop(task_name);
-            return Ok(());
+            return Ok(TaskOutcome::Skipped("skip_if"));
         }
     }
 
     // run_if
     if let Some(raw_cmd) = run_if {
-        let cmd = expand_command(&raw_cmd, extra_args, &config.env);
+        let cmd = expand_command(&raw_cmd, extra_args, &config.env, ShellKind::detect(&shell_cmd));
         // Silent execution
-        let (code, _) = run_shell_command(&cmd, &config.env, CaptureMode::Buffer, task_name, &shell_cmd, None)?;
+        let (code, _) = run_shell_command(&cmd, &config.env, &run.commands, CaptureMode::Buffer, task_name, &shell_cmd, None, None, None, None, None, None)?;
 
         if trace {
              eprintln!("{} [TRACE] run_if check: '{}' -> exit code {}", "  ".repeat(depth), cmd, code);
@@ -312,91 +740,149 @@ pub fn recursive_runner(
 
         if code != 0 {
             if !capture_output {
-                info!("{} Skipping task '{}' because 'run_if' condition failed.", "⏭️".yellow(), task_name.bold());
+                info!("{} Skipping task '{}' because 'run_if' condition failed.", glyph(Glyph::Skip).yellow(), task_name.bold());
             }
             call_stack.pop(task_name);
-            return Ok(());
+            return Ok(TaskOutcome::Skipped("run_if"));
         }
     }
 
+    // Files made out-of-band, before the cache check reads them as sources
+    let files_ready = wait::require_files(task_name, task.require_files(), &config.root_dir)
+        .and_then(|_| match task.wait_for() {
+            Some(spec) => wait::wait_for_files(task_name, spec, &config.root_dir, capture_output),
+            None => Ok(()),
+        });
+    if let Err(e) = files_ready {
+        call_stack.pop(task_name);
+        return Err(e);
+    }
+
     // 3. Check Conditional Execution (Cache Check)
-    if let (Some(srcs), Some(outs)) = (&sources, &outputs) {
-        if is_up_to_date(task_name, srcs, outs, &config.env, trace)? {
-            if !capture_output {
-                info!("{} Task '{}' is up-to-date. Skipping.", "✨".green(), task_name.bold());
-            }
-            call_stack.pop(task_name);
-            return Ok(());
+    if let (Some(srcs), Some(outs)) = (&sources, &outputs)
+        && is_up_to_date(task_name, srcs, outs, &config.env, trace)? {
+        if !capture_output {
+            info!("{} Task '{}' is up-to-date. Skipping.", glyph(Glyph::Cached).green(), task_name.bold());
         }
+        call_stack.pop(task_name);
+        return Ok(TaskOutcome::Skipped("up-to-date"));
     }
 
-    // 4. Execute Main Commands
+    // 4. Fallbacks: the first alternative to succeed stands in for the rest
+    if !task.fallbacks().is_empty()
+        && let Err(e) = run_fallbacks(task_name, task.fallbacks(), config, run, call_stack, capture_output, trace, depth) {
+        call_stack.pop(task_name);
+        return Err(e);
+    }
+
+    // 5. Execute Main Commands
 
     // OS Detection & Command Selection
     let os = std::env::consts::OS;
-    let os_cmds = match os {
-        "windows" => windows.as_ref(),
-        "linux" => linux.as_ref(),
-        "macos" => macos.as_ref(),
-        _ => None,
-    };
-
     if trace {
-        let selected = if os_cmds.is_some() { os } else { "default" };
+        let selected = if task.os_commands(os).is_some() { os } else { "default" };
         eprintln!("{} [TRACE] OS Selection: System is '{}'. Selected commands from: '{}'", "  ".repeat(depth), os, selected);
     }
+    let specs = task.command_specs_for(os)?;
+    let cmds: Vec<String> = specs.iter().map(|spec| spec.cmd.clone()).collect();
+    // --resume skips the commands that succeeded before the last failure
+    let resumed = if run.resume && depth == 0 { resume_from(&config.root_dir, task_name, &cmds) } else { 0 };
+    let progress = Cell::new(resumed);
 
-    if let Some(c) = os_cmds {
-        cmds = c.clone();
-    } 
-
-    let has_os_config = windows.is_some() || linux.is_some() || macos.is_some();
-    if cmds.is_empty() && has_os_config {
-         bail!("No commands defined for this OS ({})", os);
+    // CI log group around the task's own commands (dependencies ran above)
+    let grouped = !capture_output && !cmds.is_empty();
+    if grouped {
+        ui::group_start(task_name);
+        info!("{} Running task: {}", glyph(Glyph::Run).yellow(), task_name.bold());
     }
 
-    if !capture_output && !cmds.is_empty() {
-        info!("{} Running task: {}", "⚡".yellow(), task_name.bold());
+    if log_enabled!(Level::Debug) && !cmds.is_empty() {
+        let base: IndexMap<String, String> = std::env::vars().collect();
+        for line in env_diff(&config.env, &base) {
+            debug!("[{}] env {}", task_name, line);
+        }
     }
 
-    let main_result = execute_command_list(
-        task_name,
-        cmds,
-        config,
+    // Opened once, so the task's commands and `finally` all land in it
+    let output = match task.output_file() {
+        Some((template, mode)) => {
+            let path = output_file_path(template, config);
+            if trace {
+                eprintln!("{} [TRACE] Copying output to: {}", "  ".repeat(depth), path.display());
+            }
+            let file = OutputFile::open(&path, mode == OutputMode::Append, run.settings.plain)
+                .with_context(|| format!("Task '{}' cannot write its output_file", task_name))?;
+            Some(Arc::new(file))
+        },
+        None => None,
+    };
+
+    // push_files go up before the commands, pull_files come back only if they succeeded
+    let push_result = match &remote {
+        Some(remote) => remote.check_tools(task_name, &config.env).and_then(|_| remote.push(&config.env, run.commands.env_filter.as_ref())),
+        None => Ok(()),
+    };
+    let options = ExecOptions {
+        caps: task_caps.as_ref(),
         extra_args,
         capture_output,
-        dry_run,
-        &shell_cmd,
+        shell_cmd: &shell_cmd,
         timeout_sec,
-        retry.unwrap_or(0),
-        retry_delay.unwrap_or(0),
+        warn_after,
+        container: container.as_ref(),
+        remote: remote.as_ref(),
+        output_file: output.as_ref(),
+        limits: limits.as_ref(),
+        output_filters: task.output_filters(),
+        progress: Some(&progress),
+        retry: retry.unwrap_or(0),
+        retry_delay: retry_delay.unwrap_or(0),
         ignore_failure,
+        cleanup: false,
         trace,
-        depth
-    );
+        depth,
+    };
+    let main_result = push_result
+        .and_then(|_| execute_command_list(task_name, specs[resumed..].to_vec(), config, run, &options))
+        .and_then(|_| match &remote {
+            Some(remote) => remote.pull(&config.env, run.commands.env_filter.as_ref()),
+            None => Ok(()),
+        });
 
-    // 5. Execute Finally Commands
+    // Where to pick up with --resume; only worth keeping for tasks with several commands,
+    // and only in a project (a config built in memory has no root to keep it under)
+    if cmds.len() > 1 && !config.root_dir.as_os_str().is_empty() {
+        match &main_result {
+            Err(_) => {
+                let place = task_place(container.is_some(), remote.is_some());
+                let point = ResumePoint {
+                    run_id: run.id.clone(),
+                    failed_at: progress.get().min(cmds.len() - 1),
+                    commands: cmds.iter().map(|c| expand_task_command(c, extra_args, &config.env, &shell_cmd, place)).collect(),
+                    cmds_hash: cmds_hash(&cmds),
+                };
+                if let Err(e) = write_resume_point(&config.root_dir, task_name, &point) {
+                    log::warn!("{} Failed to record where '{}' stopped: {:#}", glyph(Glyph::Warn).yellow(), task_name, e);
+                }
+            },
+            Ok(_) => clear_resume_point(&config.root_dir, task_name),
+        }
+    }
+
+    // 6. Execute Finally Commands
     let mut finally_result = Ok(());
     if let Some(f_cmds) = finally_cmds {
         if !capture_output {
-             info!("{} Running cleanup for: {}", "🧹".magenta(), task_name.bold());
+             info!("{} Running cleanup for: {}", glyph(Glyph::Cleanup).magenta(), task_name.bold());
         }
-        finally_result = execute_command_list(
-            task_name,
-            f_cmds,
-            config,
-            extra_args,
-            capture_output,
-            dry_run,
-            &shell_cmd,
-            timeout_sec,
-            0, 
-            0,
-            false,
-            trace,
-            depth
-        );
+        let cleanup = ExecOptions { progress: None, retry: 0, retry_delay: 0, ignore_failure: false, cleanup: true, ..options };
+        finally_result = execute_command_list(task_name, f_cmds.into_iter().map(CommandSpec::from).collect(), config, run, &cleanup);
+    }
+    if grouped {
+        ui::group_end();
     }
+    // The commands may have changed files that other tasks list as sources
+    invalidate_scans();
     
     call_stack.pop(task_name);
 
@@ -411,7 +897,442 @@ pub fn recursive_runner(
             if trace {
                  eprintln!("{} [TRACE] Exiting task: {} (Duration: {:.2?})", "  ".repeat(depth), task_name.bold(), task_start.elapsed());
             }
-            Ok(())
+            Ok(TaskOutcome::Ran)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+    use std::fs;
+
+    fn grant_config(dir: &std::path::Path, parent_deps: &str) -> PavidiConfig {
+        let toml_src = format!(r#"
+[capability]
+allow_paths = ['{base}']
+
+[runner.child]
+cmds = ["p:mkdir -p '{zone}/child'"]
+
+[runner.parent]
+cmds = ["p:mkdir -p '{zone}/parent'"]
+deps = [{deps}]
+
+[runner.parent.capabilities]
+allow_paths = ['{zone}']
+"#, base = dir.join("base").display(), zone = dir.join("zone").display(), deps = parent_deps);
+        toml::from_str(&toml_src).unwrap()
+    }
+
+    #[test]
+    fn test_task_grant_applies_to_task() {
+        let dir = scratch("grant");
+        let config = grant_config(&dir, "");
+        recursive_runner("parent", &config, &RunLog::new("parent", &config), &mut CallStack::new(), &[], true, false, 0).unwrap();
+        assert!(dir.join("zone/parent").is_dir());
+    }
+
+    #[test]
+    fn test_task_grant_not_inherited_by_deps() {
+        let dir = scratch("grant_dep");
+        let config = grant_config(&dir, "\"child\"");
+        let result = recursive_runner("parent", &config, &RunLog::new("parent", &config), &mut CallStack::new(), &[], true, false, 0);
+        assert!(result.is_err());
+        assert!(!dir.join("zone/child").exists());
+    }
+
+    #[test]
+    fn test_trace_spans_follow_dependency_graph() {
+        let config: PavidiConfig = toml::from_str(r#"
+[runner.a]
+cmds = ["sleep 0.2"]
+
+[runner.b]
+cmds = ["sleep 0.2"]
+
+[runner.root]
+deps = ["a", "b"]
+parallel = true
+cmds = ["echo done"]
+"#).unwrap();
+        let run = RunLog::new("root", &config);
+        recursive_runner("root", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap();
+
+        let spans = run.report.spans();
+        let find = |name: &str| spans.iter().position(|s| s.name == name).unwrap();
+        let (root, a, b) = (find("root"), find("a"), find("b"));
+        assert_eq!(spans[root].parent, None);
+        assert_eq!(spans[a].parent, Some(root));
+        assert_eq!(spans[b].parent, Some(root));
+        assert_eq!(spans[find("echo done")].parent, Some(root));
+        assert_eq!(spans[find("echo done")].tid, spans[root].tid);
+
+        // Parallel deps get their own lanes, and their commands stay on them
+        assert_ne!(spans[a].tid, spans[b].tid);
+        assert_ne!(spans[a].tid, spans[root].tid);
+        let a_cmd = spans.iter().position(|s| s.category == "command" && s.parent == Some(a)).unwrap();
+        assert_eq!(spans[a_cmd].tid, spans[a].tid);
+        assert_eq!(spans[a_cmd].args["exit_code"], 0);
+        if rayon::current_num_threads() > 1 {
+            assert!(spans[a].start < spans[b].start + spans[b].duration && spans[b].start < spans[a].start + spans[a].duration);
+        }
+
+        let trace: serde_json::Value = serde_json::from_str(&report::render_trace_events(&run.id, &spans).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), spans.len() + 1);
+        for event in &events[1..] {
+            assert_eq!(event["ph"], "X");
+            assert!(event["ts"].is_u64() && event["dur"].is_u64());
+        }
+        let a_event = events.iter().find(|e| e["name"] == "a").unwrap();
+        assert_eq!(a_event["args"]["parent_id"], root);
+    }
+
+    #[test]
+    fn test_output_file_copies_task_output() {
+        let dir = scratch("output_file");
+        let config: PavidiConfig = toml::from_str(&format!(r#"
+[project]
+name = "demo"
+
+[env]
+REPORTS = "{dir}/reports"
+
+[runner.test]
+cmds = ["printf '\\033[32mok\\033[0m\\n'", "echo oops >&2"]
+finally = ["echo cleanup"]
+output_file = "${{REPORTS}}/{{{{project.name}}}}.txt"
+
+[runner.again]
+cmds = ["echo second"]
+output_file = "{dir}/reports/demo.txt"
+output_mode = "append"
+"#, dir = dir.display())).unwrap();
+        let report = dir.join("reports/demo.txt");
+
+        for capture_output in [true, false] {
+            let run = RunLog::new("test", &config);
+            recursive_runner("test", &config, &run, &mut CallStack::new(), &[], capture_output, false, 0).unwrap();
+            // Overwritten on every run; ANSI codes stripped, stderr included
+            let contents = fs::read_to_string(&report).unwrap();
+            let mut lines: Vec<&str> = contents.lines().collect();
+            lines[..2].sort();
+            assert_eq!(lines, ["ok", "oops", "cleanup"]);
+        }
+
+        let run = RunLog::new("again", &config);
+        recursive_runner("again", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap();
+        assert!(fs::read_to_string(&report).unwrap().ends_with("cleanup\nsecond\n"));
+    }
+
+    #[test]
+    fn test_parallel_deps_start_by_priority() {
+        let dir = scratch("priority");
+        let log = dir.join("order");
+        let task = |name: &str, priority: &str| format!("[runner.{name}]\ncmds = [\"echo {name} >> '{}'\"]\n{priority}\n", log.display());
+        let mut config: PavidiConfig = toml::from_str(&[
+            task("a", ""),
+            task("low", "priority = 1"),
+            task("b", ""),
+            task("high", "priority = 10"),
+            "[runner.root]\ndeps = [\"a\", \"low\", \"b\", \"high\"]\nparallel = true\n".to_string(),
+        ].concat()).unwrap();
+        config.root_dir = dir.to_path_buf();
+        // The last run saw `b` take longer than `a`
+        fs::create_dir_all(dir.join(".p")).unwrap();
+        fs::write(dir.join(".p/status.json"), r#"{"version": 1, "run_id": "x", "task": "root", "status": "passed", "exit_code": 0,
+            "started": "2024-05-01T12:00:00+00:00", "finished": "2024-05-01T12:00:01+00:00", "duration_ms": 1000, "log_dir": ".p/logs",
+            "tasks": [{"name": "a", "status": "passed", "duration_ms": 10, "cached": false},
+                      {"name": "b", "status": "passed", "duration_ms": 900, "cached": false}]}"#).unwrap();
+
+        // One thread: the dependencies run one at a time, in start order
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        pool.install(|| {
+            recursive_runner("root", &config, &RunLog::new("root", &config), &mut CallStack::new(), &[], true, false, 0)
+        }).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "high\nlow\nb\na\n");
+    }
+
+    #[test]
+    fn test_strict_env_fails_on_unset_variables() {
+        let tasks = r#"
+[env]
+TARGET = "out"
+
+[runner.typo]
+cmds = ["echo $TRAGET/"]
+
+[runner.fine]
+cmds = ["echo $TARGET ${TRAGET:-none}", "for f in a b; do echo $f; done"]
+"#;
+        let run = |config: &PavidiConfig, task: &str| {
+            recursive_runner(task, config, &RunLog::new(task, config), &mut CallStack::new(), &[], true, false, 0)
+        };
+        let lenient: PavidiConfig = toml::from_str(tasks).unwrap();
+        assert!(run(&lenient, "typo").is_ok());
+
+        let strict: PavidiConfig = toml::from_str(&format!("[project]\nstrict_env = true\n{}", tasks)).unwrap();
+        let err = run(&strict, "typo").unwrap_err().to_string();
+        assert!(err.contains("'echo $TRAGET/' -> Variable 'TRAGET' is not set (strict_env)"), "{}", err);
+        assert!(run(&strict, "fine").is_ok());
+    }
+
+    #[test]
+    fn test_allowed_exit_codes_count_as_success() {
+        let mut config: PavidiConfig = toml::from_str(r#"
+[runner.strict]
+cmds = ["exit 1", "echo unreachable"]
+
+[runner.grep]
+allowed_exit_codes = [0, 1]
+cmds = ["exit 1", "exit 0"]
+
+[runner.inline]
+cmds = [{ cmd = "exit 3", allowed_exit_codes = [3] }, "exit 0"]
+
+[runner.expect-failure]
+cmds = [{ cmd = "exit 0", allowed_exit_codes = [1] }]
+"#).unwrap();
+        let dir = scratch("allowed_exit_codes");
+        config.root_dir = dir.to_path_buf();
+        let run = |task: &str| recursive_runner(task, &config, &RunLog::new(task, &config), &mut CallStack::new(), &[], true, false, 0);
+        assert!(run("strict").unwrap_err().to_string().contains("'exit 1' -> Exit code 1"));
+        assert!(run("grep").is_ok());
+        assert!(run("inline").is_ok());
+        assert!(run("expect-failure").unwrap_err().to_string().contains("Exit code 0 is not in allowed_exit_codes"));
+        assert!(config.root_dir.join(".p/resume/strict.json").exists());
+    }
+
+    #[test]
+    fn test_warn_after_records_overage() {
+        let config: PavidiConfig = toml::from_str(r#"
+[project]
+name = "x"
+warn_after = "1h"
+
+[runner.slow]
+cmds = ["sleep 0.3", "true"]
+warn_after = "50ms"
+timeout = 5
+
+[runner.fast]
+cmds = ["true"]
+
+[runner.root]
+deps = ["slow", "fast"]
+"#).unwrap();
+        let run = RunLog::new("root", &config);
+        recursive_runner("root", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap();
+
+        let tasks = run.report.tasks();
+        let slow = tasks.iter().find(|t| t.name == "slow").unwrap().slow.as_ref().unwrap();
+        assert_eq!(slow.command, "sleep 0.3");
+        assert_eq!(slow.warn_after, Duration::from_millis(50));
+        assert!(slow.overage >= Duration::from_millis(200), "{:?}", slow.overage);
+        // The project default applies elsewhere, and is not exceeded
+        assert!(tasks.iter().all(|t| t.name == "slow" || t.slow.is_none()));
+
+        let spans = run.report.spans();
+        let sleep = spans.iter().find(|s| s.name == "sleep 0.3").unwrap();
+        assert!(sleep.args["overage_ms"].as_u64().unwrap() >= 200);
+        // Warning does not stand in for the timeout: the command still succeeded
+        assert_eq!(sleep.args["exit_code"], 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_image_runs_through_container_runtime() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("container");
+        // Stands in for docker: records its argv and exits like the container would
+        let runtime = dir.join("fake-docker");
+        std::fs::write(&runtime, "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$ARGV_FILE\"\nexit 7\n").unwrap();
+        std::fs::set_permissions(&runtime, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let argv_file = dir.join("argv");
+
+        let mut config: PavidiConfig = toml::from_str(&format!(r#"
+[env]
+ARGV_FILE = '{argv}'
+P_TASK_DIR = '{dir}'
+
+[runner.wasm]
+image = "rust:1.79"
+container_runtime = '{runtime}'
+container_args = ["--network", "none"]
+cmds = ["cargo build --target wasm32-unknown-unknown --out-dir ${{P_TASK_DIR}}/out"]
+"#, argv = argv_file.display(), runtime = runtime.display(), dir = dir.display())).unwrap();
+        config.root_dir = dir.to_path_buf();
+        let run = RunLog::new("wasm", &config);
+        let err = recursive_runner("wasm", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap_err();
+        assert!(err.to_string().contains("Exit code 7"), "{}", err);
+
+        let argv = std::fs::read_to_string(&argv_file).unwrap();
+        let argv: Vec<&str> = argv.lines().collect();
+        assert_eq!(&argv[..2], ["run", "--rm"]);
+        assert!(argv.windows(2).any(|w| w == ["-v", &format!("{}:/work", dir.display())]));
+        assert!(argv.windows(2).any(|w| w == ["-e", &format!("ARGV_FILE={}", argv_file.display())]));
+        // The project is mounted at /work, and that is where the task runs
+        assert!(argv.windows(2).any(|w| w == ["-e", "P_TASK_DIR=/work"]));
+        assert_eq!(&argv[argv.len() - 6..], ["--network", "none", "rust:1.79", "sh", "-c", "cargo build --target wasm32-unknown-unknown --out-dir /work/out"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_runs_over_ssh() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("remote");
+        // Stand in for ssh and scp: record argv, fail like a remote command would
+        for tool in ["ssh", "scp"] {
+            let script = format!("#!/bin/sh\nprintf '{}' >> \"$ARGV_FILE\"\nprintf ' [%s]' \"$@\" >> \"$ARGV_FILE\"\necho >> \"$ARGV_FILE\"\ncase \"$*\" in *deploy-fails*) exit 5;; esac\n", tool);
+            fs::write(dir.join(tool), script).unwrap();
+            fs::set_permissions(dir.join(tool), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(dir.join("app.tar"), "").unwrap();
+        let argv_file = dir.join("argv");
+
+        let config: PavidiConfig = toml::from_str(&format!(r#"
+[env]
+ARGV_FILE = '{argv}'
+PATH = '{dir}:{path}'
+DEPLOY_TOKEN = "hunter2"
+P_ROOT = '{dir}'
+
+[runner.deploy]
+remote = "deploy@prod-1"
+remote_cwd = "~/app"
+push_files = ['{dir}/*.tar']
+pull_files = ['{dir}/out/*.xml']
+cmds = ["./deploy.sh release --from ${{P_ROOT}}"]
+
+[runner.broken]
+remote = "deploy@prod-1"
+pull_files = ['{dir}/out/*.xml']
+cmds = ["deploy-fails"]
+"#, argv = argv_file.display(), dir = dir.display(), path = std::env::var("PATH").unwrap())).unwrap();
+        let run = RunLog::new("deploy", &config);
+        recursive_runner("deploy", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap();
+
+        let argv = fs::read_to_string(&argv_file).unwrap();
+        let lines: Vec<&str> = argv.lines().collect();
+        assert_eq!(lines[0], format!("scp [-q] [-r] [{d}/app.tar] [deploy@prod-1:~/app/]", d = dir.display()));
+        assert_eq!(lines[1], format!("ssh [deploy@prod-1] [cd ~/app && export ARGV_FILE={a} DEPLOY_TOKEN=hunter2 P_CONFIG_DIR=\"$PWD\" P_ROOT=\"$PWD\" P_TASK_DIR=\"$PWD\" && ./deploy.sh release --from $PWD]", a = argv_file.display()));
+        assert_eq!(lines[2], format!("scp [-q] [-r] [deploy@prod-1:{d}/out/*.xml] [{d}/out/]", d = dir.display()));
+
+        // The remote exit code fails the task, and nothing is pulled back
+        fs::remove_file(&argv_file).unwrap();
+        let err = recursive_runner("broken", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap_err();
+        assert!(err.to_string().contains("Exit code 5"), "{}", err);
+        let argv = fs::read_to_string(&argv_file).unwrap();
+        assert_eq!(argv.lines().count(), 1, "{}", argv);
+    }
+
+    #[test]
+    fn test_call_stack_branches() {
+        let mut stack = CallStack::new();
+        stack.push("ci").unwrap();
+        stack.push("build").unwrap();
+        let branch = stack.branch();
+        let (mut a, mut b) = (branch.clone(), branch.clone());
+        assert!(Arc::ptr_eq(&a.ancestors, &b.ancestors));
+
+        // Siblings do not see each other's tasks, but tasks above the branch still close a cycle
+        a.push("lint").unwrap();
+        b.push("lint").unwrap();
+        let err = a.push("ci").unwrap_err().to_string();
+        assert!(err.contains("Circular dependency detected: ci"), "{}", err);
+        a.pop("lint");
+        a.push("lint").unwrap();
+    }
+
+    fn fallback_config(dir: &std::path::Path, chain: &str) -> PavidiConfig {
+        let l = dir.join("order.txt");
+        let l = l.display();
+        let mut config: PavidiConfig = toml::from_str(&format!(r#"
+[runner.podman]
+cmds = ["echo podman >> '{l}'", "false"]
+
+[runner.docker]
+cmds = ["echo docker >> '{l}'"]
+
+[runner.portable]
+cmds = ["echo portable >> '{l}'"]
+
+[runner.broken]
+cmds = ["false"]
+
+[runner.missing]
+cmds = ["echo missing >> '{l}'"]
+run_if = "false"
+
+[runner.build]
+cmds = ["echo build >> '{l}'"]
+fallbacks = {chain}
+"#)).unwrap();
+        config.root_dir = dir.to_path_buf();
+        config
+    }
+
+    fn run_fallbacks_of(config: &PavidiConfig) -> (Result<()>, Vec<report::TaskRecord>) {
+        let run = RunLog::new("build", config);
+        let result = recursive_runner("build", config, &run, &mut CallStack::new(), &[], true, false, 0);
+        (result, run.report.tasks())
+    }
+
+    #[test]
+    fn test_fallbacks_stop_at_first_success() {
+        let dir = scratch("fallback_first");
+        let config = fallback_config(&dir, r#"["docker", "portable"]"#);
+        let (result, tasks) = run_fallbacks_of(&config);
+        result.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("order.txt")).unwrap(), "docker\nbuild\n");
+        assert!(!tasks.iter().any(|t| t.name == "portable"));
+    }
+
+    #[test]
+    fn test_fallbacks_move_on_after_a_failure() {
+        let dir = scratch("fallback_second");
+        let config = fallback_config(&dir, r#"["missing", "podman", "docker", "portable"]"#);
+        let (result, tasks) = run_fallbacks_of(&config);
+        result.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("order.txt")).unwrap(), "podman\ndocker\nbuild\n");
+        // The failed attempt is still reported, as failed
+        let status = |name: &str| tasks.iter().find(|t| t.name == name).map(|t| t.status);
+        assert_eq!((status("podman"), status("docker"), status("build")), (Some(TaskStatus::Failed), Some(TaskStatus::Passed), Some(TaskStatus::Passed)));
+        // Skipped by run_if does not count as a success
+        assert_eq!(status("missing"), Some(TaskStatus::Skipped));
+    }
+
+    #[test]
+    fn test_fallbacks_fail_when_all_fail() {
+        let dir = scratch("fallback_all");
+        let config = fallback_config(&dir, r#"["podman", "missing", "broken"]"#);
+        let (result, _) = run_fallbacks_of(&config);
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Task 'build' failed: no fallback succeeded:\n  podman: "), "{}", err);
+        assert!(err.contains("\n  missing: run_if condition failed\n  broken: Task 'broken' failed at: 'false'"), "{}", err);
+        // The task's own commands never ran
+        assert_eq!(fs::read_to_string(dir.join("order.txt")).unwrap(), "podman\n");
+
+        let config = fallback_config(&dir, r#"["ghost"]"#);
+        let err = run_fallbacks_of(&config).0.unwrap_err().to_string();
+        assert_eq!(err, "Fallback 'ghost' of task 'build' not found");
+    }
+
+    #[test]
+    fn test_invalid_warn_after() {
+        let config: PavidiConfig = toml::from_str("[runner.a]\ncmds = [\"true\"]\nwarn_after = \"soon\"\n").unwrap();
+        let run = RunLog::new("a", &config);
+        let err = recursive_runner("a", &config, &run, &mut CallStack::new(), &[], true, false, 0).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid warn_after for task 'a'"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_grants_do_not_restrict_unrestricted_lists() {
+        let base = CapabilityConfig::default();
+        let grants = task::TaskCapabilities { allow_paths: Some(vec!["/opt".to_string()]), allow_exec: None };
+        let effective = base.with_grants(&grants);
+        assert!(effective.allow_paths.is_none());
+    }
+}
@@ -0,0 +1,139 @@
+//! `output_filters`: per-line rules for how a task's output looks on the
+//! console. Logs, `output_file`, reports and progress events get every line
+//! unchanged.
+
+use colored::{Color, Colorize};
+use regex::Regex;
+use serde::de;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::sync::Mutex;
+
+/// What happens to a line an [`OutputFilter`] matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterAction {
+    /// Not shown
+    Suppress,
+    /// Shown in this color
+    Highlight(Color),
+    /// Shown in red, and the command fails even if it exits 0
+    Fail,
+}
+
+impl FilterAction {
+    fn parse(action: &str) -> Option<FilterAction> {
+        match action {
+            "suppress" => Some(FilterAction::Suppress),
+            "fail" => Some(FilterAction::Fail),
+            _ => {
+                let color = action.strip_prefix("highlight-")?;
+                color.replace('-', " ").parse().ok().map(FilterAction::Highlight)
+            },
+        }
+    }
+}
+
+/// One `{ match = "regex", action = "..." }` entry. The regex is compiled when
+/// the config loads, so a bad pattern is reported with the task.
+#[derive(Debug, Clone)]
+pub struct OutputFilter {
+    pub pattern: Regex,
+    pub action: FilterAction,
+}
+
+impl<'de> Deserialize<'de> for OutputFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            #[serde(rename = "match")]
+            pattern: String,
+            action: String,
+        }
+        let table = Table::deserialize(deserializer)?;
+        let pattern = Regex::new(&table.pattern)
+            .map_err(|e| de::Error::custom(format!("invalid regex '{}': {}", table.pattern, e)))?;
+        let action = FilterAction::parse(&table.action).ok_or_else(|| de::Error::custom(format!(
+            "unknown action '{}': expected suppress, fail or highlight-<color> (e.g. highlight-yellow)", table.action)))?;
+        Ok(OutputFilter { pattern, action })
+    }
+}
+
+/// The filters of a task during one command, with the first line a `fail`
+/// filter matched. Shared by the threads reading stdout and stderr.
+#[derive(Debug)]
+pub struct LineFilters {
+    filters: Vec<OutputFilter>,
+    failed: Mutex<Option<(String, String)>>,
+}
+
+impl LineFilters {
+    pub fn new(filters: &[OutputFilter]) -> Self {
+        LineFilters { filters: filters.to_vec(), failed: Mutex::new(None) }
+    }
+
+    /// How `line` is shown on the console, given `shown`, the line as it would be
+    /// printed (with its timestamp, if any); `None` hides it. Patterns match `line`,
+    /// and the first filter that matches decides.
+    pub fn apply<'a>(&self, line: &str, shown: &'a str) -> Option<Cow<'a, str>> {
+        let Some(filter) = self.filters.iter().find(|f| f.pattern.is_match(line)) else {
+            return Some(Cow::Borrowed(shown));
+        };
+        match filter.action {
+            FilterAction::Suppress => None,
+            FilterAction::Highlight(color) => Some(Cow::Owned(shown.color(color).bold().to_string())),
+            FilterAction::Fail => {
+                self.failed.lock().unwrap().get_or_insert_with(|| (filter.pattern.to_string(), line.to_string()));
+                Some(Cow::Owned(shown.red().bold().to_string()))
+            },
+        }
+    }
+
+    /// The pattern of the `fail` filter that matched first, and the line it matched.
+    pub fn failure(&self) -> Option<(String, String)> {
+        self.failed.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(toml_src: &str) -> Result<Vec<OutputFilter>, String> {
+        #[derive(Deserialize)]
+        struct Task {
+            output_filters: Vec<OutputFilter>,
+        }
+        toml::from_str::<Task>(toml_src).map(|t| t.output_filters).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_line_filters() {
+        let filters = LineFilters::new(&filters(r#"output_filters = [
+            { match = "^Compiling ", action = "suppress" },
+            { match = "^warning:", action = "highlight-bright-yellow" },
+            { match = "ERROR", action = "fail" },
+        ]"#).unwrap());
+
+        assert_eq!(filters.apply("Compiling serde v1.0", "Compiling serde v1.0"), None);
+        assert!(filters.apply("warning: unused variable", "warning: unused variable").unwrap().contains("warning: unused variable"));
+        // Patterns see the line itself, not its timestamp
+        assert_eq!(filters.apply("Compiling log v0.4", "[12:00:01] Compiling log v0.4"), None);
+        assert_eq!(filters.apply("Finished dev", "[12:00:02] Finished dev").as_deref(), Some("[12:00:02] Finished dev"));
+        assert_eq!(filters.failure(), None);
+
+        assert!(filters.apply("ERROR: disk full", "ERROR: disk full").unwrap().contains("ERROR: disk full"));
+        filters.apply("ERROR: again", "ERROR: again");
+        assert_eq!(filters.failure(), Some(("ERROR".to_string(), "ERROR: disk full".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        let bad_regex = filters(r#"output_filters = [{ match = "(unclosed", action = "suppress" }]"#).unwrap_err();
+        assert!(bad_regex.contains("invalid regex '(unclosed'"), "{}", bad_regex);
+        let bad_action = filters(r#"output_filters = [{ match = "x", action = "highlight-plaid" }]"#).unwrap_err();
+        assert!(bad_action.contains("unknown action 'highlight-plaid'"), "{}", bad_action);
+        assert_eq!(FilterAction::parse("highlight-yellow"), Some(FilterAction::Highlight(Color::Yellow)));
+        assert_eq!(FilterAction::parse("highlight-bright-red"), Some(FilterAction::Highlight(Color::BrightRed)));
+    }
+}
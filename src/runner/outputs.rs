@@ -0,0 +1,272 @@
+//! Tasks whose `outputs` overlap. Found from the patterns alone, so it works
+//! before anything is built: whichever task runs second would otherwise make
+//! the first look up to date forever.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use indexmap::IndexMap;
+use super::task::RunnerTask;
+
+/// Two tasks that declare output patterns some path matches both of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputConflict {
+    pub first: (String, String),
+    pub second: (String, String),
+}
+
+impl fmt::Display for OutputConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' ({}) and '{}' ({}) write overlapping outputs", self.first.0, self.first.1, self.second.0, self.second.1)
+    }
+}
+
+/// Every pair of tasks in `tasks` with overlapping `outputs`, in declaration order.
+/// With `only`, pairs where either task is missing from it are left out.
+pub fn output_conflicts(tasks: &IndexMap<String, RunnerTask>, only: Option<&HashSet<String>>) -> Vec<OutputConflict> {
+    let declaring: Vec<(&String, &[String])> = tasks.iter()
+        .filter(|(name, _)| only.is_none_or(|only| only.contains(*name)))
+        .map(|(name, task)| (name, task.outputs()))
+        .filter(|(_, outputs)| !outputs.is_empty())
+        .collect();
+
+    let mut conflicts = Vec::new();
+    for (i, (first, first_outputs)) in declaring.iter().enumerate() {
+        for (second, second_outputs) in &declaring[i + 1..] {
+            for a in *first_outputs {
+                for b in *second_outputs {
+                    if patterns_overlap(a, b) {
+                        conflicts.push(OutputConflict { first: ((*first).clone(), a.clone()), second: ((*second).clone(), b.clone()) });
+                    }
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+/// `targets` and every task they can run: dependencies and fallbacks, transitively.
+pub fn active_tasks(tasks: &IndexMap<String, RunnerTask>, targets: &[String]) -> HashSet<String> {
+    let mut active = HashSet::new();
+    let mut pending: Vec<&str> = targets.iter().map(String::as_str).collect();
+    while let Some(name) = pending.pop() {
+        if !active.insert(name.to_string()) {
+            continue;
+        }
+        if let Some(task) = tasks.get(name) {
+            pending.extend(task.deps().iter().chain(task.fallbacks()).map(String::as_str));
+        }
+    }
+    active
+}
+
+/// Whether some path is covered by both output patterns. A pattern also covers
+/// everything below what it matches, since an output may be a directory: `dist`
+/// overlaps `dist/app.js`. Character classes are compared loosely, so two
+/// patterns that only differ in them are reported as overlapping.
+pub fn patterns_overlap(a: &str, b: &str) -> bool {
+    let a = segments(a);
+    let b = segments(b);
+    Intersection { a: &a, b: &b, memo: HashMap::new() }.from(0, 0)
+}
+
+/// Path segments of a pattern, with `**` appended for what lies below it.
+fn segments(pattern: &str) -> Vec<Segment> {
+    let pattern = pattern.replace('\\', "/");
+    let mut segments: Vec<Segment> = pattern.split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .map(|s| if s == "**" { Segment::AnyDepth } else { Segment::Name(tokens(s)) })
+        .collect();
+    segments.push(Segment::AnyDepth);
+    segments
+}
+
+#[derive(Debug)]
+enum Segment {
+    /// `**`: zero or more directories
+    AnyDepth,
+    /// One path component
+    Name(Vec<Token>),
+}
+
+#[derive(Debug)]
+enum Token {
+    Literal(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyRun,
+    /// `[...]`; ranges of characters, matched unless `negated`
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+fn tokens(segment: &str) -> Vec<Token> {
+    let chars: Vec<char> = segment.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => tokens.push(Token::AnyRun),
+            '?' => tokens.push(Token::AnyChar),
+            '[' => if let Some((class, end)) = class(&chars, i) {
+                tokens.push(class);
+                i = end;
+            } else {
+                tokens.push(Token::Literal('['));
+            },
+            c => tokens.push(Token::Literal(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+/// The class starting at `chars[start]` (`[`) and the index of its `]`, as the glob crate reads it.
+fn class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    // A `]` right after the opening bracket is a literal member
+    let first = i;
+    while i < chars.len() && (chars[i] != ']' || i == first) {
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|c| *c != ']') {
+            ranges.push((chars[i], chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((chars[i], chars[i]));
+            i += 1;
+        }
+    }
+    (i < chars.len()).then_some((Token::Class { ranges, negated }, i))
+}
+
+impl Token {
+    /// Whether some single character matches both tokens. Neither is `AnyRun`.
+    fn meets(&self, other: &Token) -> bool {
+        match (self, other) {
+            (Token::Literal(a), Token::Literal(b)) => a == b,
+            (Token::Literal(c), class @ Token::Class { .. }) | (class @ Token::Class { .. }, Token::Literal(c)) => class.contains(*c),
+            (Token::Class { ranges: a, negated: false }, Token::Class { ranges: b, negated: false }) => {
+                a.iter().any(|(lo, hi)| b.iter().any(|(l, h)| lo <= h && l <= hi))
+            },
+            // `?`, and classes with a negated side: assume some character fits both
+            _ => true,
+        }
+    }
+
+    fn contains(&self, c: char) -> bool {
+        match self {
+            Token::Class { ranges, negated } => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated,
+            _ => true,
+        }
+    }
+}
+
+/// Whether one non-empty name matches both token lists.
+fn names_meet(a: &[Token], b: &[Token]) -> bool {
+    fn step(a: &[Token], b: &[Token], i: usize, j: usize, memo: &mut HashMap<(usize, usize), bool>) -> bool {
+        if let Some(&known) = memo.get(&(i, j)) {
+            return known;
+        }
+        let result = match (a.get(i), b.get(j)) {
+            (None, None) => true,
+            // A run matches nothing, or one character of whatever the other side has next
+            (Some(Token::AnyRun), other) => step(a, b, i + 1, j, memo) || (other.is_some() && step(a, b, i, j + 1, memo)),
+            (other, Some(Token::AnyRun)) => step(a, b, i, j + 1, memo) || (other.is_some() && step(a, b, i + 1, j, memo)),
+            (Some(x), Some(y)) => x.meets(y) && step(a, b, i + 1, j + 1, memo),
+            _ => false,
+        };
+        memo.insert((i, j), result);
+        result
+    }
+    // `*` alone matches only non-empty names, which every real path component is
+    step(a, b, 0, 0, &mut HashMap::new())
+}
+
+struct Intersection<'a> {
+    a: &'a [Segment],
+    b: &'a [Segment],
+    memo: HashMap<(usize, usize), bool>,
+}
+
+impl Intersection<'_> {
+    /// Whether some path matches both `a[i..]` and `b[j..]`.
+    fn from(&mut self, i: usize, j: usize) -> bool {
+        if let Some(&known) = self.memo.get(&(i, j)) {
+            return known;
+        }
+        let result = match (self.a.get(i), self.b.get(j)) {
+            (None, None) => true,
+            (Some(Segment::AnyDepth), other) => self.from(i + 1, j) || (other.is_some() && self.from(i, j + 1)),
+            (other, Some(Segment::AnyDepth)) => self.from(i, j + 1) || (other.is_some() && self.from(i + 1, j)),
+            (Some(Segment::Name(x)), Some(Segment::Name(y))) => names_meet(x, y) && self.from(i + 1, j + 1),
+            _ => false,
+        };
+        self.memo.insert((i, j), result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_overlap() {
+        let overlapping = [
+            ("dist/**", "dist/app/*.js"),
+            ("dist", "dist/app.js"),
+            ("dist/", "./dist/index.html"),
+            ("out/*.o", "out/main.o"),
+            ("build/**/*.js", "build/a/b/c.js"),
+            ("**/*.min.js", "public/vendor/lib.min.js"),
+            ("target/*-debug", "target/app-*"),
+            ("out/[a-c]*.bin", "out/b.bin"),
+            ("out/?.txt", "out/*.txt"),
+            ("a/**/z", "a/*/z"),
+            ("docs\\site", "docs/site/index.html"),
+        ];
+        for (a, b) in overlapping {
+            assert!(patterns_overlap(a, b), "{} and {} should overlap", a, b);
+            assert!(patterns_overlap(b, a), "{} and {} should overlap", b, a);
+        }
+
+        let separate = [
+            ("dist/app/*.js", "dist/lib/*.js"),
+            ("out/*.o", "out/*.a"),
+            ("out/main.o", "out/main.obj"),
+            ("build/debug/**", "build/release/**"),
+            ("out/[a-c].bin", "out/[x-z].bin"),
+            ("out/?.txt", "out/ab.txt"),
+            ("dist-app", "dist"),
+            ("docs/*.html", "site/*.html"),
+        ];
+        for (a, b) in separate {
+            assert!(!patterns_overlap(a, b), "{} and {} should not overlap", a, b);
+            assert!(!patterns_overlap(b, a), "{} and {} should not overlap", b, a);
+        }
+    }
+
+    #[test]
+    fn test_output_conflicts() {
+        let tasks: IndexMap<String, RunnerTask> = toml::from_str(r#"
+build = { cmds = ["true"], outputs = ["dist/**"] }
+bundle = { cmds = ["true"], outputs = ["dist/app/*.js", "stats.json"] }
+docs = { cmds = ["true"], outputs = ["site"] }
+release = { deps = ["docs"], fallbacks = ["docs-lite"], cmds = ["true"] }
+docs-lite = { cmds = ["true"], outputs = ["site/index.html"] }
+"#).unwrap();
+
+        let conflicts = output_conflicts(&tasks, None);
+        assert_eq!(conflicts.iter().map(ToString::to_string).collect::<Vec<_>>(), [
+            "'build' (dist/**) and 'bundle' (dist/app/*.js) write overlapping outputs",
+            "'docs' (site) and 'docs-lite' (site/index.html) write overlapping outputs",
+        ]);
+
+        let active = active_tasks(&tasks, &["release".to_string()]);
+        assert_eq!(output_conflicts(&tasks, Some(&active)), conflicts[1..]);
+        assert!(output_conflicts(&tasks, Some(&active_tasks(&tasks, &["build".to_string()]))).is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use anyhow::{Context, Result, bail};
+use indexmap::IndexMap;
+use colored::*;
+use serde::Serialize;
+use std::cell::Cell;
+use crate::config::{dynamic_env_command, CapabilityConfig, PavidiConfig};
+use crate::utils::{expand_command, ShellKind};
+use super::cache::{stale_reason, Sources};
+use super::last_plan::PlanDiff;
+use super::task::RunnerTask;
+use super::{expand_task_command, placed_env, start_order, task_place, task_shell, CallStack};
+
+/// What `p <task> --dry-run` would do, in the order a run visits the tasks.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub task: String,
+    pub steps: Vec<PlanStep>,
+    /// With `--diff`: how the plan differs from the last run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<PlanDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanStep {
+    pub task: String,
+    /// Levels below the root task
+    pub depth: usize,
+    pub deps: Vec<String>,
+    pub parallel: bool,
+    /// Tried in order after the deps until one succeeds
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+    /// `run`, `skip`, or `try` for tasks that only run as a fallback
+    pub action: &'static str,
+    /// Why the task would run or be skipped, e.g. `up-to-date` or `no previous cache`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// `skip_if`, `run_if`, `require_files` and `wait_for` checks; shown, never evaluated
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<String>,
+    /// Expanded, as they would be executed (ssh, scp and container commands included);
+    /// listed for skipped tasks too
+    pub commands: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub finally: Vec<String>,
+    #[serde(skip)]
+    pub capabilities: Option<CapabilityConfig>,
+}
+
+/// Walks `task_name` and its dependencies without running anything or writing
+/// to disk: conditions are not evaluated, the cache is only read, and dynamic
+/// `$(...)` env values (left unresolved by the caller) show up as placeholders.
+pub fn build_plan(task_name: &str, config: &PavidiConfig, extra_args: &[String]) -> Result<Plan> {
+    let mut steps = vec![];
+    plan_task(task_name, config, &placeholder_env(config), extra_args, &mut CallStack::new(), 0, &mut steps)?;
+    Ok(Plan { task: task_name.to_string(), steps, diff: None })
+}
+
+/// The plan of a group run (`p build:`): each member in turn, leaving out members
+/// an earlier one already pulled in as a dependency.
+pub fn build_group_plan(group: &str, members: &[String], config: &PavidiConfig) -> Result<Plan> {
+    let env = placeholder_env(config);
+    let mut steps: Vec<PlanStep> = vec![];
+    for member in members {
+        // A fallback attempt may fail, so it does not count as the member having run
+        if !steps.iter().any(|s| &s.task == member && s.action != "try") {
+            plan_task(member, config, &env, &[], &mut CallStack::new(), 0, &mut steps)?;
+        }
+    }
+    Ok(Plan { task: group.to_string(), steps, diff: None })
+}
+
+fn placeholder_env(config: &PavidiConfig) -> IndexMap<String, String> {
+    config.env.iter()
+        .map(|(k, v)| (k.clone(), match dynamic_env_command(v) {
+            Some(cmd) => format!("<$({})>", cmd),
+            None => v.clone(),
+        }))
+        .collect()
+}
+
+fn plan_task(
+    task_name: &str,
+    config: &PavidiConfig,
+    env: &IndexMap<String, String>,
+    extra_args: &[String],
+    call_stack: &mut CallStack,
+    depth: usize,
+    steps: &mut Vec<PlanStep>,
+) -> Result<()> {
+    call_stack.push(task_name)?;
+    let task = config.runner.as_ref().and_then(|r| r.get(task_name))
+        .with_context(|| format!("Task '{}' not found", task_name))?;
+
+    let deps = if task.is_parallel() { start_order(task.deps(), config) } else { task.deps().to_vec() };
+    for dep in &deps {
+        plan_task(dep, config, env, &[], call_stack, depth + 1, steps)?;
+    }
+
+    // Every alternative is listed; how many of them run depends on which succeeds first
+    for alternative in task.fallbacks() {
+        if !config.runner.as_ref().is_some_and(|r| r.contains_key(alternative)) {
+            bail!("Fallback '{}' of task '{}' not found", alternative, task_name);
+        }
+        let start = steps.len();
+        plan_task(alternative, config, env, &[], &mut call_stack.clone(), depth + 1, steps)?;
+        for step in &mut steps[start..] {
+            if step.action == "run" {
+                step.action = "try";
+            }
+        }
+    }
+
+    let shell_cmd = task_shell(config);
+    let condition = |cmd: &String| expand_command(cmd, extra_args, env, ShellKind::detect(&shell_cmd));
+    let mut step = PlanStep {
+        task: task_name.to_string(),
+        depth,
+        deps: task.deps().to_vec(),
+        parallel: task.is_parallel(),
+        fallbacks: task.fallbacks().to_vec(),
+        action: "run",
+        reason: None,
+        conditions: vec![],
+        commands: vec![],
+        finally: vec![],
+        capabilities: config.capability.as_ref().map(|base| match task.capabilities() {
+            Some(grants) => base.with_grants(grants),
+            None => base.clone(),
+        }),
+    };
+
+    let (cmds, finally) = match task {
+        RunnerTask::Single(cmd) => (vec![cmd.clone()], vec![]),
+        RunnerTask::List(cmds) => (cmds.clone(), vec![]),
+        RunnerTask::Template(_) => (task.commands_for(std::env::consts::OS)?, vec![]),
+        RunnerTask::Full { run_if, skip_if, outputs, finally, .. } => {
+            step.conditions.extend(skip_if.iter().map(|c| format!("skip_if: {}", condition(c))));
+            step.conditions.extend(run_if.iter().map(|c| format!("run_if: {}", condition(c))));
+            if !task.require_files().is_empty() {
+                step.conditions.push(format!("require_files: {}", task.require_files().join(", ")));
+            }
+            if let Some(wait) = task.wait_for() {
+                step.conditions.push(format!("wait_for: {} (timeout {})", wait.files.join(", "), wait.timeout.as_deref().unwrap_or("60s")));
+            }
+
+            if let (Some(srcs), Some(outs)) = (Sources::of(task, config), outputs) {
+                match stale_reason(task_name, &srcs, outs, env)? {
+                    Some(reason) => step.reason = Some(reason),
+                    None => {
+                        step.action = "skip";
+                        step.reason = Some("up-to-date".to_string());
+                    }
+                }
+            }
+
+            (task.commands_for(std::env::consts::OS)?, finally.clone().unwrap_or_default())
+        }
+    };
+
+    // Expanded for skipped tasks too, so --diff can compare them with the last run
+    let container = task.container(config.default_container_runtime(), &config.root_dir);
+    let remote = task.remote();
+    if container.is_some() && remote.is_some() {
+        bail!("Task '{}' sets both image and remote; a task runs either in a container or on a remote host", task_name);
+    }
+    // Same wrapping as execute_command_list; p: builtins stay on the host.
+    // Container names are numbered per task, so the same config gives the same plan
+    let containers = Cell::new(0);
+    let place = task_place(container.is_some(), remote.is_some());
+    let placed = placed_env(env, place);
+    let wrap = |cmd: &String| {
+        let cmd = expand_task_command(cmd, extra_args, env, &shell_cmd, place);
+        if cmd.trim_start().starts_with("p:") {
+            return cmd;
+        }
+        match (&container, &remote) {
+            (Some(spec), _) => {
+                containers.set(containers.get() + 1);
+                spec.command_line(&format!("p-dry-run-{}-{}", task_name, containers.get()), &placed, &cmd)
+            },
+            (_, Some(remote)) => remote.command_line(&placed, &cmd, true),
+            _ => cmd,
+        }
+    };
+    if let Some(remote) = &remote {
+        step.commands.extend(remote.push_commands()?.iter().map(shell_words::join));
+    }
+    step.commands.extend(cmds.iter().map(wrap));
+    if let Some(remote) = &remote {
+        step.commands.extend(remote.pull_commands().iter().map(shell_words::join));
+    }
+    step.finally = finally.iter().map(wrap).collect();
+
+    call_stack.pop(task_name);
+    steps.push(step);
+    Ok(())
+}
+
+impl Plan {
+    pub fn print(&self) {
+        let cached = self.steps.iter().filter(|s| s.action == "skip").count();
+        println!("{} [DRY-RUN] Plan for '{}': {} task(s), {} up-to-date", "::".yellow(), self.task.bold(), self.steps.len(), cached);
+        for (i, step) in self.steps.iter().enumerate() {
+            let indent = "  ".repeat(step.depth);
+            let status = match (step.action, &step.reason) {
+                ("skip", Some(reason)) => format!("skip ({})", reason).green(),
+                ("try", Some(reason)) => format!("try ({})", reason).cyan(),
+                ("try", None) => "try".cyan(),
+                (_, Some(reason)) => format!("run ({})", reason).yellow(),
+                _ => "run".yellow(),
+            };
+            let mode = if step.parallel && !step.deps.is_empty() { " [parallel deps]" } else { "" };
+            println!("{:>3}. {}{} {}{}", i + 1, indent, step.task.bold(), status, mode.dimmed());
+
+            let detail = format!("{}     ", indent);
+            for condition in &step.conditions {
+                println!("{}{} {}", detail, condition, "(not evaluated)".dimmed());
+            }
+            if step.action != "skip"
+                && let Some(caps) = &step.capabilities {
+                println!("{}capabilities: allow_paths={:?} allow_exec={:?} deny_paths={:?} read_only_paths={:?}",
+                    detail, caps.allow_paths, caps.allow_exec, caps.deny_paths, caps.read_only_paths);
+            }
+            if !step.fallbacks.is_empty() {
+                println!("{}fallbacks: {} {}", detail, step.fallbacks.join(" -> "), "(first to succeed)".dimmed());
+            }
+            if step.action == "skip" {
+                continue;
+            }
+            for cmd in &step.commands {
+                println!("{}$ {}", detail, cmd);
+            }
+            for cmd in &step.finally {
+                println!("{}finally: {}", detail, cmd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml_src: &str) -> PavidiConfig {
+        toml::from_str(toml_src).unwrap()
+    }
+
+    #[test]
+    fn test_plan_visits_deps_first() {
+        let config = config(r#"
+[env]
+VERSION = "$(git describe)"
+
+[runner.lint]
+cmds = ["echo lint"]
+run_if = "touch /tmp/never"
+require_files = ["assets/*.svg"]
+wait_for = { files = ["tmp/service.ready"], poll = "1s" }
+
+[runner.build]
+cmds = ["echo build ${VERSION}"]
+sources = ["src/**/*.rs"]
+outputs = ["/nonexistent/pavidi/bin"]
+finally = ["echo done"]
+
+[runner.ci]
+cmds = ["p:echo ci $1"]
+deps = ["lint", "build"]
+parallel = true
+"#);
+        let plan = build_plan("ci", &config, &["fast".to_string()]).unwrap();
+        let order: Vec<(&str, usize)> = plan.steps.iter().map(|s| (s.task.as_str(), s.depth)).collect();
+        assert_eq!(order, [("lint", 1), ("build", 1), ("ci", 0)]);
+
+        let [lint, build, ci] = &plan.steps[..] else { unreachable!() };
+        assert_eq!(lint.conditions, ["run_if: touch /tmp/never", "require_files: assets/*.svg", "wait_for: tmp/service.ready (timeout 60s)"]);
+        assert_eq!(build.commands, ["echo build <$(git describe)>"]);
+        assert_eq!(build.finally, ["echo done"]);
+        assert_eq!(build.reason.as_deref(), Some("output '/nonexistent/pavidi/bin' matched no files"));
+        assert_eq!(ci.commands, ["p:echo ci fast"]);
+        assert!(ci.parallel);
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["steps"][2]["deps"], serde_json::json!(["lint", "build"]));
+        assert_eq!(json["steps"][1]["action"], "run");
+    }
+
+    #[test]
+    fn test_plans_are_reproducible() {
+        let src: String = (0..12).map(|i| format!("[runner.t{i}]\ncmds = [\"echo {i} $A $B\"]\nimage = \"alpine\"\n\n")).collect::<String>()
+            + "[env]\nB = \"2\"\nA = \"1\"\nC = \"$(date)\"\n\n[runner.all]\ncmds = []\ndeps = [\"t9\", \"t3\", \"t11\", \"t0\"]\nparallel = true\n";
+        // Parsed and planned twice, as two CI runs would
+        let render = || serde_json::to_string(&build_plan("all", &config(&src), &[]).unwrap()).unwrap();
+        let first = render();
+        assert_eq!(first, render());
+        let plan: serde_json::Value = serde_json::from_str(&first).unwrap();
+        let order: Vec<&str> = plan["steps"].as_array().unwrap().iter().map(|s| s["task"].as_str().unwrap()).collect();
+        assert_eq!(order, ["t9", "t3", "t11", "t0", "all"]);
+    }
+
+    #[test]
+    fn test_plan_rejects_cycles_and_missing_deps() {
+        let cyclic = config("[runner.a]\ncmds = []\ndeps = [\"b\"]\n\n[runner.b]\ncmds = []\ndeps = [\"a\"]\n");
+        assert!(build_plan("a", &cyclic, &[]).unwrap_err().to_string().contains("Circular dependency"));
+
+        let missing = config("[runner.a]\ncmds = []\ndeps = [\"ghost\"]\n");
+        assert_eq!(build_plan("a", &missing, &[]).unwrap_err().to_string(), "Task 'ghost' not found");
+    }
+}
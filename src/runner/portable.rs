@@ -5,9 +5,16 @@ use crate::runner::handler::rm::handle_rm;
 use crate::runner::handler::ls::handle_ls;
 use crate::runner::handler::mv::handle_mv;
 use crate::runner::handler::cat::handle_cat;
+use crate::runner::handler::serve::handle_serve;
+use crate::runner::handler::chmod::handle_chmod;
+use crate::runner::handler::stat::handle_stat;
+use crate::runner::handler::date::{handle_date, write_date};
+use crate::runner::handler::uuid::{handle_uuid, write_uuid};
+use crate::config::CapabilityConfig;
 use colored::*;
+use crate::ui::{glyph, Glyph};
 
-pub fn run_portable_command(cmd_str: &str, trace: bool) -> Result<()> {
+pub fn run_portable_command(cmd_str: &str, caps: Option<&CapabilityConfig>, trace: bool) -> Result<()> {
     let args = shell_words::split(cmd_str).context("Failed to parse portable command arguments")?;
     if args.is_empty() {
         return Ok(());
@@ -16,16 +23,124 @@ pub fn run_portable_command(cmd_str: &str, trace: bool) -> Result<()> {
     let command = &args[0];
 
     if trace {
-        eprintln!("{} [TRACE] Portable command: {}", "⚙️".cyan(), cmd_str);
+        eprintln!("{} [TRACE] Portable command: {}", glyph(Glyph::Trace).cyan(), cmd_str);
     }
 
     match command.as_str() {
-        "p:rm" => handle_rm(&args[1..]),
-        "p:mkdir" => handle_mkdir(&args[1..]),
-        "p:cp" => handle_cp(&args[1..]),
-        "p:ls" => handle_ls(&args[1..]),
-        "p:mv" => handle_mv(&args[1..]),
-        "p:cat" => handle_cat(&args[1..]),
+        "p:rm" => handle_rm(&args[1..], caps),
+        "p:mkdir" => handle_mkdir(&args[1..], caps),
+        "p:cp" => handle_cp(&args[1..], caps),
+        "p:ls" => handle_ls(&args[1..], caps),
+        "p:mv" => handle_mv(&args[1..], caps),
+        "p:cat" => handle_cat(&args[1..], caps),
+        "p:serve" => handle_serve(&args[1..], caps),
+        "p:chmod" => handle_chmod(&args[1..], caps),
+        "p:stat" => handle_stat(&args[1..], caps),
+        "p:date" => handle_date(&args[1..]),
+        "p:uuid" => handle_uuid(&args[1..]),
         _ => bail!("Unknown portable command: {}", command),
     }
 }
+
+/// The output of a builtin that only prints a value (`p:date`, `p:uuid`), so
+/// `$(p:date --iso)` in `[env]` works without a shell. `None` for other commands.
+pub fn builtin_output(cmd_str: &str) -> Option<Result<String>> {
+    let args = match shell_words::split(cmd_str) {
+        Ok(args) => args,
+        Err(e) => return Some(Err(e).context("Failed to parse portable command arguments")),
+    };
+    let mut out = vec![];
+    let result = match args.first().map(String::as_str) {
+        Some("p:date") => {
+            let now = chrono::Local::now();
+            write_date(&args[1..], now.with_timezone(&chrono::Utc), chrono::Offset::fix(now.offset()), &mut out)
+        },
+        Some("p:uuid") => write_uuid(&args[1..], &mut out),
+        _ => return None,
+    };
+    Some(result.map(|_| String::from_utf8_lossy(&out).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use crate::test_support::{scratch, scratch_in, Scratch};
+
+    /// A directory inside the working directory, which the root check sees as the project.
+    fn project(name: &str) -> Scratch {
+        let dir = scratch_in(Path::new(""), &format!("portable_{}", name));
+        for file in ["a.log", "b.log", "keep.txt"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        dir
+    }
+
+    fn run(cmd: String) -> Result<()> {
+        run_portable_command(&cmd, None, false)
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rm_and_mkdir_expand_globs() {
+        let dir = project("rm");
+        run(format!("p:rm {}/*.log", dir.display())).unwrap();
+        assert_eq!(names(&dir), ["keep.txt"]);
+
+        // Like a shell: a pattern that matches expands, one that matches nothing is kept as is
+        fs::create_dir_all(dir.join("x")).unwrap();
+        run(format!("p:mkdir -p {0}/[x] {0}/new[0-9]", dir.display())).unwrap();
+        assert_eq!(names(&dir), ["keep.txt", "new[0-9]", "x"]);
+        assert!(run(format!("p:mkdir {}/[x]", dir.display())).is_err());
+    }
+
+    #[test]
+    fn test_cp_and_mv_expand_globs() {
+        let dir = project("cp");
+        fs::create_dir_all(dir.join("copies")).unwrap();
+        fs::create_dir_all(dir.join("moved")).unwrap();
+        run(format!("p:cp {0}/*.log {0}/copies", dir.display())).unwrap();
+        assert_eq!(names(&dir.join("copies")), ["a.log", "b.log"]);
+        run(format!("p:mv {0}/*.log {0}/moved", dir.display())).unwrap();
+        assert_eq!(names(&dir.join("moved")), ["a.log", "b.log"]);
+        assert_eq!(names(&dir), ["copies", "keep.txt", "moved"]);
+    }
+
+    #[test]
+    fn test_read_only_verbs_expand_globs() {
+        let dir = project("read");
+        run(format!("p:ls {}/*.log", dir.display())).unwrap();
+        run(format!("p:cat {}/*.log", dir.display())).unwrap();
+        run(format!("p:stat {}/*.txt", dir.display())).unwrap();
+        assert!(format!("{:#}", run(format!("p:stat {}/*.png", dir.display())).unwrap_err()).contains("cannot stat"));
+    }
+
+    #[test]
+    fn test_changes_outside_the_project_are_refused() {
+        let outside = scratch("portable_outside");
+        fs::write(outside.join("a.log"), "").unwrap();
+        let dir = project("outside");
+
+        let err = run(format!("p:rm {}/*.log", outside.display())).unwrap_err().to_string();
+        assert!(err.starts_with("p:rm: refusing to change") && err.ends_with("pass --allow-outside to allow it"), "{}", err);
+        assert!(outside.join("a.log").exists());
+        assert!(run(format!("p:cp {}/keep.txt {}", dir.display(), outside.display())).is_err());
+        assert!(run(format!("p:mv {}/a.log {}/moved.log", outside.display(), dir.display())).is_err());
+        // A [capability] section without allow_paths keeps the check
+        let audit_only = CapabilityConfig { audit: Some(true), ..Default::default() };
+        let err = run_portable_command(&format!("p:rm {}/a.log", outside.display()), Some(&audit_only), false).unwrap_err().to_string();
+        assert!(err.starts_with("p:rm: refusing to change"), "{}", err);
+        assert!(outside.join("a.log").exists());
+        // Reading is fine, and so is anything allow_paths allows
+        run(format!("p:ls {}", outside.display())).unwrap();
+        let caps = CapabilityConfig { allow_paths: Some(vec![outside.display().to_string()]), ..Default::default() };
+        run_portable_command(&format!("p:rm {}/a.log", outside.display()), Some(&caps), false).unwrap();
+        assert!(!outside.join("a.log").exists());
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use crate::ui::{glyph, Glyph};
+use super::events::{Event, EventSink};
+
+/// A consumer this slow to take an event gets a warning; the run waits for it either way.
+const SLOW_CONSUMER: Duration = Duration::from_secs(1);
+
+static PROGRESS: OnceLock<EventSink> = OnceLock::new();
+
+/// The `--progress-fd`/`--progress-pipe` subscriber, if one was installed.
+pub fn sink() -> Option<EventSink> {
+    PROGRESS.get().cloned()
+}
+
+/// `--progress-pipe PATH`: a named pipe (or any file) opened for writing. Opening
+/// a FIFO waits until the wrapper opens it for reading.
+pub fn install_pipe(path: &Path) -> Result<()> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)
+        .with_context(|| format!("Failed to open progress pipe {}", path.display()))?;
+    install(file)
+}
+
+/// `--progress-fd N`: a descriptor the wrapper left open, e.g. with `3>progress`.
+#[cfg(unix)]
+pub fn install_fd(fd: i32) -> Result<()> {
+    use std::os::fd::FromRawFd;
+    if fd < 3 {
+        bail!("--progress-fd {} would mix events into stdin, stdout or stderr; use 3 or higher", fd);
+    }
+    // Close-on-exec, so task commands do not hold the pipe open after p exits
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        bail!("--progress-fd {}: the descriptor is not open", fd);
+    }
+    install(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(windows)]
+pub fn install_fd(_fd: i32) -> Result<()> {
+    bail!("--progress-fd is not supported on Windows; use --progress-pipe \\\\.\\pipe\\<name>")
+}
+
+fn install(file: File) -> Result<()> {
+    let writer = EventWriter { out: Mutex::new(Some(LineWriter::new(file))), warned: Mutex::new(false) };
+    let sink: EventSink = Arc::new(move |event: &Event| writer.write(event));
+    if PROGRESS.set(sink).is_err() {
+        bail!("Only one of --progress-fd and --progress-pipe can be given");
+    }
+    Ok(())
+}
+
+struct EventWriter {
+    /// `None` once the consumer went away
+    out: Mutex<Option<LineWriter<File>>>,
+    warned: Mutex<bool>,
+}
+
+impl EventWriter {
+    /// Blocks while the consumer is not reading: dropping events would leave
+    /// a wrapper UI with tasks that never finish.
+    fn write(&self, event: &Event) {
+        let mut out = self.out.lock().unwrap();
+        let Some(file) = out.as_mut() else { return };
+        let started = Instant::now();
+        if let Err(e) = writeln!(file, "{}", event_json(event)) {
+            log::warn!("{} Progress events stopped: {}", glyph(Glyph::Warn).yellow(), e);
+            *out = None;
+            return;
+        }
+        let mut warned = self.warned.lock().unwrap();
+        if started.elapsed() >= SLOW_CONSUMER && !*warned {
+            log::warn!("{} The progress consumer is not keeping up; the run waits for it", glyph(Glyph::Warn).yellow());
+            *warned = true;
+        }
+    }
+}
+
+/// One line of the progress stream. Field names are a public interface, like `--json`.
+pub fn event_json(event: &Event) -> Value {
+    match event {
+        Event::TaskStarted { task } => json!({ "event": "task_started", "task": task }),
+        Event::CommandStarted { task, command } => json!({ "event": "command_started", "task": task, "command": command }),
+        Event::OutputChunk { task, stream, line } => json!({ "event": "output_chunk", "task": task, "stream": stream, "line": line }),
+        Event::TaskFinished { task, status, duration, cached } => json!({
+            "event": "task_finished",
+            "task": task,
+            "status": status,
+            "duration_ms": duration.as_millis() as u64,
+            "cached": cached,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::events::OutputStream;
+    use super::super::report::TaskStatus;
+
+    #[test]
+    fn test_event_json() {
+        let chunk = Event::OutputChunk { task: "build".into(), stream: OutputStream::Stderr, line: "warning: x".into() };
+        assert_eq!(event_json(&chunk), json!({ "event": "output_chunk", "task": "build", "stream": "stderr", "line": "warning: x" }));
+        let finished = Event::TaskFinished { task: "lint".into(), status: TaskStatus::Skipped, duration: Duration::from_millis(1500), cached: true };
+        assert_eq!(event_json(&finished), json!({ "event": "task_finished", "task": "lint", "status": "skipped", "duration_ms": 1500, "cached": true }));
+    }
+}
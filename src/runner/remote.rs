@@ -0,0 +1,221 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use indexmap::IndexMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use super::container::HOST_ONLY_VARS;
+use crate::config::PATH_VARS;
+use crate::logger::is_sensitive_key;
+use crate::utils::{child_environment, EnvFilter};
+use crate::ui::{glyph, Glyph};
+
+/// Runs a task's commands on another machine over `ssh` (the task's `remote` field).
+#[derive(Debug, Clone)]
+pub struct RemoteSpec {
+    /// `user@host`, or a `Host` alias from `~/.ssh/config`
+    pub host: String,
+    /// `remote_cwd`; without it commands run in the login directory
+    pub cwd: Option<String>,
+    /// `push_files`: local globs copied before the commands
+    pub push: Vec<String>,
+    /// `pull_files`: remote paths (globs allowed) copied back after them
+    pub pull: Vec<String>,
+}
+
+/// Quotes a remote path, leaving a leading `~` for the remote shell to expand.
+fn quote_remote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_words::quote(rest)),
+        None if path == "~" => path.to_string(),
+        None => shell_words::quote(path).into_owned(),
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl RemoteSpec {
+    /// The script run by the remote shell: `cd <remote_cwd> && export KEY='value' ... && <cmd>`.
+    /// With `redact`, sensitive values are replaced so the line can be printed.
+    pub fn script(&self, env: &IndexMap<String, String>, cmd: &str, redact: bool) -> String {
+        let mut parts = vec![];
+        if let Some(cwd) = &self.cwd {
+            parts.push(format!("cd {}", quote_remote_path(cwd)));
+        }
+        let mut keys: Vec<&String> = env.keys()
+            .filter(|k| !HOST_ONLY_VARS.contains(&k.as_str()) && is_identifier(k))
+            .collect();
+        keys.sort();
+        if !keys.is_empty() {
+            let assignments: Vec<String> = keys.iter().map(|key| {
+                // P_ROOT and friends name the directory the remote shell is in
+                if PATH_VARS.contains(&key.as_str()) {
+                    return format!("{}=\"$PWD\"", key);
+                }
+                let value = if redact && is_sensitive_key(key) { "[REDACTED]" } else { env[*key].as_str() };
+                format!("{}={}", key, shell_words::quote(value))
+            }).collect();
+            parts.push(format!("export {}", assignments.join(" ")));
+        }
+        parts.push(cmd.to_string());
+        parts.join(" && ")
+    }
+
+    /// `ssh <host> <script>` as one line for the host shell.
+    pub fn command_line(&self, env: &IndexMap<String, String>, cmd: &str, redact: bool) -> String {
+        shell_words::join(["ssh", &self.host, &self.script(env, cmd, redact)])
+    }
+
+    /// `path` under `remote_cwd`, as given to scp. Absolute and `~` paths are used as they are.
+    fn remote_path(&self, path: &str) -> String {
+        let path = path.trim_start_matches("./");
+        if path.starts_with('/') || path.starts_with('~') {
+            return path.to_string();
+        }
+        match &self.cwd {
+            Some(cwd) if path.is_empty() => format!("{}/", cwd.trim_end_matches('/')),
+            Some(cwd) => format!("{}/{}", cwd.trim_end_matches('/'), path),
+            None if path.is_empty() => ".".to_string(),
+            None => path.to_string(),
+        }
+    }
+
+    /// Fails with a hint when `ssh` (or `scp`, if files are transferred) is not on the task's PATH.
+    pub fn check_tools(&self, task_name: &str, env: &IndexMap<String, String>) -> Result<()> {
+        let paths = env.get("PATH").map(OsString::from).or_else(|| std::env::var_os("PATH"));
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let transfers = !self.push.is_empty() || !self.pull.is_empty();
+        for tool in ["ssh", "scp"].into_iter().filter(|t| *t == "ssh" || transfers) {
+            if which::which_in(tool, paths.clone(), &cwd).is_err() {
+                bail!("Task '{}' runs on '{}', but '{}' was not found on PATH. Install an OpenSSH client", task_name, self.host, tool);
+            }
+        }
+        Ok(())
+    }
+
+    /// The commands copying `push_files`: one `ssh mkdir -p` for nested directories,
+    /// then one `scp` per directory, since scp does not create them.
+    pub fn push_commands(&self) -> Result<Vec<Vec<String>>> {
+        let mut by_dir: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for pattern in &self.push {
+            let mut matched = false;
+            for entry in glob::glob(pattern).with_context(|| format!("Invalid push_files pattern '{}'", pattern))? {
+                let path = entry?;
+                // Relative paths keep their directory on the remote side; absolute ones land in remote_cwd
+                let dir = path.parent().filter(|_| path.is_relative())
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .filter(|d| d != ".")
+                    .unwrap_or_default();
+                by_dir.entry(dir).or_default().push(path.to_string_lossy().into_owned());
+                matched = true;
+            }
+            if !matched {
+                warn!("{} push_files pattern '{}' matched no files", glyph(Glyph::Warn).yellow(), pattern);
+            }
+        }
+
+        let mut commands = vec![];
+        let nested: BTreeSet<&String> = by_dir.keys().filter(|d| !d.is_empty()).collect();
+        if !nested.is_empty() {
+            let dirs: Vec<String> = nested.iter().map(|d| self.remote_path(d)).map(|d| quote_remote_path(&d)).collect();
+            commands.push(vec!["ssh".to_string(), self.host.clone(), format!("mkdir -p {}", dirs.join(" "))]);
+        }
+        for (dir, files) in by_dir {
+            let mut argv = vec!["scp".to_string(), "-q".to_string(), "-r".to_string()];
+            argv.extend(files);
+            let dest = if dir.is_empty() { self.remote_path("") } else { self.remote_path(&format!("{}/", dir)) };
+            argv.push(format!("{}:{}", self.host, dest));
+            commands.push(argv);
+        }
+        Ok(commands)
+    }
+
+    /// The `scp` commands copying `pull_files` back, each into the same relative directory.
+    pub fn pull_commands(&self) -> Vec<Vec<String>> {
+        self.pull.iter().map(|path| {
+            let local_dir = Path::new(path).parent()
+                .map(|p| p.to_string_lossy().into_owned())
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| ".".to_string());
+            vec![
+                "scp".to_string(), "-q".to_string(), "-r".to_string(),
+                format!("{}:{}", self.host, self.remote_path(path)),
+                format!("{}/", local_dir.trim_end_matches('/')),
+            ]
+        }).collect()
+    }
+
+    pub fn push(&self, env: &IndexMap<String, String>, filter: Option<&EnvFilter>) -> Result<()> {
+        transfer(self.push_commands()?, env, filter).with_context(|| format!("Failed to push files to '{}'", self.host))
+    }
+
+    pub fn pull(&self, env: &IndexMap<String, String>, filter: Option<&EnvFilter>) -> Result<()> {
+        let commands = self.pull_commands();
+        for argv in &commands {
+            if let Some(dir) = argv.last() {
+                fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir))?;
+            }
+        }
+        transfer(commands, env, filter).with_context(|| format!("Failed to pull files from '{}'", self.host))
+    }
+}
+
+fn transfer(commands: Vec<Vec<String>>, env: &IndexMap<String, String>, filter: Option<&EnvFilter>) -> Result<()> {
+    for argv in commands {
+        let line = shell_words::join(&argv);
+        info!("{} Executing: {}", "::".blue(), line);
+        let status = Command::new(&argv[0]).args(&argv[1..]).env_clear().envs(child_environment(filter, env)).status()
+            .with_context(|| format!("Failed to start '{}'", argv[0]))?;
+        if !status.success() {
+            bail!("'{}' exited with code {}", line, status.code().unwrap_or(1));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> RemoteSpec {
+        RemoteSpec {
+            host: "deploy@prod-1".to_string(),
+            cwd: Some("~/app".to_string()),
+            push: vec![],
+            pull: vec!["reports/*.xml".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_remote_script() {
+        let env = IndexMap::from([
+            ("MODE".to_string(), "it's live".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("API_TOKEN".to_string(), "hunter2".to_string()),
+            ("not-a-name".to_string(), "x".to_string()),
+            ("P_TASK_DIR".to_string(), "/home/dev/app".to_string()),
+        ]);
+        assert_eq!(spec().script(&env, "./migrate up", false),
+            "cd ~/app && export API_TOKEN=hunter2 MODE='it'\\''s live' P_TASK_DIR=\"$PWD\" && ./migrate up");
+        assert_eq!(spec().script(&env, "./migrate up", true),
+            "cd ~/app && export API_TOKEN='[REDACTED]' MODE='it'\\''s live' P_TASK_DIR=\"$PWD\" && ./migrate up");
+
+        let plain = RemoteSpec { cwd: None, ..spec() };
+        assert_eq!(plain.command_line(&IndexMap::new(), "uptime", false), "ssh deploy@prod-1 uptime");
+        assert_eq!(quote_remote_path("/srv/my app"), "'/srv/my app'");
+    }
+
+    #[test]
+    fn test_pull_commands() {
+        assert_eq!(spec().pull_commands(), [["scp", "-q", "-r", "deploy@prod-1:~/app/reports/*.xml", "reports/"]]);
+        let plain = RemoteSpec { cwd: None, pull: vec!["out.log".to_string()], ..spec() };
+        assert_eq!(plain.pull_commands(), [["scp", "-q", "-r", "deploy@prod-1:out.log", "./"]]);
+    }
+}
@@ -0,0 +1,531 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::logger::strip_ansi;
+
+/// Bytes of command output kept in a failure record.
+const FAILURE_OUTPUT_LIMIT: usize = 4096;
+
+/// What happened to a task that finished without error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskOutcome {
+    Ran,
+    Skipped(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub message: String,
+    pub output: String,
+}
+
+/// The slowest command of a task that ran past its `warn_after` threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowCommand {
+    pub command: String,
+    #[serde(rename = "warn_after_ms", serialize_with = "as_millis")]
+    pub warn_after: Duration,
+    #[serde(rename = "overage_ms", serialize_with = "as_millis")]
+    pub overage: Duration,
+}
+
+fn as_millis<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(d.as_millis() as u64)
+}
+
+/// One executed task in a run.
+#[derive(Debug, Clone)]
+pub struct TaskRecord {
+    pub name: String,
+    pub status: TaskStatus,
+    pub skip_reason: Option<String>,
+    pub failure: Option<Failure>,
+    pub slow: Option<SlowCommand>,
+    /// Offset from the start of the run
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// A task or command on the run timeline, for `--trace-file`.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    pub category: &'static str,
+    pub start: Duration,
+    pub duration: Duration,
+    /// Timeline lane; each parallel dependency gets its own
+    pub tid: u64,
+    /// Index of the enclosing span
+    pub parent: Option<usize>,
+    pub args: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Default)]
+struct ReportData {
+    tasks: Vec<TaskRecord>,
+    // Command failures waiting for their task to finish
+    pending: HashMap<String, Failure>,
+    // Slowest over-threshold command per task, waiting the same way
+    slow: HashMap<String, SlowCommand>,
+    spans: Vec<Span>,
+    next_tid: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Lane {
+    tid: u64,
+    parent: Option<usize>,
+}
+
+thread_local! {
+    // Where new spans go on this thread. Rayon workers that steal a job while
+    // waiting run it to completion, so set/restore stays properly nested.
+    static LANE: Cell<Lane> = const { Cell::new(Lane { tid: 1, parent: None }) };
+}
+
+/// Handle to an open span, closed with `RunReport::close_span`.
+pub struct SpanId {
+    index: usize,
+    started: Instant,
+    outer: Lane,
+}
+
+/// Per-task results collected by `recursive_runner` for `--report`.
+pub struct RunReport {
+    started: Instant,
+    started_at: DateTime<Local>,
+    capture: bool,
+    data: Mutex<ReportData>,
+}
+
+impl RunReport {
+    /// `capture` asks the runner to keep output tails for failure records.
+    pub fn new(capture: bool) -> Self {
+        RunReport {
+            started: Instant::now(),
+            started_at: Local::now(),
+            capture,
+            data: Mutex::new(ReportData { next_tid: 2, ..Default::default() }),
+        }
+    }
+
+    /// Opens a span on the current lane; spans opened until it is closed become its children.
+    pub fn open_span(&self, name: &str, category: &'static str) -> SpanId {
+        let outer = LANE.with(Cell::get);
+        let started = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        let index = data.spans.len();
+        data.spans.push(Span {
+            name: name.to_string(),
+            category,
+            start: started.saturating_duration_since(self.started),
+            duration: Duration::ZERO,
+            tid: outer.tid,
+            parent: outer.parent,
+            args: BTreeMap::new(),
+        });
+        LANE.with(|l| l.set(Lane { parent: Some(index), ..outer }));
+        SpanId { index, started, outer }
+    }
+
+    pub fn close_span(&self, span: SpanId, args: BTreeMap<String, serde_json::Value>) {
+        LANE.with(|l| l.set(span.outer));
+        let mut data = self.data.lock().unwrap();
+        let entry = &mut data.spans[span.index];
+        entry.duration = span.started.elapsed();
+        entry.args = args;
+    }
+
+    /// The span that new work on this thread belongs to, for handing to `in_lane`.
+    pub fn current_span(&self) -> Option<usize> {
+        LANE.with(Cell::get).parent
+    }
+
+    /// Runs `f` on a fresh lane under `parent`, so parallel work shows up side by side.
+    pub fn in_lane<R>(&self, parent: Option<usize>, f: impl FnOnce() -> R) -> R {
+        let tid = {
+            let mut data = self.data.lock().unwrap();
+            data.next_tid += 1;
+            data.next_tid - 1
+        };
+        let outer = LANE.with(|l| l.replace(Lane { tid, parent }));
+        let result = f();
+        LANE.with(|l| l.set(outer));
+        result
+    }
+
+    pub fn spans(&self) -> Vec<Span> {
+        self.data.lock().unwrap().spans.clone()
+    }
+
+    pub fn captures_output(&self) -> bool {
+        self.capture
+    }
+
+    /// Remembers the command that failed `task`, with the tail of its output.
+    pub fn command_failed(&self, task: &str, command: &str, message: &str, output: &str) {
+        let failure = Failure {
+            command: Some(command.to_string()),
+            message: message.to_string(),
+            output: tail(&strip_ansi(output), FAILURE_OUTPUT_LIMIT),
+        };
+        self.data.lock().unwrap().pending.insert(task.to_string(), failure);
+    }
+
+    /// Remembers that `command` of `task` ran `elapsed`, past its `warn_after`.
+    pub fn command_slow(&self, task: &str, command: &str, warn_after: Duration, elapsed: Duration) {
+        let overage = elapsed.saturating_sub(warn_after);
+        let mut data = self.data.lock().unwrap();
+        if data.slow.get(task).is_none_or(|s| s.overage < overage) {
+            data.slow.insert(task.to_string(), SlowCommand { command: command.to_string(), warn_after, overage });
+        }
+    }
+
+    pub fn record(&self, task: &str, started: Instant, result: &Result<TaskOutcome>) {
+        let mut data = self.data.lock().unwrap();
+        let pending = data.pending.remove(task);
+        let slow = data.slow.remove(task);
+        let (status, skip_reason, failure) = match result {
+            Ok(TaskOutcome::Ran) => (TaskStatus::Passed, None, None),
+            Ok(TaskOutcome::Skipped(reason)) => (TaskStatus::Skipped, Some(reason.to_string()), None),
+            Err(e) => {
+                // Without a pending command failure the error came from a dependency or setup
+                let failure = pending.unwrap_or_else(|| Failure { command: None, message: e.to_string(), output: String::new() });
+                (TaskStatus::Failed, None, Some(failure))
+            },
+        };
+        data.tasks.push(TaskRecord {
+            name: task.to_string(),
+            status,
+            skip_reason,
+            failure,
+            slow,
+            start: started.saturating_duration_since(self.started),
+            duration: started.elapsed(),
+        });
+    }
+
+    pub fn tasks(&self) -> Vec<TaskRecord> {
+        self.data.lock().unwrap().tasks.clone()
+    }
+
+    pub fn started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+}
+
+fn tail(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let mut cut = text.len() - limit;
+    while !text.is_char_boundary(cut) {
+        cut += 1;
+    }
+    text[cut..].to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Junit,
+    Json,
+}
+
+/// A `--report FORMAT=PATH` request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportSpec {
+    pub format: ReportFormat,
+    pub path: PathBuf,
+}
+
+pub fn parse_report_spec(s: &str) -> Result<ReportSpec, String> {
+    let (format, path) = s.split_once('=').ok_or_else(|| format!("expected FORMAT=PATH, got '{}'", s))?;
+    let format = match format {
+        "junit" => ReportFormat::Junit,
+        "json" => ReportFormat::Json,
+        other => return Err(format!("unknown report format '{}' (expected junit or json)", other)),
+    };
+    if path.is_empty() {
+        return Err("report path is empty".to_string());
+    }
+    Ok(ReportSpec { format, path: PathBuf::from(path) })
+}
+
+/// Escapes text for XML attributes and content. Characters that XML 1.0 cannot
+/// represent (most control characters) are replaced with U+FFFD.
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in strip_ansi(text).chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 || c == '\u{FFFE}' || c == '\u{FFFF}' => out.push('\u{FFFD}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn render_junit(suite: &str, timestamp: DateTime<Local>, tasks: &[TaskRecord]) -> String {
+    let failures = tasks.iter().filter(|t| t.status == TaskStatus::Failed).count();
+    let skipped = tasks.iter().filter(|t| t.status == TaskStatus::Skipped).count();
+    let total = tasks.iter().map(|t| t.start + t.duration).max().unwrap_or_default();
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"p\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+        tasks.len(), failures, skipped, total.as_secs_f64()
+    );
+    let _ = writeln!(
+        xml,
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">",
+        xml_escape(suite), tasks.len(), failures, skipped, total.as_secs_f64(), timestamp.format("%Y-%m-%dT%H:%M:%S")
+    );
+
+    for task in tasks {
+        let open = format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            xml_escape(&task.name), xml_escape(suite), task.duration.as_secs_f64()
+        );
+        match (&task.status, &task.failure) {
+            (TaskStatus::Failed, Some(failure)) => {
+                let mut body = String::new();
+                if let Some(cmd) = &failure.command {
+                    let _ = writeln!(body, "Command: {}", cmd);
+                }
+                if !failure.output.is_empty() {
+                    body.push('\n');
+                    body.push_str(&failure.output);
+                }
+                let _ = writeln!(xml, "{}>", open);
+                let _ = writeln!(
+                    xml,
+                    "      <failure message=\"{}\" type=\"failure\">{}</failure>",
+                    xml_escape(&failure.message), xml_escape(&body)
+                );
+                xml.push_str("    </testcase>\n");
+            },
+            (TaskStatus::Skipped, _) => {
+                let _ = writeln!(xml, "{}>", open);
+                let _ = writeln!(xml, "      <skipped message=\"{}\"/>", xml_escape(task.skip_reason.as_deref().unwrap_or("skipped")));
+                xml.push_str("    </testcase>\n");
+            },
+            _ => {
+                let _ = writeln!(xml, "{}/>", open);
+            },
+        }
+    }
+
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+pub fn render_json(run_id: &str, root_task: &str, timestamp: DateTime<Local>, tasks: &[TaskRecord]) -> Result<String> {
+    let tasks: Vec<serde_json::Value> = tasks.iter().map(|t| {
+        let mut value = serde_json::json!({
+            "name": t.name,
+            "status": t.status,
+            "duration_ms": t.duration.as_millis() as u64,
+        });
+        if let Some(reason) = &t.skip_reason {
+            value["skip_reason"] = serde_json::json!(reason);
+        }
+        if let Some(failure) = &t.failure {
+            value["failure"] = serde_json::to_value(failure).unwrap_or_default();
+        }
+        if let Some(slow) = &t.slow {
+            value["slow"] = serde_json::to_value(slow).unwrap_or_default();
+        }
+        value
+    }).collect();
+
+    let report = serde_json::json!({
+        "run_id": run_id,
+        "task": root_task,
+        "timestamp": timestamp.to_rfc3339(),
+        "tasks": tasks,
+    });
+    Ok(serde_json::to_string_pretty(&report)? + "\n")
+}
+
+/// Renders spans as Chrome trace-event JSON (complete "X" events), which
+/// Perfetto and chrome://tracing open directly.
+pub fn render_trace_events(run_id: &str, spans: &[Span]) -> Result<String> {
+    let mut events = vec![serde_json::json!({
+        "name": "process_name", "ph": "M", "pid": 1, "tid": 1,
+        "args": { "name": format!("p run {}", run_id) },
+    })];
+    for (i, span) in spans.iter().enumerate() {
+        let mut args = serde_json::Map::new();
+        args.insert("span_id".to_string(), serde_json::json!(i));
+        if let Some(parent) = span.parent {
+            args.insert("parent_id".to_string(), serde_json::json!(parent));
+        }
+        args.extend(span.args.iter().map(|(k, v)| (k.clone(), v.clone())));
+        events.push(serde_json::json!({
+            "name": span.name,
+            "cat": span.category,
+            "ph": "X",
+            "ts": span.start.as_micros() as u64,
+            "dur": span.duration.as_micros() as u64,
+            "pid": 1,
+            "tid": span.tid,
+            "args": args,
+        }));
+    }
+    Ok(serde_json::to_string(&serde_json::json!({ "traceEvents": events, "displayTimeUnit": "ms" }))? + "\n")
+}
+
+pub fn write_trace(path: &Path, run_id: &str, report: &RunReport) -> Result<()> {
+    let content = render_trace_events(run_id, &report.spans())?;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create trace directory {}", dir.display()))?;
+    }
+    fs::write(path, content).with_context(|| format!("Failed to write trace {}", path.display()))
+}
+
+/// Writes every requested report for a finished run.
+pub fn write_reports(specs: &[ReportSpec], run_id: &str, root_task: &str, report: &RunReport) -> Result<()> {
+    let tasks = report.tasks();
+    for spec in specs {
+        let content = match spec.format {
+            ReportFormat::Junit => render_junit(root_task, report.started_at(), &tasks),
+            ReportFormat::Json => render_json(run_id, root_task, report.started_at(), &tasks)?,
+        };
+        if let Some(dir) = spec.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create report directory {}", dir.display()))?;
+        }
+        fs::write(&spec.path, content).with_context(|| format!("Failed to write report {}", spec.path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fixture() -> Vec<TaskRecord> {
+        vec![
+            TaskRecord {
+                name: "lint".to_string(),
+                status: TaskStatus::Skipped,
+                skip_reason: Some("up-to-date".to_string()),
+                failure: None,
+                slow: None,
+                start: Duration::ZERO,
+                duration: Duration::from_millis(3),
+            },
+            TaskRecord {
+                name: "build".to_string(),
+                status: TaskStatus::Passed,
+                skip_reason: None,
+                failure: None,
+                slow: Some(SlowCommand {
+                    command: "cargo build".to_string(),
+                    warn_after: Duration::from_secs(1),
+                    overage: Duration::from_millis(500),
+                }),
+                start: Duration::from_millis(3),
+                duration: Duration::from_millis(1500),
+            },
+            TaskRecord {
+                name: "test".to_string(),
+                status: TaskStatus::Failed,
+                skip_reason: None,
+                failure: Some(Failure {
+                    command: Some("cargo test --features \"a&b\"".to_string()),
+                    message: "Exit code 101".to_string(),
+                    output: "test <it> ... FAILED\x07\nbell\x00\n".to_string(),
+                }),
+                slow: None,
+                start: Duration::from_millis(1503),
+                duration: Duration::from_millis(2250),
+            },
+            TaskRecord {
+                name: "ci".to_string(),
+                status: TaskStatus::Failed,
+                skip_reason: None,
+                failure: Some(Failure { command: None, message: "dependency 'test' failed".to_string(), output: String::new() }),
+                slow: None,
+                start: Duration::ZERO,
+                duration: Duration::from_millis(3760),
+            },
+        ]
+    }
+
+    fn timestamp() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_junit_golden() {
+        let xml = render_junit("ci", timestamp(), &fixture());
+        assert_eq!(xml, include_str!("testdata/report.junit.xml"));
+        // No raw control characters survive
+        assert!(!xml.chars().any(|c| (c as u32) < 0x20 && !matches!(c, '\n' | '\t' | '\r')));
+    }
+
+    #[test]
+    fn test_json_golden() {
+        let json = render_json("abcd1234", "ci", timestamp(), &fixture()).unwrap();
+        let expected = include_str!("testdata/report.json")
+            .replace("{timestamp}", &timestamp().to_rfc3339());
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn test_record_uses_pending_failure() {
+        let report = RunReport::new(true);
+        report.command_failed("build", "make", "Exit code 2", &"x".repeat(10_000));
+        let start = Instant::now();
+        report.record("build", start, &Err(anyhow::anyhow!("Task 'build' failed")));
+        report.record("ci", start, &Err(anyhow::anyhow!("dependency failed")));
+        report.record("docs", start, &Ok(TaskOutcome::Skipped("skip_if")));
+        report.command_failed("lint", "clippy", "Exit code 1", "\x1b[31merror\x1b[0m: unused");
+        report.record("lint", start, &Err(anyhow::anyhow!("Task 'lint' failed")));
+
+        let tasks = report.tasks();
+        let build = tasks[0].failure.as_ref().unwrap();
+        assert_eq!(build.command.as_deref(), Some("make"));
+        assert_eq!(build.output.len(), FAILURE_OUTPUT_LIMIT);
+        assert!(tasks[1].failure.as_ref().unwrap().command.is_none());
+        assert_eq!(tasks[2].status, TaskStatus::Skipped);
+        assert_eq!(tasks[3].failure.as_ref().unwrap().output, "error: unused");
+    }
+
+    #[test]
+    fn test_parse_report_spec() {
+        assert_eq!(
+            parse_report_spec("junit=out/report.xml").unwrap(),
+            ReportSpec { format: ReportFormat::Junit, path: PathBuf::from("out/report.xml") }
+        );
+        assert_eq!(parse_report_spec("json=r.json").unwrap().format, ReportFormat::Json);
+        assert!(parse_report_spec("xml=r.xml").is_err());
+        assert!(parse_report_spec("junit").is_err());
+        assert!(parse_report_spec("junit=").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::logger::ensure_state_gitignore;
+use crate::ui::{glyph, Glyph};
+use crate::state::{read_json, write_atomic};
+
+/// `.p/resume/<task>.json`: where a task with several commands failed, for `p <task> --resume`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumePoint {
+    pub run_id: String,
+    /// Index of the command that failed; the ones before it succeeded
+    pub failed_at: usize,
+    /// The commands as they were run, expanded
+    pub commands: Vec<String>,
+    /// Hash of the commands as defined; once the task changes, it starts over
+    pub cmds_hash: String,
+}
+
+pub fn resume_path(root: &Path, task: &str) -> PathBuf {
+    root.join(".p").join("resume").join(format!("{}.json", task.replace(['/', '\\', ':'], "_")))
+}
+
+pub fn cmds_hash(cmds: &[String]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for cmd in cmds {
+        hasher.update(cmd.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+pub fn write_resume_point(root: &Path, task: &str, point: &ResumePoint) -> Result<()> {
+    let path = resume_path(root, task);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        ensure_state_gitignore(&root.join(".p"));
+    }
+    write_atomic(&path, &format!("{}\n", serde_json::to_string_pretty(point)?))
+}
+
+pub fn read_resume_point(root: &Path, task: &str) -> Result<Option<ResumePoint>> {
+    read_json(&resume_path(root, task))
+}
+
+pub fn clear_resume_point(root: &Path, task: &str) {
+    let _ = fs::remove_file(resume_path(root, task));
+}
+
+/// How many of `cmds` `--resume` skips: those that succeeded before the recorded failure,
+/// or none if nothing was recorded or the task has changed since.
+pub fn resume_from(root: &Path, task: &str, cmds: &[String]) -> usize {
+    let point = match read_resume_point(root, task) {
+        Ok(Some(point)) => point,
+        Ok(None) => {
+            log::warn!("{} Nothing to resume for '{}'; running all of its commands", glyph(Glyph::Warn).yellow(), task);
+            return 0;
+        },
+        Err(e) => {
+            log::warn!("{} Ignoring the resume point of '{}': {:#}", glyph(Glyph::Warn).yellow(), task, e);
+            return 0;
+        },
+    };
+    if point.cmds_hash != cmds_hash(cmds) || point.failed_at >= cmds.len() {
+        log::warn!("{} The commands of '{}' changed since run {}; starting from the first one", glyph(Glyph::Warn).yellow(), task, point.run_id);
+        clear_resume_point(root, task);
+        return 0;
+    }
+    eprintln!("{} Resuming '{}' at command {}/{} ({} succeeded in run {})",
+        glyph(Glyph::Retry).cyan(), task, point.failed_at + 1, cmds.len(), point.failed_at, point.run_id);
+    point.failed_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    #[test]
+    fn test_resume_from() {
+        let root = scratch("resume");
+        let cmds: Vec<String> = ["cargo fmt", "cargo clippy", "cargo test"].iter().map(|c| c.to_string()).collect();
+        assert_eq!(resume_from(&root, "db:check", &cmds), 0);
+
+        let point = ResumePoint { run_id: "abcd1234".to_string(), failed_at: 2, commands: cmds.clone(), cmds_hash: cmds_hash(&cmds) };
+        write_resume_point(&root, "db:check", &point).unwrap();
+        assert!(root.join(".p/resume/db_check.json").exists());
+        assert_eq!(read_resume_point(&root, "db:check").unwrap(), Some(point));
+        assert_eq!(resume_from(&root, "db:check", &cmds), 2);
+
+        // A changed definition discards the record
+        let changed = [&cmds[..2], &["cargo test --all".to_string()]].concat();
+        assert_eq!(resume_from(&root, "db:check", &changed), 0);
+        assert_eq!(read_resume_point(&root, "db:check").unwrap(), None);
+    }
+}
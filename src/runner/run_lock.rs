@@ -0,0 +1,140 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{IsTerminal, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::config::LockMode;
+use crate::logger::ensure_state_gitignore;
+use crate::process;
+use crate::ui::{glyph, Glyph};
+
+/// Set for the commands of a run to the pid holding the lock, so `p` started by a
+/// task runs under its parent's lock instead of waiting for it.
+pub const LOCK_ENV: &str = "P_LOCK_PID";
+
+/// Who holds `.p/lock`, written into the file for the runs that find it taken.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub task: String,
+    pub started: DateTime<Local>,
+}
+
+/// `.p/lock`, held for the length of a run. The OS drops the lock when the
+/// process exits, so a run that was killed never leaves a stale lock behind.
+pub struct RunLock {
+    file: File,
+}
+
+pub fn lock_path(root: &Path) -> PathBuf {
+    root.join(".p").join("lock")
+}
+
+impl RunLock {
+    /// Takes the project lock for `task`. `None` when `mode` is `off` or a parent
+    /// run (`P_LOCK_PID`) already holds it.
+    pub fn acquire(root: &Path, task: &str, mode: LockMode) -> Result<Option<RunLock>> {
+        if mode == LockMode::Off {
+            return Ok(None);
+        }
+        let path = lock_path(root);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            ensure_state_gitignore(dir);
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {},
+                Err(TryLockError::Error(e)) => return Err(e).with_context(|| format!("Failed to lock {}", path.display())),
+            }
+            let holder = read_holder(&mut file);
+            let parent = std::env::var(LOCK_ENV).ok().and_then(|pid| pid.parse::<u32>().ok());
+            if let (Some(holder), Some(parent)) = (&holder, parent)
+                && holder.pid == parent {
+                return Ok(None);
+            }
+            let who = match &holder {
+                Some(h) => format!("'{}' (pid {}, started {})", h.task, h.pid, h.started.format("%H:%M:%S")),
+                None => "another run".to_string(),
+            };
+            if mode == LockMode::Fail {
+                bail!("{} The project is locked by {}; pass --wait or set [project] lock = \"wait\" to wait for it", glyph(Glyph::Fail), who);
+            }
+            if let Some(sig) = process::abort_signal() {
+                bail!("Stopped by {} while waiting for the project lock", process::signal_name(sig));
+            }
+            if !waiting || std::io::stderr().is_terminal() {
+                let spinner = ['|', '/', '-', '\\'][(Local::now().timestamp_subsec_millis() / 250) as usize];
+                let end = if std::io::stderr().is_terminal() { "\r" } else { "\n" };
+                eprint!("{} Waiting for {} to finish {}{}", glyph(Glyph::Wait).yellow(), who, spinner, end);
+                waiting = true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        if waiting && std::io::stderr().is_terminal() {
+            eprintln!();
+        }
+
+        let holder = LockHolder { pid: std::process::id(), task: task.to_string(), started: Local::now() };
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+        file.flush()?;
+        Ok(Some(RunLock { file }))
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_holder(file: &mut File) -> Option<LockHolder> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch;
+
+    // Windows locks are mandatory: other handles cannot read the holder while it is locked
+    #[cfg(unix)]
+    #[test]
+    fn test_run_lock() {
+        let root = scratch("run_lock");
+
+        let first = RunLock::acquire(&root, "build", LockMode::Fail).unwrap();
+        assert!(first.is_some());
+        let holder: LockHolder = serde_json::from_str(&fs::read_to_string(lock_path(&root)).unwrap()).unwrap();
+        assert_eq!((holder.pid, holder.task.as_str()), (std::process::id(), "build"));
+
+        let err = RunLock::acquire(&root, "test", LockMode::Fail).err().unwrap().to_string();
+        assert!(err.contains(&format!("locked by 'build' (pid {}", std::process::id())), "{}", err);
+        assert!(RunLock::acquire(&root, "test", LockMode::Off).unwrap().is_none());
+
+        // A waiting run gets the lock once the first one is done
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(first);
+        });
+        let second = RunLock::acquire(&root, "test", LockMode::Wait).unwrap();
+        assert!(second.is_some());
+        release.join().unwrap();
+        drop(second);
+        assert_eq!(fs::read_to_string(lock_path(&root)).unwrap(), "");
+    }
+}
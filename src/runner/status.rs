@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::logger::{ensure_state_gitignore, RunLog};
+use crate::state::{read_json, write_atomic};
+use super::report::{TaskRecord, TaskStatus};
+
+/// Bumped only for incompatible changes; new fields may be added without it.
+pub const STATUS_VERSION: u32 = 1;
+
+/// `.p/status.json`: the outcome of the last `p <task>` in this project, for editors and dashboards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunStatus {
+    pub version: u32,
+    pub run_id: String,
+    pub task: String,
+    /// `passed` or `failed`
+    pub status: String,
+    /// What `p` exited with, and what `p status` exits with
+    pub exit_code: i32,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub duration_ms: u64,
+    pub log_dir: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub combined_log: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_log: Option<PathBuf>,
+    pub tasks: Vec<TaskStatusEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskStatusEntry {
+    pub name: String,
+    /// `passed`, `failed` or `skipped`
+    pub status: String,
+    pub duration_ms: u64,
+    /// Skipped because its outputs were up to date
+    pub cached: bool,
+}
+
+fn status_name(status: TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::Passed => "passed",
+        TaskStatus::Failed => "failed",
+        TaskStatus::Skipped => "skipped",
+    }
+}
+
+impl RunStatus {
+    pub fn from_run(run: &RunLog, success: bool, finished: DateTime<Local>) -> Self {
+        let started = run.report.started_at();
+        RunStatus {
+            version: STATUS_VERSION,
+            run_id: run.id.clone(),
+            task: run.root_task.clone(),
+            status: if success { "passed" } else { "failed" }.to_string(),
+            exit_code: if success { 0 } else { 1 },
+            started,
+            finished,
+            duration_ms: (finished - started).num_milliseconds().max(0) as u64,
+            log_dir: run.settings.root.clone(),
+            combined_log: run.combined_log().map(Path::to_path_buf),
+            failure_log: run.failure_log(),
+            tasks: run.report.tasks().iter().map(TaskStatusEntry::from).collect(),
+        }
+    }
+
+    /// The entry for `task`, if it took part in the run.
+    pub fn task(&self, task: &str) -> Option<&TaskStatusEntry> {
+        self.tasks.iter().find(|t| t.name == task)
+    }
+}
+
+impl From<&TaskRecord> for TaskStatusEntry {
+    fn from(record: &TaskRecord) -> Self {
+        TaskStatusEntry {
+            name: record.name.clone(),
+            status: status_name(record.status).to_string(),
+            duration_ms: record.duration.as_millis() as u64,
+            cached: record.skip_reason.as_deref() == Some("up-to-date"),
+        }
+    }
+}
+
+pub fn status_path(root: &Path) -> PathBuf {
+    root.join(".p").join("status.json")
+}
+
+/// Replaces the status file. Concurrent runs each write a complete file; the last one wins.
+pub fn write_status(root: &Path, status: &RunStatus) -> Result<()> {
+    let path = status_path(root);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        ensure_state_gitignore(dir);
+    }
+    let json = serde_json::to_string_pretty(status)?;
+    write_atomic(&path, &format!("{}\n", json))
+}
+
+pub fn read_status(root: &Path) -> Result<Option<RunStatus>> {
+    read_json(&status_path(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::test_support::scratch;
+
+    fn sample() -> RunStatus {
+        let started = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        RunStatus {
+            version: STATUS_VERSION,
+            run_id: "abcd1234".to_string(),
+            task: "ci".to_string(),
+            status: "failed".to_string(),
+            exit_code: 1,
+            started,
+            finished: started + chrono::Duration::milliseconds(3760),
+            duration_ms: 3760,
+            log_dir: PathBuf::from(".p/logs"),
+            combined_log: None,
+            failure_log: Some(PathBuf::from(".p/logs/2024-05-01/abcd1234_test.log")),
+            tasks: vec![
+                TaskStatusEntry { name: "lint".to_string(), status: "skipped".to_string(), duration_ms: 3, cached: true },
+                TaskStatusEntry { name: "ci".to_string(), status: "failed".to_string(), duration_ms: 3760, cached: false },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_status_schema() {
+        let value = serde_json::to_value(sample()).unwrap();
+        let keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["duration_ms", "exit_code", "failure_log", "finished", "log_dir", "run_id", "started", "status", "task", "tasks", "version"]);
+        let task_keys: Vec<&String> = value["tasks"][0].as_object().unwrap().keys().collect();
+        assert_eq!(task_keys, ["cached", "duration_ms", "name", "status"]);
+        assert_eq!(value["version"], 1);
+        assert_eq!(value["tasks"][0]["cached"], true);
+    }
+
+    #[test]
+    fn test_status_round_trip() {
+        let root = scratch("status");
+        assert!(read_status(&root).unwrap().is_none());
+
+        write_status(&root, &sample()).unwrap();
+        let newer = RunStatus { run_id: "ffff0000".to_string(), ..sample() };
+        write_status(&root, &newer).unwrap();
+        assert_eq!(read_status(&root).unwrap(), Some(newer));
+    }
+}
@@ -1,20 +1,164 @@
-use serde::Deserialize;
+use anyhow::{Result, bail};
+use indexmap::IndexMap;
+use serde::de::{self, DeserializeSeed, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::Path;
+use super::container::{mount_root, ContainerSpec};
+use super::remote::RemoteSpec;
+use super::output_filter::OutputFilter;
+use crate::config::SourcesIgnore;
+use crate::diagnostic::Diagnostic;
+use crate::process::{parse_memory, ResourceLimits};
 
+/// Keys of a task table, in the order `p fmt` writes them. Others are ignored, see `unknown_keys`.
+pub const TASK_KEYS: &[&str] = &[
+    "description", "template", "params", "deps", "parallel", "priority", "run_if", "skip_if", "sources", "sources_ignore", "exclude", "outputs", "artifacts", "require_files", "wait_for", "fallbacks",
+    "cmds", "windows", "linux", "macos", "os_mode", "finally",
+    "ignore_failure", "allowed_exit_codes", "retry", "retry_delay", "timeout", "warn_after", "nice", "max_memory", "output_file", "output_mode", "output_filters",
+    "image", "container_args", "container_runtime", "remote", "remote_cwd", "push_files", "pull_files",
+    "capabilities",
+];
+
+/// Extra capability grants for a single task, layered on top of `[capability]`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TaskCapabilities {
+    pub allow_paths: Option<Vec<String>>,
+    pub allow_exec: Option<Vec<String>>,
+}
+
+/// `[runner.x] template = "name"`: a task made from `[template.name]`. Replaced
+/// by the task it stands for when the config loads.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateUse {
+    pub template: String,
+    /// Values for `{{param.NAME}}` in the template
+    #[serde(default)]
+    pub params: IndexMap<String, String>,
+    /// Added after the template's own deps
+    #[serde(default)]
+    pub deps: Vec<String>,
+    /// Replaces the template's description
+    pub description: Option<String>,
+}
+
+/// `wait_for = { files = [...], timeout = "60s", poll = "500ms" }`: files that
+/// appear out-of-band (a service's ready file), waited for before the task runs.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WaitFor {
+    pub files: Vec<String>,
+    /// Default 60s
+    pub timeout: Option<String>,
+    /// How often to look, default 500ms
+    pub poll: Option<String>,
+}
+
+/// An entry of `cmds`: a command string, or `{ cmd = "...", allowed_exit_codes = [0, 1] }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSpec {
+    pub cmd: String,
+    /// Exit codes that count as success; `None` means only 0
+    pub allowed_exit_codes: Option<Vec<i32>>,
+}
+
+impl CommandSpec {
+    /// Whether the command succeeded with `code`.
+    pub fn allows(&self, code: i32) -> bool {
+        match &self.allowed_exit_codes {
+            Some(codes) => codes.contains(&code),
+            None => code == 0,
+        }
+    }
+}
+
+impl From<String> for CommandSpec {
+    fn from(cmd: String) -> Self {
+        CommandSpec { cmd, allowed_exit_codes: None }
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SpecVisitor;
+
+        impl<'de> Visitor<'de> for SpecVisitor {
+            type Value = CommandSpec;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a command string or a table like { cmd = \"...\", allowed_exit_codes = [0, 1] }")
+            }
+
+            fn visit_str<E: de::Error>(self, cmd: &str) -> Result<CommandSpec, E> {
+                Ok(CommandSpec::from(cmd.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<CommandSpec, A::Error> {
+                #[derive(Deserialize)]
+                #[serde(deny_unknown_fields)]
+                struct Table {
+                    cmd: String,
+                    allowed_exit_codes: Option<Vec<i32>>,
+                }
+                let table = Table::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(CommandSpec { cmd: table.cmd, allowed_exit_codes: table.allowed_exit_codes })
+            }
+        }
+
+        deserializer.deserialize_any(SpecVisitor)
+    }
+}
+
+/// Entry in `cmds` where the OS-specific commands are spliced in.
+pub const OS_MARKER: &str = "@os";
+
+/// How a task's `windows`/`linux`/`macos` list relates to `cmds`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OsMode {
+    /// The OS list runs instead of `cmds`
+    #[default]
+    Replace,
+    /// The OS list runs after `cmds`
+    Append,
+}
+
+/// Whether `output_file` is truncated when the task starts.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    #[default]
+    Overwrite,
+    Append,
+}
+
+/// A `[runner]` entry. Deserialized by shape (see [`RunnerTask::from_toml`]); the
+/// derive only reads the externally tagged form that `from_toml` builds.
 #[derive(Debug, Deserialize, Clone)]
-#[serde(untagged)]
+#[serde(remote = "Self")]
+#[allow(clippy::large_enum_variant)]
 pub enum RunnerTask {
     /// Simple string command
     Single(String),
     /// List of sequential commands
     List(Vec<String>),
+    /// Instance of a `[template.*]`; never left after loading
+    Template(TemplateUse),
     /// Full configuration with dependencies and caching
     Full {
         #[serde(default)]
-        cmds: Vec<String>,
+        cmds: Vec<CommandSpec>,
         #[serde(default)]
         deps: Vec<String>,
         #[serde(default)]
         parallel: bool,
+        // Parallel dependencies with a higher priority start first
+        #[serde(default)]
+        priority: Option<i32>,
+        // Alternatives tried in order after the deps; the first to succeed is enough
+        #[serde(default)]
+        fallbacks: Option<Vec<String>>,
         // Description for listing
         #[serde(default)]
         description: Option<String>,
@@ -23,16 +167,37 @@ pub enum RunnerTask {
         run_if: Option<String>,
         skip_if: Option<String>,
         sources: Option<Vec<String>>,
+        // "gitignore" leaves out what .gitignore and .ignore files do when scanning sources
+        #[serde(default)]
+        sources_ignore: Option<SourcesIgnore>,
+        // Globs dropped from the sources scan, e.g. ["vendor/**"]
+        #[serde(default)]
+        exclude: Option<Vec<String>>,
         outputs: Option<Vec<String>>,
 
+        // Release files gathered by `p artifacts`, e.g. ["target/release/p", "dist/*.tar.gz"]
+        #[serde(default)]
+        artifacts: Option<Vec<String>>,
+
+        // Files made out-of-band: fail at once if missing, or wait for them to appear
+        #[serde(default)]
+        require_files: Option<Vec<String>>,
+        #[serde(default)]
+        wait_for: Option<WaitFor>,
+
         // OS-specific commands
         windows: Option<Vec<String>>,
         linux: Option<Vec<String>>,
         macos: Option<Vec<String>>,
+        #[serde(default)]
+        os_mode: Option<OsMode>,
 
         // Error Handling
         #[serde(default)]
         ignore_failure: bool,
+        // Exit codes that count as success for every command, e.g. [0, 1] for grep
+        #[serde(default)]
+        allowed_exit_codes: Option<Vec<i32>>,
 
         // Retry Logic
         #[serde(default)]
@@ -44,8 +209,608 @@ pub enum RunnerTask {
         #[serde(default)]
         timeout: Option<u64>,
 
+        // Warn when a command runs longer than this, e.g. "30s"
+        #[serde(default)]
+        warn_after: Option<String>,
+
+        // Resource limits for the task's commands, e.g. nice = 10, max_memory = "2G"
+        #[serde(default)]
+        nice: Option<i32>,
+        #[serde(default)]
+        max_memory: Option<String>,
+
+        // Copy the output of the task's commands into this file, e.g. "reports/test.txt"
+        #[serde(default)]
+        output_file: Option<String>,
+        #[serde(default)]
+        output_mode: Option<OutputMode>,
+        // Per-line rules for the console, e.g. [{ match = "^warning:", action = "highlight-yellow" }]
+        #[serde(default)]
+        output_filters: Option<Vec<OutputFilter>>,
+
+        // Run the commands in this container image
+        #[serde(default)]
+        image: Option<String>,
+        #[serde(default)]
+        container_args: Option<Vec<String>>,
+        #[serde(default)]
+        container_runtime: Option<String>,
+
+        // Run the commands on this host over ssh, e.g. "deploy@prod-1"
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(default)]
+        remote_cwd: Option<String>,
+        #[serde(default)]
+        push_files: Option<Vec<String>>,
+        #[serde(default)]
+        pull_files: Option<Vec<String>>,
+
         // Finally/Cleanup
         #[serde(default)]
         finally: Option<Vec<String>>,
+
+        // Capability grants for this task only (not inherited by deps)
+        #[serde(default)]
+        capabilities: Option<TaskCapabilities>,
     },
 }
+
+/// Why a task entry could not be read; `field` is the key of a task table at fault.
+#[derive(Debug, PartialEq)]
+pub struct TaskError {
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl TaskError {
+    /// The message for task `name`, or for an unnamed task.
+    pub fn describe(&self, name: Option<&str>) -> String {
+        let task = name.map(|n| format!("task '{}'", n)).unwrap_or_else(|| "task".to_string());
+        match &self.field {
+            Some(field) => format!("Invalid `{}` in {}: {}", field, task, self.message),
+            None => format!("Invalid {}: {}", task, self.message),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RunnerTask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        RunnerTask::from_toml(value).map_err(|e| de::Error::custom(e.describe(None)))
+    }
+}
+
+/// The `[runner]` table: like `IndexMap<String, RunnerTask>`, with the task name in errors.
+pub fn deserialize_tasks<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IndexMap<String, RunnerTask>>, D::Error> {
+    struct TasksVisitor;
+    struct NamedTask<'a>(&'a str);
+
+    impl<'de> DeserializeSeed<'de> for NamedTask<'_> {
+        type Value = RunnerTask;
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<RunnerTask, D::Error> {
+            let value = toml::Value::deserialize(deserializer)?;
+            RunnerTask::from_toml(value).map_err(|e| de::Error::custom(e.describe(Some(self.0))))
+        }
+    }
+
+    impl<'de> Visitor<'de> for TasksVisitor {
+        type Value = IndexMap<String, RunnerTask>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a table of tasks")
+        }
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut tasks = IndexMap::new();
+            while let Some(name) = map.next_key::<String>()? {
+                let task = map.next_value_seed(NamedTask(&name))?;
+                tasks.insert(name, task);
+            }
+            Ok(tasks)
+        }
+    }
+
+    deserializer.deserialize_map(TasksVisitor).map(Some)
+}
+
+/// `a string`, `an integer`, ... for messages about the wrong kind of value.
+fn kind(value: &toml::Value) -> &'static str {
+    match value {
+        toml::Value::String(_) => "a string",
+        toml::Value::Integer(_) => "an integer",
+        toml::Value::Float(_) => "a float",
+        toml::Value::Boolean(_) => "a boolean",
+        toml::Value::Datetime(_) => "a date",
+        toml::Value::Array(_) => "a list",
+        toml::Value::Table(_) => "a table",
+    }
+}
+
+/// serde's type names in the words of a config file.
+fn plain(message: &str) -> String {
+    message
+        .replace("expected u64", "expected a non-negative integer")
+        .replace("expected u32", "expected a non-negative integer")
+        .replace("expected i32", "expected an integer")
+        .replace("a sequence", "a list")
+        .replace("a map", "a table")
+}
+
+/// Levenshtein distance, for suggesting the key a typo was meant to be.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// The known task key closest to `key`, if it is likely a typo of it.
+pub fn suggest_key(key: &str) -> Option<&'static str> {
+    TASK_KEYS.iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, known)| *distance <= 2 && *distance < known.len() / 2 + 1)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+/// The keys no full task under `[runner]` or template under `[template]` of `source`
+/// knows, one diagnostic each, naming the task and the key it probably meant.
+/// Template uses are left out: their keys go to `TemplateUse`, which rejects others.
+pub fn unknown_keys(file: &Path, source: &str) -> Vec<Diagnostic> {
+    let Ok(doc) = toml_edit::Document::parse(source) else { return vec![] };
+    let mut found = vec![];
+    for (section, kind) in [("runner", "Task"), ("template", "Template")] {
+        let Some(tasks) = doc.get(section).and_then(|item| item.as_table_like()) else { continue };
+        for (name, item) in tasks.iter() {
+            let Some(task) = item.as_table_like() else { continue };
+            if task.contains_key("template") {
+                continue;
+            }
+            for (key, _) in task.iter().filter(|(k, _)| !TASK_KEYS.contains(k)) {
+                let span = task.get_key_value(key).and_then(|(k, _)| k.span());
+                let hint = suggest_key(key).map(|known| format!("did you mean `{}`?", known));
+                found.push(Diagnostic::at(file, source, format!("{} '{}' has an unknown key `{}`", kind, name, key), hint, span));
+            }
+        }
+    }
+    found
+}
+
+impl RunnerTask {
+    /// Reads a task by the shape of its value: a string is one command, a list is
+    /// commands, a table with `template` is a template use and any other table a
+    /// full task. Errors name the field at fault instead of serde's "did not match
+    /// any variant". Unknown keys of a full task are ignored; loading a file reports
+    /// them (`unknown_keys`).
+    pub fn from_toml(value: toml::Value) -> Result<RunnerTask, TaskError> {
+        let shape_error = |message: String| TaskError { field: None, message };
+        let table = match value {
+            toml::Value::String(cmd) => return Ok(RunnerTask::Single(cmd)),
+            toml::Value::Array(items) => {
+                let mut cmds = vec![];
+                for (i, item) in items.into_iter().enumerate() {
+                    match item {
+                        toml::Value::String(cmd) => cmds.push(cmd),
+                        other => return Err(shape_error(format!("command {} of the list is {}, not a string", i + 1, kind(&other)))),
+                    }
+                }
+                return Ok(RunnerTask::List(cmds));
+            },
+            toml::Value::Table(table) => table,
+            other => return Err(shape_error(format!(
+                "a task is a command string, a list of commands or a table, not {}", kind(&other)))),
+        };
+
+        let template = table.contains_key("template");
+
+        // serde would read a list as the struct's fields in order
+        for (key, example) in [("wait_for", "{ files = [...] }"), ("capabilities", "{ allow_paths = [...] }")] {
+            if let Some(value) = table.get(key).filter(|v| !v.is_table()) {
+                return Err(TaskError { field: Some(key.to_string()), message: format!("expected a table like {}, found {}", example, kind(value)) });
+            }
+        }
+
+        // The derived (remote = "Self") reader takes `{ Full = {...} }` or `{ Template = {...} }`
+        let variant = if template { "Template" } else { "Full" };
+        let tagged = |fields: toml::Table| toml::Value::Table(toml::Table::from_iter([(variant.to_string(), toml::Value::Table(fields))]));
+        let err = match RunnerTask::deserialize(tagged(table.clone())) {
+            Ok(task) => return Ok(task),
+            Err(e) => e,
+        };
+
+        // Find the field at fault by reading the keys one at a time (`template` is required)
+        for (key, value) in &table {
+            let mut single = toml::Table::new();
+            if template {
+                single.insert("template".to_string(), table["template"].clone());
+            }
+            single.insert(key.clone(), value.clone());
+            if let Err(e) = RunnerTask::deserialize(tagged(single)) {
+                if template && e.message().starts_with("unknown field") {
+                    let message = format!("`{}` cannot be used with `template`; a template use only takes template, params, deps and description", key);
+                    return Err(TaskError { field: None, message });
+                }
+                return Err(TaskError { field: Some(key.clone()), message: plain(e.message()) });
+            }
+        }
+        Err(shape_error(plain(err.message())))
+    }
+
+    pub fn capabilities(&self) -> Option<&TaskCapabilities> {
+        match self {
+            RunnerTask::Full { capabilities, .. } => capabilities.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn deps(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { deps, .. } => deps,
+            _ => &[],
+        }
+    }
+
+    pub fn require_files(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { require_files: Some(files), .. } => files,
+            _ => &[],
+        }
+    }
+
+    pub fn wait_for(&self) -> Option<&WaitFor> {
+        match self {
+            RunnerTask::Full { wait_for, .. } => wait_for.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// `artifacts`: globs of the files `p artifacts` collects.
+    pub fn artifacts(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { artifacts: Some(artifacts), .. } => artifacts,
+            _ => &[],
+        }
+    }
+
+    /// `exclude`: globs left out of the sources scan.
+    pub fn exclude(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { exclude: Some(exclude), .. } => exclude,
+            _ => &[],
+        }
+    }
+
+    /// `sources_ignore`, when the task sets it.
+    pub fn sources_ignore(&self) -> Option<SourcesIgnore> {
+        match self {
+            RunnerTask::Full { sources_ignore, .. } => *sources_ignore,
+            _ => None,
+        }
+    }
+
+    /// `outputs`: globs of the files the task writes.
+    pub fn outputs(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { outputs: Some(outputs), .. } => outputs,
+            _ => &[],
+        }
+    }
+
+    /// `sources`: globs of the files the cache hashes.
+    pub fn sources(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { sources: Some(sources), .. } => sources,
+            _ => &[],
+        }
+    }
+
+    /// `fallbacks`: tasks tried in order until one succeeds.
+    pub fn fallbacks(&self) -> &[String] {
+        match self {
+            RunnerTask::Full { fallbacks: Some(fallbacks), .. } => fallbacks,
+            _ => &[],
+        }
+    }
+
+    pub fn is_parallel(&self) -> bool {
+        matches!(self, RunnerTask::Full { parallel: true, .. })
+    }
+
+    /// Start order among parallel dependencies: higher first, 0 when unset.
+    pub fn priority(&self) -> i32 {
+        match self {
+            RunnerTask::Full { priority, .. } => priority.unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    pub fn warn_after(&self) -> Option<&str> {
+        match self {
+            RunnerTask::Full { warn_after, .. } => warn_after.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// `nice` and `max_memory`, with the size parsed.
+    pub fn resource_limits(&self) -> Result<ResourceLimits> {
+        let RunnerTask::Full { nice, max_memory, .. } = self else {
+            return Ok(ResourceLimits::default());
+        };
+        Ok(ResourceLimits {
+            nice: *nice,
+            max_memory: max_memory.as_deref().map(parse_memory).transpose()?,
+        })
+    }
+
+    /// The unexpanded `output_file` and how it is opened.
+    pub fn output_file(&self) -> Option<(&str, OutputMode)> {
+        match self {
+            RunnerTask::Full { output_file: Some(path), output_mode, .. } => Some((path, output_mode.unwrap_or_default())),
+            _ => None,
+        }
+    }
+
+    /// The `output_filters` applied to the task's console output.
+    pub fn output_filters(&self) -> &[OutputFilter] {
+        match self {
+            RunnerTask::Full { output_filters: Some(filters), .. } => filters,
+            _ => &[],
+        }
+    }
+
+    /// The container the task runs in, if it has an `image`. `default_runtime` is the
+    /// project's `container_runtime`; without either, `docker` is used.
+    pub fn container(&self, default_runtime: Option<&str>, root: &Path) -> Option<ContainerSpec> {
+        let RunnerTask::Full { image: Some(image), container_args, container_runtime, .. } = self else {
+            return None;
+        };
+        Some(ContainerSpec {
+            runtime: container_runtime.as_deref().or(default_runtime).unwrap_or("docker").to_string(),
+            image: image.clone(),
+            extra_args: container_args.clone().unwrap_or_default(),
+            root: mount_root(root),
+        })
+    }
+
+    /// The host the task runs on, if it has a `remote`.
+    pub fn remote(&self) -> Option<RemoteSpec> {
+        let RunnerTask::Full { remote: Some(host), remote_cwd, push_files, pull_files, .. } = self else {
+            return None;
+        };
+        Some(RemoteSpec {
+            host: host.clone(),
+            cwd: remote_cwd.clone(),
+            push: push_files.clone().unwrap_or_default(),
+            pull: pull_files.clone().unwrap_or_default(),
+        })
+    }
+
+    /// The commands the task runs on this OS, `finally` included.
+    pub fn commands(&self) -> Vec<String> {
+        let mut cmds = self.commands_for(std::env::consts::OS).unwrap_or_default();
+        if let RunnerTask::Full { finally: Some(finally), .. } = self {
+            cmds.extend(finally.iter().cloned());
+        }
+        cmds
+    }
+
+    /// The `windows`, `linux` or `macos` list for `os`, if the task has one.
+    pub fn os_commands(&self, os: &str) -> Option<&Vec<String>> {
+        let RunnerTask::Full { windows, linux, macos, .. } = self else {
+            return None;
+        };
+        match os {
+            "windows" => windows.as_ref(),
+            "linux" => linux.as_ref(),
+            "macos" => macos.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn has_os_commands(&self) -> bool {
+        matches!(self, RunnerTask::Full { windows, linux, macos, .. } if windows.is_some() || linux.is_some() || macos.is_some())
+    }
+
+    /// The main commands on `os`. An `@os` entry in `cmds` is replaced by the OS
+    /// list (or dropped without one); otherwise the OS list replaces `cmds`, or
+    /// follows them with `os_mode = "append"`.
+    pub fn commands_for(&self, os: &str) -> Result<Vec<String>> {
+        Ok(self.command_specs_for(os)?.into_iter().map(|spec| spec.cmd).collect())
+    }
+
+    /// [`Self::commands_for`] with the exit codes each command may end with; the
+    /// task's `allowed_exit_codes` apply where a command sets none.
+    pub fn command_specs_for(&self, os: &str) -> Result<Vec<CommandSpec>> {
+        let (cmds, os_mode, allowed) = match self {
+            RunnerTask::Single(cmd) => return Ok(vec![CommandSpec::from(cmd.clone())]),
+            RunnerTask::List(cmds) => return Ok(cmds.iter().cloned().map(CommandSpec::from).collect()),
+            RunnerTask::Template(t) => bail!("Template '{}' was not expanded; load the config with load_config", t.template),
+            RunnerTask::Full { cmds, os_mode, allowed_exit_codes, .. } => (cmds, os_mode.unwrap_or_default(), allowed_exit_codes),
+        };
+        let os_cmds = || self.os_commands(os).into_iter().flatten().cloned().map(CommandSpec::from);
+        let selected: Vec<CommandSpec> = if cmds.iter().any(|c| c.cmd == OS_MARKER) {
+            cmds.iter()
+                .flat_map(|c| if c.cmd == OS_MARKER { os_cmds().collect() } else { vec![c.clone()] })
+                .collect()
+        } else {
+            match (self.os_commands(os).is_some(), os_mode) {
+                (true, OsMode::Replace) => os_cmds().collect(),
+                (true, OsMode::Append) => cmds.iter().cloned().chain(os_cmds()).collect(),
+                (false, _) => cmds.clone(),
+            }
+        };
+        if selected.is_empty() && self.has_os_commands() {
+            bail!("No commands defined for this OS ({})", os);
+        }
+        Ok(selected.into_iter().map(|mut spec| {
+            if spec.allowed_exit_codes.is_none() {
+                spec.allowed_exit_codes = allowed.clone();
+            }
+            spec
+        }).collect())
+    }
+
+    /// Why the OS settings of the task have no effect, if they do not.
+    pub fn os_problem(&self) -> Option<&'static str> {
+        let RunnerTask::Full { cmds, os_mode, .. } = self else {
+            return None;
+        };
+        if self.has_os_commands() {
+            return None;
+        }
+        if cmds.iter().any(|c| c.cmd == OS_MARKER) {
+            Some("uses @os but defines no windows, linux or macos commands")
+        } else if os_mode.is_some() {
+            Some("sets os_mode but defines no windows, linux or macos commands")
+        } else {
+            None
+        }
+    }
+
+    /// Whether the task can be skipped as up-to-date (needs both sources and outputs).
+    pub fn has_cache(&self) -> bool {
+        matches!(self, RunnerTask::Full { sources: Some(_), outputs: Some(_), .. })
+    }
+}
+
+/// The namespace of a task: `db` for `db:migrate` (and `db:seed:dev`), `None` for `test`.
+pub fn task_group(name: &str) -> Option<&str> {
+    name.split_once(':').map(|(group, _)| group).filter(|g| !g.is_empty())
+}
+
+/// The tasks of group `group` (`build` or `build:`), in declaration order.
+pub fn group_members<'a>(tasks: &'a IndexMap<String, RunnerTask>, group: &str) -> Vec<&'a String> {
+    let group = group.trim_end_matches(':');
+    tasks.keys().filter(|name| task_group(name) == Some(group)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(toml_src: &str) -> RunnerTask {
+        toml::from_str(toml_src).unwrap()
+    }
+
+    #[test]
+    fn test_os_commands_replace_by_default() {
+        let t = task("cmds = [\"make\"]\nwindows = [\"nmake\"]");
+        assert_eq!(t.commands_for("windows").unwrap(), ["nmake"]);
+        assert_eq!(t.commands_for("linux").unwrap(), ["make"]);
+
+        let only_linux = task("linux = [\"make\"]");
+        assert_eq!(only_linux.commands_for("linux").unwrap(), ["make"]);
+        assert!(only_linux.commands_for("windows").unwrap_err().to_string().contains("No commands defined for this OS (windows)"));
+    }
+
+    #[test]
+    fn test_os_commands_append_and_splice() {
+        let append = task("cmds = [\"fetch\"]\nlinux = [\"./build.sh\"]\nos_mode = \"append\"");
+        assert_eq!(append.commands_for("linux").unwrap(), ["fetch", "./build.sh"]);
+        assert_eq!(append.commands_for("macos").unwrap(), ["fetch"]);
+
+        let spliced = task("cmds = [\"fetch\", \"@os\", \"test\"]\nlinux = [\"./build.sh\"]\nwindows = [\"build.bat\", \"sign.bat\"]");
+        assert_eq!(spliced.commands_for("linux").unwrap(), ["fetch", "./build.sh", "test"]);
+        assert_eq!(spliced.commands_for("windows").unwrap(), ["fetch", "build.bat", "sign.bat", "test"]);
+        assert_eq!(spliced.commands_for("macos").unwrap(), ["fetch", "test"]);
+    }
+
+    #[test]
+    fn test_os_problem() {
+        assert_eq!(task("cmds = [\"a\", \"@os\"]").os_problem(), Some("uses @os but defines no windows, linux or macos commands"));
+        assert_eq!(task("cmds = [\"a\"]\nos_mode = \"append\"").os_problem(), Some("sets os_mode but defines no windows, linux or macos commands"));
+        assert_eq!(task("cmds = [\"a\", \"@os\"]\nmacos = []").os_problem(), None);
+        assert_eq!(task("cmds = [\"a\"]").os_problem(), None);
+    }
+
+    /// The error for a `p.toml` with these tasks.
+    fn config_error(src: &str) -> String {
+        toml::from_str::<crate::config::PavidiConfig>(src).unwrap_err().message().to_string()
+    }
+
+    #[test]
+    fn test_malformed_tasks_name_task_and_field() {
+        let cases = [
+            ("[runner.build]\ncmds = [\"cargo build\"]\ntimeout = \"30\"\n",
+                "Invalid `timeout` in task 'build': invalid type: string \"30\", expected a non-negative integer"),
+            ("[runner]\nbuild = { cmds = [\"x\"], parallel = \"yes\" }\n",
+                "Invalid `parallel` in task 'build': invalid type: string \"yes\", expected a boolean"),
+            ("[runner.test]\nretry = -1\n",
+                "Invalid `retry` in task 'test': invalid value: integer `-1`, expected a non-negative integer"),
+            ("[runner.build]\ncmds = \"cargo build\"\n",
+                "Invalid `cmds` in task 'build': invalid type: string \"cargo build\", expected a list"),
+            ("[runner.e2e]\nwait_for = { file = [\"ready\"] }\n",
+                "Invalid `wait_for` in task 'e2e': unknown field `file`, expected one of `files`, `timeout`, `poll`"),
+            ("[runner.e2e]\nwait_for = [\"ready\"]\n",
+                "Invalid `wait_for` in task 'e2e': expected a table like { files = [...] }, found a list"),
+            ("[runner.deploy]\ncapabilities = { allow_paths = \"dist\" }\n",
+                "Invalid `capabilities` in task 'deploy': invalid type: string \"dist\", expected a list"),
+            ("[runner]\nbuild = 3\n",
+                "Invalid task 'build': a task is a command string, a list of commands or a table, not an integer"),
+            ("[runner]\nbuild = [\"cargo build\", true]\n",
+                "Invalid task 'build': command 2 of the list is a boolean, not a string"),
+            ("[runner.api]\ntemplate = \"svc\"\ncmds = [\"run\"]\n",
+                "Invalid task 'api': `cmds` cannot be used with `template`; a template use only takes template, params, deps and description"),
+            ("[runner.api]\ntemplate = \"svc\"\nparams = { port = 8080 }\n",
+                "Invalid `params` in task 'api': invalid type: integer `8080`, expected a string"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(config_error(src), expected, "for:\n{}", src);
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys_are_ignored_with_a_suggestion() {
+        let t = task("cmds = [\"cargo test\"]\ntimout = 30");
+        assert!(matches!(t, RunnerTask::Full { timeout: None, .. }));
+        assert_eq!(suggest_key("timout"), Some("timeout"));
+        assert_eq!(suggest_key("dep"), Some("deps"));
+        assert_eq!(suggest_key("command"), None);
+        assert_eq!(suggest_key("env"), None);
+    }
+
+    #[test]
+    fn test_group_members() {
+        let tasks: IndexMap<String, RunnerTask> = toml::from_str(r#"
+"db:seed" = "true"
+test = "true"
+"db:migrate" = "true"
+"db:seed:dev" = "true"
+database = "true"
+":odd" = "true"
+"#).unwrap();
+        assert_eq!(task_group("db:seed:dev"), Some("db"));
+        assert_eq!(task_group("test"), None);
+        assert_eq!(task_group(":odd"), None);
+        assert_eq!(group_members(&tasks, "db:"), ["db:seed", "db:migrate", "db:seed:dev"]);
+        assert_eq!(group_members(&tasks, "db"), group_members(&tasks, "db:"));
+        assert!(group_members(&tasks, "data").is_empty());
+    }
+
+    #[test]
+    fn test_allowed_exit_codes() {
+        let tasks: IndexMap<String, RunnerTask> = toml::from_str(r#"
+[search]
+allowed_exit_codes = [0, 1]
+cmds = ["grep -q x file", { cmd = "robocopy a b", allowed_exit_codes = [0, 1, 3] }, "@os"]
+linux = ["true"]
+"#).unwrap();
+        let specs = tasks["search"].command_specs_for("linux").unwrap();
+        let codes: Vec<(&str, Option<&[i32]>)> = specs.iter().map(|s| (s.cmd.as_str(), s.allowed_exit_codes.as_deref())).collect();
+        assert_eq!(codes, [("grep -q x file", Some(&[0, 1][..])), ("robocopy a b", Some(&[0, 1, 3][..])), ("true", Some(&[0, 1][..]))]);
+        assert_eq!(tasks["search"].commands_for("linux").unwrap(), ["grep -q x file", "robocopy a b", "true"]);
+        assert!(specs[1].allows(3) && !specs[1].allows(2));
+        assert!(CommandSpec::from("make".to_string()).allows(0));
+
+        let err = toml::from_str::<IndexMap<String, RunnerTask>>("[t]\ncmds = [{ command = \"make\" }]\n").unwrap_err();
+        assert!(err.message().contains("Invalid `cmds` in task"), "{}", err.message());
+        assert!(err.message().contains("unknown field `command`"), "{}", err.message());
+    }
+}
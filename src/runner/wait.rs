@@ -0,0 +1,143 @@
+use anyhow::{Context, Result, bail};
+use colored::*;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::notify::parse_duration;
+use crate::process;
+use crate::ui::{glyph, Glyph};
+use super::task::WaitFor;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_POLL: Duration = Duration::from_millis(500);
+
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// Whether a `wait_for` of this run timed out, wherever the error ended up
+/// (a parallel dependency reports only that it failed).
+pub fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::SeqCst)
+}
+
+/// `wait_for` ran out of time. `p` exits with 124 for it, like `timeout(1)`.
+#[derive(Debug)]
+pub struct WaitTimeout {
+    pub task: String,
+    pub missing: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for WaitTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Task '{}' gave up after {:?} waiting for: {}", glyph(Glyph::Timer), self.task, self.timeout, self.missing.join(", "))
+    }
+}
+
+impl std::error::Error for WaitTimeout {}
+
+/// The patterns of `files` that match nothing. Relative ones are resolved against `root`.
+pub fn missing_files(files: &[String], root: &Path) -> Result<Vec<String>> {
+    let mut missing = vec![];
+    for pattern in files {
+        let full = if Path::new(pattern).is_absolute() {
+            pattern.clone()
+        } else {
+            format!("{}/{}", glob::Pattern::escape(&root.display().to_string()), pattern)
+        };
+        let mut matches = glob::glob(&full).with_context(|| format!("Invalid file pattern '{}'", pattern))?;
+        if matches.next().is_none() {
+            missing.push(pattern.clone());
+        }
+    }
+    Ok(missing)
+}
+
+/// `require_files`: fails before the task runs, naming every missing file.
+pub fn require_files(task: &str, files: &[String], root: &Path) -> Result<()> {
+    let missing = missing_files(files, root)?;
+    if !missing.is_empty() {
+        bail!("{} Task '{}' requires files that do not exist: {}", glyph(Glyph::Fail), task, missing.join(", "));
+    }
+    Ok(())
+}
+
+/// `wait_for`: polls until every file exists, failing with [`WaitTimeout`] at the timeout.
+pub fn wait_for_files(task: &str, spec: &WaitFor, root: &Path, quiet: bool) -> Result<()> {
+    let timeout = spec.timeout.as_deref().map(parse_duration).transpose()
+        .with_context(|| format!("Invalid wait_for timeout for task '{}'", task))?
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let poll = spec.poll.as_deref().map(parse_duration).transpose()
+        .with_context(|| format!("Invalid wait_for poll for task '{}'", task))?
+        .unwrap_or(DEFAULT_POLL)
+        .max(Duration::from_millis(10));
+
+    let started = Instant::now();
+    let mut missing = missing_files(&spec.files, root)?;
+    if !missing.is_empty() && !quiet {
+        log::info!("{} Task '{}' is waiting for: {}", glyph(Glyph::Wait).yellow(), task.bold(), missing.join(", "));
+    }
+    while !missing.is_empty() {
+        if let Some(sig) = process::abort_signal() {
+            bail!("Stopped by {} while task '{}' was waiting for files", process::signal_name(sig), task);
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            TIMED_OUT.store(true, Ordering::SeqCst);
+            return Err(WaitTimeout { task: task.to_string(), missing, timeout }.into());
+        }
+        std::thread::sleep(poll.min(timeout - elapsed));
+        missing = missing_files(&spec.files, root)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_support::scratch;
+
+    fn files(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_require_files_lists_every_missing_file() {
+        let dir = scratch("wait_require");
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets").join("logo.svg"), "").unwrap();
+
+        require_files("pack", &files(&["assets/logo.svg", "assets/*.svg"]), &dir).unwrap();
+        let err = require_files("pack", &files(&["assets/logo.svg", "bundle.tar.gz", "assets/*.png"]), &dir).unwrap_err();
+        assert!(err.to_string().ends_with("Task 'pack' requires files that do not exist: bundle.tar.gz, assets/*.png"), "{}", err);
+    }
+
+    #[test]
+    fn test_wait_for_sees_files_created_later() {
+        let dir = scratch("wait_later");
+        let ready = dir.join("service.ready");
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            fs::write(ready, "").unwrap();
+        });
+        let spec = WaitFor { files: files(&["service.ready"]), timeout: Some("5s".to_string()), poll: Some("20ms".to_string()) };
+        let started = Instant::now();
+        wait_for_files("e2e", &spec, &dir, true).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_times_out() {
+        let dir = scratch("wait_timeout");
+        let spec = WaitFor { files: files(&["never.ready"]), timeout: Some("100ms".to_string()), poll: None };
+        let err = wait_for_files("e2e", &spec, &dir, true).unwrap_err();
+        let timeout = err.downcast_ref::<WaitTimeout>().unwrap();
+        assert_eq!((timeout.missing.as_slice(), timeout.timeout), (&files(&["never.ready"])[..], Duration::from_millis(100)));
+
+        let spec = WaitFor { files: vec![], timeout: Some("soon".to_string()), poll: None };
+        assert!(format!("{:#}", wait_for_files("e2e", &spec, &dir, true).unwrap_err()).contains("Invalid wait_for timeout for task 'e2e'"));
+    }
+}
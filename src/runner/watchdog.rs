@@ -0,0 +1,51 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Calls a function once if a command is still running after a threshold.
+/// It only observes: killing on `timeout` stays with the code waiting on the child.
+pub struct Watchdog {
+    done: Sender<()>,
+    handle: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    pub fn start(threshold: Duration, on_overrun: impl FnOnce() + Send + 'static) -> Self {
+        let (done, rx) = mpsc::channel();
+        let handle = thread::spawn(move || match rx.recv_timeout(threshold) {
+            Err(RecvTimeoutError::Timeout) => {
+                on_overrun();
+                true
+            },
+            _ => false,
+        });
+        Watchdog { done, handle }
+    }
+
+    /// Stops watching. Returns whether the threshold was exceeded.
+    pub fn finish(self) -> bool {
+        let _ = self.done.send(());
+        self.handle.join().unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_fires_only_after_threshold() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let flag = fired.clone();
+        let dog = Watchdog::start(Duration::from_millis(20), move || flag.store(true, Ordering::SeqCst));
+        thread::sleep(Duration::from_millis(100));
+        // The warning is printed while the command is still running, not at the end
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(dog.finish());
+
+        let dog = Watchdog::start(Duration::from_secs(60), || panic!("must not fire"));
+        assert!(!dog.finish());
+    }
+}
@@ -0,0 +1,104 @@
+//! The files `p` keeps under `.p/`: written atomically, and read so that a
+//! damaged file is moved aside instead of breaking every later run.
+
+use anyhow::{Context, Result};
+use colored::*;
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::ui::{glyph, Glyph};
+
+/// Writes `contents` to a temporary file next to `path`, syncs it and renames it
+/// into place, so readers never see a half-written file, even after a crash.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let written = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Failed to write {}", tmp.display()));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written?;
+    fs::rename(&tmp, path).with_context(|| {
+        let _ = fs::remove_file(&tmp);
+        format!("Failed to replace {}", path.display())
+    })
+}
+
+/// Reads the state file at `path` with `parse`. `None` when it does not exist,
+/// or when it is not UTF-8 or `parse` rejects it: such a file is renamed to
+/// `<name>.corrupt-<timestamp>` with a warning, and the caller goes on as if it
+/// were absent. Only I/O errors other than a missing file are returned.
+pub fn read_state<T>(path: &Path, parse: impl FnOnce(&str) -> Result<T>) -> Result<Option<T>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let parsed = String::from_utf8(data).map_err(anyhow::Error::from).and_then(|text| parse(&text));
+    match parsed {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => {
+            quarantine(path, &e);
+            Ok(None)
+        },
+    }
+}
+
+/// [`read_state`] for JSON files.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    read_state(path, |text| Ok(serde_json::from_str(text)?))
+}
+
+/// Paths already reported, so a file that cannot be moved aside warns once per run.
+static REPORTED: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+fn quarantine(path: &Path, error: &anyhow::Error) {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let aside = path.with_file_name(format!("{}.corrupt-{}", name, chrono::Local::now().format("%Y%m%d%H%M%S")));
+    let moved = match fs::rename(path, &aside) {
+        Ok(()) => format!("moved it to {}", aside.display()),
+        Err(e) => {
+            if !REPORTED.lock().unwrap().get_or_insert_default().insert(path.to_path_buf()) {
+                return;
+            }
+            format!("could not move it aside: {}", e)
+        },
+    };
+    log::warn!("{} {} is corrupt ({}); {} and continuing without it", glyph(Glyph::Warn).yellow(), path.display(), error, moved);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::test_support::scratch;
+
+    #[test]
+    fn test_corrupt_state_is_moved_aside() {
+        let dir = scratch("state");
+        let path = dir.join("status.json");
+
+        assert_eq!(read_json::<BTreeMap<String, u32>>(&path).unwrap(), None);
+        write_atomic(&path, "{\"a\": 1}").unwrap();
+        assert_eq!(read_json::<BTreeMap<String, u32>>(&path).unwrap(), Some(BTreeMap::from([("a".to_string(), 1)])));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temporary file left behind");
+
+        for garbage in [&b"{\"a\": "[..], &[0xff, 0xfe, 0x00][..]] {
+            fs::write(&path, garbage).unwrap();
+            assert_eq!(read_json::<BTreeMap<String, u32>>(&path).unwrap(), None);
+            assert!(!path.exists());
+            let aside: Vec<String> = fs::read_dir(&dir).unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+            assert!(aside.len() == 1 && aside[0].starts_with("status.json.corrupt-"), "{:?}", aside);
+            fs::remove_file(dir.join(&aside[0])).unwrap();
+        }
+    }
+}
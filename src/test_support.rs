@@ -0,0 +1,47 @@
+//! Helpers shared by the unit tests.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::utils::canonicalize;
+
+/// An empty directory for one test, removed with everything in it when dropped.
+/// Derefs to its canonical path, which the capability checks compare against.
+pub struct Scratch(PathBuf);
+
+/// A fresh `pavidi_<prefix>_…` directory under the system temp dir.
+pub fn scratch(prefix: &str) -> Scratch {
+    scratch_in(&std::env::temp_dir(), prefix)
+}
+
+/// A fresh `pavidi_<prefix>_…` directory in `parent`, for tests that need it
+/// inside the working directory.
+pub fn scratch_in(parent: &Path, prefix: &str) -> Scratch {
+    // Tests run in parallel threads of one process, so the pid alone is not enough
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = parent.join(format!("pavidi_{}_{}_{}", prefix, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Scratch(canonicalize(&dir).unwrap())
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Scratch {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,528 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::io::{self, ErrorKind, Write};
+use log::{Level, LevelFilter};
+use std::io::IsTerminal;
+use std::process::{Command, Stdio};
+use std::sync::{Once, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use serde::Deserialize;
+use crate::cli::ColorChoice;
+
+static STYLE: AtomicU8 = AtomicU8::new(UiStyle::Emoji as u8);
+static CI: OnceLock<CiMode> = OnceLock::new();
+static PAGER: AtomicBool = AtomicBool::new(true);
+
+/// How decorations are rendered: `[project] ui` or `P_UI`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UiStyle {
+    /// `⚡ Running task: build`
+    #[default]
+    Emoji,
+    /// `[run] Running task: build`; also `ascii = true` and `P_ASCII=1`
+    Plain,
+    /// `> Running task: build`
+    Minimal,
+}
+
+impl UiStyle {
+    pub fn parse(value: &str) -> Option<UiStyle> {
+        match value.to_ascii_lowercase().as_str() {
+            "emoji" => Some(UiStyle::Emoji),
+            "plain" | "ascii" => Some(UiStyle::Plain),
+            "minimal" => Some(UiStyle::Minimal),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UiStyle::Emoji => "emoji",
+            UiStyle::Plain => "plain",
+            UiStyle::Minimal => "minimal",
+        }
+    }
+
+    fn from_u8(value: u8) -> UiStyle {
+        match value {
+            1 => UiStyle::Plain,
+            2 => UiStyle::Minimal,
+            _ => UiStyle::Emoji,
+        }
+    }
+}
+
+/// Decorations used in console output, rendered in the current [`UiStyle`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Glyph {
+    Run,
+    Ok,
+    Skip,
+    Cached,
+    Fail,
+    Warn,
+    Retry,
+    Parallel,
+    Sequence,
+    Cleanup,
+    Log,
+    Trace,
+    Inspect,
+    Timer,
+    Wait,
+    Lock,
+    Env,
+    Extension,
+    Project,
+    Module,
+}
+
+impl Glyph {
+    fn emoji(self) -> &'static str {
+        match self {
+            Glyph::Run => "⚡",
+            Glyph::Ok => "✅",
+            Glyph::Skip => "⏭️",
+            Glyph::Cached => "✨",
+            Glyph::Fail => "❌",
+            Glyph::Warn => "⚠️",
+            Glyph::Retry => "🔄",
+            Glyph::Parallel => "🚀",
+            Glyph::Sequence => "🔗",
+            Glyph::Cleanup => "🧹",
+            Glyph::Log => "📝",
+            Glyph::Trace => "⚙️",
+            Glyph::Inspect => "🔍",
+            Glyph::Timer => "⏱️",
+            Glyph::Wait => "⏳",
+            Glyph::Lock => "🔒",
+            Glyph::Env => "🌿",
+            Glyph::Extension => "➕",
+            Glyph::Project => "📦",
+            Glyph::Module => "🧩",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Glyph::Run => "[run]",
+            Glyph::Ok => "[ok]",
+            Glyph::Skip => "[skip]",
+            Glyph::Cached => "[cached]",
+            Glyph::Fail => "[fail]",
+            Glyph::Warn => "[warn]",
+            Glyph::Retry => "[retry]",
+            Glyph::Parallel => "[deps]",
+            Glyph::Sequence => "[deps]",
+            Glyph::Cleanup => "[cleanup]",
+            Glyph::Log => "[log]",
+            Glyph::Trace => "[trace]",
+            Glyph::Inspect => "[inspect]",
+            Glyph::Timer => "[time]",
+            Glyph::Wait => "[wait]",
+            Glyph::Lock => "[net]",
+            Glyph::Env => "[env]",
+            Glyph::Extension => "[ext]",
+            Glyph::Project => "[project]",
+            Glyph::Module => "[module]",
+        }
+    }
+
+    fn mark(self) -> &'static str {
+        match self {
+            Glyph::Run | Glyph::Parallel | Glyph::Sequence => ">",
+            Glyph::Ok => "+",
+            Glyph::Skip | Glyph::Cleanup => "-",
+            Glyph::Cached => "=",
+            Glyph::Fail => "x",
+            Glyph::Warn => "!",
+            Glyph::Retry => "~",
+            Glyph::Log | Glyph::Lock => "#",
+            Glyph::Trace | Glyph::Inspect => "?",
+            Glyph::Timer | Glyph::Wait => ".",
+            Glyph::Env => "$",
+            Glyph::Extension | Glyph::Project | Glyph::Module => "*",
+        }
+    }
+
+    pub fn render(self, style: UiStyle) -> &'static str {
+        match style {
+            UiStyle::Emoji => self.emoji(),
+            UiStyle::Plain => self.tag(),
+            UiStyle::Minimal => self.mark(),
+        }
+    }
+}
+
+/// The decoration for `g` in the current output style.
+pub fn glyph(g: Glyph) -> &'static str {
+    g.render(style())
+}
+
+pub fn style() -> UiStyle {
+    UiStyle::from_u8(STYLE.load(Ordering::Relaxed))
+}
+
+/// Whether output is restricted to ASCII, as in the plain and minimal styles.
+pub fn ascii() -> bool {
+    style() != UiStyle::Emoji
+}
+
+/// Columns of the terminal on stdout: `COLUMNS` when set, else what the
+/// terminal reports. `None` when stdout is not a terminal, so piped output is
+/// never cut.
+pub fn terminal_width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    if let Some(columns) = env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return Some(columns);
+    }
+    #[cfg(unix)]
+    {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            return Some(size.ws_col as usize);
+        }
+    }
+    None
+}
+
+/// Rows of the terminal on stdout: `LINES` when set, else what the terminal
+/// reports. `None` when stdout is not a terminal.
+pub fn terminal_height() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    if let Some(lines) = env::var("LINES").ok().and_then(|l| l.parse().ok()) {
+        return Some(lines);
+    }
+    #[cfg(unix)]
+    {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_row > 0 {
+            return Some(size.ws_row as usize);
+        }
+    }
+    None
+}
+
+/// Turns the pager off, for `--no-pager` and `pager = false`.
+pub fn disable_pager() {
+    PAGER.store(false, Ordering::Relaxed);
+}
+
+/// Output meant for people, such as `p --list` and `p --env`. It is collected,
+/// then [`Pager::finish`] writes it to stdout, through `$PAGER` when stdout is
+/// a terminal that it would not fit on.
+#[derive(Debug, Default)]
+pub struct Pager {
+    buf: Vec<u8>,
+}
+
+impl Write for Pager {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Pager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows the output. A reader that goes away early, by quitting the pager
+    /// or closing the pipe (`p --list | head`), is not an error.
+    pub fn finish(self) -> Result<()> {
+        let rows = self.buf.iter().filter(|b| **b == b'\n').count();
+        if PAGER.load(Ordering::Relaxed)
+            && terminal_height().is_some_and(|height| rows >= height)
+            && let Some(pager) = pager_command(env::var("PAGER").ok())
+            && let Some(shown) = page_through(&pager, &self.buf) {
+            return shown;
+        }
+        let mut stdout = io::stdout().lock();
+        ignore_broken_pipe(stdout.write_all(&self.buf).and_then(|_| stdout.flush()))
+    }
+}
+
+/// `$PAGER`, split like a shell would, else `less -FRX` outside Windows. An
+/// empty `PAGER` means no pager.
+fn pager_command(pager: Option<String>) -> Option<Vec<String>> {
+    match pager {
+        Some(pager) => shell_words::split(&pager).ok().filter(|words| !words.is_empty()),
+        None if cfg!(windows) => None,
+        None => Some(vec!["less".to_string(), "-FRX".to_string()]),
+    }
+}
+
+/// Feeds `text` to `pager` and waits for it to exit. `None` when it cannot be
+/// started, so the output is printed instead.
+fn page_through(pager: &[String], text: &[u8]) -> Option<Result<()>> {
+    let mut child = Command::new(&pager[0]).args(&pager[1..]).stdin(Stdio::piped()).spawn().ok()?;
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(text),
+        None => Ok(()),
+    };
+    let waited = child.wait().with_context(|| format!("Failed to wait for the pager '{}'", pager[0]));
+    Some(ignore_broken_pipe(written).and(waited.map(|_| ())))
+}
+
+fn ignore_broken_pipe(result: io::Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result.context("Failed to write output"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CiMode {
+    None,
+    Generic,
+    GitHubActions,
+}
+
+fn is_truthy(value: Option<String>) -> bool {
+    value.is_some_and(|v| !v.is_empty() && v != "0" && !v.eq_ignore_ascii_case("false"))
+}
+
+fn detect_ci(github_actions: Option<String>, ci: Option<String>) -> CiMode {
+    if is_truthy(github_actions) {
+        CiMode::GitHubActions
+    } else if is_truthy(ci) {
+        CiMode::Generic
+    } else {
+        CiMode::None
+    }
+}
+
+pub fn ci_mode() -> CiMode {
+    *CI.get_or_init(|| detect_ci(env::var("GITHUB_ACTIONS").ok(), env::var("CI").ok()))
+}
+
+/// The style asked for by `P_UI`, or `P_ASCII=1`. `Err` holds an unknown `P_UI` value.
+fn env_style(p_ui: Option<String>, p_ascii: Option<String>) -> Result<Option<UiStyle>, String> {
+    match p_ui.filter(|v| !v.is_empty()) {
+        Some(value) => UiStyle::parse(&value).map(Some).ok_or(value),
+        None => Ok(is_truthy(p_ascii).then_some(UiStyle::Plain)),
+    }
+}
+
+/// Sets up the output style from the environment. Called once at startup.
+pub fn init() {
+    if let Ok(Some(style)) = env_style(env::var("P_UI").ok(), env::var("P_ASCII").ok()) {
+        STYLE.store(style as u8, Ordering::Relaxed);
+    }
+}
+
+/// Whether output is colored. `--color` wins, then NO_COLOR (any non-empty
+/// value), then CLICOLOR_FORCE. Otherwise colors need a terminal on both
+/// stdout (results) and stderr (progress), outside CI.
+fn use_color(choice: ColorChoice, no_color: Option<String>, clicolor_force: Option<String>, terminals: bool, ci: CiMode) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto if no_color.is_some_and(|v| !v.is_empty()) => false,
+        ColorChoice::Auto if is_truthy(clicolor_force) => true,
+        ColorChoice::Auto => terminals && ci == CiMode::None,
+    }
+}
+
+/// Applies `--color`. Called once the command line is parsed.
+pub fn init_color(choice: ColorChoice) {
+    let terminals = std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    let enabled = use_color(choice, env::var("NO_COLOR").ok(), env::var("CLICOLOR_FORCE").ok(), terminals, ci_mode());
+    colored::control::set_override(enabled);
+}
+
+/// Applies `ui` (or `ascii = true`) from the loaded configuration. `P_UI` and
+/// `P_ASCII` win over it.
+pub fn set_configured_style(configured: Option<UiStyle>) {
+    static UNKNOWN_NOTICE: Once = Once::new();
+    match env_style(env::var("P_UI").ok(), env::var("P_ASCII").ok()) {
+        Ok(Some(_)) => {},
+        Ok(None) => STYLE.store(configured.unwrap_or_default() as u8, Ordering::Relaxed),
+        Err(value) => {
+            UNKNOWN_NOTICE.call_once(|| log::warn!("{} Ignoring P_UI='{}': expected emoji, plain or minimal", glyph(Glyph::Warn), value));
+            STYLE.store(configured.unwrap_or_default() as u8, Ordering::Relaxed);
+        },
+    }
+}
+
+/// The log level selected by `-q`/`-v`, or `None` to keep the default
+/// (`warn`, or `P_LOG`/`RUST_LOG` when set).
+pub fn verbosity_level(verbose: u8, quiet: bool) -> Option<LevelFilter> {
+    match (quiet, verbose) {
+        (true, _) => Some(LevelFilter::Error),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Info),
+        (false, 2) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    }
+}
+
+/// Sets up the logger from `-v`/`-q` and `P_LOG`. Porcelain output (`--json`,
+/// `--export`, `--dotenv`) keeps stderr down to errors at any verbosity.
+pub fn init_logging(verbose: u8, quiet: bool, porcelain: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Warn);
+    if let Ok(spec) = env::var("P_LOG").or_else(|_| env::var("RUST_LOG")) {
+        builder.parse_filters(&spec);
+    }
+    if let Some(level) = verbosity_level(verbose, quiet) {
+        builder.filter_level(level);
+    }
+    // Progress messages carry their own glyphs; only debug output gets a tag
+    builder.format(|buf, record| match record.level() {
+        Level::Debug | Level::Trace => writeln!(buf, "[{}] {}", record.level().as_str().to_lowercase(), record.args()),
+        _ => writeln!(buf, "{}", record.args()),
+    });
+    if builder.try_init().is_ok() && porcelain {
+        log::set_max_level(log::max_level().min(LevelFilter::Error));
+    }
+}
+
+fn format_group_start(task: &str) -> String {
+    format!("::group::{}", escape_workflow_data(task))
+}
+
+/// Opens a collapsible log group for a task (GitHub Actions only).
+pub fn group_start(task: &str) {
+    if ci_mode() == CiMode::GitHubActions {
+        println!("{}", format_group_start(task));
+    }
+}
+
+pub fn group_end() {
+    if ci_mode() == CiMode::GitHubActions {
+        println!("::endgroup::");
+    }
+}
+
+// Workflow commands end at the first newline, so the message must be escaped
+fn escape_workflow_data(msg: &str) -> String {
+    msg.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn format_error_annotation(msg: &str) -> String {
+    format!("::error file=p.toml::{}", escape_workflow_data(msg))
+}
+
+/// Surfaces a failure as an error annotation on the workflow run (GitHub Actions only).
+pub fn annotate_error(msg: &str) {
+    if ci_mode() == CiMode::GitHubActions {
+        println!("{}", format_error_annotation(msg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_level() {
+        assert_eq!(verbosity_level(0, false), None);
+        assert_eq!(verbosity_level(1, false), Some(LevelFilter::Info));
+        assert_eq!(verbosity_level(2, false), Some(LevelFilter::Debug));
+        assert_eq!(verbosity_level(5, false), Some(LevelFilter::Trace));
+        assert_eq!(verbosity_level(0, true), Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn test_use_color() {
+        let auto = |no_color: Option<&str>, force: Option<&str>, terminals: bool, ci: CiMode| {
+            use_color(ColorChoice::Auto, no_color.map(String::from), force.map(String::from), terminals, ci)
+        };
+        assert!(auto(None, None, true, CiMode::None));
+        // `p build > build.txt`
+        assert!(!auto(None, None, false, CiMode::None));
+        assert!(!auto(None, None, true, CiMode::GitHubActions));
+        assert!(!auto(Some("1"), None, true, CiMode::None));
+        assert!(auto(Some(""), None, true, CiMode::None));
+        assert!(auto(None, Some("1"), false, CiMode::Generic));
+        assert!(!auto(None, Some("0"), false, CiMode::None));
+        assert!(!auto(Some("1"), Some("1"), true, CiMode::None));
+
+        assert!(use_color(ColorChoice::Always, Some("1".into()), None, false, CiMode::Generic));
+        assert!(!use_color(ColorChoice::Never, None, Some("1".into()), true, CiMode::None));
+    }
+
+    #[test]
+    fn test_detect_ci() {
+        assert_eq!(detect_ci(Some("true".into()), None), CiMode::GitHubActions);
+        assert_eq!(detect_ci(Some("1".into()), Some("1".into())), CiMode::GitHubActions);
+        assert_eq!(detect_ci(None, Some("1".into())), CiMode::Generic);
+        assert_eq!(detect_ci(None, Some("false".into())), CiMode::None);
+        assert_eq!(detect_ci(Some("0".into()), None), CiMode::None);
+        assert_eq!(detect_ci(None, None), CiMode::None);
+    }
+
+    #[test]
+    fn test_github_actions_output() {
+        assert_eq!(format_group_start("build"), "::group::build");
+        assert_eq!(
+            format_error_annotation("Task 'build' failed at: 'cargo build' -> Exit code 101"),
+            "::error file=p.toml::Task 'build' failed at: 'cargo build' -> Exit code 101"
+        );
+        assert_eq!(
+            format_error_annotation("100% broken\nsecond line\r"),
+            "::error file=p.toml::100%25 broken%0Asecond line%0D"
+        );
+    }
+
+    #[test]
+    fn test_ascii_glyphs() {
+        assert_eq!(Glyph::Run.render(UiStyle::Plain), "[run]");
+        assert_eq!(Glyph::Ok.render(UiStyle::Plain), "[ok]");
+        assert_eq!(Glyph::Skip.render(UiStyle::Plain), "[skip]");
+        assert_eq!(Glyph::Run.render(UiStyle::Emoji), "⚡");
+        assert_eq!(Glyph::Fail.render(UiStyle::Minimal), "x");
+        let all = [
+            Glyph::Run, Glyph::Ok, Glyph::Skip, Glyph::Cached, Glyph::Fail, Glyph::Warn, Glyph::Retry,
+            Glyph::Parallel, Glyph::Sequence, Glyph::Cleanup, Glyph::Log, Glyph::Trace, Glyph::Inspect,
+            Glyph::Timer, Glyph::Wait, Glyph::Lock, Glyph::Env, Glyph::Extension, Glyph::Project, Glyph::Module,
+        ];
+        for g in all {
+            assert!(g.render(UiStyle::Plain).is_ascii(), "{:?} is not ASCII", g);
+            assert_eq!(g.render(UiStyle::Minimal).len(), 1, "{:?} is not a single mark", g);
+        }
+    }
+
+    #[test]
+    fn test_env_style() {
+        let style = |p_ui: Option<&str>, p_ascii: Option<&str>| env_style(p_ui.map(String::from), p_ascii.map(String::from));
+        assert_eq!(style(None, None), Ok(None));
+        assert_eq!(style(None, Some("1")), Ok(Some(UiStyle::Plain)));
+        assert_eq!(style(Some("minimal"), Some("1")), Ok(Some(UiStyle::Minimal)));
+        assert_eq!(style(Some("Emoji"), None), Ok(Some(UiStyle::Emoji)));
+        assert_eq!(style(Some(""), None), Ok(None));
+        assert_eq!(style(Some("fancy"), None), Err("fancy".to_string()));
+    }
+
+    #[test]
+    fn test_pager_command() {
+        assert_eq!(pager_command(Some("most -s".into())), Some(vec!["most".to_string(), "-s".to_string()]));
+        assert_eq!(pager_command(Some("'/opt/my pager'".into())), Some(vec!["/opt/my pager".to_string()]));
+        assert_eq!(pager_command(Some("".into())), None);
+        assert_eq!(pager_command(None).is_some(), !cfg!(windows));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pager_quit_early() {
+        // Far more than a pipe holds, to a pager that reads one line and quits
+        let text = "line of output\n".repeat(100_000);
+        let pager = ["sh".to_string(), "-c".to_string(), "head -n 1 > /dev/null".to_string()];
+        assert!(page_through(&pager, text.as_bytes()).unwrap().is_ok());
+        // A pager that cannot be started leaves the output to stdout
+        assert!(page_through(&["p-no-such-pager".to_string()], b"x\n").is_none());
+        assert!(ignore_broken_pipe(Err(io::Error::from(ErrorKind::BrokenPipe))).is_ok());
+        assert!(ignore_broken_pipe(Err(io::Error::from(ErrorKind::PermissionDenied))).is_err());
+    }
+}
@@ -1,15 +1,172 @@
 use anyhow::{Context, Result, bail};
 use colored::*;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use indexmap::IndexMap;
 use std::process::{Command, Stdio};
 use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use log::{info, error};
 use wait_timeout::ChildExt;
 use std::time::Duration;
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, BufRead, Read};
 use regex::Regex;
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, LazyLock, Mutex};
+use crate::config::EnvPolicy;
+use crate::logger::{is_sensitive_key, LogStream, OutputFile};
+use crate::process::{self, ResourceLimits};
+use crate::runner::events::OutputStream;
+use crate::runner::output_filter::LineFilters;
+
+/// KB of output kept per stream in memory, unless `buffer_limit_kb` says otherwise.
+pub const DEFAULT_BUFFER_LIMIT_KB: usize = 512;
+
+/// How `--timestamps` stamps command output: wall clock or time since p started.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampStyle {
+    /// `14:02:11.532`
+    Clock,
+    /// `+12.345s`
+    Relative,
+}
+
+/// What relative stamps count from
+static STARTED: LazyLock<std::time::Instant> = LazyLock::new(std::time::Instant::now);
+
+/// Starts the clock relative stamps count from, if nothing has yet.
+pub fn start_clock() {
+    LazyLock::force(&STARTED);
+}
+
+/// The `[project]` settings that shape how commands run: read from the config
+/// once per load, and carried by the [`RunLog`](crate::logger::RunLog) of a run.
+#[derive(Debug, Clone)]
+pub struct CommandSettings {
+    /// Bytes of output kept per stream in memory (`buffer_limit_kb`)
+    pub buffer_limit: usize,
+    /// `timestamps`, or `--timestamps`
+    pub timestamps: Option<TimestampStyle>,
+    /// Stamp log files too (`log_timestamps`)
+    pub log_timestamps: bool,
+    /// From SIGTERM to SIGKILL when a command is stopped (`grace_period`)
+    pub grace_period: Duration,
+    /// `[env_policy]`, compiled; `None` passes all of p's environment on
+    pub env_filter: Option<EnvFilter>,
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        CommandSettings {
+            buffer_limit: DEFAULT_BUFFER_LIMIT_KB * 1024,
+            timestamps: None,
+            log_timestamps: false,
+            grace_period: process::DEFAULT_GRACE_PERIOD,
+            env_filter: None,
+        }
+    }
+}
+
+impl CommandSettings {
+    /// The stamped line for the terminal, and for the log with `log_timestamps`; `None` when not stamping.
+    fn stamped(&self, line: &str) -> (Option<String>, bool) {
+        match self.timestamps {
+            Some(style) => (Some(stamp_line(line, style, chrono::Local::now(), STARTED.elapsed())), self.log_timestamps),
+            None => (None, false),
+        }
+    }
+}
+
+/// `line` prefixed with its stamp, e.g. `14:02:11.532 compiling` or `+12.345s compiling`.
+pub fn stamp_line(line: &str, style: TimestampStyle, now: chrono::DateTime<chrono::Local>, elapsed: Duration) -> String {
+    match style {
+        TimestampStyle::Clock => format!("{} {}", now.format("%H:%M:%S%.3f").to_string().dimmed(), line),
+        TimestampStyle::Relative => format!("{} {}", format!("+{}.{:03}s", elapsed.as_secs(), elapsed.subsec_millis()).dimmed(), line),
+    }
+}
+
+/// `[env_policy]` with its patterns compiled.
+#[derive(Debug, Clone)]
+pub struct EnvFilter {
+    inherit: bool,
+    allow: Vec<glob::Pattern>,
+    deny: Vec<glob::Pattern>,
+}
+
+impl EnvFilter {
+    pub fn new(policy: &EnvPolicy) -> Result<Self> {
+        let compile = |patterns: &Option<Vec<String>>| -> Result<Vec<glob::Pattern>> {
+            patterns.iter().flatten()
+                .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid pattern '{}'", p)))
+                .collect()
+        };
+        Ok(EnvFilter { inherit: policy.inherit.unwrap_or(true), allow: compile(&policy.allow)?, deny: compile(&policy.deny)? })
+    }
+
+    /// Whether p's own variable `key` is passed on. PATH is, unless denied, since nothing resolves without it.
+    pub fn passes(&self, key: &str) -> bool {
+        let options = glob::MatchOptions { case_sensitive: !cfg!(windows), ..Default::default() };
+        let matches = |patterns: &[glob::Pattern]| patterns.iter().any(|p| p.matches_with(key, options));
+        !matches(&self.deny) && (self.inherit || key.eq_ignore_ascii_case("PATH") || matches(&self.allow))
+    }
+}
+
+/// Exactly the environment a task command receives: p's own environment, filtered
+/// by `filter` (`[env_policy]`), with `env` on top.
+pub fn child_environment(filter: Option<&EnvFilter>, env: &IndexMap<String, String>) -> HashMap<OsString, OsString> {
+    let mut child: HashMap<OsString, OsString> = env::vars_os()
+        .filter(|(k, _)| filter.is_none_or(|f| f.passes(&k.to_string_lossy())))
+        .collect();
+    child.extend(env.iter().map(|(k, v)| (k.into(), v.into())));
+    child
+}
+
+/// The last `limit` bytes of a stream, in whole lines.
+struct OutputTail {
+    lines: VecDeque<String>,
+    bytes: usize,
+    dropped: usize,
+    limit: usize,
+}
+
+impl OutputTail {
+    fn with_limit(limit: usize) -> Self {
+        OutputTail { lines: VecDeque::new(), bytes: 0, dropped: 0, limit }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.bytes += line.len() + 1;
+        self.lines.push_back(line.to_string());
+        while self.bytes > self.limit && self.lines.len() > 1 {
+            if let Some(old) = self.lines.pop_front() {
+                self.bytes -= old.len() + 1;
+                self.dropped += 1;
+            }
+        }
+    }
+
+    fn body(&self) -> String {
+        self.lines.iter().map(String::as_str).collect::<Vec<_>>().join("\n")
+    }
+
+    fn render(&self) -> String {
+        if self.dropped > 0 {
+            format!("... ({} earlier lines omitted)\n{}", self.dropped, self.body())
+        } else {
+            self.body()
+        }
+    }
+}
+
+/// First line of a Buffer-mode summary that lost its beginning.
+fn truncation_note(dropped: usize, full_log: Option<&Path>) -> String {
+    match full_log {
+        Some(path) => format!("… (output truncated, full log at {})", path.display()),
+        None => format!("… (output truncated, {} earlier lines omitted)", dropped),
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CaptureMode {
@@ -18,10 +175,120 @@ pub enum CaptureMode {
     Tee,
 }
 
+static ENV_VAR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{([a-zA-Z_][a-zA-Z0-9_]*)\}|\$([a-zA-Z_][a-zA-Z0-9_]*)").unwrap());
+
+/// How dash, bash, zsh and busybox sh report an unset variable under `set -u`.
+static UNSET_VAR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"([a-zA-Z_][a-zA-Z0-9_]*): (?:parameter not set|unbound variable)").unwrap());
+
+/// The variable a command failed on under `set -u`, read from its output.
+pub fn unset_variable(output: &str) -> Option<&str> {
+    UNSET_VAR_RE.captures(output).and_then(|caps| caps.get(1)).map(|m| m.as_str())
+}
+
+/// The shell running task commands: how it takes a command string and how an
+/// argument is quoted so it reaches the program unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShellKind {
+    /// sh, bash, zsh... including Git Bash and MSYS on Windows
+    Sh,
+    Cmd,
+    /// Windows PowerShell (`powershell.exe`)
+    PowerShell,
+    /// PowerShell 7+ (`pwsh`)
+    Pwsh,
+}
+
+impl ShellKind {
+    /// Detected from the file name alone, so a directory name cannot make Git Bash
+    /// (`C:\tools\cmder\vendor\git\bin\bash.exe`) look like cmd.
+    pub fn detect(shell: &str) -> Self {
+        let name = shell.rsplit(['/', '\\']).next().unwrap_or(shell).to_lowercase();
+        match name.strip_suffix(".exe").unwrap_or(&name) {
+            "cmd" => ShellKind::Cmd,
+            "powershell" => ShellKind::PowerShell,
+            "pwsh" => ShellKind::Pwsh,
+            _ => ShellKind::Sh,
+        }
+    }
+
+    /// Adds `cmd_str` to the shell invocation.
+    pub fn add_command(self, command: &mut Command, cmd_str: &str) {
+        match self {
+            ShellKind::Sh => {
+                command.arg("-c").arg(cmd_str);
+            },
+            ShellKind::Cmd => {
+                // With /s, cmd strips the outer quotes and runs the rest verbatim.
+                // Quoting it like a regular argument would escape inner quotes in a way cmd does not understand
+                command.args(["/d", "/s", "/c"]);
+                #[cfg(windows)]
+                std::os::windows::process::CommandExt::raw_arg(command, format!("\"{}\"", cmd_str));
+                #[cfg(not(windows))]
+                command.arg(cmd_str);
+            },
+            ShellKind::PowerShell | ShellKind::Pwsh => {
+                command.args(["-NoProfile", "-NonInteractive", "-Command", cmd_str]);
+            },
+        }
+    }
+
+    /// Quotes `arg` as a single argument, leaving plain words as they are.
+    pub fn quote(self, arg: &str) -> String {
+        match self {
+            ShellKind::Sh => shell_words::quote(arg).into_owned(),
+            ShellKind::Cmd => quote_cmd(arg),
+            ShellKind::PowerShell | ShellKind::Pwsh => {
+                let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@".contains(c));
+                if plain { arg.to_string() } else { format!("'{}'", arg.replace('\'', "''")) }
+            },
+        }
+    }
+}
+
+/// Quotes for the program's own argument parsing (the MSVC rules), then escapes
+/// everything cmd.exe would interpret (`%VAR%`, `&`, `|`, `^`...) with a caret.
+fn quote_cmd(arg: &str) -> String {
+    let quoted = if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '"']) {
+        arg.to_string()
+    } else {
+        let mut quoted = String::from('"');
+        let mut backslashes = 0;
+        for c in arg.chars() {
+            match c {
+                '\\' => backslashes += 1,
+                '"' => {
+                    quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                    backslashes = 0;
+                },
+                _ => {
+                    quoted.push_str(&"\\".repeat(backslashes));
+                    backslashes = 0;
+                },
+            }
+            if c != '\\' {
+                quoted.push(c);
+            }
+        }
+        quoted.push_str(&"\\".repeat(backslashes * 2));
+        quoted.push('"');
+        quoted
+    };
+    let mut escaped = String::with_capacity(quoted.len());
+    for c in quoted.chars() {
+        if "()%!^\"<>&|".contains(c) {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 /// Replaces $1, $2... with corresponding args.
 /// Then replaces ${VAR} or $VAR with values from env_vars.
-/// Fallback for args: If no placeholders found, append args to the end.
-pub fn expand_command(cmd_template: &str, args: &[String], env_vars: &HashMap<String, String>) -> String {
+/// Fallback for args: If no placeholders found, append args to the end, quoted for `shell`.
+pub fn expand_command(cmd_template: &str, args: &[String], env_vars: &IndexMap<String, String>, shell: ShellKind) -> String {
     let mut expanded = cmd_template.to_string();
     let mut replaced_args = false;
 
@@ -44,15 +311,15 @@ pub fn expand_command(cmd_template: &str, args: &[String], env_vars: &HashMap<St
 
         // Backward Compatibility: Append if no placeholders used (neither $@ nor $N)
         if !replaced_args {
-            expanded.push_str(" ");
-            expanded.push_str(&args.join(" "));
+            for arg in args {
+                expanded.push(' ');
+                expanded.push_str(&shell.quote(arg));
+            }
         }
     }
 
     // 2. Env Var Interpolation (${VAR} or $VAR)
-    let re = Regex::new(r"\$\{([a-zA-Z_][a-zA-Z0-9_]*)\}|\$([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-    
-    expanded = re.replace_all(&expanded, |caps: &regex::Captures| {
+    expanded = ENV_VAR_RE.replace_all(&expanded, |caps: &regex::Captures| {
         let key = caps.get(1).or_else(|| caps.get(2)).map(|m| m.as_str()).unwrap_or("");
         match env_vars.get(key) {
             Some(val) => val.to_string(),
@@ -63,24 +330,33 @@ pub fn expand_command(cmd_template: &str, args: &[String], env_vars: &HashMap<St
     expanded
 }
 
+/// Called with each line a command prints, from the threads reading its output.
+pub type LineSink = Arc<dyn Fn(&str, OutputStream) + Send + Sync>;
+
+/// Runs `cmd_str` through the shell. Output lines are written to the `log`
+/// stream, the task's `output` file and `on_line` as they arrive; only a bounded
+/// tail of the merged output (`buffer_limit_kb`) is kept in memory and returned.
+/// `filters` only change what is shown on the console; the others see every line.
+/// `Inherit` mode has no pipes, so nothing reaches them.
+#[allow(clippy::too_many_arguments)]
 pub fn run_shell_command(
     cmd_str: &str, 
-    env_vars: &HashMap<String, String>, 
+    env_vars: &IndexMap<String, String>, 
+    settings: &CommandSettings,
     mode: CaptureMode,
     task_label: &str,
     shell_cmd: &str,
-    timeout: Option<Duration>
+    timeout: Option<Duration>,
+    log: Option<Arc<LogStream>>,
+    output: Option<Arc<OutputFile>>,
+    limits: Option<&ResourceLimits>,
+    on_line: Option<LineSink>,
+    filters: Option<Arc<LineFilters>>,
 ) -> Result<(i32, String)> {
-    let flag = if shell_cmd.contains("cmd") && !shell_cmd.contains("sh") { 
-        "/C" 
-    } else { 
-        "-c" 
-    };
-
     let mut command = Command::new(shell_cmd);
-    command.arg(flag)
-           .arg(cmd_str)
-           .envs(env_vars)
+    ShellKind::detect(shell_cmd).add_command(&mut command, cmd_str);
+    command.env_clear()
+           .envs(child_environment(settings.env_filter.as_ref(), env_vars))
            .stdin(Stdio::inherit()); 
 
     match mode {
@@ -94,65 +370,75 @@ pub fn run_shell_command(
         }
     }
 
+    if let Some(limits) = limits {
+        limits.apply(&mut command);
+    }
+
+    // Own process group where possible, so a timeout stops the command's children too
+    let isolated = process::should_isolate(mode);
+    if isolated {
+        process::isolate(&mut command);
+    }
+
     let mut child = command.spawn().context("Failed to spawn shell process")?;
+    let _tracked = process::Tracked::new(&child, isolated);
     
-    // For logging (merged)
-    let captured_log = Arc::new(Mutex::new(String::new()));
+    // Bounded tail of the merged output; the full stream goes to the log file
+    let merged_tail = Arc::new(Mutex::new(OutputTail::with_limit(settings.buffer_limit)));
     
-    // For Buffer mode printing (separated)
-    let captured_stdout = if mode == CaptureMode::Buffer { Some(Arc::new(Mutex::new(String::new()))) } else { None };
-    let captured_stderr = if mode == CaptureMode::Buffer { Some(Arc::new(Mutex::new(String::new()))) } else { None };
+    // For Buffer mode printing (separated, bounded)
+    let captured_stdout = if mode == CaptureMode::Buffer { Some(Arc::new(Mutex::new(OutputTail::with_limit(settings.buffer_limit)))) } else { None };
+    let captured_stderr = if mode == CaptureMode::Buffer { Some(Arc::new(Mutex::new(OutputTail::with_limit(settings.buffer_limit)))) } else { None };
 
     let mut threads = vec![];
 
     if mode != CaptureMode::Inherit {
-        if let Some(stdout) = child.stdout.take() {
-            let log_clone = captured_log.clone();
-            let buf_clone = captured_stdout.clone();
+        let stdout = child.stdout.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+        let stderr = child.stderr.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+
+        for (pipe, buf_clone, is_stderr) in [(stdout, captured_stdout.clone(), false), (stderr, captured_stderr.clone(), true)] {
+            let Some(pipe) = pipe else { continue };
+            let tail_clone = merged_tail.clone();
+            let stream_clone = log.clone();
+            let output_clone = output.clone();
+            let on_line_clone = on_line.clone();
+            let filters_clone = filters.clone();
             let mode_clone = mode;
+            let settings_clone = settings.clone();
             threads.push(thread::spawn(move || {
-                let reader = BufReader::new(stdout);
-                for line in reader.lines() {
-                    if let Ok(l) = line {
-                        if mode_clone == CaptureMode::Tee {
-                            println!("{}", l);
-                        }
-                        
-                        let mut g_log = log_clone.lock().unwrap();
-                        g_log.push_str(&l);
-                        g_log.push('\n');
-
-                        if let Some(buf) = &buf_clone {
-                            let mut g_buf = buf.lock().unwrap();
-                            g_buf.push_str(&l);
-                            g_buf.push('\n');
+                let reader = BufReader::new(pipe);
+                for l in reader.lines().map_while(Result::ok) {
+                    // Stamps go to the terminal; the log keeps the raw line unless log_timestamps
+                    let (stamped, stamp_log) = settings_clone.stamped(&l);
+                    let shown = stamped.as_deref().unwrap_or(&l);
+                    let filtered = match &filters_clone {
+                        Some(filters) => filters.apply(&l, shown),
+                        None => Some(Cow::Borrowed(shown)),
+                    };
+                    if let Some(filtered) = &filtered
+                        && mode_clone == CaptureMode::Tee {
+                        let shown = filtered.as_ref();
+                        if is_stderr {
+                            eprintln!("{}", shown);
+                        } else {
+                            println!("{}", shown);
                         }
                     }
-                }
-            }));
-        }
-        
-        if let Some(stderr) = child.stderr.take() {
-            let log_clone = captured_log.clone();
-            let buf_clone = captured_stderr.clone();
-            let mode_clone = mode;
-            threads.push(thread::spawn(move || {
-                let reader = BufReader::new(stderr);
-                for line in reader.lines() {
-                    if let Ok(l) = line {
-                        if mode_clone == CaptureMode::Tee {
-                            eprintln!("{}", l);
-                        }
 
-                        let mut g_log = log_clone.lock().unwrap();
-                        g_log.push_str(&l);
-                        g_log.push('\n');
+                    if let Some(stream) = &stream_clone {
+                        stream.write_line(if stamp_log { shown } else { &l });
+                    }
+                    if let Some(output) = &output_clone {
+                        output.write_line(&l);
+                    }
+                    if let Some(on_line) = &on_line_clone {
+                        on_line(&l, if is_stderr { OutputStream::Stderr } else { OutputStream::Stdout });
+                    }
+                    tail_clone.lock().unwrap().push(&l);
 
-                        if let Some(buf) = &buf_clone {
-                            let mut g_buf = buf.lock().unwrap();
-                            g_buf.push_str(&l);
-                            g_buf.push('\n');
-                        }
+                    if let Some(buf) = &buf_clone
+                        && let Some(filtered) = &filtered {
+                        buf.lock().unwrap().push(filtered);
                     }
                 }
             }));
@@ -164,43 +450,52 @@ pub fn run_shell_command(
             match child.wait_timeout(t).context("Failed to wait on child")? {
                 Some(status) => status,
                 None => {
-                    let _ = child.kill();
-                    child.wait().context("Failed to wait on killed child")?;
+                    process::terminate(&mut child, isolated, settings.grace_period);
                     bail!("Execution timed out after {:?}", t);
                 }
             }
         },
         None => child.wait().context("Failed to wait on child")?,
     };
+    process::settle_interrupt();
 
     // Wait for readers to finish
     for t in threads {
         let _ = t.join();
     }
 
+    let code = status.code().unwrap_or(1);
+
     let final_log = if mode != CaptureMode::Inherit {
-        let log = captured_log.lock().unwrap().clone();
+        let merged = merged_tail.lock().unwrap().render();
 
         if mode == CaptureMode::Buffer {
-             if let Some(stdout_buf) = captured_stdout {
-                 let s = stdout_buf.lock().unwrap();
-                 if !s.trim().is_empty() {
-                     info!("[{}] {}", task_label.cyan(), s.trim());
-                 }
-             }
-             if let Some(stderr_buf) = captured_stderr {
-                 let s = stderr_buf.lock().unwrap();
-                 if !s.trim().is_empty() {
-                     error!("[{}] {}", task_label.red(), s.trim());
-                 }
-             }
+            let full_log = log.as_ref().and_then(|stream| stream.final_path(code));
+            for (buf, is_stderr) in [(captured_stdout, false), (captured_stderr, true)] {
+                let Some(buf) = buf else { continue };
+                let tail = buf.lock().unwrap();
+                let mut s = tail.body().trim().to_string();
+                if s.is_empty() {
+                    continue;
+                }
+                if tail.dropped > 0 {
+                    s = format!("{}\n{}", truncation_note(tail.dropped, full_log.as_deref()), s);
+                }
+                if is_stderr {
+                    error!("[{}] {}", task_label.red(), s);
+                } else {
+                    info!("[{}] {}", task_label.cyan(), s);
+                }
+            }
         }
-        log
+        merged
     } else {
         String::new()
     };
 
-    let code = status.code().unwrap_or(1);
+    if let Some(reason) = limits.and_then(|l| l.memory_failure(&status, &final_log)) {
+        bail!(reason);
+    }
     
     if !status.success() {
          return Ok((code, final_log));
@@ -209,6 +504,61 @@ pub fn run_shell_command(
     Ok((0, final_log))
 }
 
+/// `fs::canonicalize`, without the `\\?\` prefix Windows adds where the plain
+/// form means the same path. Child programs and `starts_with` checks against
+/// `allow_paths` entries expect `C:\dir`, not `\\?\C:\dir`.
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    let canonical = std::fs::canonicalize(path)?;
+    if cfg!(windows)
+        && let Some(plain) = canonical.to_str().and_then(strip_verbatim) {
+        return Ok(PathBuf::from(plain));
+    }
+    Ok(canonical)
+}
+
+/// `\\?\C:\dir` -> `C:\dir` and `\\?\UNC\server\share` -> `\\server\share`, when the
+/// result names the same file: short enough, and no component the plain form
+/// would reinterpret (device names, trailing dots or spaces, `.`/`..`).
+fn strip_verbatim(path: &str) -> Option<String> {
+    const MAX_PATH: usize = 260;
+    const DEVICES: &[&str] = &["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+    let (plain, rest) = if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        (format!(r"\\{}", unc), unc)
+    } else {
+        let rest = path.strip_prefix(r"\\?\")?;
+        let bytes = rest.as_bytes();
+        if bytes.len() < 3 || !bytes[0].is_ascii_alphabetic() || bytes[1] != b':' || bytes[2] != b'\\' {
+            return None;
+        }
+        (rest.to_string(), &rest[3..])
+    };
+    if plain.len() >= MAX_PATH {
+        return None;
+    }
+    let safe = rest.split('\\').filter(|c| !c.is_empty()).all(|component| {
+        let stem = component.split('.').next().unwrap_or_default();
+        component != "." && component != ".."
+            && !component.ends_with(['.', ' '])
+            && !component.contains(['/', ':', '*', '?', '"', '<', '>', '|'])
+            && !DEVICES.iter().any(|d| d.eq_ignore_ascii_case(stem.trim_end()))
+    });
+    safe.then_some(plain)
+}
+
+/// How `env` differs from the process environment `base`, one sorted line per
+/// variable: `+KEY=value` for new ones, `~KEY=value` for changed ones.
+/// Sensitive values are redacted.
+pub fn env_diff(env: &IndexMap<String, String>, base: &IndexMap<String, String>) -> Vec<String> {
+    let mut keys: Vec<&String> = env.keys().filter(|k| base.get(*k) != env.get(*k)).collect();
+    keys.sort();
+    keys.into_iter().map(|k| {
+        let mark = if base.contains_key(k) { '~' } else { '+' };
+        let value = if is_sensitive_key(k) { "[REDACTED]" } else { env[k].as_str() };
+        format!("{}{}={}", mark, k, value)
+    }).collect()
+}
+
 pub fn detect_shell(config_shell: Option<&String>) -> String {
     if let Some(s) = config_shell {
         return s.clone();
@@ -234,13 +584,180 @@ pub fn detect_shell(config_shell: Option<&String>) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_stamp_line() {
+        use chrono::TimeZone;
+        let now = chrono::Local.with_ymd_and_hms(2024, 5, 1, 14, 2, 11).unwrap() + chrono::Duration::milliseconds(7);
+        let plain = |style, elapsed| crate::logger::strip_ansi(&stamp_line("compiling", style, now, elapsed));
+        assert_eq!(plain(TimestampStyle::Clock, Duration::ZERO), "14:02:11.007 compiling");
+        assert_eq!(plain(TimestampStyle::Relative, Duration::from_millis(12_345)), "+12.345s compiling");
+        assert_eq!(plain(TimestampStyle::Relative, Duration::from_millis(40)), "+0.040s compiling");
+    }
+
+    #[test]
+    fn test_strip_verbatim() {
+        assert_eq!(strip_verbatim(r"\\?\C:\Users\dev\app").as_deref(), Some(r"C:\Users\dev\app"));
+        assert_eq!(strip_verbatim(r"\\?\UNC\server\share\src").as_deref(), Some(r"\\server\share\src"));
+        assert_eq!(strip_verbatim(r"C:\already\plain"), None);
+        // The plain form would name something else, or nothing
+        assert_eq!(strip_verbatim(r"\\?\C:\logs\nul.txt"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\dir\trailing."), None);
+        assert_eq!(strip_verbatim(r"\\?\Volume{1234}\dir"), None);
+        assert_eq!(strip_verbatim(&format!(r"\\?\C:\{}", "a".repeat(300))), None);
+    }
+
+    #[test]
+    fn test_output_tail_is_bounded() {
+        let mut tail = OutputTail::with_limit(64 * 1024);
+        let line = "x".repeat(1023);
+        for _ in 0..200 {
+            tail.push(&line);
+        }
+        assert!(tail.bytes <= 64 * 1024);
+        assert_eq!(tail.lines.len() + tail.dropped, 200);
+        assert!(tail.render().starts_with(&format!("... ({} earlier lines omitted)", tail.dropped)));
+
+        assert_eq!(truncation_note(3, Some(Path::new(".p/logs/x.log"))), "… (output truncated, full log at .p/logs/x.log)");
+        assert_eq!(truncation_note(3, None), "… (output truncated, 3 earlier lines omitted)");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_buffered_output_keeps_only_the_tail() {
+        // About 1.3 MB of output against the default 512 KB limit
+        let (code, output) = run_shell_command("seq 1 200000", &IndexMap::new(), &CommandSettings::default(), CaptureMode::Buffer, "t", "sh", None, None, None, None, None, None).unwrap();
+        assert_eq!(code, 0);
+        assert!(output.len() <= DEFAULT_BUFFER_LIMIT_KB * 1024 + 64, "{} bytes", output.len());
+        assert!(output.starts_with("... ("), "{}", &output[..40]);
+        assert!(output.ends_with("\n200000"));
+    }
+
+    #[test]
+    fn test_env_diff() {
+        let base = IndexMap::from([("PATH".to_string(), "/bin".to_string()), ("HOME".to_string(), "/root".to_string())]);
+        let env = IndexMap::from([
+            ("PATH".to_string(), "/opt/bin:/bin".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+            ("MODE".to_string(), "dev".to_string()),
+            ("API_TOKEN".to_string(), "hunter2".to_string()),
+        ]);
+        assert_eq!(env_diff(&env, &base), ["+API_TOKEN=[REDACTED]", "+MODE=dev", "~PATH=/opt/bin:/bin"]);
+    }
+
+    #[test]
+    fn test_env_filter_patterns() {
+        let filter = |toml_src: &str| EnvFilter::new(&toml::from_str::<EnvPolicy>(toml_src).unwrap()).unwrap();
+        let deny = filter(r#"deny = ["AWS_*", "SSH_AUTH_SOCK"]"#);
+        assert!(!deny.passes("AWS_SECRET_ACCESS_KEY"));
+        assert!(!deny.passes("SSH_AUTH_SOCK"));
+        assert!(deny.passes("HOME"));
+
+        let closed = filter(r#"inherit = false
+allow = ["HOME", "LC_*"]"#);
+        assert!(closed.passes("PATH") && closed.passes("HOME") && closed.passes("LC_ALL"));
+        assert!(!closed.passes("AWS_PROFILE"));
+        assert!(!filter(r#"deny = ["PATH"]"#).passes("PATH"));
+        assert!(EnvFilter::new(&toml::from_str::<EnvPolicy>(r#"deny = ["[x"]"#).unwrap()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_child_environment_under_policies() {
+        let filter = |toml_src: &str| EnvFilter::new(&toml::from_str::<EnvPolicy>(toml_src).unwrap()).unwrap();
+        // Any inherited variable besides PATH will do
+        let inherited = env::vars().map(|(k, _)| k).find(|k| k != "PATH" && !k.starts_with("AWS_")).unwrap();
+        let env = IndexMap::from([("AWS_PROFILE".to_string(), "ci".to_string())]);
+        let child_keys = |filter: Option<&EnvFilter>| -> HashSet<String> {
+            let out = Command::new("env").env_clear().envs(child_environment(filter, &env)).output().unwrap();
+            String::from_utf8_lossy(&out.stdout).lines().filter_map(|l| l.split_once('=')).map(|(k, _)| k.to_string()).collect()
+        };
+
+        let open = child_keys(None);
+        assert!(open.contains(&inherited) && open.contains("PATH") && open.contains("AWS_PROFILE"));
+
+        // [env] values are passed even when they match deny
+        let denied = child_keys(Some(&filter(&format!(r#"deny = ["{}", "AWS_*"]"#, inherited))));
+        assert!(!denied.contains(&inherited) && denied.contains("PATH") && denied.contains("AWS_PROFILE"));
+
+        let closed = child_keys(Some(&filter("inherit = false")));
+        assert_eq!(closed, HashSet::from(["PATH".to_string(), "AWS_PROFILE".to_string()]));
+
+        let allowed = child_keys(Some(&filter(&format!("inherit = false\nallow = [\"{}\"]", inherited))));
+        assert_eq!(allowed, HashSet::from(["PATH".to_string(), "AWS_PROFILE".to_string(), inherited]));
+    }
+
+    #[test]
+    fn test_shell_kind_detect() {
+        assert_eq!(ShellKind::detect("/bin/bash"), ShellKind::Sh);
+        assert_eq!(ShellKind::detect("sh"), ShellKind::Sh);
+        assert_eq!(ShellKind::detect(r"C:\Program Files\Git\bin\bash.exe"), ShellKind::Sh);
+        assert_eq!(ShellKind::detect(r"C:\tools\cmder\vendor\git\bin\bash.exe"), ShellKind::Sh);
+        assert_eq!(ShellKind::detect("cmd"), ShellKind::Cmd);
+        assert_eq!(ShellKind::detect(r"C:\Windows\System32\CMD.EXE"), ShellKind::Cmd);
+        assert_eq!(ShellKind::detect("powershell"), ShellKind::PowerShell);
+        assert_eq!(ShellKind::detect(r"C:\Program Files\PowerShell\7\pwsh.exe"), ShellKind::Pwsh);
+    }
+
+    #[test]
+    fn test_shell_kind_quote() {
+        assert_eq!(ShellKind::Sh.quote("--release"), "--release");
+        assert_eq!(ShellKind::Sh.quote("two words"), "'two words'");
+        assert_eq!(ShellKind::Sh.quote("it's"), r"'it'\''s'");
+
+        assert_eq!(ShellKind::Cmd.quote("--release"), "--release");
+        assert_eq!(ShellKind::Cmd.quote("two words"), r#"^"two words^""#);
+        assert_eq!(ShellKind::Cmd.quote("%PATH%"), "^%PATH^%");
+        assert_eq!(ShellKind::Cmd.quote("a&b|c^d"), "a^&b^|c^^d");
+        assert_eq!(ShellKind::Cmd.quote(r#"say "hi""#), r#"^"say \^"hi\^"^""#);
+        assert_eq!(ShellKind::Cmd.quote(r"C:\dir with space\"), r#"^"C:\dir with space\\^""#);
+        assert_eq!(ShellKind::Cmd.quote(""), r#"^"^""#);
+
+        assert_eq!(ShellKind::Pwsh.quote("-Verbose"), "-Verbose");
+        assert_eq!(ShellKind::PowerShell.quote("it's $HOME"), "'it''s $HOME'");
+        assert_eq!(ShellKind::Pwsh.quote(""), "''");
+    }
+
+    #[test]
+    fn test_expand_command_quotes_appended_args() {
+        let args = vec!["two words".to_string(), "100%".to_string()];
+        let env = IndexMap::new();
+        assert_eq!(expand_command("echo", &args, &env, ShellKind::Sh), "echo 'two words' '100%'");
+        assert_eq!(expand_command("echo", &args, &env, ShellKind::Cmd), r#"echo ^"two words^" 100^%"#);
+        assert_eq!(expand_command("echo", &args, &env, ShellKind::Pwsh), "echo 'two words' '100%'");
+        // Placeholders are substituted as written
+        assert_eq!(expand_command("echo $1", &args, &env, ShellKind::Sh), "echo two words");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_shells_receive_args_unchanged() {
+        let args = vec!["a&b".to_string(), "%PATH%".to_string(), "say \"hi\"".to_string()];
+        let env: IndexMap<String, String> = std::env::vars().collect();
+        let cmd = expand_command("echo", &args, &env, ShellKind::Cmd);
+        let (code, out) = run_shell_command(&cmd, &env, &CommandSettings::default(), CaptureMode::Buffer, "t", "cmd", None, None, None, None, None, None).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(out.trim(), r#"a&b %PATH% "say \"hi\"""#);
+
+        // URLs and forward slashes reach the program as written
+        let url = vec!["https://example.com/a/b".to_string(), "src/main.rs".to_string()];
+        let cmd = expand_command("echo", &url, &env, ShellKind::Cmd);
+        let (_, out) = run_shell_command(&cmd, &env, &CommandSettings::default(), CaptureMode::Buffer, "t", "cmd", None, None, None, None, None, None).unwrap();
+        assert_eq!(out.trim(), "https://example.com/a/b src/main.rs");
+
+        let cmd = expand_command("Write-Output", &args, &env, ShellKind::PowerShell);
+        let (code, out) = run_shell_command(&cmd, &env, &CommandSettings::default(), CaptureMode::Buffer, "t", "powershell", None, None, None, None, None, None).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(out.lines().collect::<Vec<_>>(), ["a&b", "%PATH%", "say \"hi\""]);
+    }
 
     #[test]
     fn test_expand_command_legacy_append() {
         let cmd = "echo hello";
         let args = vec!["world".to_string()];
-        let env = HashMap::new();
-        let expanded = expand_command(cmd, &args, &env);
+        let env = IndexMap::new();
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo hello world");
     }
 
@@ -248,8 +765,8 @@ mod tests {
     fn test_expand_command_positional_args() {
         let cmd = "echo $1 $2";
         let args = vec!["hello".to_string(), "world".to_string()];
-        let env = HashMap::new();
-        let expanded = expand_command(cmd, &args, &env);
+        let env = IndexMap::new();
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo hello world");
     }
 
@@ -257,8 +774,8 @@ mod tests {
     fn test_expand_command_splat_args() {
         let cmd = "echo $@ end";
         let args = vec!["hello".to_string(), "world".to_string()];
-        let env = HashMap::new();
-        let expanded = expand_command(cmd, &args, &env);
+        let env = IndexMap::new();
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo hello world end");
     }
 
@@ -266,8 +783,8 @@ mod tests {
     fn test_expand_command_splat_args_no_args() {
         let cmd = "echo $@ end";
         let args = vec![];
-        let env = HashMap::new();
-        let expanded = expand_command(cmd, &args, &env);
+        let env = IndexMap::new();
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo  end"); // Note the double space, depends on join empty logic
     }
     
@@ -275,8 +792,8 @@ mod tests {
     fn test_expand_command_splat_overrides_append() {
         let cmd = "echo $@";
         let args = vec!["hello".to_string()];
-        let env = HashMap::new();
-        let expanded = expand_command(cmd, &args, &env);
+        let env = IndexMap::new();
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo hello"); 
         // Should NOT be "echo hello hello"
     }
@@ -285,9 +802,9 @@ mod tests {
     fn test_expand_command_env_vars() {
         let cmd = "echo $MY_VAR";
         let args = vec![];
-        let mut env = HashMap::new();
+        let mut env = IndexMap::new();
         env.insert("MY_VAR".to_string(), "value".to_string());
-        let expanded = expand_command(cmd, &args, &env);
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo value");
     }
     
@@ -295,9 +812,9 @@ mod tests {
     fn test_expand_command_mixed_splat_and_env() {
         let cmd = "echo $@ $MY_VAR";
         let args = vec!["arg1".to_string()];
-        let mut env = HashMap::new();
+        let mut env = IndexMap::new();
         env.insert("MY_VAR".to_string(), "value".to_string());
-        let expanded = expand_command(cmd, &args, &env);
+        let expanded = expand_command(cmd, &args, &env, ShellKind::Sh);
         assert_eq!(expanded, "echo arg1 value");
     }
 }
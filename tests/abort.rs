@@ -0,0 +1,47 @@
+//! `p` stopped by SIGTERM, as when CI cancels a job.
+#![cfg(unix)]
+
+mod common;
+
+use common::Project;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn sigterm_runs_finally_and_on_abort() {
+    let dir = Project::new("abort", r#"
+[project]
+name = "abort"
+grace_period = "1s"
+on_abort = ["echo aborted > sentinel"]
+
+[runner.deploy]
+cmds = ["touch started; sleep 30", "touch never"]
+finally = ["touch finally"]
+"#);
+
+    let p = dir.p(&["deploy"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !dir.join("started").exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(dir.join("started").exists(), "the task never started");
+
+    let started = Instant::now();
+    Command::new("kill").args(["-TERM", &p.id().to_string()]).status().unwrap();
+    let output = p.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(128 + 15), "{}", stderr);
+    assert!(started.elapsed() < Duration::from_secs(10), "p did not stop the running command");
+    assert!(stderr.contains("stopped by SIGTERM"), "{}", stderr);
+    assert_eq!(dir.read("sentinel"), "aborted\n");
+    assert!(dir.join("finally").exists());
+    assert!(!dir.join("never").exists());
+}
@@ -0,0 +1,44 @@
+//! `p artifacts` builds what is stale, then gathers the declared files with a
+//! manifest; `--check` fails once a source is newer than an artifact.
+
+mod common;
+
+use common::{stderr, Project};
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn artifacts_are_built_collected_and_checked() {
+    let dir = Project::new("artifacts_cli", r#"
+[runner.build]
+cmds = ["p:mkdir -p out", "p:cp src/app.txt out/app.bin"]
+sources = ["src/*.txt"]
+outputs = ["out/app.bin"]
+artifacts = ["out/app.bin"]
+
+[runner.release]
+deps = ["build"]
+cmds = ["p:mkdir -p dist", "p:cp out/app.bin dist/app.tar.gz"]
+artifacts = ["dist/*.tar.gz"]
+"#);
+    dir.write("src/app.txt", "app");
+
+    let missing = dir.run(&["artifacts", "--check"]);
+    assert!(!missing.status.success());
+    assert!(stderr(&missing).contains("'out/app.bin' of task 'build' matched no files"));
+
+    let collected = dir.run(&["artifacts", "release", "--out", "bundle"]);
+    assert!(collected.status.success(), "{}", stderr(&collected));
+    assert_eq!(dir.read("bundle/dist/app.tar.gz"), "app");
+    let manifest: serde_json::Value = serde_json::from_str(&dir.read("bundle/manifest.json")).unwrap();
+    let paths: Vec<&str> = manifest["files"].as_array().unwrap().iter().map(|f| f["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["out/app.bin", "dist/app.tar.gz"]);
+    assert!(dir.run(&["artifacts", "--check"]).status.success());
+
+    // A source edited after the build makes the binary stale
+    let later = SystemTime::now() + Duration::from_secs(60);
+    fs::File::options().write(true).open(dir.join("src/app.txt")).unwrap().set_modified(later).unwrap();
+    let stale = dir.run(&["artifacts", "--check"]);
+    assert!(!stale.status.success());
+    assert!(stderr(&stale).contains("'out/app.bin' of task 'build' is older than its source src/app.txt"));
+}
@@ -0,0 +1,51 @@
+//! `log_mode = "combined"`: one log per run, listed by `p logs` like any other.
+
+mod common;
+
+use common::{text, Project};
+use std::fs;
+
+const CONFIG: &str = r#"
+[project]
+log_strategy = "always"
+log_mode = "combined"
+
+[runner.setup]
+cmds = ["echo preparing"]
+
+[runner.build]
+deps = ["setup"]
+cmds = ["echo compiling", "exit 3"]
+"#;
+
+fn p(dir: &Project, args: &[&str]) -> (bool, String) {
+    let output = dir.run(args);
+    (output.status.success(), text(&output))
+}
+
+#[test]
+fn combined_runs_are_listed() {
+    let dir = Project::new("combined_logs", CONFIG);
+
+    let (ok, out) = p(&dir, &["build"]);
+    assert!(!ok, "{}", out);
+    let (ok, out) = p(&dir, &["setup"]);
+    assert!(ok, "{}", out);
+
+    // One file per run directly under the date, nothing under running/
+    let date_dir = fs::read_dir(dir.join(".p/logs")).unwrap().next().unwrap().unwrap().path();
+    let files: Vec<String> = fs::read_dir(&date_dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    assert_eq!(files.len(), 2, "{:?}", files);
+    assert!(files.iter().all(|f| f.ends_with(".log") && !f.starts_with('.')), "{:?}", files);
+
+    let (ok, out) = p(&dir, &["--color", "never", "logs"]);
+    assert!(ok, "{}", out);
+    assert!(out.contains("build") && out.contains("exit 1") && out.contains("setup") && out.contains("exit 0"), "{}", out);
+    let (ok, out) = p(&dir, &["--color", "never", "logs", "--failed"]);
+    assert!(ok, "{}", out);
+    assert!(out.contains("build") && !out.contains("setup"), "{}", out);
+    // Both runs may start within the same second, so look the failed one up by name
+    let build = files.iter().find(|f| f.ends_with("_build.log")).unwrap().trim_end_matches(".log");
+    let (ok, out) = p(&dir, &["--color", "never", "logs", "show", build]);
+    assert!(ok && out.contains("compiling") && out.contains("preparing"), "{}", out);
+}
@@ -0,0 +1,94 @@
+//! Setup shared by the integration tests: a project in a scratch directory and
+//! the `p` binary run inside it.
+// Each test file is its own crate and uses only some of these
+#![allow(dead_code)]
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// The `p` binary under test.
+pub const P: &str = env!("CARGO_BIN_EXE_p");
+
+/// A fresh project directory, removed with everything in it when dropped.
+/// Derefs to its path.
+pub struct Project(PathBuf);
+
+impl Project {
+    /// `pavidi_<name>_<pid>` in the temp dir, with `config` as its `p.toml`.
+    pub fn new(name: &str, config: &str) -> Project {
+        let project = Project::empty(name);
+        project.write("p.toml", config);
+        project
+    }
+
+    /// `pavidi_<name>_<pid>` in the temp dir, with nothing in it.
+    pub fn empty(name: &str) -> Project {
+        let dir = std::env::temp_dir().join(format!("pavidi_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Project(dir)
+    }
+
+    /// Writes `file`, relative to the project, creating its directory.
+    pub fn write(&self, file: &str, content: impl AsRef<[u8]>) {
+        let path = self.0.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// The text of `file`, relative to the project.
+    pub fn read(&self, file: &str) -> String {
+        fs::read_to_string(self.0.join(file)).unwrap()
+    }
+
+    /// `p` with `args`, run in the project. Settings the test machine may have
+    /// exported (`P_ENV`, `P_UI`, ...) are removed, and errors carry no backtrace.
+    pub fn p(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(P);
+        command.args(args).current_dir(&self.0).env("RUST_BACKTRACE", "0");
+        for var in ["P_ENV", "P_UI", "P_ASCII", "P_LOG_DIR", "GITHUB_ACTIONS"] {
+            command.env_remove(var);
+        }
+        command
+    }
+
+    /// Runs `p` with `args` in the project to the end.
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.p(args).output().unwrap()
+    }
+}
+
+impl Deref for Project {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Project {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// stdout followed by stderr.
+pub fn text(output: &Output) -> String {
+    format!("{}{}", stdout(output), stderr(output))
+}
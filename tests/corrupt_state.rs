@@ -0,0 +1,79 @@
+//! Damaged files under `.p/` are moved aside once, and runs carry on without them.
+
+mod common;
+
+use common::{text, Project};
+use std::fs;
+
+const CONFIG: &str = r#"
+[runner.gen]
+cmds = ["echo gen > out.txt"]
+sources = ["in.txt"]
+outputs = ["out.txt"]
+
+[runner.build]
+deps = ["gen"]
+cmds = ["echo building", "echo linking"]
+"#;
+
+fn p(dir: &Project, args: &[&str]) -> (bool, String) {
+    let output = dir.run(args);
+    (output.status.success(), text(&output))
+}
+
+#[test]
+fn corrupt_state_files_do_not_break_runs() {
+    let dir = Project::new("corrupt_state", CONFIG);
+    dir.write("in.txt", "a\n");
+    let (ok, out) = p(&dir, &["build"]);
+    assert!(ok, "{}", out);
+    let (ok, out) = p(&dir, &["-e", "--set", "REGION=eu"]);
+    assert!(ok, "{}", out);
+
+    // What a killed run or a bad disk could leave behind: truncated and garbage files
+    let state = dir.join(".p");
+    fs::create_dir_all(state.join("resume")).unwrap();
+    let damaged: [(&str, &[u8]); 6] = [
+        ("cache/gen.hash", &b"3f2a9c"[..]),
+        ("history.json", &b"{\"version\": 1, \"tasks\": {\"gen\": {\"sta"[..]),
+        ("status.json", &[0xff, 0x00, 0x13, 0x37][..]),
+        ("last-plan.json", &b""[..]),
+        ("resume/build.json", &b"not json at all"[..]),
+        ("session-env.toml", &b"REGION = \"e"[..]),
+    ];
+    for (file, content) in damaged {
+        assert!(file == "resume/build.json" || state.join(file).exists(), "{} was never written", file);
+        fs::write(state.join(file), content).unwrap();
+    }
+
+    // As if there were no status file: `p status` has no run to report
+    let (ok, out) = p(&dir, &["status"]);
+    assert!(!ok && out.contains("status.json is corrupt") && out.contains("No run recorded yet"), "{}", out);
+    let readers: [&[&str]; 2] = [&["--list", "--status"], &["build", "--dry-run", "--diff"]];
+    for args in readers {
+        let (ok, out) = p(&dir, args);
+        assert!(ok, "p {:?}: {}", args, out);
+    }
+    let (ok, out) = p(&dir, &["build", "--resume"]);
+    assert!(ok, "{}", out);
+    let (ok, out) = p(&dir, &["-e", "--set", "REGION=us"]);
+    assert!(ok, "{}", out);
+    for args in [&["--list", "--status"][..], &["status"][..], &["-e"][..]] {
+        let (ok, out) = p(&dir, args);
+        assert!(ok, "p {:?}: {}", args, out);
+        assert!(!out.contains("is corrupt"), "warned again: {}", out);
+    }
+
+    // Each file was moved aside exactly once, next to where it was
+    for (file, _) in damaged {
+        let path = state.join(file);
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let aside: Vec<_> = fs::read_dir(path.parent().unwrap()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with(&format!("{}.corrupt-", name)))
+            .collect();
+        assert_eq!(aside.len(), 1, "{}: {:?}", file, aside);
+    }
+    let (_, env) = p(&dir, &["-e"]);
+    assert!(env.contains("REGION") && env.contains("us"), "{}", env);
+}
@@ -0,0 +1,41 @@
+//! `p -e --use` saves a profile in `.p/profile`; runs pick it up unless `P_ENV`
+//! is set, and `p -e` says which profile is active and why.
+
+mod common;
+
+use common::{stderr, stdout, Project};
+
+#[test]
+fn saved_profile_yields_to_p_env() {
+    let dir = Project::new("env_profile", "[runner]\nshow = \"echo profile=$P_ENV mode=$MODE\"\n");
+    dir.write(".env.staging", "MODE=stage\n");
+    dir.write(".env.prod", "MODE=prod\n");
+    let p = |args: &[&str], p_env: Option<&str>| {
+        let mut command = dir.p(args);
+        if let Some(value) = p_env {
+            command.env("P_ENV", value);
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        stdout(&output)
+    };
+
+    // Nothing saved, nothing exported
+    assert!(p(&["-e"], None).contains("Profile: none"));
+    assert!(p(&["show"], None).contains("profile= mode=\n"));
+    assert_eq!(p(&["-e", "--profiles"], None), "  prod\n  staging\n");
+
+    p(&["-e", "--use", "staging"], None);
+    assert_eq!(dir.read(".p/profile"), "staging\n");
+    assert!(p(&["-e"], None).contains("Profile: staging (from .p/profile)"));
+    assert!(p(&["show"], None).contains("profile=staging mode=stage\n"));
+    assert_eq!(p(&["-e", "--profiles"], None), "  prod\n* staging\n");
+
+    // An explicit P_ENV wins over the saved profile
+    assert!(p(&["-e"], Some("prod")).contains("Profile: prod (from P_ENV)"));
+    assert!(p(&["show"], Some("prod")).contains("profile=prod mode=prod\n"));
+
+    p(&["-e", "--unset"], None);
+    assert!(!dir.join(".p/profile").exists());
+    assert!(p(&["show"], None).contains("profile= mode=\n"));
+}
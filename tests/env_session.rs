@@ -0,0 +1,34 @@
+//! `p -e --set` saves variables in `.p/session-env.toml`, layered over `[env]`
+//! and under `.env`; `--unset-var` removes them again.
+
+mod common;
+
+use common::{stderr, Project};
+
+#[test]
+fn session_vars_round_trip() {
+    let dir = Project::new("env_session", "[env]\nREGION = \"eu\"\nMODE = \"base\"\n\n[runner]\nshow = \"echo region=$REGION mode=$MODE\"\n");
+    dir.write(".env", "MODE=dotenv\n");
+    let stdout = |args: &[&str]| {
+        let output = dir.run(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        common::stdout(&output)
+    };
+
+    stdout(&["-e", "--set", "REGION=us", "--set", "MODE=session"]);
+    assert_eq!(dir.read(".p/session-env.toml"), "MODE = \"session\"\nREGION = \"us\"\n");
+    // Over p.toml, under .env
+    assert!(stdout(&["show"]).contains("region=us mode=dotenv\n"));
+    assert!(stdout(&["-e", "--trace"]).contains("session"));
+
+    // Secrets never reach the file
+    let refused = dir.run(&["-e", "--set", "API_TOKEN=abc"]);
+    assert!(!refused.status.success());
+    assert!(stderr(&refused).contains("looks like a secret"));
+    assert!(!dir.read(".p/session-env.toml").contains("API_TOKEN"));
+
+    stdout(&["-e", "--unset-var", "REGION"]);
+    assert!(stdout(&["show"]).contains("region=eu mode=dotenv\n"));
+    stdout(&["-e", "--unset-var", "MODE"]);
+    assert!(!dir.join(".p/session-env.toml").exists());
+}
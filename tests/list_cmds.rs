@@ -0,0 +1,51 @@
+//! `--list-cmds` prints exactly what a run hands to the shell.
+#![cfg(unix)]
+
+mod common;
+
+use common::{stderr, stdout, Project};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+#[test]
+fn list_cmds_matches_what_a_run_executes() {
+    let dir = Project::empty("list_cmds");
+    // A shell that writes down every command it is given, then runs it
+    let record = dir.join("executed.txt");
+    let stub = dir.join("record-sh");
+    fs::write(&stub, format!("#!/bin/sh\nprintf '%s\\n' \"$2\" >> '{}'\nexec /bin/sh -c \"$2\"\n", record.display())).unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+    dir.write("p.toml", format!(r#"
+[project]
+shell = "{}"
+
+[env]
+TARGET = "staging"
+
+[runner.prepare]
+cmds = ["echo preparing"]
+
+[runner.deploy]
+deps = ["prepare"]
+cmds = ["echo deploying to ${{TARGET}} as $1", "echo all: $@", "echo done"]
+"#, stub.display()));
+    let p = |args: &[&str]| {
+        let output = dir.run(args);
+        (output.status.success(), stdout(&output), stderr(&output))
+    };
+
+    let (ok, listed, stderr) = p(&["deploy", "--list-cmds", "--", "alice", "two words"]);
+    assert!(ok, "{}", stderr);
+    assert!(!record.exists(), "--list-cmds ran something");
+    assert_eq!(listed.lines().count(), 3, "{}", listed);
+
+    let (ok, _, stderr) = p(&["deploy", "--", "alice", "two words"]);
+    assert!(ok, "{}", stderr);
+    let executed = fs::read_to_string(&record).unwrap();
+    // The run also executes its dependency first; the task's own commands follow
+    assert_eq!(executed.strip_prefix("echo preparing\n").unwrap(), listed);
+
+    let (ok, _, stderr) = p(&["missing", "--list-cmds"]);
+    assert!(!ok);
+    assert!(stderr.contains("Task 'missing' not found"), "{}", stderr);
+}
@@ -0,0 +1,55 @@
+//! `p --list --status` reads the cache and `.p/history.json`; plain `--list` reads neither.
+
+mod common;
+
+use common::{stderr, Project};
+
+#[test]
+fn list_status_shows_cache_and_history() {
+    let dir = Project::new("list_status", r#"
+[runner.cached]
+cmds = ["p:cp in.txt cached.txt"]
+sources = ["in.txt"]
+outputs = ["cached.txt"]
+
+[runner.stale]
+cmds = ["p:cp in.txt stale.txt"]
+sources = ["in.txt"]
+outputs = ["stale.txt"]
+
+[runner.pack]
+cmds = ["p:cp in.txt out.txt"]
+require_files = ["assets/*.svg"]
+"#);
+    dir.write("in.txt", "a");
+    let p = |args: &[&str]| {
+        let output = dir.run(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    p(&["cached"]);
+    // A history from earlier runs, one of them long ago
+    dir.write(".p/history.json", r#"{
+  "version": 1,
+  "tasks": {
+    "cached": { "status": "passed", "finished": "2024-05-01T12:00:00+00:00", "run_id": "old" },
+    "stale": { "status": "failed", "finished": "2024-05-01T12:00:00+00:00", "run_id": "old" }
+  }
+}"#);
+
+    let json: serde_json::Value = serde_json::from_str(&p(&["--list", "--status", "--json"])).unwrap();
+    let state = |i: usize| &json[i]["state"];
+    assert_eq!((json[0]["name"].as_str(), &state(0)["up_to_date"], &state(0)["last_status"]), (Some("cached"), &true.into(), &"passed".into()));
+    assert_eq!((json[2]["name"].as_str(), &state(2)["up_to_date"], &state(2)["last_status"]), (Some("stale"), &false.into(), &"failed".into()));
+    assert_eq!((&state(1)["up_to_date"], &state(1)["last_status"], &state(1)["missing_files"]), (&serde_json::Value::Null, &serde_json::Value::Null, &serde_json::json!(["assets/*.svg"])));
+
+    let text = p(&["--list", "--status", "--color", "never"]);
+    let line = |name: &str| text.lines().find(|l| l.trim_start().starts_with(name)).unwrap().to_string();
+    assert!(line("cached").starts_with("  cached  ● ok ") && line("cached").ends_with("d ago"), "{}", text);
+    assert!(line("stale").starts_with("  stale   ○ failed ") && line("stale").ends_with("d ago"), "{}", text);
+    assert!(line("pack").starts_with("  pack      never run") && line("pack").ends_with("missing assets/*.svg"), "{}", text);
+
+    // The plain listing is unchanged
+    assert!(!p(&["--list", "--json"]).contains("\"state\""));
+}
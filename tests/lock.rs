@@ -0,0 +1,57 @@
+//! Two `p` runs of the same project at once.
+#![cfg(unix)]
+
+mod common;
+
+use common::{stderr, Project, P};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn p(dir: &Project, args: &[&str]) -> Command {
+    let mut command = dir.p(args);
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command
+}
+
+fn wait_for(path: &Path) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !path.exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(path.exists(), "{} never appeared", path.display());
+}
+
+#[test]
+fn second_run_fails_fast_or_waits() {
+    let dir = Project::new("lock", r#"
+[project]
+name = "lock"
+
+[runner]
+slow = "touch started; sleep 1; echo slow >> order.txt"
+quick = "echo quick >> order.txt"
+nested = "p quick"
+"#);
+
+    let first = p(&dir, &["slow"]).spawn().unwrap();
+    wait_for(&dir.join("started"));
+    let started = Instant::now();
+    let failed = p(&dir, &["quick"]).output().unwrap();
+    assert!(!failed.status.success());
+    assert!(started.elapsed() < Duration::from_secs(1), "did not fail fast");
+    assert!(stderr(&failed).contains("The project is locked by 'slow' (pid"), "{}", stderr(&failed));
+
+    let waited = p(&dir, &["quick", "--wait"]).output().unwrap();
+    assert!(waited.status.success(), "{}", stderr(&waited));
+    assert!(first.wait_with_output().unwrap().status.success());
+    // The waiting run only started once the first had finished
+    assert_eq!(dir.read("order.txt"), "slow\nquick\n");
+
+    // A task that runs p runs under its parent's lock
+    let nested = p(&dir, &["nested"])
+        .env("PATH", format!("{}:{}", Path::new(P).parent().unwrap().display(), std::env::var("PATH").unwrap()))
+        .output().unwrap();
+    assert!(nested.status.success(), "{}", stderr(&nested));
+}
@@ -0,0 +1,52 @@
+//! `output_filters` change what the console shows, never what is recorded.
+
+mod common;
+
+use common::{stderr, stdout, Project};
+
+const CONFIG: &str = r#"
+[runner.build]
+cmds = ["echo 'Compiling serde' && echo 'warning: unused import' && echo 'Finished'"]
+output_file = "build.txt"
+output_filters = [
+    { match = "^Compiling ", action = "suppress" },
+    { match = "^warning:", action = "highlight-yellow" },
+]
+
+[runner.check]
+cmds = ["echo 'ERROR: disk full' && exit 0", "echo 'after the failure'"]
+output_filters = [{ match = "^ERROR", action = "fail" }]
+"#;
+
+#[test]
+fn filters_shape_the_console_only() {
+    let dir = Project::new("output_filters", CONFIG);
+    let p = |args: &[&str]| {
+        let output = dir.run(args);
+        (output.status.success(), stdout(&output), stderr(&output))
+    };
+
+    // suppress and highlight-<color>
+    let (ok, stdout, stderr) = p(&["--color", "always", "build"]);
+    assert!(ok, "{}", stderr);
+    assert!(!stdout.contains("Compiling"), "{}", stdout);
+    let warning = stdout.lines().find(|l| l.contains("warning: unused import")).unwrap();
+    assert!(warning.starts_with("\x1b[") && warning.contains("33"), "{:?}", warning);
+    assert!(stdout.lines().any(|l| l == "Finished"), "{}", stdout);
+    // The output file gets every line, uncolored
+    assert_eq!(dir.read("build.txt"), "Compiling serde\nwarning: unused import\nFinished\n");
+
+    // fail: the command exited 0, but the task fails and stops there
+    let (ok, stdout, stderr) = p(&["--color", "never", "check"]);
+    assert!(!ok);
+    assert!(stdout.contains("ERROR: disk full"), "{}", stdout);
+    assert!(!stdout.contains("after the failure"), "{}", stdout);
+    assert!(stderr.contains("Output matched fail filter '^ERROR': ERROR: disk full"), "{}", stderr);
+
+    // A bad pattern is a config error, before anything runs
+    dir.write("p.toml", "[runner.build]\ncmds = [\"echo hi\"]\noutput_filters = [{ match = \"[a-\", action = \"suppress\" }]\n");
+    let (ok, stdout, stderr) = p(&["build"]);
+    assert!(!ok);
+    assert!(!stdout.contains("hi"), "{}", stdout);
+    assert!(stderr.contains("invalid regex '[a-'"), "{}", stderr);
+}
@@ -0,0 +1,34 @@
+//! Long `p --list` and `p --env` output, read by something that stops early.
+
+mod common;
+
+use common::{stderr, Project};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+
+#[test]
+fn reader_closing_early_is_not_an_error() {
+    // Far more than a pipe holds, so p is still writing when the reader goes away
+    let mut config = String::from("[env]\n");
+    for i in 0..2000 {
+        writeln!(config, "VAR_{} = \"{}\"", i, "v".repeat(100)).unwrap();
+    }
+    for i in 0..2000 {
+        writeln!(config, "[runner.task-{}]\ncmds = [\"true\"]\ndescription = \"{}\"", i, "d".repeat(100)).unwrap();
+    }
+    let dir = Project::new("pager", &config);
+
+    for args in [&["--list"][..], &["--env"][..], &["--env", "--trace"][..]] {
+        let mut child = dir.p(args).env("PAGER", "cat")
+            .stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        // What `p --list | head -n 1` does
+        let mut first = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut first).unwrap();
+        assert!(!first.is_empty());
+        let output = child.wait_with_output().unwrap();
+        let stderr = stderr(&output);
+        assert!(output.status.success(), "p {:?}: {}", args, stderr);
+        assert!(!stderr.contains("panicked"), "p {:?}: {}", args, stderr);
+    }
+}
@@ -0,0 +1,86 @@
+//! `--progress-fd`/`--progress-pipe` stream one JSON event per line, in the
+//! order a wrapper UI needs: a task's commands and output between its start
+//! and finish, dependencies before the task that needs them.
+
+mod common;
+
+use common::{stderr, Project};
+use serde_json::Value;
+
+fn project(name: &str) -> Project {
+    Project::new(&format!("progress_{}", name), r#"
+[runner.gen]
+cmds = ["echo generated"]
+
+[runner.build]
+deps = ["gen"]
+cmds = ["echo built >&2"]
+"#)
+}
+
+fn events(text: &str) -> Vec<String> {
+    text.lines().map(|line| {
+        let event: Value = serde_json::from_str(line).unwrap();
+        let kind = event["event"].as_str().unwrap();
+        match kind {
+            "command_started" => format!("{} {}", kind, event["command"].as_str().unwrap()),
+            "output_chunk" => format!("{} {} {}", kind, event["stream"].as_str().unwrap(), event["line"].as_str().unwrap()),
+            "task_finished" => format!("{} {} {}", kind, event["task"].as_str().unwrap(), event["status"].as_str().unwrap()),
+            _ => format!("{} {}", kind, event["task"].as_str().unwrap()),
+        }
+    }).collect()
+}
+
+const EXPECTED: [&str; 8] = [
+    "task_started build",
+    "task_started gen",
+    "command_started echo generated",
+    "output_chunk stdout generated",
+    "task_finished gen passed",
+    "command_started echo built >&2",
+    "output_chunk stderr built",
+    "task_finished build passed",
+];
+
+#[cfg(unix)]
+#[test]
+fn progress_fd_streams_events_in_order() {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let dir = project("fd");
+    let (mut reader, writer) = std::io::pipe().unwrap();
+    let fd = writer.as_raw_fd();
+    let mut cmd = dir.p(&["build", "--progress-fd", "3"]);
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::dup2(fd, 3) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = cmd.stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped()).spawn().unwrap();
+    drop(cmd);
+    drop(writer);
+    let mut text = String::new();
+    reader.read_to_string(&mut text).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(events(&text), EXPECTED);
+    // The console output is still there
+    assert!(String::from_utf8_lossy(&out.stdout).contains("generated"));
+
+    let bad = dir.run(&["build", "--progress-fd", "1"]);
+    assert!(!bad.status.success());
+    assert!(stderr(&bad).contains("use 3 or higher"));
+}
+
+#[test]
+fn progress_pipe_writes_to_a_path() {
+    let dir = project("pipe");
+    let out = dir.run(&["build", "--progress-pipe", "events.jsonl"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(events(&dir.read("events.jsonl")), EXPECTED);
+}
@@ -0,0 +1,99 @@
+//! `extends_remote` files are fetched once, verified against their sha256 and
+//! merged like an extension; a tampered file stops the run.
+#![cfg(feature = "net")]
+
+mod common;
+
+use common::{text, Project};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use pavidi::remote_config::sha256_hex;
+
+const BASE: &str = "[env]\nREGION = \"eu\"\n\n[runner]\nlint = \"echo lint from base in $REGION\"\n";
+
+/// Serves the current body at every path until the test process exits.
+fn serve(body: &str) -> (String, Arc<Mutex<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/rust-base.toml", listener.local_addr().unwrap());
+    let body = Arc::new(Mutex::new(body.to_string()));
+    let served = body.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+            let body = served.lock().unwrap().clone();
+            let mut stream = reader.into_inner();
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        }
+    });
+    (url, body)
+}
+
+#[test]
+fn remote_includes_are_pinned_and_cached() {
+    let project = Project::empty("remote_extends");
+    // The download cache lives next to the project, not in the user's
+    let cache = Project::empty("remote_extends_cache");
+    let (url, body) = serve(BASE);
+    let hash = sha256_hex(BASE.as_bytes()).unwrap();
+    let write_config = |url: &str, hash: &str| {
+        project.write("p.toml", format!("extends_remote = [{{ url = \"{}\", sha256 = \"{}\" }}]\n\n[env]\nREGION = \"us\"\n", url, hash));
+    };
+    let p = |args: &[&str]| -> Output {
+        project.p(args).env("P_CACHE_DIR", &*cache).output().unwrap()
+    };
+
+    // Listing and completion never download: a cold include is left out with a warning
+    write_config(&url, &hash);
+    for args in [&["--list"][..], &["__complete-tasks"][..]] {
+        let listed = p(args);
+        assert!(listed.status.success(), "{}", text(&listed));
+        assert!(!text(&listed).contains("lint"), "{}", text(&listed));
+    }
+    assert!(text(&p(&["--list"])).contains("is not cached yet, so its tasks are left out"));
+    assert!(!cache.join("remote").join(format!("{}.toml", hash)).exists());
+
+    // Fetched on first use, merged over p.toml, and named by its URL
+    let run = p(&["lint"]);
+    assert!(run.status.success(), "{}", text(&run));
+    assert!(text(&run).contains("lint from base in eu"), "{}", text(&run));
+    assert!(cache.join("remote").join(format!("{}.toml", hash)).exists());
+    assert!(text(&p(&["-e", "--trace"])).contains(&url));
+
+    // Offline with a warm cache
+    let dead = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/rust-base.toml", listener.local_addr().unwrap())
+    };
+    write_config(&dead, &hash);
+    assert!(p(&["lint"]).status.success());
+
+    // Offline with nothing cached
+    write_config(&dead, &"0".repeat(64));
+    let cold = p(&["lint"]);
+    assert!(!cold.status.success());
+    assert!(text(&cold).contains("is not cached yet and could not be downloaded"), "{}", text(&cold));
+
+    // Tampered upstream: a refresh fails and the verified copy stays in use
+    *body.lock().unwrap() = BASE.replace("echo lint", "echo pwned");
+    write_config(&url, &hash);
+    let refreshed = p(&["fetch-remotes", "--refresh"]);
+    assert!(!refreshed.status.success());
+    assert!(text(&refreshed).contains("Checksum mismatch"), "{}", text(&refreshed));
+    assert!(text(&p(&["lint"])).contains("lint from base in eu"));
+
+    // Pinning the new content fetches it; no pin is refused
+    let tampered = BASE.replace("echo lint", "echo pwned");
+    write_config(&url, &sha256_hex(tampered.as_bytes()).unwrap());
+    let fetched = p(&["fetch-remotes"]);
+    assert!(text(&fetched).contains("downloaded, sha256 verified"), "{}", text(&fetched));
+    project.write("p.toml", format!("extends_remote = [{{ url = \"{}\" }}]\n", url));
+    let unpinned = p(&["lint"]);
+    assert!(!unpinned.status.success());
+    assert!(text(&unpinned).contains("has no sha256"), "{}", text(&unpinned));
+}
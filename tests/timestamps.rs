@@ -0,0 +1,35 @@
+//! `--timestamps` stamps task output, never machine-readable output.
+
+mod common;
+
+use common::{stderr, Project};
+
+#[test]
+fn timestamps_stamp_task_output_only() {
+    let dir = Project::new("timestamps", r#"
+[project]
+name = "stamps"
+
+[env]
+MODE = "dev"
+
+[runner]
+hello = "echo hello"
+"#);
+    let p = |args: &[&str]| {
+        let output = dir.run(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let stamped = p(&["--timestamps=relative", "--color", "never", "hello"]);
+    let (stamp, rest) = stamped.trim_end().split_once(' ').unwrap();
+    assert_eq!(rest, "hello");
+    assert!(stamp.starts_with('+') && stamp.ends_with('s'), "{}", stamped);
+
+    for args in [&["--list"][..], &["-e"], &["--info"], &["hello", "--dry-run"]] {
+        let args = [&["--timestamps", "--json"], args].concat();
+        let json = p(&args);
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok(), "{:?}: {}", args, json);
+    }
+}
@@ -0,0 +1,98 @@
+//! The same run in each `ui` style, line for line.
+
+mod common;
+
+use common::Project;
+
+/// stderr of a first run, a cached run and a failing run in a fresh project, with run IDs masked.
+fn progress(config: &str, p_ui: Option<&str>) -> String {
+    let dir = Project::new("ui_styles", config);
+    dir.write("in.txt", "a\n");
+    let mut stderr = String::new();
+    for task in ["build", "build", "broken"] {
+        let mut command = dir.p(&["-v", "--color", "never", task]);
+        if let Some(style) = p_ui {
+            command.env("P_UI", style);
+        }
+        let output = command.output().unwrap();
+        stderr.push_str(&common::stderr(&output));
+    }
+    stderr.lines()
+        .map(|line| match line.split_once(" Run ") {
+            Some((mark, rest)) if rest.ends_with(" failed") => format!("{} Run <id> failed", mark),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const TASKS: &str = r#"
+[runner.gen]
+cmds = ["echo gen > out.txt"]
+sources = ["in.txt"]
+outputs = ["out.txt"]
+
+[runner.build]
+deps = ["gen"]
+cmds = ["echo building"]
+
+[runner.broken]
+cmds = ["exit 3"]
+"#;
+
+const EMOJI: &str = "\
+🔗 Running dependencies sequentially...
+⚡ Running task: gen
+:: Executing: echo gen > out.txt
+⚡ Running task: build
+:: Executing: echo building
+🔗 Running dependencies sequentially...
+✨ Task 'gen' is up-to-date. Skipping.
+⚡ Running task: build
+:: Executing: echo building
+⚡ Running task: broken
+:: Executing: exit 3
+📝 Run <id> failed
+Error: ❌ Task 'broken' failed at: 'exit 3' -> Exit code 3";
+
+const PLAIN: &str = "\
+[deps] Running dependencies sequentially...
+[run] Running task: gen
+:: Executing: echo gen > out.txt
+[run] Running task: build
+:: Executing: echo building
+[deps] Running dependencies sequentially...
+[cached] Task 'gen' is up-to-date. Skipping.
+[run] Running task: build
+:: Executing: echo building
+[run] Running task: broken
+:: Executing: exit 3
+[log] Run <id> failed
+Error: [fail] Task 'broken' failed at: 'exit 3' -> Exit code 3";
+
+const MINIMAL: &str = "\
+> Running dependencies sequentially...
+> Running task: gen
+:: Executing: echo gen > out.txt
+> Running task: build
+:: Executing: echo building
+> Running dependencies sequentially...
+= Task 'gen' is up-to-date. Skipping.
+> Running task: build
+:: Executing: echo building
+> Running task: broken
+:: Executing: exit 3
+# Run <id> failed
+Error: x Task 'broken' failed at: 'exit 3' -> Exit code 3";
+
+#[test]
+fn each_style_renders_the_same_run() {
+    assert_eq!(progress(TASKS, None), EMOJI);
+    assert_eq!(progress(TASKS, Some("plain")), PLAIN);
+    assert_eq!(progress(TASKS, Some("minimal")), MINIMAL);
+
+    // From p.toml; `ascii = true` is the plain style, and P_UI wins over both
+    assert_eq!(progress(&format!("[project]\nui = \"minimal\"\n{}", TASKS), None), MINIMAL);
+    assert_eq!(progress(&format!("[project]\nascii = true\n{}", TASKS), None), PLAIN);
+    assert_eq!(progress(&format!("[project]\nui = \"minimal\"\n{}", TASKS), Some("emoji")), EMOJI);
+}
@@ -0,0 +1,37 @@
+//! `wait_for` blocks a task until a service's files appear, and `p` exits with
+//! 124 when it gives up, even from a parallel dependency.
+
+mod common;
+
+use common::{stderr, Project};
+
+#[test]
+fn wait_for_timeout_exits_124() {
+    let dir = Project::new("wait_for", r#"
+[runner.service]
+cmds = ["sleep 0.2", "touch tmp.ready"]
+
+[runner.e2e]
+cmds = ["echo e2e"]
+wait_for = { files = ["tmp.ready"], timeout = "5s", poll = "50ms" }
+
+[runner.ci]
+deps = ["service", "e2e"]
+parallel = true
+
+[runner.stuck]
+cmds = ["echo never"]
+wait_for = { files = ["never.ready"], timeout = "200ms" }
+
+[runner.ci-stuck]
+deps = ["stuck", "e2e"]
+parallel = true
+"#);
+
+    let ok = dir.run(&["ci"]);
+    assert!(ok.status.success(), "{}", stderr(&ok));
+
+    let stuck = dir.run(&["ci-stuck"]);
+    assert_eq!(stuck.status.code(), Some(124), "{}", stderr(&stuck));
+    assert!(stderr(&stuck).contains("gave up after 200ms waiting for: never.ready"));
+}